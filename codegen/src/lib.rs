@@ -127,6 +127,13 @@ fn valuety(value: &spec::Value, pubvis: bool, super_name: &str) -> String {
     )
 }
 
+// variants that can be told apart by their type alone
+fn unique_variants(variants: &[spec::Value]) -> impl Iterator<Item=&spec::Value> {
+    variants.iter().filter(move |variant|
+        variants.iter().filter(|v| typename(&v.ty) == typename(&variant.ty)).count() == 1
+    )
+}
+
 struct Context<W> {
     includes: Vec<String>,
     included: HashSet<PathBuf>,
@@ -207,6 +214,9 @@ impl ::qapi_spec::Command for {} {{
                     writeln!(self.out, "::qapi_spec::Empty;")
                 }?;
                 writeln!(self.out, "}}")?;
+                if v.allow_oob {
+                    writeln!(self.out, "impl ::qapi_spec::OobCommand for {} {{ }}", type_id)?;
+                }
             },
             Spec::Struct(v) => {
                 self.types.insert(v.id.clone(), v);
@@ -217,6 +227,7 @@ impl ::qapi_spec::Command for {} {{
 #[serde(untagged)]
 pub enum {} {{
 ", type_identifier(&v.id))?;
+                let mut boxed_variants = HashSet::new();
                 for data in &v.data.fields {
                     assert!(!data.optional);
                     let boxed = if data.name == "definition" && data.ty.name == "BlockdevOptions" {
@@ -225,6 +236,7 @@ pub enum {} {{
                         false
                     };
                     let ty = if boxed {
+                        boxed_variants.insert(&data.name);
                         format!("Box<{}>", typename(&data.ty))
                     } else {
                         typename(&data.ty)
@@ -232,6 +244,19 @@ pub enum {} {{
                     writeln!(self.out, "\t#[serde(rename = \"{}\")] {}({}),", data.name, type_identifier(&data.name), ty)?;
                 }
                 writeln!(self.out, "}}")?;
+
+                let type_id = type_identifier(&v.id);
+                for data in unique_variants(&v.data.fields) {
+                    let boxed = boxed_variants.contains(&data.name);
+                    let ty = typename(&data.ty);
+                    write!(self.out, "
+impl From<{}> for {} {{
+    fn from(val: {}) -> Self {{
+        Self::{}({})
+    }}
+}}
+", ty, type_id, ty, type_identifier(&data.name), if boxed { "Box::new(val)" } else { "val" })?;
+                }
             },
             Spec::Enum(v) => {
                 let type_id = type_identifier(&v.id);
@@ -297,6 +322,18 @@ pub enum {} {{
                     writeln!(self.out, "\t#[serde(rename = \"{}\")]\n\t{} {{ data: {} }},", data.name, type_identifier(&data.name), typename(&data.ty))?;
                 }
                 writeln!(self.out, "}}")?;
+
+                let type_id = type_identifier(&v.id);
+                for data in unique_variants(&v.data.fields) {
+                    let ty = typename(&data.ty);
+                    write!(self.out, "
+impl From<{}> for {} {{
+    fn from(data: {}) -> Self {{
+        Self::{} {{ data }}
+    }}
+}}
+", ty, type_id, ty, type_identifier(&data.name))?;
+                }
             },
             Spec::CombinedUnion(v) => {
                 self.unions.push(v);
@@ -480,11 +517,13 @@ pub enum {} {{
             }
             writeln!(self.out, "}}")?;
 
+            // the variant alone is enough to construct the union when none of the base is required
+            let base_default = base_fields.clone().all(|f| f.optional);
             if create_base {
                 write!(self.out, "
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize{})]
 pub struct {} {{
-", base.as_ref().unwrap().ty.name)?;
+", if base_default { ", Default" } else { "" }, base.as_ref().unwrap().ty.name)?;
                 for field in base_fields.clone() {
                     writeln!(self.out, "\t{},", valuety(&field, true, &u.id))?;
                 }
@@ -509,19 +548,7 @@ impl {} {{
                 panic!("missing discriminator type for {}", u.id);
             };
 
-            let mut duptypes = HashSet::new();
-            let mut dups = HashSet::new();
-            for variant in &u.data.fields {
-                if duptypes.contains(&&variant.ty.name) {
-                    dups.insert(&variant.ty.name);
-                } else {
-                    duptypes.insert(&variant.ty.name);
-                }
-            }
-            for variant in &u.data.fields {
-                if dups.contains(&&variant.ty.name) {
-                    continue
-                }
+            for variant in unique_variants(&u.data.fields) {
                 let variant_ty = typename(&variant.ty);
                 let variant_name = type_identifier(&variant.name);
                 match &base {
@@ -561,6 +588,18 @@ impl From<({}, {})> for {} {{
     }}
 }}
 ")?;
+                        if (base.name == "base" && create_base && base_default) || (base.name != "base" && base.optional) {
+                            write!(self.out, "
+impl From<{}> for {} {{
+    fn from(val: {}) -> Self {{
+        Self::{} {{
+            {}: val,
+            {}: Default::default(),
+        }}
+    }}
+}}
+", variant_ty, type_id, variant_ty, type_identifier(&variant.name), identifier(&variant.name), identifier(&base.name))?;
+                        }
                     },
                 }
             }
@@ -571,9 +610,28 @@ impl From<({}, {})> for {} {{
 
     fn process_events(&mut self) -> io::Result<()> {
         writeln!(self.out, "
-#[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(tag = \"event\")]
+#[derive(Debug, Clone)]
 pub enum Event {{")?;
+        for event in &self.events {
+            let id = event_identifier(&event.id);
+            writeln!(self.out, "\t{} {{
+        data: {},
+        timestamp: ::qapi_spec::Timestamp,
+    }},", id, id)?;
+        }
+        writeln!(self.out, "\t/// An event missing from the schema, such as one added by a newer or patched QEMU.
+    Unknown {{
+        name: ::std::string::String,
+        data: ::qapi_spec::Any,
+        timestamp: ::qapi_spec::Timestamp,
+    }},
+}}")?;
+
+        writeln!(self.out, "
+#[derive(Serialize, Deserialize)]
+#[serde(tag = \"event\", remote = \"Event\")]
+#[allow(dead_code, clippy::upper_case_acronyms)]
+enum EventDef {{")?;
         for event in &self.events {
             let id = event_identifier(&event.id);
             writeln!(self.out, "\t#[serde(rename = \"{}\")] {} {{
@@ -581,7 +639,13 @@ pub enum Event {{")?;
         timestamp: ::qapi_spec::Timestamp,
     }},", event.id, id, if event.data.is_empty() { "#[serde(default)] " } else { "" }, id)?;
         }
-        writeln!(self.out, "}}")?;
+        writeln!(self.out, "\t#[serde(skip)]
+    Unknown {{
+        name: ::std::string::String,
+        data: ::qapi_spec::Any,
+        timestamp: ::qapi_spec::Timestamp,
+    }},
+}}")?;
 
         writeln!(self.out, "
 impl Event {{
@@ -590,10 +654,88 @@ impl Event {{
         for event in &self.events {
             writeln!(self.out, "Event::{} {{ timestamp, .. }} => timestamp,", event_identifier(&event.id))?;
         }
+        writeln!(self.out, "Event::Unknown {{ timestamp, .. }} => timestamp,
+        }}
+    }}
+
+    pub fn name(&self) -> &str {{
+        match *self {{")?;
+        for event in &self.events {
+            writeln!(self.out, "Event::{} {{ .. }} => \"{}\",", event_identifier(&event.id), event.id)?;
+        }
+        writeln!(self.out, "Event::Unknown {{ ref name, .. }} => name,
+        }}
+    }}
+
+    /// Whether the schema describes the event called `name`.
+    pub fn is_known(name: &str) -> bool {{")?;
+        if self.events.is_empty() {
+            writeln!(self.out, "let _ = name;\nfalse")?;
+        } else {
+            let names: Vec<_> = self.events.iter().map(|event| format!("\"{}\"", event.id)).collect();
+            writeln!(self.out, "matches!(name, {})", names.join(" | "))?;
+        }
         writeln!(self.out, "
+    }}
+}}
+
+impl Serialize for Event {{
+    #[allow(unreachable_patterns)]
+    fn serialize<S: ::serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {{
+        match self {{
+            Event::Unknown {{ name, data, timestamp }} => {{
+                use ::serde::ser::SerializeMap;
+
+                let mut map = serializer.serialize_map(Some(3))?;
+                map.serialize_entry(\"event\", name)?;
+                map.serialize_entry(\"data\", data)?;
+                map.serialize_entry(\"timestamp\", timestamp)?;
+                map.end()
+            }},
+            event => EventDef::serialize(event, serializer),
         }}
     }}
+}}
+
+/// Events that aren't in the schema are kept as `Event::Unknown` rather than failing.
+impl<'de> Deserialize<'de> for Event {{
+    fn deserialize<D: ::serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {{
+        use ::serde::de::Error;
+
+        let mut message = ::qapi_spec::Dictionary::deserialize(deserializer)?;
+        let name = match message.get(\"event\") {{
+            Some(::qapi_spec::Any::String(name)) if Event::is_known(name) =>
+                return EventDef::deserialize(::qapi_spec::Any::Object(message)).map_err(D::Error::custom),
+            Some(::qapi_spec::Any::String(name)) => name.clone(),
+            _ => return Err(D::Error::missing_field(\"event\")),
+        }};
+        let timestamp = match message.remove(\"timestamp\") {{
+            Some(timestamp) => ::qapi_spec::Timestamp::deserialize(timestamp).map_err(D::Error::custom)?,
+            None => return Err(D::Error::missing_field(\"timestamp\")),
+        }};
+
+        Ok(Event::Unknown {{
+            name,
+            data: message.remove(\"data\").unwrap_or_else(|| ::qapi_spec::Any::Object(Default::default())),
+            timestamp,
+        }})
+    }}
 }}")?;
+
+        for event in &self.events {
+            writeln!(self.out, "
+impl ::std::convert::TryFrom<Event> for {0} {{
+    type Error = Event;
+
+    #[allow(unreachable_patterns)]
+    fn try_from(event: Event) -> Result<Self, Event> {{
+        match event {{
+            Event::{0} {{ data, .. }} => Ok(data),
+            event => Err(event),
+        }}
+    }}
+}}", event_identifier(&event.id))?;
+        }
         Ok(())
     }
 }
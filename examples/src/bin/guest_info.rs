@@ -14,8 +14,7 @@ pub fn main() {
 
     let mut qga = Qga::from_stream(&stream);
 
-    let sync_value = &stream as *const _ as usize as i32;
-    qga.guest_sync(sync_value).expect("handshake failed");
+    qga.handshake().expect("handshake failed");
 
     let info = qga.execute(&qga::guest_info { }).unwrap();
     println!("Guest Agent version: {}", info.version);
@@ -0,0 +1,88 @@
+//! Measures allocations while reading a flood of QMP events, with and without a presized read buffer.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::env::args;
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+use futures::StreamExt;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+struct CountingAlloc;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+const EVENT: &str = r#"{"event": "VSERPORT_CHANGE", "data": {"id": "channel0", "open": true}, "timestamp": {"seconds": 1, "microseconds": 2}}"#;
+
+/// A QMP peer that negotiates and then sends `count` events at once.
+async fn flood(io: tokio::io::DuplexStream, count: usize) -> io::Result<()> {
+    let (r, mut w) = tokio::io::split(io);
+    w.write_all(b"{\"QMP\": {\"version\": {\"qemu\": {\"major\": 7, \"minor\": 2, \"micro\": 0}, \"package\": \"\"}, \"capabilities\": []}}\n").await?;
+    BufReader::new(r).read_line(&mut String::new()).await?;
+    w.write_all(b"{\"return\": {}}\n").await?;
+
+    let mut events = String::with_capacity((EVENT.len() + 1) * count);
+    for _ in 0..count {
+        events.push_str(EVENT);
+        events.push('\n');
+    }
+    w.write_all(events.as_bytes()).await?;
+    w.shutdown().await
+}
+
+async fn measure(count: usize, capacity: Option<usize>) -> io::Result<()> {
+    let (a, b) = tokio::io::duplex(1 << 20);
+    let server = tokio::spawn(flood(b, count));
+    let stream = qapi::futures::QmpStreamTokio::open(a).await?.negotiate().await?;
+    let (_service, events) = stream.into_parts();
+    let mut events = match capacity {
+        Some(capacity) => events.with_capacity(capacity),
+        None => events,
+    };
+
+    let start = Instant::now();
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    let mut received = 0;
+    while let Some(event) = events.next().await {
+        event?;
+        received += 1;
+    }
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed) - allocations;
+    server.await.unwrap()?;
+
+    println!("capacity {:>10}: {} events in {:?}, {} allocations ({:.2} per event)",
+        capacity.map(|c| c.to_string()).unwrap_or_else(|| "default".into()),
+        received, start.elapsed(), allocations, allocations as f64 / received as f64,
+    );
+    Ok(())
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> io::Result<()> {
+    ::env_logger::init();
+
+    let count = args().nth(1).map(|count| count.parse().expect("argument: event count")).unwrap_or(100_000);
+    measure(count, None).await?;
+    measure(count, Some(1 << 20)).await?;
+
+    Ok(())
+}
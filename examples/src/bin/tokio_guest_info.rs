@@ -13,8 +13,7 @@ async fn main() -> io::Result<()> {
     let stream = qapi::futures::QgaStreamTokio::open_tcp(socket_addr).await?;
     let (qga, handle) = stream.spawn_tokio();
 
-    let sync_value = &qga as *const _ as usize as i32;
-    qga.guest_sync(sync_value).await?;
+    qga.handshake().await?;
 
     let info = qga.execute(qapi::qga::guest_info { }).await?;
     println!("Guest Agent version: {}", info.version);
@@ -14,7 +14,8 @@ async fn main() -> io::Result<()> {
     let stream = qapi::futures::QmpStreamTokio::open_tcp(socket_addr).await?;
     println!("{:#?}", stream.capabilities);
     let stream = stream.negotiate().await?;
-    let (_, mut events) = stream.into_parts();
+    // the events end once the service is dropped
+    let (_service, mut events) = stream.into_parts();
 
     while let Some(event) = events.next().await {
         println!("Got event {:#?}", event?);
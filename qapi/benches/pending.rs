@@ -0,0 +1,58 @@
+//! Compares the maps that can track pending commands, under the load of many concurrent
+//! out-of-band commands whose responses arrive in any order.
+//!
+//! Run with `cargo bench -p qapi --bench pending`.
+
+use std::collections::BTreeMap;
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+#[allow(dead_code)]
+#[path = "../src/futures/pending.rs"]
+mod pending;
+
+use pending::{PendingMap, PendingSlots};
+
+/// Sends `commands` commands, keeping `inflight` of them pending, and completes a random one
+/// whenever the limit is reached.
+fn run<M: PendingMap<Box<u64>>>(commands: u64, inflight: usize) -> Duration {
+    let mut map = M::default();
+    let mut keys = Vec::with_capacity(inflight);
+    let mut rng = 0x2545f4914f6cdd1du64;
+    let start = Instant::now();
+    for key in 0..commands {
+        map.insert(key, Box::new(key));
+        keys.push(key);
+        if keys.len() == inflight {
+            // xorshift
+            rng ^= rng << 13;
+            rng ^= rng >> 7;
+            rng ^= rng << 17;
+            let key = keys.swap_remove(rng as usize % keys.len());
+            black_box(map.remove(key));
+            black_box(map.oldest());
+        }
+    }
+    for key in keys.drain(..) {
+        black_box(map.remove(key));
+    }
+    start.elapsed()
+}
+
+fn report<M: PendingMap<Box<u64>>>(name: &str, commands: u64, inflight: usize) {
+    let elapsed = run::<M>(commands, inflight);
+    let per_command = elapsed.as_nanos() as f64 / commands as f64;
+    println!("{:>12} {:>6} in flight: {:>8.1} ns/command, {:>6.2} M commands/s", name, inflight, per_command, 1e3 / per_command);
+}
+
+fn main() {
+    // `cargo test --benches` only checks that this runs
+    let commands = match std::env::args().any(|arg| arg == "--bench") {
+        true => 4_000_000,
+        false => 10_000,
+    };
+    for &inflight in &[1, 16, 256, 4096] {
+        report::<BTreeMap<u64, Box<u64>>>("BTreeMap", commands, inflight);
+        report::<PendingSlots<Box<u64>>>("PendingSlots", commands, inflight);
+    }
+}
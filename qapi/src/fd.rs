@@ -0,0 +1,44 @@
+use std::io;
+use std::mem;
+use std::ptr;
+use std::os::unix::io::RawFd;
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const SEND_FLAGS: libc::c_int = libc::MSG_NOSIGNAL;
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+const SEND_FLAGS: libc::c_int = 0;
+
+/// Writes the start of `buf` to a Unix `socket`, passing `fd` alongside it as `SCM_RIGHTS`
+/// ancillary data.
+///
+/// The file descriptor accompanies the first byte sent, so any remainder can be written normally.
+pub(crate) fn send_with_fd(socket: RawFd, buf: &[u8], fd: Option<RawFd>) -> io::Result<usize> {
+    let mut iov = libc::iovec {
+        iov_base: buf.as_ptr() as *mut _,
+        iov_len: buf.len(),
+    };
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+
+    // u64 keeps the control buffer aligned for cmsghdr
+    let mut control = Vec::<u64>::new();
+    if let Some(fd) = fd {
+        let space = unsafe { libc::CMSG_SPACE(mem::size_of::<RawFd>() as _) } as usize;
+        control.resize(space / mem::size_of::<u64>() + 1, 0);
+        msg.msg_control = control.as_mut_ptr() as *mut _;
+        msg.msg_controllen = space as _;
+        unsafe {
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            (*cmsg).cmsg_level = libc::SOL_SOCKET;
+            (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of::<RawFd>() as _) as _;
+            ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut RawFd, fd);
+        }
+    }
+
+    match unsafe { libc::sendmsg(socket, &msg, SEND_FLAGS) } {
+        res if res < 0 => Err(io::Error::last_os_error()),
+        res => Ok(res as usize),
+    }
+}
@@ -0,0 +1,174 @@
+use std::io;
+use futures::{Future, FutureExt, Sink, SinkExt};
+use futures::channel::oneshot;
+use serde::Serialize;
+use crate::{Command, OobCommand, Execute, ExecuteOob, ExecuteRaw, ExecuteError, ExecuteResult};
+use super::{CommandMessage, CommandTiming, QapiPendingGuard, QapiService, RawReturn, Serialized, WireDirection};
+
+type Response = oneshot::Receiver<Result<RawReturn, ExecuteError>>;
+
+/// Commands that are written together, in the order they were added, with a single write and
+/// flush.
+///
+/// Each command still has its own id and pending response, so results are delivered to the
+/// futures returned by [`CommandBatch::execute`] and [`CommandBatch::execute_oob`] as they
+/// arrive. Nothing is written until [`CommandBatch::send`] is awaited, and the commands fail if
+/// the batch is dropped instead.
+///
+/// Created by [`QapiService::batch`].
+#[must_use = "commands are only written by `CommandBatch::send`"]
+pub struct CommandBatch<'a, W> {
+    service: &'a QapiService<W>,
+    commands: Vec<BatchCommand>,
+}
+
+struct BatchCommand {
+    key: u64,
+    timing: Option<CommandTiming>,
+    message: io::Result<Vec<u8>>,
+    sent: oneshot::Sender<io::Result<(Response, Option<CommandTiming>)>>,
+}
+
+impl<'a, W> CommandBatch<'a, W> {
+    pub(super) fn new(service: &'a QapiService<W>) -> Self {
+        Self {
+            service,
+            commands: Vec::new(),
+        }
+    }
+
+    /// The number of commands added to the batch.
+    pub fn len(&self) -> usize {
+        self.commands.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.commands.is_empty()
+    }
+
+    /// Adds a command to the batch, returning a future that resolves to its result once the
+    /// batch has been sent.
+    pub fn execute<C: Command>(&mut self, command: C) -> impl Future<Output=ExecuteResult<C>> {
+        let id = self.service.command_id();
+        let key = self.service.command_key(id);
+        self.push::<C, _>(key, Execute::new(command, id))
+    }
+
+    /// Adds a command to be executed out-of-band, see [`QapiService::execute_oob`].
+    pub fn execute_oob<C: OobCommand>(&mut self, command: C) -> impl Future<Output=ExecuteResult<C>> {
+        if !self.service.oob_enabled() {
+            return futures::future::err(QapiService::<W>::oob_unsupported::<C>().into()).left_future()
+        }
+
+        let id = self.service.next_id();
+        self.push::<C, _>(id, ExecuteOob::new(command, id)).right_future()
+    }
+
+    fn push<C: Command, M: Serialize + CommandMessage>(&mut self, key: u64, message: M) -> impl Future<Output=ExecuteResult<C>> {
+        let shared = self.service.shared.clone();
+        let (sent, receiver) = oneshot::channel();
+        self.commands.push(BatchCommand {
+            key,
+            timing: CommandTiming::new(&shared, key, &message),
+            message: Serialized::new(&message).map(|message| message.json),
+            sent,
+        });
+
+        async move {
+            let (response, timing) = match receiver.await {
+                Ok(sent) => sent?,
+                Err(_cancelled) => return Err(io::Error::new(io::ErrorKind::Interrupted, "QAPI command batch was dropped without being sent").into()),
+            };
+            let _pending = QapiPendingGuard {
+                shared: &shared,
+                key,
+                sent: true,
+            };
+
+            let res = QapiService::<W>::command_response::<C::Ok>(response).await;
+            if let Some(timing) = &timing {
+                timing.finish(&res);
+            }
+            res
+        }
+    }
+
+    /// Writes every command in the batch, waiting only until they have been flushed.
+    ///
+    /// When the peer doesn't match responses by id, other commands are held back until the whole
+    /// batch has been answered, and this waits for the responses too. The events must be driven
+    /// elsewhere in the meantime, such as by [`super::QapiEvents::spawn_tokio`].
+    pub async fn send(self) -> io::Result<()> where
+        W: Sink<Serialized<ExecuteRaw<u64>>, Error=io::Error> + Unpin
+    {
+        let shared = &self.service.shared;
+        if let Err(e) = shared.command_accepting() {
+            for command in self.commands {
+                let _ = command.sent.send(Err(io::Error::new(e.kind(), e.to_string())));
+            }
+            return Err(e)
+        }
+
+        let mut sink = self.service.write.lock().await;
+        let mut line = Vec::new();
+        let mut commands = Vec::with_capacity(self.commands.len());
+        for command in self.commands {
+            let key = command.key;
+            let res = command.message.and_then(|message| shared.command_insert(key).map(|response| (message, response)));
+            match res {
+                Ok((message, response)) => {
+                    shared.wire_log.log(WireDirection::Send, &message);
+                    if !line.is_empty() {
+                        line.push(b'\n');
+                    }
+                    line.extend_from_slice(&message);
+                    commands.push((key, command.timing, command.sent, response));
+                },
+                Err(e) => {
+                    let _ = command.sent.send(Err(e));
+                },
+            }
+        }
+        if commands.is_empty() {
+            return Ok(())
+        }
+
+        let res = match sink.feed(Serialized::from_json(line)).await {
+            Ok(()) => sink.flush().await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = res {
+            for (key, _, sent, _) in commands {
+                shared.command_remove(key);
+                let _ = sent.send(Err(io::Error::new(e.kind(), e.to_string())));
+            }
+            return Err(e)
+        }
+
+        let mut forward = Vec::new();
+        for (key, mut timing, sent, response) in commands {
+            if let Some(timing) = &mut timing {
+                timing.sent();
+            }
+            if shared.oob_enabled() {
+                if sent.send(Ok((response, timing))).is_err() {
+                    shared.command_abandon(key);
+                }
+            } else {
+                // the responses arrive in order, so nothing else may be written until they have
+                let (forward_sender, forward_receiver) = oneshot::channel();
+                if sent.send(Ok((forward_receiver, timing))).is_err() {
+                    shared.command_abandon(key);
+                }
+                forward.push((response, forward_sender));
+            }
+        }
+        for (response, sender) in forward {
+            if let Ok(res) = response.await {
+                let _ = sender.send(res);
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,369 @@
+use std::io;
+use std::sync::Arc;
+#[cfg(unix)]
+use std::path::PathBuf;
+#[cfg(all(windows, feature = "async-tokio-time"))]
+use std::ffi::OsString;
+#[cfg(feature = "async-tokio-time")]
+use std::time::Duration;
+use futures::{Future, FutureExt};
+use futures::future::BoxFuture;
+use tokio::io::{AsyncRead, AsyncWrite, ReadHalf, WriteHalf, split};
+#[cfg(feature = "qapi-qmp")]
+use qapi_qmp::QMPCapability;
+#[cfg(feature = "qapi-qmp")]
+use super::{QmpStreamNegotiation, QmpStreamTokio};
+#[cfg(feature = "qapi-qga")]
+use super::QgaStreamTokio;
+use super::{codec::{DEFAULT_MAX_LINE_LENGTH, Framing}, MetricsSink, QapiStream, ResponseOrder, WireDirection, WireLogger};
+
+/// How a [`QapiStreamBuilder`] reaches its peer.
+pub trait Transport {
+    type Socket: AsyncRead + AsyncWrite + Send + Unpin + 'static;
+
+    fn connect(&self) -> BoxFuture<'_, io::Result<Self::Socket>>;
+}
+
+/// A Unix socket, see [`QapiStreamBuilder::unix`].
+#[cfg(unix)]
+#[derive(Debug, Clone)]
+pub struct UnixTransport {
+    path: PathBuf,
+}
+
+#[cfg(unix)]
+impl Transport for UnixTransport {
+    type Socket = tokio::net::UnixStream;
+
+    fn connect(&self) -> BoxFuture<'_, io::Result<Self::Socket>> {
+        super::tokio::connect_uds(&self.path).boxed()
+    }
+}
+
+/// A Windows named pipe, see [`QapiStreamBuilder::named_pipe`].
+#[cfg(all(windows, feature = "async-tokio-time"))]
+#[derive(Debug, Clone)]
+pub struct NamedPipeTransport {
+    name: OsString,
+}
+
+#[cfg(all(windows, feature = "async-tokio-time"))]
+impl Transport for NamedPipeTransport {
+    type Socket = tokio::net::windows::named_pipe::NamedPipeClient;
+
+    fn connect(&self) -> BoxFuture<'_, io::Result<Self::Socket>> {
+        super::tokio::connect_named_pipe(&self.name).boxed()
+    }
+}
+
+/// A TCP socket, see [`QapiStreamBuilder::tcp`].
+#[derive(Debug, Clone)]
+pub struct TcpTransport {
+    addr: String,
+    nodelay: bool,
+}
+
+impl Transport for TcpTransport {
+    type Socket = tokio::net::TcpStream;
+
+    fn connect(&self) -> BoxFuture<'_, io::Result<Self::Socket>> {
+        super::tokio::connect_tcp(&self.addr[..], self.nodelay).boxed()
+    }
+}
+
+/// Connects to a QMP or guest agent socket with the chosen options.
+///
+/// ```no_run
+/// # #[cfg(feature = "qapi-qmp")]
+/// # async fn connect() -> std::io::Result<()> {
+/// let stream = qapi::futures::QapiStreamBuilder::unix("/run/qemu/qmp.sock")
+///     .oob(true)
+///     .max_line_length(1024 * 1024)
+///     .connect().await?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct QapiStreamBuilder<T> {
+    transport: T,
+    max_length: usize,
+    /// creates the framing for each half of the stream
+    framing: Option<Arc<dyn Fn() -> Box<dyn Framing> + Send + Sync>>,
+    oob: bool,
+    read_capacity: Option<usize>,
+    response_order: Option<ResponseOrder>,
+    strict_responses: bool,
+    max_inflight: Option<usize>,
+    lenient: bool,
+    wire_logger: Option<WireLogger>,
+    metrics: Option<Arc<dyn MetricsSink>>,
+    #[cfg(feature = "async-tokio-time")]
+    timeout: Option<Duration>,
+    #[cfg(feature = "async-tokio-time")]
+    idle_timeout: Option<Duration>,
+    #[cfg(feature = "qapi-qmp")]
+    history: Option<usize>,
+    #[cfg(all(feature = "qapi-qga", feature = "async-tokio-spawn", feature = "async-tokio-time"))]
+    keepalive: Option<(Duration, Duration)>,
+}
+
+#[cfg(unix)]
+impl QapiStreamBuilder<UnixTransport> {
+    /// Connects to the Unix socket at `path`.
+    pub fn unix<P: Into<PathBuf>>(path: P) -> Self {
+        Self::new(UnixTransport {
+            path: path.into(),
+        })
+    }
+}
+
+#[cfg(all(windows, feature = "async-tokio-time"))]
+impl QapiStreamBuilder<NamedPipeTransport> {
+    /// Connects to the named pipe `name`, such as `\\.\pipe\qmp`, waiting while it is busy.
+    pub fn named_pipe<N: Into<OsString>>(name: N) -> Self {
+        Self::new(NamedPipeTransport {
+            name: name.into(),
+        })
+    }
+}
+
+impl QapiStreamBuilder<TcpTransport> {
+    /// Connects over TCP to `addr`, such as `"localhost:4444"`, with `TCP_NODELAY` enabled.
+    pub fn tcp<A: ToString>(addr: A) -> Self {
+        Self::new(TcpTransport {
+            addr: addr.to_string(),
+            nodelay: true,
+        })
+    }
+
+    /// Chooses whether `TCP_NODELAY` is enabled.
+    pub fn nodelay(mut self, nodelay: bool) -> Self {
+        self.transport.nodelay = nodelay;
+        self
+    }
+}
+
+impl<T: Transport> QapiStreamBuilder<T> {
+    pub fn new(transport: T) -> Self {
+        Self {
+            transport,
+            max_length: DEFAULT_MAX_LINE_LENGTH,
+            framing: None,
+            oob: false,
+            read_capacity: None,
+            response_order: None,
+            strict_responses: false,
+            max_inflight: None,
+            lenient: false,
+            wire_logger: None,
+            metrics: None,
+            #[cfg(feature = "async-tokio-time")]
+            timeout: None,
+            #[cfg(feature = "async-tokio-time")]
+            idle_timeout: None,
+            #[cfg(feature = "qapi-qmp")]
+            history: None,
+            #[cfg(all(feature = "qapi-qga", feature = "async-tokio-spawn", feature = "async-tokio-time"))]
+            keepalive: None,
+        }
+    }
+
+    /// Fails the stream when an incoming message exceeds `max_length` bytes.
+    ///
+    /// Defaults to [`DEFAULT_MAX_LINE_LENGTH`](super::DEFAULT_MAX_LINE_LENGTH).
+    pub fn max_line_length(mut self, max_length: usize) -> Self {
+        self.max_length = max_length;
+        self
+    }
+
+    /// Frames messages with `framing` rather than separating them with newlines.
+    ///
+    /// See [`QmpStreamTokio::open_split_framed`](super::QmpStreamTokio::open_split_framed).
+    pub fn framing<F: Framing + Clone + 'static>(mut self, framing: F) -> Self {
+        self.framing = Some(Arc::new(move || Box::new(framing.clone())));
+        self
+    }
+
+    fn split_framing(&self) -> Option<(Box<dyn Framing>, Box<dyn Framing>)> {
+        self.framing.as_ref().map(|framing| (framing(), framing()))
+    }
+
+    /// Enables out-of-band execution during QMP negotiation, if QEMU supports it.
+    pub fn oob(mut self, oob: bool) -> Self {
+        self.oob = oob;
+        self
+    }
+
+    /// Sizes the buffer that messages are read into, see [`QapiEvents::with_capacity`](super::QapiEvents::with_capacity).
+    pub fn read_capacity(mut self, capacity: usize) -> Self {
+        self.read_capacity = Some(capacity);
+        self
+    }
+
+    /// See [`QapiService::set_response_order`](super::QapiService::set_response_order).
+    pub fn response_order(mut self, order: ResponseOrder) -> Self {
+        self.response_order = Some(order);
+        self
+    }
+
+    /// See [`QapiService::set_strict_responses`](super::QapiService::set_strict_responses).
+    pub fn strict_responses(mut self, strict: bool) -> Self {
+        self.strict_responses = strict;
+        self
+    }
+
+    /// See [`QapiService::set_max_inflight`](super::QapiService::set_max_inflight).
+    pub fn max_inflight(mut self, max: usize) -> Self {
+        self.max_inflight = Some(max);
+        self
+    }
+
+    /// See [`QapiService::set_lenient`](super::QapiService::set_lenient).
+    pub fn lenient(mut self, lenient: bool) -> Self {
+        self.lenient = lenient;
+        self
+    }
+
+    /// Installs a wire logger before anything is received, so that the QMP greeting and
+    /// negotiation are logged too.
+    ///
+    /// See [`QapiService::set_wire_logger`](super::QapiService::set_wire_logger).
+    pub fn wire_logger<F: Fn(WireDirection, &[u8]) + Send + Sync + 'static>(mut self, logger: F) -> Self {
+        self.wire_logger = Some(Box::new(logger));
+        self
+    }
+
+    /// Reports the latency of commands, including QMP negotiation.
+    ///
+    /// See [`QapiService::set_metrics_sink`](super::QapiService::set_metrics_sink).
+    pub fn metrics_sink<M: MetricsSink + 'static>(mut self, sink: M) -> Self {
+        self.metrics = Some(Arc::new(sink));
+        self
+    }
+
+    /// Fails with `TimedOut` if connecting, including waiting for the QMP greeting and
+    /// negotiating capabilities, takes longer than `timeout`.
+    #[cfg(feature = "async-tokio-time")]
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Fails the stream if nothing is received for `timeout`, see [`QapiEvents::with_idle_timeout`](super::QapiEvents::with_idle_timeout).
+    #[cfg(feature = "async-tokio-time")]
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Retains the last `capacity` events, see [`QapiEvents::with_history`](super::QapiEvents::with_history).
+    #[cfg(feature = "qapi-qmp")]
+    pub fn history(mut self, capacity: usize) -> Self {
+        self.history = Some(capacity);
+        self
+    }
+
+    /// Pings the guest agent every `interval`, see [`QapiService::spawn_keepalive`](super::QapiService::spawn_keepalive).
+    ///
+    /// Only applies to [`QapiStreamBuilder::connect_qga`], and must be called from within a tokio runtime.
+    #[cfg(all(feature = "qapi-qga", feature = "async-tokio-spawn", feature = "async-tokio-time"))]
+    pub fn keepalive(mut self, interval: Duration, timeout: Duration) -> Self {
+        self.keepalive = Some((interval, timeout));
+        self
+    }
+
+    async fn timed<O, F: Future<Output=io::Result<O>>>(&self, connect: F) -> io::Result<O> {
+        #[cfg(feature = "async-tokio-time")]
+        if let Some(timeout) = self.timeout {
+            return match tokio::time::timeout(timeout, connect).await {
+                Ok(res) => res,
+                Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "timed out connecting to QAPI socket")),
+            }
+        }
+
+        connect.await
+    }
+
+    fn configure<R, W>(&self, stream: QapiStream<R, W>, wire_logger: Option<WireLogger>) -> QapiStream<R, W> where
+        R: super::ReadBuffer,
+    {
+        if let Some(logger) = wire_logger {
+            stream.service.shared.wire_log.set(Some(logger));
+        }
+        if let Some(sink) = &self.metrics {
+            *stream.service.shared.metrics.lock().unwrap() = Some(sink.clone());
+        }
+        if let Some(order) = self.response_order {
+            stream.set_response_order(order);
+        }
+        stream.set_strict_responses(self.strict_responses);
+        stream.set_max_inflight(self.max_inflight);
+        stream.set_lenient(self.lenient);
+        let stream = match self.read_capacity {
+            Some(capacity) => stream.with_capacity(capacity),
+            None => stream,
+        };
+        #[cfg(feature = "async-tokio-time")]
+        let stream = match self.idle_timeout {
+            Some(timeout) => stream.with_idle_timeout(timeout),
+            None => stream,
+        };
+        #[cfg(feature = "qapi-qmp")]
+        let stream = match self.history {
+            Some(capacity) => stream.with_history(capacity),
+            None => stream,
+        };
+        stream
+    }
+
+    /// Connects to QEMU and negotiates QMP capabilities.
+    #[cfg(feature = "qapi-qmp")]
+    pub async fn connect(mut self) -> io::Result<QapiStream<QmpStreamTokio<ReadHalf<T::Socket>>, QmpStreamTokio<WriteHalf<T::Socket>>>> {
+        let wire_logger = self.wire_logger.take();
+        let connect = async {
+            let negotiation = self.open_qmp(wire_logger).await?;
+            let caps = if self.oob && negotiation.capabilities.supports(QMPCapability::oob) {
+                Some(QMPCapability::oob)
+            } else {
+                None
+            };
+            negotiation.negotiate_caps(caps).await
+        };
+        self.timed(connect).await
+    }
+
+    /// Connects to QEMU and reads its greeting, leaving the caller to choose which of the
+    /// advertised capabilities to enable with [`QmpStreamNegotiation::negotiate_caps`].
+    ///
+    /// [`QapiStreamBuilder::oob`] is ignored, and the timeout only covers waiting for the greeting.
+    #[cfg(feature = "qapi-qmp")]
+    pub async fn connect_raw(mut self) -> io::Result<QmpStreamNegotiation<QmpStreamTokio<ReadHalf<T::Socket>>, QmpStreamTokio<WriteHalf<T::Socket>>>> {
+        let wire_logger = self.wire_logger.take();
+        self.timed(self.open_qmp(wire_logger)).await
+    }
+
+    #[cfg(feature = "qapi-qmp")]
+    async fn open_qmp(&self, wire_logger: Option<WireLogger>) -> io::Result<QmpStreamNegotiation<QmpStreamTokio<ReadHalf<T::Socket>>, QmpStreamTokio<WriteHalf<T::Socket>>>> {
+        let (r, w) = split(self.transport.connect().await?);
+        // installed before the greeting is read, so that it's logged too
+        let wire_log = Arc::new(super::WireLog::default());
+        wire_log.set(wire_logger);
+        let mut negotiation = QmpStreamTokio::open_split_with(r, w, self.max_length, self.split_framing(), wire_log).await?;
+        negotiation.stream = self.configure(negotiation.stream, None);
+        Ok(negotiation)
+    }
+
+    /// Connects to a guest agent.
+    #[cfg(feature = "qapi-qga")]
+    pub async fn connect_qga(mut self) -> io::Result<QapiStream<QgaStreamTokio<ReadHalf<T::Socket>>, QgaStreamTokio<WriteHalf<T::Socket>>>> {
+        let wire_logger = self.wire_logger.take();
+        let (r, w) = split(self.timed(self.transport.connect()).await?);
+        let stream = QgaStreamTokio::open_split_with(r, w, self.max_length, self.split_framing());
+        let stream = self.configure(stream, wire_logger);
+        #[cfg(all(feature = "async-tokio-spawn", feature = "async-tokio-time"))]
+        let stream = match self.keepalive {
+            Some((interval, timeout)) => stream.with_keepalive(interval, timeout),
+            None => stream,
+        };
+        Ok(stream)
+    }
+}
@@ -0,0 +1,155 @@
+use std::io;
+use std::convert::TryFrom;
+use std::marker::PhantomData;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex as StdMutex};
+use std::task::{Context, Poll};
+use futures::{Future, Stream};
+use futures::channel::mpsc;
+use qapi_qmp::Event;
+use qapi_spec::Timestamp;
+#[cfg(feature = "async-tokio-spawn")]
+use log::{info, warn};
+use super::QapiEvents;
+
+/// Fans out the events of a QMP connection to typed subscriptions.
+///
+/// The bus must be driven, either by awaiting it or with [`EventBus::spawn_tokio`], for
+/// subscribers to receive anything. Command responses continue to be processed meanwhile.
+#[must_use]
+pub struct EventBus<S> {
+    events: QapiEvents<S>,
+    subscriber: EventSubscriber,
+}
+
+/// Subscribes to the events of an [`EventBus`], even after the bus has been spawned.
+#[derive(Clone)]
+pub struct EventSubscriber {
+    routes: Arc<StdMutex<EventRoutes>>,
+}
+
+#[derive(Default)]
+struct EventRoutes {
+    routes: Vec<(&'static str, mpsc::UnboundedSender<Event>)>,
+    closed: bool,
+}
+
+/// The events of a single type, see [`EventSubscriber::subscribe`].
+///
+/// Ends when the [`EventBus`] does.
+pub struct EventSubscription<E> {
+    receiver: mpsc::UnboundedReceiver<Event>,
+    _event: PhantomData<fn() -> E>,
+}
+
+impl<S> EventBus<S> {
+    pub fn new(events: QapiEvents<S>) -> Self {
+        Self {
+            events,
+            subscriber: EventSubscriber {
+                routes: Default::default(),
+            },
+        }
+    }
+
+    /// A handle for subscribing to events once the bus is being driven elsewhere.
+    pub fn subscriber(&self) -> EventSubscriber {
+        self.subscriber.clone()
+    }
+
+    pub fn subscribe<E: qapi_spec::Event + TryFrom<Event>>(&self) -> EventSubscription<E> {
+        self.subscriber.subscribe()
+    }
+
+    #[cfg(feature = "async-tokio-spawn")]
+    pub fn spawn_tokio(self) -> ::tokio::task::JoinHandle<()> where
+        Self: Future<Output=io::Result<()>> + Send + 'static,
+    {
+        ::tokio::spawn(async move {
+            if self.events.release().is_err() {
+                info!("QAPI service abandoned before spawning");
+                return
+            }
+
+            if let Err(e) = self.await {
+                warn!("QAPI stream closed with error {:?}", e);
+            }
+        })
+    }
+}
+
+impl EventSubscriber {
+    /// Receives every event of type `E` from now on.
+    pub fn subscribe<E: qapi_spec::Event + TryFrom<Event>>(&self) -> EventSubscription<E> {
+        let (sender, receiver) = mpsc::unbounded();
+        let mut routes = self.routes.lock().unwrap();
+        if !routes.closed {
+            routes.routes.push((E::NAME, sender));
+        }
+
+        EventSubscription {
+            receiver,
+            _event: PhantomData,
+        }
+    }
+
+    fn route(&self, event: Event) {
+        let mut routes = self.routes.lock().unwrap();
+        let name = event.name();
+        routes.routes.retain(|(route, sender)| *route != name || sender.unbounded_send(event.clone()).is_ok());
+    }
+
+    fn close(&self) {
+        let mut routes = self.routes.lock().unwrap();
+        routes.closed = true;
+        routes.routes.clear();
+    }
+}
+
+impl<S> Future for EventBus<S> where
+    QapiEvents<S>: Stream<Item=io::Result<Event>> + Unpin,
+{
+    type Output = io::Result<()>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        loop {
+            match futures::ready!(Pin::new(&mut this.events).poll_next(cx)) {
+                Some(Ok(event)) => this.subscriber.route(event),
+                Some(Err(e)) => {
+                    this.subscriber.close();
+                    return Poll::Ready(Err(e))
+                },
+                None => {
+                    this.subscriber.close();
+                    return Poll::Ready(Ok(()))
+                },
+            }
+        }
+    }
+}
+
+impl<S> Drop for EventBus<S> {
+    fn drop(&mut self) {
+        self.subscriber.close();
+    }
+}
+
+impl<E: TryFrom<Event>> Stream for EventSubscription<E> {
+    type Item = (E, Timestamp);
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        loop {
+            match futures::ready!(Pin::new(&mut this.receiver).poll_next(cx)) {
+                Some(event) => {
+                    let timestamp = event.timestamp();
+                    if let Ok(event) = E::try_from(event) {
+                        return Poll::Ready(Some((event, timestamp)))
+                    }
+                },
+                None => return Poll::Ready(None),
+            }
+        }
+    }
+}
@@ -1,51 +1,484 @@
 use std::io;
+use std::cell::Cell;
+#[cfg(feature = "tokio-util")]
+use std::error;
+use std::fmt;
 use std::marker::PhantomData;
-use bytes::{BytesMut, BufMut};
-use serde::{de::DeserializeOwned, Serialize};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use bytes::{Buf, BytesMut};
+#[cfg(feature = "tokio-util")]
+use bytes::BufMut;
+use log::warn;
+use serde::de::{self, Deserialize, DeserializeOwned, DeserializeSeed, Deserializer, IgnoredAny, IntoDeserializer, MapAccess, Visitor};
+use serde::de::value::MapAccessDeserializer;
+#[cfg(feature = "tokio-util")]
+use serde::Serialize;
+use super::{ProtocolError, WireLog, WireDirection};
+#[cfg(any(feature = "tokio-util", feature = "qapi-qmp", feature = "qapi-qga"))]
+use super::Serialized;
+
+/// The default limit on the length of a single incoming message.
+///
+/// Generous enough for large responses such as `query-qmp-schema`.
+pub const DEFAULT_MAX_LINE_LENGTH: usize = 16 * 1024 * 1024;
 
 pub struct JsonLinesCodec<D = ()> {
     next_index: usize,
+    scanner: JsonScanner,
+    max_length: usize,
+    expected: Option<&'static str>,
+    wire_log: Option<Arc<WireLog>>,
+    /// events are discarded without being parsed while set
+    skip_events: Option<Arc<AtomicBool>>,
+    /// messages that fail to parse are retried with [`relax_json`] while set
+    lenient: Option<Arc<AtomicBool>>,
+    /// messages that fail to parse are discarded while set
+    syncing: Option<Arc<AtomicBool>>,
+    framing: Option<Box<dyn Framing>>,
     _decoder: PhantomData<fn() -> D>,
 }
 
+/// Frames QAPI messages on transports that don't separate them with newlines, such as
+/// gateways that prefix each message with its length.
+///
+/// Implemented for any `tokio_util` codec of `String`s. Each frame holds a single message,
+/// without the trailing newline. See [`super::QmpStreamTokio::open_split_framed`].
+pub trait Framing: Send + Sync {
+    fn decode(&mut self, buf: &mut BytesMut) -> io::Result<Option<String>>;
+    fn decode_eof(&mut self, buf: &mut BytesMut) -> io::Result<Option<String>>;
+    fn encode(&mut self, message: String, buf: &mut BytesMut) -> io::Result<()>;
+}
+
+#[cfg(feature = "tokio-util")]
+impl<C> Framing for C where
+    C: tokio_util::codec::Decoder<Item=String> + tokio_util::codec::Encoder<String> + Send + Sync,
+    <C as tokio_util::codec::Decoder>::Error: Into<Box<dyn error::Error + Send + Sync>>,
+    <C as tokio_util::codec::Encoder<String>>::Error: Into<Box<dyn error::Error + Send + Sync>>,
+{
+    fn decode(&mut self, buf: &mut BytesMut) -> io::Result<Option<String>> {
+        tokio_util::codec::Decoder::decode(self, buf).map_err(framing_error)
+    }
+
+    fn decode_eof(&mut self, buf: &mut BytesMut) -> io::Result<Option<String>> {
+        tokio_util::codec::Decoder::decode_eof(self, buf).map_err(framing_error)
+    }
+
+    fn encode(&mut self, message: String, buf: &mut BytesMut) -> io::Result<()> {
+        tokio_util::codec::Encoder::encode(self, message, buf).map_err(framing_error)
+    }
+}
+
+#[cfg(feature = "tokio-util")]
+fn framing_error<E: Into<Box<dyn error::Error + Send + Sync>>>(e: E) -> io::Error {
+    match e.into().downcast::<io::Error>() {
+        Ok(e) => *e,
+        Err(e) => io::Error::new(io::ErrorKind::InvalidData, e),
+    }
+}
+
+/// Decodes a message in a single pass, giving up on it as soon as it turns out to be an event.
+///
+/// An event is skipped without deserializing its data, while anything else is handed to `D`
+/// as it's read, so skipping events doesn't cost other messages a second parse.
+struct SkipEvents<'a, D> {
+    skipped: &'a Cell<bool>,
+    _message: PhantomData<fn() -> D>,
+}
+
+impl<'de, 'a, D: Deserialize<'de>> DeserializeSeed<'de> for SkipEvents<'a, D> {
+    type Value = Option<D>;
+
+    fn deserialize<De: Deserializer<'de>>(self, deserializer: De) -> Result<Self::Value, De::Error> {
+        deserializer.deserialize_map(self)
+    }
+}
+
+impl<'de, 'a, D: Deserialize<'de>> Visitor<'de> for SkipEvents<'a, D> {
+    type Value = Option<D>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a QAPI message")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<Self::Value, A::Error> {
+        let mut map = EventKeys {
+            map,
+            skipped: self.skipped,
+        };
+        match D::deserialize(MapAccessDeserializer::new(&mut map)) {
+            Err(..) if self.skipped.get() => {
+                map.map.next_value::<IgnoredAny>()?;
+                while map.map.next_entry::<IgnoredAny, IgnoredAny>()?.is_some() { }
+                Ok(None)
+            },
+            res => res.map(Some),
+        }
+    }
+}
+
+/// Fails at the `event` key of a message, leaving its value unread.
+struct EventKeys<'a, A> {
+    map: A,
+    skipped: &'a Cell<bool>,
+}
+
+impl<'de, 'a, A: MapAccess<'de>> MapAccess<'de> for EventKeys<'a, A> {
+    type Error = A::Error;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>, A::Error> {
+        match self.map.next_key::<String>()? {
+            Some(key) if key == "event" => {
+                self.skipped.set(true);
+                Err(de::Error::custom("skipped event"))
+            },
+            Some(key) => seed.deserialize(key.into_deserializer()).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, A::Error> {
+        self.map.next_value_seed(seed)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        self.map.size_hint()
+    }
+}
+
+/// Finds where a JSON value ends when it spans several lines.
+#[derive(Default)]
+struct JsonScanner {
+    depth: usize,
+    in_string: bool,
+    escaped: bool,
+}
+
+impl JsonScanner {
+    /// Returns the length of the message ending within `bytes`, which are scanned
+    /// as a continuation of any previous calls.
+    ///
+    /// Values other than objects and arrays are terminated by a newline.
+    fn scan(&mut self, bytes: &[u8]) -> Option<usize> {
+        for (i, &b) in bytes.iter().enumerate() {
+            if self.in_string {
+                match b {
+                    _ if self.escaped => self.escaped = false,
+                    b'\\' => self.escaped = true,
+                    b'"' => self.in_string = false,
+                    _ => (),
+                }
+                continue
+            }
+
+            let complete = match b {
+                b'"' => {
+                    self.in_string = true;
+                    false
+                },
+                b'{' | b'[' => {
+                    self.depth += 1;
+                    false
+                },
+                b'}' | b']' => {
+                    self.depth = self.depth.saturating_sub(1);
+                    self.depth == 0
+                },
+                b'\n' => self.depth == 0,
+                _ => false,
+            };
+            if complete {
+                *self = Default::default();
+                return Some(i + 1)
+            }
+        }
+
+        None
+    }
+}
+
 impl<D> JsonLinesCodec<D> {
     pub fn new() -> Self {
+        Self::new_with_max_length(DEFAULT_MAX_LINE_LENGTH)
+    }
+
+    /// Creates a codec that fails rather than buffering a message longer than `max_length` bytes.
+    pub fn new_with_max_length(max_length: usize) -> Self {
         Self {
             next_index: 0,
+            scanner: Default::default(),
+            max_length,
+            expected: None,
+            wire_log: None,
+            skip_events: None,
+            lenient: None,
+            syncing: None,
+            framing: None,
+            _decoder: PhantomData,
+        }
+    }
+
+    /// Continues decoding where this codec left off, as a different kind of message.
+    #[cfg(feature = "qapi-qmp")]
+    pub(super) fn decoding<E>(self) -> JsonLinesCodec<E> {
+        JsonLinesCodec {
+            next_index: self.next_index,
+            scanner: self.scanner,
+            max_length: self.max_length,
+            expected: None,
+            wire_log: self.wire_log,
+            skip_events: self.skip_events,
+            lenient: self.lenient,
+            syncing: self.syncing,
+            framing: self.framing,
             _decoder: PhantomData,
         }
     }
+
+    /// Frames messages with `framing` instead of separating them with newlines.
+    pub(super) fn set_framing(&mut self, framing: Box<dyn Framing>) {
+        self.framing = Some(framing);
+    }
+
+    /// Describes messages that fail to parse as not being the `expected` kind of message,
+    /// such as when connected to something other than a QMP socket.
+    #[cfg(feature = "qapi-qmp")]
+    pub(super) fn expecting(mut self, expected: &'static str) -> Self {
+        self.expected = Some(expected);
+        self
+    }
+
+    fn parse_error(&self, message: &[u8], e: serde_json::Error) -> io::Error {
+        match self.expected {
+            Some(expected) => crate::unexpected_message(expected, message),
+            None => ProtocolError::Parse(e).into(),
+        }
+    }
+
+    /// Reports each received line to `wire_log`.
+    pub(crate) fn set_wire_log(&mut self, wire_log: Arc<WireLog>) {
+        self.wire_log = Some(wire_log);
+    }
+
+    /// Discards events while `skip_events` is set, see [`super::QapiEvents`]'s `Future` implementation.
+    #[cfg(feature = "qapi-qmp")]
+    pub(crate) fn set_skip_events(&mut self, skip_events: Arc<AtomicBool>) {
+        self.skip_events = Some(skip_events);
+    }
+
+    /// Tolerates non-standard JSON while `lenient` is set, see [`super::QapiService::set_lenient`].
+    pub(crate) fn set_lenient(&mut self, lenient: Arc<AtomicBool>) {
+        self.lenient = Some(lenient);
+    }
+
+    /// Discards garbage while `syncing` is set, see [`super::QapiService::guest_sync`].
+    pub(crate) fn set_syncing(&mut self, syncing: Arc<AtomicBool>) {
+        self.syncing = Some(syncing);
+    }
+
+    /// Whether messages that fail to parse are stale output to be discarded.
+    fn discard_invalid(&self) -> bool {
+        self.syncing.as_ref().map(|syncing| syncing.load(Ordering::Relaxed)).unwrap_or(false)
+    }
+
+    fn log_line(&self, line: &[u8]) {
+        if let Some(wire_log) = &self.wire_log {
+            wire_log.log(WireDirection::Receive, line)
+        }
+    }
+
+    fn length_exceeded(&self) -> io::Error {
+        ProtocolError::TooLong(self.max_length).into()
+    }
 }
 
 impl<D: DeserializeOwned> JsonLinesCodec<D> {
-    fn priv_decode(&mut self, buf: &mut BytesMut) -> Result<Option<D>, io::Error> {
-        match memchr::memchr(b'\n', &buf[self.next_index..]) {
-            Some(offset) => {
-                let index = offset + self.next_index;
-                self.next_index = 0;
-                let line = buf.split_to(index + 1);
-                serde_json::from_slice(&line)
-                    .map_err(From::from)
-                    .map(Some)
-            },
-            None => {
-                self.next_index = buf.len();
-                Ok(None)
+    /// Parses `message`, or returns `None` if it's an event that nobody will see.
+    fn parse(&self, message: &[u8]) -> serde_json::Result<Option<D>> {
+        match self.parse_json(message) {
+            Err(e) if !e.is_eof() && self.lenient.as_ref().map(|lenient| lenient.load(Ordering::Relaxed)).unwrap_or(false) =>
+                self.parse_json(&relax_json(message)).map_err(|_| e),
+            res => res,
+        }
+    }
+
+    fn parse_json(&self, message: &[u8]) -> serde_json::Result<Option<D>> {
+        match &self.skip_events {
+            Some(skip) if skip.load(Ordering::Relaxed) => {
+                let skipped = Cell::new(false);
+                let mut deserializer = serde_json::Deserializer::from_slice(message);
+                let message = SkipEvents { skipped: &skipped, _message: PhantomData }.deserialize(&mut deserializer)?;
+                deserializer.end().map(|()| message)
             },
+            _ => serde_json::from_slice(message).map(Some),
+        }
+    }
+
+    fn decode_message(&self, buf: &mut BytesMut, len: usize) -> Result<Option<D>, io::Error> {
+        let message = buf.split_to(len);
+        self.log_line(&message);
+        self.parse(&message)
+            .map_err(|e| self.parse_error(&message, e))
+    }
+
+    pub(super) fn priv_decode(&mut self, buf: &mut BytesMut) -> Result<Option<D>, io::Error> {
+        match self.framing {
+            Some(_) => self.decode_framed(buf, false),
+            None => self.decode_lines(buf),
+        }
+    }
+
+    pub(super) fn priv_decode_eof(&mut self, buf: &mut BytesMut) -> Result<Option<D>, io::Error> {
+        match self.framing {
+            Some(_) => self.decode_framed(buf, true),
+            None => self.decode_lines_eof(buf),
         }
     }
 
-    fn priv_decode_eof(&mut self, buf: &mut BytesMut) -> Result<Option<D>, io::Error> {
-        if buf.is_empty() {
+    /// Parses each frame as a whole message, which may span several lines.
+    fn decode_framed(&mut self, buf: &mut BytesMut, eof: bool) -> Result<Option<D>, io::Error> {
+        loop {
+            let framing = match &mut self.framing {
+                Some(framing) => framing,
+                None => return Ok(None),
+            };
+            let frame = match eof {
+                true => framing.decode_eof(buf)?,
+                false => framing.decode(buf)?,
+            };
+            let frame = match frame {
+                Some(frame) => frame,
+                None => return Ok(None),
+            };
+            if frame.len() > self.max_length {
+                return Err(self.length_exceeded())
+            }
+
+            let message = frame.trim().as_bytes();
+            if message.is_empty() {
+                continue
+            }
+            self.log_line(message);
+            match self.parse(message) {
+                Ok(None) => (),
+                Err(..) if self.discard_invalid() => (),
+                res => return res.map_err(|e| self.parse_error(message, e)),
+            }
+        }
+    }
+
+    fn decode_lines(&mut self, buf: &mut BytesMut) -> Result<Option<D>, io::Error> {
+        loop {
+            if self.next_index == 0 {
+                let start = buf.iter().position(|b| !b.is_ascii_whitespace()).unwrap_or(buf.len());
+                buf.advance(start);
+
+                // fast path: QEMU normally sends each message on a single line
+                if let Some(index) = memchr::memchr(b'\n', buf) {
+                    if index > self.max_length {
+                        return Err(self.length_exceeded())
+                    }
+                    match self.parse(&buf[..index]) {
+                        Err(e) if e.is_eof() => (),
+                        res => {
+                            let line = buf.split_to(index + 1);
+                            self.log_line(&line);
+                            match res {
+                                Ok(None) => continue,
+                                Err(..) if self.discard_invalid() => continue,
+                                res => return res.map_err(|e| self.parse_error(&line, e)),
+                            }
+                        },
+                    }
+                }
+            }
+
+            let len = match self.scanner.scan(&buf[self.next_index..]) {
+                Some(len) => len,
+                None if buf.len() > self.max_length => return Err(self.length_exceeded()),
+                None => {
+                    self.next_index = buf.len();
+                    return Ok(None)
+                },
+            };
+            let index = self.next_index + len;
+            self.next_index = 0;
+            if index > self.max_length {
+                return Err(self.length_exceeded())
+            }
+            match self.decode_message(buf, index) {
+                Ok(None) => (),
+                Err(..) if self.discard_invalid() => (),
+                res => return res,
+            }
+        }
+    }
+
+    fn decode_lines_eof(&mut self, buf: &mut BytesMut) -> Result<Option<D>, io::Error> {
+        if let Some(res) = self.decode_lines(buf)? {
+            return Ok(Some(res))
+        }
+
+        self.next_index = 0;
+        self.scanner = Default::default();
+        if buf.iter().all(|b| b.is_ascii_whitespace()) {
+            buf.clear();
             Ok(None)
         } else {
-            serde_json::from_slice(buf)
-                .map_err(From::from)
-                .map(Some)
+            // a peer killed mid-write leaves a truncated message behind, which is just EOF
+            let message = buf.split_to(buf.len());
+            self.log_line(&message);
+            match self.parse(&message) {
+                Err(e) if e.is_eof() => {
+                    warn!("QAPI stream closed after a truncated message of {} bytes", message.len());
+                    Ok(None)
+                },
+                res => res.map_err(|e| self.parse_error(&message, e)),
+            }
         }
     }
 }
 
+/// Rewrites the non-standard JSON that some peers emit: commas before a closing bracket are
+/// dropped, and `NaN` and infinities become `null`.
+fn relax_json(message: &[u8]) -> Vec<u8> {
+    const NON_FINITE: [&[u8]; 3] = [b"NaN", b"Infinity", b"-Infinity"];
+
+    let mut relaxed = Vec::with_capacity(message.len());
+    let (mut in_string, mut escaped) = (false, false);
+    let mut i = 0;
+    while i < message.len() {
+        let b = message[i];
+        if in_string {
+            match b {
+                _ if escaped => escaped = false,
+                b'\\' => escaped = true,
+                b'"' => in_string = false,
+                _ => (),
+            }
+        } else {
+            match b {
+                b'"' => in_string = true,
+                b',' => match message[i + 1..].iter().find(|b| !b.is_ascii_whitespace()) {
+                    Some(b'}') | Some(b']') => {
+                        i += 1;
+                        continue
+                    },
+                    _ => (),
+                },
+                _ => if let Some(token) = NON_FINITE.iter().find(|token| message[i..].starts_with(token)) {
+                    relaxed.extend_from_slice(b"null");
+                    i += token.len();
+                    continue
+                },
+            }
+        }
+        relaxed.push(b);
+        i += 1;
+    }
+    relaxed
+}
+
 #[cfg(feature = "tokio-util")]
 impl<D: DeserializeOwned> tokio_util::codec::Decoder for JsonLinesCodec<D> {
     type Item = D;
@@ -60,6 +493,7 @@ impl<D: DeserializeOwned> tokio_util::codec::Decoder for JsonLinesCodec<D> {
     }
 }
 
+#[cfg(feature = "tokio-util")]
 fn encode<S: Serialize>(item: S, bytes: &mut BytesMut) -> Result<(), io::Error> {
     serde_json::to_writer(bytes.writer(), &item)?;
     bytes.put_u8(b'\n');
@@ -71,6 +505,179 @@ impl<T, S: Serialize> tokio_util::codec::Encoder<S> for JsonLinesCodec<T> {
     type Error = io::Error;
 
     fn encode(&mut self, item: S, bytes: &mut BytesMut) -> Result<(), Self::Error> {
-        encode(item, bytes)
+        match &mut self.framing {
+            Some(framing) => framing.encode(serde_json::to_string(&item)?, bytes),
+            None => encode(item, bytes),
+        }
+    }
+}
+
+#[cfg(any(feature = "tokio-util", feature = "qapi-qmp", feature = "qapi-qga"))]
+impl<T> JsonLinesCodec<T> {
+    /// Writes a message that was already serialized, see [`Serialized`].
+    pub(super) fn encode_serialized<M>(&mut self, item: &Serialized<M>, bytes: &mut BytesMut) -> io::Result<()> {
+        let framing = match &mut self.framing {
+            Some(framing) => framing,
+            None => {
+                bytes.reserve(item.as_bytes().len() + 1);
+                bytes.extend_from_slice(item.as_bytes());
+                bytes.extend_from_slice(b"\n");
+                return Ok(())
+            },
+        };
+
+        // a batch of commands is serialized as several lines
+        for line in item.as_bytes().split(|&b| b == b'\n').filter(|line| !line.is_empty()) {
+            let message = String::from_utf8(line.to_vec())
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            framing.encode(message, bytes)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "tokio-util")]
+impl<T, M> tokio_util::codec::Encoder<Serialized<M>> for JsonLinesCodec<T> {
+    type Error = io::Error;
+
+    fn encode(&mut self, item: Serialized<M>, bytes: &mut BytesMut) -> Result<(), Self::Error> {
+        self.encode_serialized(&item, bytes)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use qapi_spec::Any;
+
+    fn decode_all(chunks: &[&str]) -> Vec<Any> {
+        decode_all_with(JsonLinesCodec::<Any>::new(), chunks)
+    }
+
+    fn decode_all_with(mut codec: JsonLinesCodec<Any>, chunks: &[&str]) -> Vec<Any> {
+        let mut buf = BytesMut::new();
+        let mut res = Vec::new();
+        for chunk in chunks {
+            buf.extend_from_slice(chunk.as_bytes());
+            while let Some(value) = codec.priv_decode(&mut buf).unwrap() {
+                res.push(value);
+            }
+        }
+        while let Some(value) = codec.priv_decode_eof(&mut buf).unwrap() {
+            res.push(value);
+        }
+        res
+    }
+
+    #[test]
+    fn decode_lines() {
+        let res = decode_all(&["{\"return\": {}}\n{\"ret", "urn\": 1}\n", "{\"return\": 2}"]);
+        assert_eq!(res, vec![serde_json::json!({"return": {}}), serde_json::json!({"return": 1}), serde_json::json!({"return": 2})]);
+    }
+
+    #[test]
+    fn decode_multiline() {
+        let res = decode_all(&["{\n  \"return\": {\n    \"a\": \"}\\\"\\n{\"\n", "  }\n}\n{\"return\": [\n1\n]}\n"]);
+        assert_eq!(res, vec![serde_json::json!({"return": {"a": "}\"\n{"}}), serde_json::json!({"return": [1]})]);
+    }
+
+    #[test]
+    fn decode_skip_events() {
+        let mut codec = JsonLinesCodec::<Any>::new();
+        codec.skip_events = Some(Arc::new(AtomicBool::new(true)));
+        let res = decode_all_with(codec, &["{\"event\": \"STOP\"}\n{\"return\": 1}\n{\n\"event\": \"STOP\"\n}\n", "{\"return\": 2}"]);
+        assert_eq!(res, vec![serde_json::json!({"return": 1}), serde_json::json!({"return": 2})]);
+
+        let mut codec = JsonLinesCodec::<Any>::new();
+        codec.skip_events = Some(Arc::new(AtomicBool::new(true)));
+        let res = decode_all_with(codec, &["{\"timestamp\": {\"seconds\": 1}, \"event\": \"STOP\", \"data\": {\"a\": [1]}}\n", "{\"id\": 1, \"return\": {\"event\": 3}}\n"]);
+        assert_eq!(res, vec![serde_json::json!({"id": 1, "return": {"event": 3}})]);
+    }
+
+    #[test]
+    fn decode_lenient() {
+        let message = "{\"return\": {\"a\": [1, NaN, -Infinity,], \"b\": \"NaN, ]\",}}\n";
+        assert!(JsonLinesCodec::<Any>::new().priv_decode(&mut BytesMut::from(message)).is_err());
+
+        let mut codec = JsonLinesCodec::<Any>::new();
+        codec.set_lenient(Arc::new(AtomicBool::new(true)));
+        let res = decode_all_with(codec, &[message]);
+        assert_eq!(res, vec![serde_json::json!({"return": {"a": [1, null, null], "b": "NaN, ]"}})]);
+    }
+
+    #[test]
+    fn decode_syncing() {
+        let message = "garbage\n{\"return\": 1}\n";
+        assert!(JsonLinesCodec::<Any>::new().priv_decode(&mut BytesMut::from(message)).is_err());
+
+        let mut codec = JsonLinesCodec::<Any>::new();
+        codec.set_syncing(Arc::new(AtomicBool::new(true)));
+        let res = decode_all_with(codec, &[message]);
+        assert_eq!(res, vec![serde_json::json!({"return": 1})]);
+    }
+
+    /// Events are recognized whatever order their fields are in, and results are kept as
+    /// they were received until the command they belong to parses them.
+    #[cfg(feature = "qapi-qmp")]
+    #[test]
+    fn decode_qmp_messages() {
+        use qapi_qmp::{Event, QmpMessage};
+
+        let mut codec = JsonLinesCodec::<QmpMessage<super::super::RawReturn>>::new();
+        let mut buf = BytesMut::from("{\"timestamp\": {\"seconds\": 1, \"microseconds\": 2}, \"event\": \"STOP\"}\n{\"return\": {\"a\": [1]}, \"id\": 3}\n");
+        assert!(matches!(codec.priv_decode(&mut buf).unwrap(), Some(QmpMessage::Event(Event::STOP { .. }))));
+        match codec.priv_decode(&mut buf).unwrap() {
+            Some(QmpMessage::Response(res)) => {
+                assert_eq!(res.id(), Some(&serde_json::json!(3)));
+                assert_eq!(res.result().unwrap().get(), "{\"a\": [1]}");
+            },
+            res => panic!("expected a response, got {:?}", res),
+        }
+    }
+
+    /// Messages separated by NUL bytes.
+    struct NulFraming;
+
+    impl Framing for NulFraming {
+        fn decode(&mut self, buf: &mut BytesMut) -> io::Result<Option<String>> {
+            Ok(memchr::memchr(0, buf).map(|index| {
+                let frame = buf.split_to(index + 1);
+                String::from_utf8_lossy(&frame[..index]).into_owned()
+            }))
+        }
+
+        fn decode_eof(&mut self, buf: &mut BytesMut) -> io::Result<Option<String>> {
+            self.decode(buf)
+        }
+
+        fn encode(&mut self, message: String, buf: &mut BytesMut) -> io::Result<()> {
+            buf.extend_from_slice(message.as_bytes());
+            buf.extend_from_slice(b"\0");
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn decode_framed() {
+        let mut codec = JsonLinesCodec::<Any>::new();
+        codec.framing = Some(Box::new(NulFraming));
+        let res = decode_all_with(codec, &["{\"return\": 1}\0{\"ret", "urn\": 2}\0{\"return\": 3}\0\0{\"return\":"]);
+        assert_eq!(res, vec![serde_json::json!({"return": 1}), serde_json::json!({"return": 2}), serde_json::json!({"return": 3})]);
+
+        // each frame is a whole message, however it's laid out
+        let mut codec = JsonLinesCodec::<Any>::new();
+        codec.framing = Some(Box::new(NulFraming));
+        let res = decode_all_with(codec, &["{\n  \"return\": {\n    \"a\": 1\n  }\n}\n\0 {\"return\": 2} \0"]);
+        assert_eq!(res, vec![serde_json::json!({"return": {"a": 1}}), serde_json::json!({"return": 2})]);
+    }
+
+    #[test]
+    fn decode_truncated_eof() {
+        let res = decode_all(&["{\"return\": 1}\n{\"return\": {\"a\"", ": [1, 2"]);
+        assert_eq!(res, vec![serde_json::json!({"return": 1})]);
+
+        let mut codec = JsonLinesCodec::<Any>::new();
+        let mut buf = BytesMut::from(&b"{\"return\" 1}"[..]);
+        assert!(codec.priv_decode_eof(&mut buf).is_err());
     }
 }
@@ -0,0 +1,108 @@
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll, Waker};
+use futures::{Future, FutureExt, Sink, Stream};
+use futures::future::BoxFuture;
+use futures::stream::FuturesOrdered;
+use crate::{Command, Execute, ExecuteResult};
+use super::{QapiService, Serialized};
+
+/// Executes the commands sent to it, yielding their results in the same order.
+///
+/// Commands are written as the results are polled, so the stream must be polled even when
+/// commands are fed to it with combinators such as `forward`; [`futures::StreamExt::split`]
+/// allows driving the two halves independently. Closing the sink ends the stream once the
+/// remaining results have been yielded. Commands aren't object safe, so each `CommandStream`
+/// accepts a single type of command.
+///
+/// Created by [`QapiService::command_stream`] or [`super::QapiStream::command_stream`].
+#[must_use = "streams do nothing unless polled"]
+pub struct CommandStream<'a, W, C: Command, D = futures::future::Pending<io::Result<()>>> {
+    service: &'a QapiService<W>,
+    pending: FuturesOrdered<BoxFuture<'a, ExecuteResult<C>>>,
+    /// processes responses when the events aren't being driven elsewhere
+    events: Option<D>,
+    closed: bool,
+    waker: Option<Waker>,
+}
+
+impl<'a, W, C: Command, D> CommandStream<'a, W, C, D> {
+    pub(super) fn new(service: &'a QapiService<W>, events: D) -> Self {
+        Self {
+            service,
+            pending: FuturesOrdered::new(),
+            events: Some(events),
+            closed: false,
+            waker: None,
+        }
+    }
+
+    /// The number of commands sent that haven't yet yielded a result.
+    pub fn pending_count(&self) -> usize {
+        self.pending.len()
+    }
+}
+
+impl<'a, W, C, D> Sink<C> for CommandStream<'a, W, C, D> where
+    W: Sink<Serialized<Execute<C, u64>>, Error=io::Error> + Unpin + Send + 'a,
+    C: Command + 'a,
+    D: Unpin,
+{
+    type Error = io::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(self: Pin<&mut Self>, command: C) -> Result<(), Self::Error> {
+        let this = self.get_mut();
+        let execute = this.service.execute(command).boxed();
+        this.pending.push_back(execute);
+        if let Some(waker) = this.waker.take() {
+            waker.wake()
+        }
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    /// Ends the stream of results once those already sent have been yielded.
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        let this = self.get_mut();
+        this.closed = true;
+        if let Some(waker) = this.waker.take() {
+            waker.wake()
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<'a, W, C, D> Stream for CommandStream<'a, W, C, D> where
+    C: Command,
+    D: Future<Output=io::Result<()>> + Unpin,
+{
+    type Item = ExecuteResult<C>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        if let Some(events) = &mut this.events {
+            if let Poll::Ready(res) = Pin::new(events).poll(cx) {
+                // no more responses will arrive
+                this.events = None;
+                let e = res.err().unwrap_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "unexpected EOF when executing command"));
+                this.service.shared.command_fail_all(|| io::Error::new(e.kind(), e.to_string()));
+            }
+        }
+
+        match Pin::new(&mut this.pending).poll_next(cx) {
+            Poll::Ready(Some(res)) => Poll::Ready(Some(res)),
+            _ if this.pending.is_empty() && (this.closed || this.events.is_none()) => Poll::Ready(None),
+            _ => {
+                this.waker = Some(cx.waker().clone());
+                Poll::Pending
+            },
+        }
+    }
+}
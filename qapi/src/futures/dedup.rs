@@ -0,0 +1,140 @@
+use std::io;
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use futures::Stream;
+use qapi_qmp::Event;
+use qapi_spec::{Any, Timestamp};
+
+/// Items of an event stream that [`DedupEvents`] can inspect.
+pub trait EventItem {
+    /// The event carried by this item, if any.
+    fn event(&self) -> Option<&Event>;
+}
+
+impl EventItem for Event {
+    fn event(&self) -> Option<&Event> {
+        Some(self)
+    }
+}
+
+impl EventItem for io::Result<Event> {
+    fn event(&self) -> Option<&Event> {
+        self.as_ref().ok()
+    }
+}
+
+/// Suppresses events identical to one of the last `window` events yielded, such as those
+/// seen twice around a reconnection or when replaying history.
+///
+/// Events are considered identical if their names, timestamps and data all match. Other items
+/// pass through untouched.
+///
+/// Created by [`DedupEvents::new`] or [`super::QapiEvents::dedup_events`].
+#[must_use = "streams do nothing unless polled"]
+pub struct DedupEvents<S> {
+    stream: S,
+    window: usize,
+    recent: VecDeque<(Timestamp, String, Any)>,
+}
+
+impl<S> DedupEvents<S> {
+    pub fn new(stream: S, window: usize) -> Self {
+        Self {
+            stream,
+            window,
+            recent: VecDeque::with_capacity(window),
+        }
+    }
+
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+
+    /// Records `event`, returning whether it was already among the recent events.
+    fn is_duplicate(&mut self, event: &Event) -> bool {
+        if self.window == 0 {
+            return false
+        }
+
+        let (timestamp, name) = (event.timestamp(), event.name().to_owned());
+        let data = match serde_json::to_value(event) {
+            Ok(data) => data,
+            Err(..) => return false,
+        };
+        let duplicate = self.recent.iter()
+            .any(|(t, n, d)| *t == timestamp && *n == name && *d == data);
+        if !duplicate {
+            if self.recent.len() >= self.window {
+                self.recent.pop_front();
+            }
+            self.recent.push_back((timestamp, name, data));
+        }
+        duplicate
+    }
+}
+
+impl<S> Stream for DedupEvents<S> where
+    S: Stream + Unpin,
+    S::Item: EventItem,
+{
+    type Item = S::Item;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        loop {
+            match futures::ready!(Pin::new(&mut this.stream).poll_next(cx)) {
+                Some(item) => match item.event() {
+                    Some(event) if this.is_duplicate(event) => (),
+                    _ => return Poll::Ready(Some(item)),
+                },
+                None => return Poll::Ready(None),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use futures::StreamExt;
+    use serde_json::json;
+    use super::*;
+
+    fn event(name: &str, seconds: u64) -> Event {
+        serde_json::from_value(json!({
+            "event": name,
+            "timestamp": { "seconds": seconds, "microseconds": 0 },
+        })).unwrap()
+    }
+
+    fn seconds(event: &Event) -> u64 {
+        serde_json::to_value(event.timestamp()).unwrap()["seconds"].as_u64().unwrap()
+    }
+
+    /// Only events seen within the window are suppressed, and errors always pass through.
+    #[test]
+    fn dedup_window() {
+        let items = vec![
+            Ok(event("STOP", 1)),
+            Ok(event("STOP", 1)),
+            Err(io::Error::from(io::ErrorKind::InvalidData)),
+            Ok(event("STOP", 2)),
+            Ok(event("RESUME", 2)),
+            Ok(event("STOP", 1)),
+            Ok(event("RESUME", 3)),
+            Ok(event("STOP", 1)),
+        ];
+        let items: Vec<_> = futures::executor::block_on(DedupEvents::new(futures::stream::iter(items), 2).collect());
+        let items: Vec<_> = items.into_iter()
+            .map(|item| item.map(|event| (event.name().to_owned(), seconds(&event))).map_err(|e| e.kind()))
+            .collect();
+        assert_eq!(items, [
+            Ok(("STOP".into(), 1)),
+            Err(io::ErrorKind::InvalidData),
+            Ok(("STOP".into(), 2)),
+            Ok(("RESUME".into(), 2)),
+            Ok(("STOP".into(), 1)),
+            Ok(("RESUME".into(), 3)),
+        ]);
+    }
+}
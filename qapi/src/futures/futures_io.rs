@@ -0,0 +1,473 @@
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::sync::Arc;
+use bytes::{Buf, BytesMut};
+use futures::{Stream, ready};
+#[cfg(any(feature = "qapi-qmp", feature = "qapi-qga"))]
+use futures::Sink;
+use futures::io::{AsyncRead, AsyncWrite, AsyncReadExt, ReadHalf, WriteHalf};
+use serde::de::DeserializeOwned;
+use qapi_spec::Response;
+#[cfg(any(feature = "qapi-qmp", feature = "qapi-qga"))]
+use qapi_spec::Execute;
+#[cfg(feature = "qapi-qmp")]
+use qapi_spec::ExecuteOob;
+#[cfg(any(feature = "qapi-qmp", feature = "qapi-qga"))]
+use qapi_spec::ExecuteRaw;
+#[cfg(feature = "qapi-qmp")]
+use qapi_qmp::{QmpMessage, QmpCommand, QapiCapabilities};
+#[cfg(feature = "qapi-qmp")]
+use super::QmpStreamNegotiation;
+use super::{codec::{JsonLinesCodec, Framing, DEFAULT_MAX_LINE_LENGTH}, CloseSink, ReadBuffer, QapiEvents, QapiService, QapiStream, QapiShared, RawReturn, WireLog};
+#[cfg(any(feature = "qapi-qmp", feature = "qapi-qga"))]
+use super::Serialized;
+
+/// How much is read from the transport at once.
+const READ_CHUNK: usize = 0x2000;
+
+/// Buffered writes are flushed before accepting more once they reach this size.
+#[cfg(any(feature = "qapi-qmp", feature = "qapi-qga"))]
+const WRITE_HIGH_WATER: usize = 0x10000;
+
+/// Newline-delimited JSON over a `futures::io` transport.
+struct JsonLines<S, D> {
+    io: S,
+    codec: JsonLinesCodec<D>,
+    read_buf: BytesMut,
+    write_buf: BytesMut,
+    eof: bool,
+}
+
+impl<S, D> JsonLines<S, D> {
+    fn new(io: S, codec: JsonLinesCodec<D>) -> Self {
+        Self {
+            io,
+            codec,
+            read_buf: BytesMut::new(),
+            write_buf: BytesMut::new(),
+            eof: false,
+        }
+    }
+
+    /// Switches to decoding a different message type, keeping anything already buffered.
+    #[cfg(feature = "qapi-qmp")]
+    fn decoding<D2>(self) -> JsonLines<S, D2> {
+        JsonLines {
+            io: self.io,
+            codec: self.codec.decoding(),
+            read_buf: self.read_buf,
+            write_buf: self.write_buf,
+            eof: self.eof,
+        }
+    }
+
+    fn project(self: Pin<&mut Self>) -> (Pin<&mut S>, &mut JsonLinesCodec<D>, &mut BytesMut, &mut BytesMut, &mut bool) {
+        unsafe {
+            let this = self.get_unchecked_mut();
+            (Pin::new_unchecked(&mut this.io), &mut this.codec, &mut this.read_buf, &mut this.write_buf, &mut this.eof)
+        }
+    }
+}
+
+impl<S: AsyncRead, D: DeserializeOwned> Stream for JsonLines<S, D> {
+    type Item = io::Result<D>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let (mut io, codec, read_buf, _, eof) = self.project();
+        loop {
+            if *eof {
+                return Poll::Ready(codec.priv_decode_eof(read_buf).transpose())
+            }
+
+            if let Some(res) = codec.priv_decode(read_buf).transpose() {
+                return Poll::Ready(Some(res))
+            }
+
+            let mut chunk = [0u8; READ_CHUNK];
+            match ready!(io.as_mut().poll_read(cx, &mut chunk))? {
+                0 => *eof = true,
+                len => read_buf.extend_from_slice(&chunk[..len]),
+            }
+        }
+    }
+}
+
+impl<S: AsyncWrite, D> JsonLines<S, D> {
+    fn poll_write_buf(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        let (mut io, _, _, write_buf, _) = self.project();
+        while !write_buf.is_empty() {
+            match ready!(io.as_mut().poll_write(cx, write_buf))? {
+                0 => return Poll::Ready(Err(io::Error::new(io::ErrorKind::WriteZero, "failed to write QAPI message"))),
+                len => write_buf.advance(len),
+            }
+        }
+
+        Poll::Ready(Ok(()))
+    }
+
+    #[cfg(any(feature = "qapi-qmp", feature = "qapi-qga"))]
+    fn poll_ready_buf(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        if self.write_buf.len() >= WRITE_HIGH_WATER {
+            self.poll_write_buf(cx)
+        } else {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[cfg(any(feature = "qapi-qmp", feature = "qapi-qga"))]
+    fn poll_flush_buf(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        ready!(self.as_mut().poll_write_buf(cx))?;
+        self.project().0.poll_flush(cx)
+    }
+
+    fn poll_close_buf(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        ready!(self.as_mut().poll_write_buf(cx))?;
+        self.project().0.poll_close(cx)
+    }
+
+    #[cfg(any(feature = "qapi-qmp", feature = "qapi-qga"))]
+    fn start_send_buf<M>(self: Pin<&mut Self>, item: Serialized<M>) -> io::Result<()> {
+        let (_, codec, _, write_buf, _) = self.project();
+        codec.encode_serialized(&item, write_buf)
+    }
+}
+
+/// A QGA stream over any `futures::io` transport, for use with executors such as async-std or smol.
+pub struct QgaStreamFutures<S> {
+    stream: JsonLines<S, Response<RawReturn>>,
+}
+
+impl<S> QgaStreamFutures<S> {
+    fn new(stream: S) -> Self {
+        Self {
+            stream: JsonLines::new(stream, JsonLinesCodec::new()),
+        }
+    }
+
+    fn with_max_length(stream: S, max_length: usize) -> Self {
+        Self {
+            stream: JsonLines::new(stream, JsonLinesCodec::new_with_max_length(max_length)),
+        }
+    }
+
+    fn pair<W>(mut self, write: W) -> QapiStream<Self, W> {
+        let wire_log = Arc::new(WireLog::default());
+        self.stream.codec.set_wire_log(wire_log.clone());
+        let shared = Arc::new(QapiShared::new(wire_log));
+        self.stream.codec.set_lenient(shared.lenient.clone());
+        self.stream.codec.set_syncing(shared.syncing.clone());
+        let events = QapiEvents {
+            stream: self,
+            shared: shared.clone(),
+            idle: Default::default(),
+        };
+        let service = QapiService::new(write, shared);
+        QapiStream::with_parts(service, events)
+    }
+
+    pub fn open_split<W>(read: S, write: W) -> QapiStream<Self, QgaStreamFutures<W>> {
+        Self::open_split_with_max_length(read, write, DEFAULT_MAX_LINE_LENGTH)
+    }
+
+    /// Like `open_split`, but fails the stream when an incoming message exceeds `max_length` bytes.
+    pub fn open_split_with_max_length<W>(read: S, write: W, max_length: usize) -> QapiStream<Self, QgaStreamFutures<W>> {
+        Self::open_split_with(read, write, max_length, None)
+    }
+
+    /// Like `open_split`, but messages are framed by `framing` rather than separated by
+    /// newlines, see [`Framing`].
+    pub fn open_split_framed<W, F: Framing + Clone + 'static>(read: S, write: W, framing: F) -> QapiStream<Self, QgaStreamFutures<W>> {
+        Self::open_split_with(read, write, DEFAULT_MAX_LINE_LENGTH, Some((Box::new(framing.clone()), Box::new(framing))))
+    }
+
+    fn open_split_with<W>(read: S, write: W, max_length: usize, framing: Option<(Box<dyn Framing>, Box<dyn Framing>)>) -> QapiStream<Self, QgaStreamFutures<W>> {
+        let mut r = Self::with_max_length(read, max_length);
+        let mut w = QgaStreamFutures::new(write);
+        if let Some((read_framing, write_framing)) = framing {
+            r.stream.codec.set_framing(read_framing);
+            w.stream.codec.set_framing(write_framing);
+        }
+
+        r.pair(w)
+    }
+
+    fn stream(self: Pin<&mut Self>) -> Pin<&mut JsonLines<S, Response<RawReturn>>> {
+        unsafe {
+            self.map_unchecked_mut(|this| &mut this.stream)
+        }
+    }
+}
+
+impl<RW: AsyncRead + AsyncWrite> QgaStreamFutures<ReadHalf<RW>> {
+    pub fn open(stream: RW) -> QapiStream<Self, QgaStreamFutures<WriteHalf<RW>>> {
+        let (r, w) = stream.split();
+        Self::open_split(r, w)
+    }
+
+    /// Like `open`, but messages are framed by `framing`, see [`Framing`].
+    pub fn open_framed<F: Framing + Clone + 'static>(stream: RW, framing: F) -> QapiStream<Self, QgaStreamFutures<WriteHalf<RW>>> {
+        let (r, w) = stream.split();
+        Self::open_split_framed(r, w, framing)
+    }
+}
+
+impl<S: AsyncRead> Stream for QgaStreamFutures<S> {
+    type Item = io::Result<Response<RawReturn>>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        self.stream().poll_next(cx)
+    }
+}
+
+impl<S> ReadBuffer for QgaStreamFutures<S> {
+    fn reserve_read(&mut self, capacity: usize) {
+        self.stream.read_buf.reserve(capacity)
+    }
+}
+
+impl<S: AsyncWrite> CloseSink for QgaStreamFutures<S> {
+    fn poll_close_sink(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        self.stream().poll_close_buf(cx)
+    }
+}
+
+#[cfg(feature = "qapi-qga")]
+impl<S: AsyncWrite, C: qapi_qga::QgaCommand, I: serde::Serialize> Sink<Serialized<Execute<C, I>>> for QgaStreamFutures<S> {
+    type Error = io::Error;
+
+    fn start_send(self: Pin<&mut Self>, item: Serialized<Execute<C, I>>) -> Result<(), Self::Error> {
+        self.stream().start_send_buf(item)
+    }
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.stream().poll_ready_buf(cx)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.stream().poll_flush_buf(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.stream().poll_close_buf(cx)
+    }
+}
+
+#[cfg(feature = "qapi-qga")]
+impl<S: AsyncWrite, I: serde::Serialize> Sink<Serialized<ExecuteRaw<I>>> for QgaStreamFutures<S> {
+    type Error = io::Error;
+
+    fn start_send(self: Pin<&mut Self>, item: Serialized<ExecuteRaw<I>>) -> Result<(), Self::Error> {
+        self.stream().start_send_buf(item)
+    }
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.stream().poll_ready_buf(cx)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.stream().poll_flush_buf(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.stream().poll_close_buf(cx)
+    }
+}
+
+/// A QMP stream over any `futures::io` transport, for use with executors such as async-std or smol.
+#[cfg(feature = "qapi-qmp")]
+pub struct QmpStreamFutures<S> {
+    stream: JsonLines<S, QmpMessage<RawReturn>>,
+}
+
+#[cfg(feature = "qapi-qmp")]
+impl<S> QmpStreamFutures<S> {
+    pub fn new(stream: S) -> Self {
+        Self {
+            stream: JsonLines::new(stream, JsonLinesCodec::new()),
+        }
+    }
+
+    pub async fn open_split<W>(read: S, write: W) -> io::Result<QmpStreamNegotiation<Self, QmpStreamFutures<W>>> where
+        S: AsyncRead + Unpin,
+    {
+        Self::open_split_with_max_length(read, write, DEFAULT_MAX_LINE_LENGTH).await
+    }
+
+    /// Like `open_split`, but fails the stream when an incoming message exceeds `max_length` bytes.
+    pub async fn open_split_with_max_length<W>(read: S, write: W, max_length: usize) -> io::Result<QmpStreamNegotiation<Self, QmpStreamFutures<W>>> where
+        S: AsyncRead + Unpin,
+    {
+        Self::open_split_with(read, write, max_length, None).await
+    }
+
+    /// Like `open_split`, but messages are framed by `framing` rather than separated by newlines,
+    /// see [`QmpStreamTokio::open_split_framed`](super::QmpStreamTokio::open_split_framed).
+    pub async fn open_split_framed<W, F: Framing + Clone + 'static>(read: S, write: W, framing: F) -> io::Result<QmpStreamNegotiation<Self, QmpStreamFutures<W>>> where
+        S: AsyncRead + Unpin,
+    {
+        Self::open_split_with(read, write, DEFAULT_MAX_LINE_LENGTH, Some((Box::new(framing.clone()), Box::new(framing)))).await
+    }
+
+    async fn open_split_with<W>(read: S, write: W, max_length: usize, framing: Option<(Box<dyn Framing>, Box<dyn Framing>)>) -> io::Result<QmpStreamNegotiation<Self, QmpStreamFutures<W>>> where
+        S: AsyncRead + Unpin,
+    {
+        use futures::StreamExt;
+
+        let mut codec = JsonLinesCodec::<QapiCapabilities>::new_with_max_length(max_length).expecting("QMP greeting");
+        let mut write = QmpStreamFutures::new(write);
+        if let Some((read_framing, write_framing)) = framing {
+            codec.set_framing(read_framing);
+            write.stream.codec.set_framing(write_framing);
+        }
+        let mut lines = JsonLines::new(read, codec);
+
+        let capabilities = lines.next().await.ok_or_else(||
+            io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed before the QMP greeting")
+        )??;
+
+        Ok(Self::with_greeting(lines.decoding(), write, capabilities))
+    }
+
+    /// Builds the stream from halves whose QMP greeting has already been read by the caller,
+    /// such as after handling a preamble that a proxy prefixes the stream with.
+    ///
+    /// Pass `None` for `greeting` to read it from `read` as [`QmpStreamFutures::open_split`] does.
+    pub async fn from_parts<W>(read: S, write: W, greeting: Option<QapiCapabilities>) -> io::Result<QmpStreamNegotiation<Self, QmpStreamFutures<W>>> where
+        S: AsyncRead + Unpin,
+    {
+        match greeting {
+            Some(capabilities) => Ok(Self::with_greeting(JsonLines::new(read, JsonLinesCodec::new()), QmpStreamFutures::new(write), capabilities)),
+            None => Self::open_split(read, write).await,
+        }
+    }
+
+    fn with_greeting<W>(mut stream: JsonLines<S, QmpMessage<RawReturn>>, write: QmpStreamFutures<W>, capabilities: QapiCapabilities) -> QmpStreamNegotiation<Self, QmpStreamFutures<W>> {
+        let wire_log = Arc::new(WireLog::default());
+        stream.codec.set_wire_log(wire_log.clone());
+
+        let shared = Arc::new(QapiShared::new(wire_log));
+        stream.codec.set_skip_events(shared.skip_events.clone());
+        stream.codec.set_lenient(shared.lenient.clone());
+        let events = QapiEvents {
+            stream: Self { stream },
+            shared: shared.clone(),
+            idle: Default::default(),
+        };
+        let service = QapiService::new(write, shared);
+
+        let mut stream = QapiStream::with_parts(service, events);
+        stream.qmp.greeting = Some(capabilities.QMP.clone());
+
+        QmpStreamNegotiation {
+            stream,
+            capabilities,
+        }
+    }
+
+    fn stream(self: Pin<&mut Self>) -> Pin<&mut JsonLines<S, QmpMessage<RawReturn>>> {
+        unsafe {
+            self.map_unchecked_mut(|this| &mut this.stream)
+        }
+    }
+}
+
+#[cfg(feature = "qapi-qmp")]
+impl<RW: AsyncRead + AsyncWrite> QmpStreamFutures<ReadHalf<RW>> {
+    pub async fn open(stream: RW) -> io::Result<QmpStreamNegotiation<Self, QmpStreamFutures<WriteHalf<RW>>>> {
+        let (r, w) = stream.split();
+        Self::open_split(r, w).await
+    }
+
+    /// Like `open`, but messages are framed by `framing`, see [`QmpStreamFutures::open_split_framed`].
+    pub async fn open_framed<F: Framing + Clone + 'static>(stream: RW, framing: F) -> io::Result<QmpStreamNegotiation<Self, QmpStreamFutures<WriteHalf<RW>>>> {
+        let (r, w) = stream.split();
+        Self::open_split_framed(r, w, framing).await
+    }
+}
+
+#[cfg(feature = "qapi-qmp")]
+impl<S: AsyncRead> Stream for QmpStreamFutures<S> {
+    type Item = io::Result<QmpMessage<RawReturn>>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        self.stream().poll_next(cx)
+    }
+}
+
+#[cfg(feature = "qapi-qmp")]
+impl<S> ReadBuffer for QmpStreamFutures<S> {
+    fn reserve_read(&mut self, capacity: usize) {
+        self.stream.read_buf.reserve(capacity)
+    }
+}
+
+#[cfg(feature = "qapi-qmp")]
+impl<S: AsyncWrite> CloseSink for QmpStreamFutures<S> {
+    fn poll_close_sink(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        self.stream().poll_close_buf(cx)
+    }
+}
+
+#[cfg(feature = "qapi-qmp")]
+impl<S: AsyncWrite, C: QmpCommand, I: serde::Serialize> Sink<Serialized<Execute<C, I>>> for QmpStreamFutures<S> {
+    type Error = io::Error;
+
+    fn start_send(self: Pin<&mut Self>, item: Serialized<Execute<C, I>>) -> Result<(), Self::Error> {
+        self.stream().start_send_buf(item)
+    }
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.stream().poll_ready_buf(cx)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.stream().poll_flush_buf(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.stream().poll_close_buf(cx)
+    }
+}
+
+#[cfg(feature = "qapi-qmp")]
+impl<S: AsyncWrite, C: QmpCommand, I: serde::Serialize> Sink<Serialized<ExecuteOob<C, I>>> for QmpStreamFutures<S> {
+    type Error = io::Error;
+
+    fn start_send(self: Pin<&mut Self>, item: Serialized<ExecuteOob<C, I>>) -> Result<(), Self::Error> {
+        self.stream().start_send_buf(item)
+    }
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.stream().poll_ready_buf(cx)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.stream().poll_flush_buf(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.stream().poll_close_buf(cx)
+    }
+}
+
+#[cfg(feature = "qapi-qmp")]
+impl<S: AsyncWrite, I: serde::Serialize> Sink<Serialized<ExecuteRaw<I>>> for QmpStreamFutures<S> {
+    type Error = io::Error;
+
+    fn start_send(self: Pin<&mut Self>, item: Serialized<ExecuteRaw<I>>) -> Result<(), Self::Error> {
+        self.stream().start_send_buf(item)
+    }
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.stream().poll_ready_buf(cx)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.stream().poll_flush_buf(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.stream().poll_close_buf(cx)
+    }
+}
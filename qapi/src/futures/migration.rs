@@ -0,0 +1,112 @@
+use std::io;
+use std::time::Duration;
+use futures::{Future, FutureExt, Sink, Stream, StreamExt};
+use qapi_qmp::{Event, MigrationInfo, MigrationStatus, query_migrate};
+use qapi_spec::Execute;
+use super::{QapiDeferGuard, QapiEvents, QapiStream, Serialized};
+
+/// The state of a migration, as reported by `query-migrate`.
+#[derive(Debug, Clone)]
+pub struct MigrationProgress {
+    pub status: MigrationStatus,
+    pub info: MigrationInfo,
+}
+
+impl MigrationProgress {
+    /// Bytes of RAM transferred so far.
+    pub fn transferred(&self) -> Option<i64> {
+        self.info.ram.as_ref().map(|ram| ram.transferred)
+    }
+
+    /// Bytes of RAM still to be transferred.
+    pub fn remaining(&self) -> Option<i64> {
+        self.info.ram.as_ref().map(|ram| ram.remaining)
+    }
+
+    /// The total bytes of guest RAM.
+    pub fn total(&self) -> Option<i64> {
+        self.info.ram.as_ref().map(|ram| ram.total)
+    }
+
+    /// The current throughput, in megabits per second.
+    pub fn mbps(&self) -> Option<f64> {
+        self.info.ram.as_ref().map(|ram| ram.mbps)
+    }
+
+    /// Why the migration failed, if QEMU said.
+    pub fn error(&self) -> Option<&str> {
+        self.info.error_desc.as_deref()
+    }
+
+    /// Whether the migration has completed, failed, or been cancelled.
+    pub fn is_finished(&self) -> bool {
+        matches!(self.status, MigrationStatus::completed | MigrationStatus::failed | MigrationStatus::cancelled)
+    }
+}
+
+impl From<MigrationInfo> for MigrationProgress {
+    fn from(info: MigrationInfo) -> Self {
+        Self {
+            status: info.status.unwrap_or(MigrationStatus::none),
+            info,
+        }
+    }
+}
+
+impl<R, W> QapiStream<R, W> where
+    QapiEvents<R>: Stream<Item=io::Result<Event>> + Future<Output=io::Result<()>> + Unpin,
+    W: Sink<Serialized<Execute<query_migrate, u64>>, Error=io::Error> + Unpin,
+{
+    /// Reports the progress of a migration whenever a `MIGRATION` event arrives, or every
+    /// `interval` otherwise.
+    ///
+    /// Yields the result of `query-migrate` right away, and ends after yielding a finished
+    /// migration or an error. A migration that hasn't started yet is reported with the
+    /// `none` status. Other events received meanwhile are yielded by the events afterwards.
+    pub fn watch_migration(&mut self, interval: Duration) -> impl Stream<Item=io::Result<MigrationProgress>> + '_ {
+        // whether a `MIGRATION` event arrived during the last query, or `None` before the first
+        futures::stream::unfold(Some((self, None)), move |state| async move {
+            let (stream, migrated) = state?;
+            if migrated == Some(false) {
+                match stream.events.wait_for(|event| matches!(event, Event::MIGRATION { .. }), interval).await {
+                    Err(e) if e.kind() != io::ErrorKind::TimedOut => return Some((Err(e), None)),
+                    _ => (),
+                }
+            }
+
+            match stream.query_migrate().await {
+                Ok((info, migrated)) => {
+                    let progress = MigrationProgress::from(info);
+                    let next = if progress.is_finished() { None } else { Some((stream, Some(migrated))) };
+                    Some((Ok(progress), next))
+                },
+                Err(e) => Some((Err(e), None)),
+            }
+        })
+    }
+
+    /// Executes `query-migrate`, also returning whether a `MIGRATION` event arrived meanwhile.
+    ///
+    /// Other events are yielded by the events afterwards.
+    async fn query_migrate(&mut self) -> io::Result<(MigrationInfo, bool)> {
+        let shared = self.service.shared.clone();
+        let mut skipped = QapiDeferGuard {
+            shared: &shared,
+            events: Vec::new(),
+        };
+        let mut migrated = false;
+        let query = self.service.execute(query_migrate { }).fuse();
+        futures::pin_mut!(query);
+        loop {
+            futures::select_biased! {
+                info = query => return Ok((info?, migrated)),
+                event = self.events.next().fuse() => match event {
+                    Some(Ok(Event::MIGRATION { .. })) => migrated = true,
+                    Some(Ok(event)) => skipped.events.push(event),
+                    Some(Err(e)) => return Err(e),
+                    None => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "QAPI stream ended while querying migration")),
+                },
+            }
+        }
+    }
+}
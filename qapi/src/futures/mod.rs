@@ -1,37 +1,113 @@
 #[cfg(feature = "qapi-qmp")]
-use qapi_qmp::{QmpMessage, QmpMessageAny, QapiCapabilities, QMPCapability};
+use qapi_qmp::{QmpMessage, QapiCapabilities, QMPCapability};
 
 use qapi_spec::Response;
-use crate::{Any, Execute, ExecuteResult, Command};
+use crate::{Any, Execute, ExecuteOob, ExecuteResult, ExecuteError, Command, OobCommand};
 
-use std::collections::BTreeMap;
-use std::convert::TryInto;
-use std::marker::Unpin;
-use std::sync::{Arc, Mutex as StdMutex, atomic::{AtomicUsize, AtomicBool, Ordering}};
-use std::task::{Context, Poll};
+use std::collections::{BTreeMap, VecDeque};
+use std::marker::{PhantomData, Unpin};
+use std::sync::{Arc, Mutex as StdMutex, atomic::{AtomicU64, AtomicU8, AtomicBool, Ordering}};
+use std::task::{Context, Poll, Waker};
 use std::pin::Pin;
 use std::io;
+use std::{error, fmt};
+use std::time::{Duration, Instant};
+use std::borrow::Cow;
+#[cfg(unix)]
+use std::os::unix::io::RawFd;
 use futures::channel::oneshot;
+use futures::future::Either;
 use futures::task::AtomicWaker;
 use futures::lock::Mutex;
 use futures::{Future, FutureExt, Sink, SinkExt, Stream};
-use serde::Deserialize;
+#[cfg(feature = "qapi-qmp")]
+use futures::TryStreamExt;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
 use log::{trace, info, warn};
 
-#[cfg(feature = "tokio-util")]
+#[cfg(any(feature = "tokio-util", feature = "async-futures-io"))]
 mod codec;
+#[cfg(any(feature = "tokio-util", feature = "async-futures-io"))]
+pub use self::codec::{DEFAULT_MAX_LINE_LENGTH, Framing};
 
 #[cfg(feature = "tokio")]
 mod tokio;
 #[cfg(feature = "tokio")]
 pub use self::tokio::*;
 
+#[cfg(all(feature = "async-tokio-net", any(feature = "qapi-qmp", feature = "qapi-qga")))]
+mod builder;
+#[cfg(all(feature = "async-tokio-net", any(feature = "qapi-qmp", feature = "qapi-qga")))]
+pub use self::builder::{QapiStreamBuilder, Transport, TcpTransport};
+#[cfg(all(unix, feature = "async-tokio-net", any(feature = "qapi-qmp", feature = "qapi-qga")))]
+pub use self::builder::UnixTransport;
+#[cfg(all(windows, feature = "async-tokio-net", feature = "async-tokio-time", any(feature = "qapi-qmp", feature = "qapi-qga")))]
+pub use self::builder::NamedPipeTransport;
+
+#[cfg(feature = "async-futures-io")]
+mod futures_io;
+#[cfg(feature = "async-futures-io")]
+pub use self::futures_io::*;
+
 #[cfg(feature = "tower-service")]
 mod tower;
 
+mod commands;
+pub use self::commands::CommandStream;
+
+mod pending;
+use self::pending::{PendingMap, PendingSlots};
+
+/// How long [`QapiService::ping`] waits for QEMU to respond.
+#[cfg(all(feature = "qapi-qmp", feature = "async-tokio-time"))]
+const PING_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[cfg(any(feature = "qapi-qmp", feature = "qapi-qga"))]
+mod batch;
+#[cfg(any(feature = "qapi-qmp", feature = "qapi-qga"))]
+pub use self::batch::CommandBatch;
+
+#[cfg(feature = "qapi-qmp")]
+mod bus;
+#[cfg(feature = "qapi-qmp")]
+pub use self::bus::{EventBus, EventSubscriber, EventSubscription};
+
+#[cfg(feature = "qapi-qmp")]
+mod dedup;
+#[cfg(feature = "qapi-qmp")]
+pub use self::dedup::{DedupEvents, EventItem};
+
+#[cfg(all(feature = "qapi-qmp", feature = "async-tokio-time"))]
+mod migration;
+#[cfg(all(feature = "qapi-qmp", feature = "async-tokio-time"))]
+pub use self::migration::MigrationProgress;
+
+#[cfg(feature = "testing")]
+pub mod testing;
+
+#[cfg(all(any(feature = "qapi-qmp", feature = "qapi-qga"), feature = "async-tokio-spawn", feature = "async-tokio-time"))]
+mod reconnect;
+#[cfg(all(any(feature = "qapi-qmp", feature = "qapi-qga"), feature = "async-tokio-spawn", feature = "async-tokio-time"))]
+pub use self::reconnect::ReconnectPolicy;
+#[cfg(all(feature = "qapi-qmp", feature = "async-tokio-spawn", feature = "async-tokio-time"))]
+pub use self::reconnect::{ReconnectingStream, ReconnectEvent};
+#[cfg(all(feature = "qapi-qga", feature = "async-tokio-spawn", feature = "async-tokio-time"))]
+pub use self::reconnect::ReconnectingQgaStream;
+
 pub struct QapiStream<R, W> {
     service: QapiService<W>,
     events: QapiEvents<R>,
+    #[cfg(feature = "qapi-qmp")]
+    qmp: QmpState,
+}
+
+/// What was learned about a QMP peer while connecting.
+#[cfg(feature = "qapi-qmp")]
+#[derive(Default)]
+struct QmpState {
+    greeting: Option<qapi_qmp::QMP>,
+    capabilities: Vec<QMPCapability>,
 }
 
 impl<R, W> QapiStream<R, W> {
@@ -39,6 +115,8 @@ impl<R, W> QapiStream<R, W> {
         Self {
             service,
             events,
+            #[cfg(feature = "qapi-qmp")]
+            qmp: Default::default(),
         }
     }
 
@@ -54,17 +132,436 @@ impl<R, W> QapiStream<R, W> {
         (self.service, handle)
     }
 
+    /// Separates the service from a future that must be driven for its commands to complete,
+    /// see [`QapiEvents::spin`].
+    ///
+    /// This suits the guest agent, which has no events to process, and works with any executor.
+    pub fn into_spin(self) -> (QapiService<W>, impl Future<Output=io::Result<()>>) where
+        QapiEvents<R>: Future<Output=io::Result<()>>,
+    {
+        (self.service, self.events.spin())
+    }
+
+    /// Passes events to `on_event` in a spawned task, see [`QapiEvents::run`].
+    #[cfg(all(feature = "qapi-qmp", feature = "async-tokio-spawn"))]
+    pub fn spawn_run_tokio<F: FnMut(qapi_qmp::Event) + Send + 'static>(self, on_event: F) -> (QapiService<W>, ::tokio::task::JoinHandle<io::Result<()>>) where
+        QapiEvents<R>: Stream<Item=io::Result<qapi_qmp::Event>> + Send + 'static,
+    {
+        let handle = self.events.spawn_run_tokio(on_event);
+        (self.service, handle)
+    }
+
+    /// Pings the guest agent every `interval` in the background, see [`QapiService::spawn_keepalive`].
+    ///
+    /// Responses are only received while the stream is being driven, so this is meant to be
+    /// followed by [`QapiStream::spawn_tokio`].
+    #[cfg(all(feature = "qapi-qga", feature = "async-tokio-spawn", feature = "async-tokio-time"))]
+    pub fn with_keepalive(self, interval: Duration, timeout: Duration) -> Self where
+        W: Sink<Serialized<Execute<qapi_qga::guest_ping, u64>>, Error=io::Error> + Unpin + Send + 'static,
+    {
+        self.service.spawn_keepalive(interval, timeout);
+        self
+    }
+
+    /// Shuts down the stream, see [`QapiService::close`].
+    ///
+    /// Responses that were already received are routed first, so commands answered just
+    /// before closing complete rather than failing, see [`QapiEvents::drain_buffered`].
+    pub async fn close(&mut self) -> io::Result<()> where
+        QapiEvents<R>: Future<Output=io::Result<()>> + Unpin,
+        W: CloseSink + Unpin,
+    {
+        if let Err(e) = self.events.drain_buffered() {
+            trace!("QAPI stream failed while closing: {:?}", e);
+        }
+        self.service.close().await
+    }
+
+    /// Shuts down the stream gracefully, passing any events that arrive meanwhile to `on_event`.
+    ///
+    /// Unlike [`QapiStream::close`], this waits up to `timeout` for pending commands to
+    /// complete and delivers the events already received before closing the write half.
+    /// See [`QapiService::shutdown`].
+    #[cfg(all(feature = "qapi-qmp", feature = "async-tokio-time"))]
+    pub async fn shutdown<F: FnMut(qapi_qmp::Event)>(mut self, timeout: Duration, mut on_event: F) -> io::Result<()> where
+        QapiEvents<R>: Stream<Item=io::Result<qapi_qmp::Event>> + Unpin,
+        W: CloseSink + Unpin,
+    {
+        use futures::StreamExt;
+
+        self.service.shared.draining.store(true, Ordering::Relaxed);
+        let idle = ::tokio::time::timeout(timeout, self.service.idle()).fuse();
+        futures::pin_mut!(idle);
+        loop {
+            futures::select_biased! {
+                res = idle => {
+                    if res.is_err() {
+                        warn!("QAPI shutdown timed out with {} commands pending", self.service.pending_count());
+                    }
+                    break
+                },
+                event = self.events.next().fuse() => match event {
+                    Some(Ok(event)) => on_event(event),
+                    Some(Err(e)) => return Err(e),
+                    None => break,
+                },
+            }
+        }
+
+        while let Some(Some(Ok(event))) = self.events.next().now_or_never() {
+            on_event(event);
+        }
+
+        self.service.close().await
+    }
+
+    /// The capabilities that were enabled by [`QmpStreamNegotiation::negotiate_caps`] or
+    /// [`QmpStreamNegotiation::negotiate_supported`].
+    ///
+    /// These are the requested capabilities that `qmp_capabilities` accepted, each listed once,
+    /// leaving out any that weren't advertised in the greeting.
+    #[cfg(feature = "qapi-qmp")]
+    pub fn capabilities(&self) -> &[QMPCapability] {
+        &self.qmp.capabilities
+    }
+
+    /// Whether out-of-band execution was enabled during negotiation.
+    pub fn supports_oob(&self) -> bool {
+        self.service.oob_enabled()
+    }
+
+    /// The QEMU version from the QMP greeting.
+    ///
+    /// Returns `None` for streams that weren't opened with a QMP greeting.
+    #[cfg(feature = "qapi-qmp")]
+    pub fn version(&self) -> Option<&qapi_qmp::VersionInfo> {
+        self.qmp.greeting.as_ref().map(|greeting| &greeting.version)
+    }
+
+    /// Executes several commands at once, see [`QapiService::execute_pipelined`].
+    pub fn execute_pipelined<'a, C: Command + 'a, I: IntoIterator<Item=C>>(&'a mut self, commands: I) -> impl Future<Output=Vec<ExecuteResult<C>>> + 'a where
+        QapiEvents<R>: Future<Output=io::Result<()>> + Unpin,
+        W: Sink<Serialized<Execute<C, u64>>, Error=io::Error> + Unpin
+    {
+        let commands: Vec<C> = commands.into_iter().collect();
+        let count = commands.len();
+        let execute = self.service.execute_pipelined(commands).map(Ok);
+        self.drive(execute).map(move |res| res.unwrap_or_else(|e| fail_each(count, e)))
+    }
+
+    /// Installs a hook that sees raw messages, see [`QapiService::set_wire_logger`].
+    pub fn set_wire_logger<F: Fn(WireDirection, &[u8]) + Send + Sync + 'static>(&self, logger: F) {
+        self.service.set_wire_logger(logger)
+    }
+
+    /// Removes the hook installed by [`QapiStream::set_wire_logger`].
+    pub fn clear_wire_logger(&self) {
+        self.service.clear_wire_logger()
+    }
+
+    /// Reports the latency of commands, see [`QapiService::set_metrics_sink`].
+    pub fn set_metrics_sink<M: MetricsSink + 'static>(&self, sink: M) {
+        self.service.set_metrics_sink(sink)
+    }
+
+    /// Removes the sink installed by [`QapiStream::set_metrics_sink`].
+    pub fn clear_metrics_sink(&self) {
+        self.service.clear_metrics_sink()
+    }
+
+    /// Checks that responses arrive in order, see [`QapiService::set_response_order`].
+    pub fn set_response_order(&self, order: ResponseOrder) {
+        self.service.set_response_order(order)
+    }
+
+    /// Fails the stream on unmatched responses, see [`QapiService::set_strict_responses`].
+    pub fn set_strict_responses(&self, strict: bool) {
+        self.service.set_strict_responses(strict)
+    }
+
+    /// Tolerates malformed JSON, see [`QapiService::set_lenient`].
+    pub fn set_lenient(&self, lenient: bool) {
+        self.service.set_lenient(lenient)
+    }
+
+    /// Limits how many commands await a response at once, see [`QapiService::set_max_inflight`].
+    pub fn set_max_inflight(&self, max: Option<usize>) {
+        self.service.set_max_inflight(max)
+    }
+
+    /// Sizes the buffer that messages are read into, see [`QapiEvents::with_capacity`].
+    pub fn with_capacity(mut self, capacity: usize) -> Self where
+        R: ReadBuffer,
+    {
+        self.events.stream.reserve_read(capacity);
+        self
+    }
+
+    /// Fails the stream if the peer goes quiet, see [`QapiEvents::with_idle_timeout`].
+    #[cfg(feature = "async-tokio-time")]
+    pub fn with_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.events = self.events.with_idle_timeout(timeout);
+        self
+    }
+
+    /// Retains the last `capacity` events, see [`QapiEvents::with_history`].
+    #[cfg(feature = "qapi-qmp")]
+    pub fn with_history(self, capacity: usize) -> Self {
+        self.service.shared.history.lock().unwrap().set_capacity(capacity);
+        self
+    }
+
+    /// The events retained by [`QapiStream::with_history`], oldest first.
+    #[cfg(feature = "qapi-qmp")]
+    pub fn recent_events(&self) -> Vec<qapi_qmp::Event> {
+        self.service.recent_events()
+    }
+
+    /// Whether the connection is still up, see [`QapiService::is_connected`].
+    pub fn is_connected(&self) -> bool {
+        self.service.is_connected()
+    }
+
+    /// The number of commands still waiting for a response, see [`QapiService::pending_count`].
+    pub fn pending_count(&self) -> usize {
+        self.service.pending_count()
+    }
+
+    /// The ids of commands still waiting for a response, see [`QapiService::pending_ids`].
+    pub fn pending_ids(&self) -> Vec<u64> {
+        self.service.pending_ids()
+    }
+
+    /// Fails every command still waiting for a response, see [`QapiService::fail_pending`].
+    pub fn fail_pending(&self, kind: io::ErrorKind) -> usize {
+        self.service.fail_pending(kind)
+    }
+
     pub fn execute<'a, C: Command + 'a>(&'a mut self, command: C) -> impl Future<Output=ExecuteResult<C>> + 'a where
         QapiEvents<R>: Future<Output=io::Result<()>> + Unpin,
-        W: Sink<Execute<C, u32>, Error=io::Error> + Unpin
+        W: Sink<Serialized<Execute<C, u64>>, Error=io::Error> + Unpin
+    {
+        let execute = self.service.execute(command);
+        self.drive(execute)
+    }
+
+    /// Executes a command with an id chosen by the caller, see [`QapiService::execute_with_id`].
+    pub fn execute_with_id<'a, C: Command + 'a>(&'a mut self, command: C, id: Any) -> impl Future<Output=ExecuteResult<C>> + 'a where
+        QapiEvents<R>: Future<Output=io::Result<()>> + Unpin,
+        W: Sink<Serialized<Execute<C, Any>>, Error=io::Error> + Unpin
+    {
+        let execute = self.service.execute_with_id(command, id);
+        self.drive(execute)
+    }
+
+    /// Executes a command, failing with `TimedOut` if no response arrives within `timeout`.
+    #[cfg(feature = "async-tokio-time")]
+    pub fn execute_timeout<'a, C: Command + 'a>(&'a mut self, command: C, timeout: Duration) -> impl Future<Output=ExecuteResult<C>> + 'a where
+        QapiEvents<R>: Future<Output=io::Result<()>> + Unpin,
+        W: Sink<Serialized<Execute<C, u64>>, Error=io::Error> + Unpin
+    {
+        let execute = self.service.execute_timeout(command, timeout);
+        self.drive(execute)
+    }
+
+    /// Waits until QEMU's run state is `state`, returning its status once it is.
+    ///
+    /// The status is checked right away, and again after every event, since run state changes
+    /// are announced by many of them (`STOP`, `RESUME`, `MIGRATION`, `RESET`, `GUEST_PANICKED`
+    /// and more). Events received meanwhile aren't lost, and are yielded by the events afterwards.
+    ///
+    /// Fails with `TimedOut` if QEMU doesn't reach `state` within `timeout`.
+    #[cfg(all(feature = "qapi-qmp", feature = "async-tokio-time"))]
+    pub async fn wait_for_state(&mut self, state: qapi_qmp::RunState, timeout: Duration) -> Result<qapi_qmp::StatusInfo, ExecuteError> where
+        QapiEvents<R>: Stream<Item=io::Result<qapi_qmp::Event>> + Unpin,
+        W: Sink<Serialized<Execute<qapi_qmp::query_status, u64>>, Error=io::Error> + Unpin,
+    {
+        use futures::StreamExt;
+        use futures::future::FusedFuture;
+
+        let shared = self.service.shared.clone();
+        let mut skipped = QapiDeferGuard {
+            shared: &shared,
+            events: Vec::new(),
+        };
+        let query = self.service.execute(qapi_qmp::query_status { }).fuse();
+        let deadline = ::tokio::time::sleep(timeout).fuse();
+        futures::pin_mut!(query, deadline);
+        // the state changed while a query was already in flight
+        let mut stale = false;
+        loop {
+            futures::select_biased! {
+                status = query => {
+                    let status = status?;
+                    if status.status == state {
+                        return Ok(status)
+                    }
+                    if stale {
+                        stale = false;
+                        query.set(self.service.execute(qapi_qmp::query_status { }).fuse());
+                    }
+                },
+                event = self.events.next().fuse() => match event {
+                    Some(Ok(event)) => {
+                        if query.is_terminated() {
+                            query.set(self.service.execute(qapi_qmp::query_status { }).fuse());
+                        } else {
+                            stale = true;
+                        }
+                        skipped.events.push(event);
+                    },
+                    Some(Err(e)) => return Err(e.into()),
+                    None => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, format!("QAPI stream ended while waiting for QEMU to be {:?}", state)).into()),
+                },
+                () = deadline => return Err(io::Error::new(io::ErrorKind::TimedOut, format!("timed out waiting for QEMU to be {:?}", state)).into()),
+            }
+        }
+    }
+
+    /// Executes a command and waits for the first event of type `E` that satisfies `matcher`.
+    ///
+    /// Events are watched from before the command is sent, so one that arrives ahead of the
+    /// command's response isn't missed. Other events received meanwhile are discarded.
+    /// Fails with `TimedOut` if the response and event don't both arrive within `timeout`.
+    #[cfg(all(feature = "qapi-qmp", feature = "async-tokio-time"))]
+    pub async fn execute_and_await_event<C, E, F>(&mut self, command: C, timeout: Duration, mut matcher: F) -> Result<(C::Ok, E), ExecuteError> where
+        C: Command,
+        E: qapi_spec::Event + std::convert::TryFrom<qapi_qmp::Event>,
+        F: FnMut(&E) -> bool,
+        QapiEvents<R>: Stream<Item=io::Result<qapi_qmp::Event>> + Unpin,
+        W: Sink<Serialized<Execute<C, u64>>, Error=io::Error> + Unpin,
     {
+        use futures::StreamExt;
+
         let execute = self.service.execute(command).fuse();
+        let deadline = ::tokio::time::sleep(timeout).fuse();
+        futures::pin_mut!(execute, deadline);
+        let mut response = None;
+        let mut matched = None;
+        loop {
+            futures::select_biased! {
+                res = execute => response = Some(res?),
+                event = self.events.next().fuse() => match event {
+                    Some(Ok(event)) => match E::try_from(event) {
+                        Ok(event) if matched.is_none() && matcher(&event) => matched = Some(event),
+                        _ => (),
+                    },
+                    Some(Err(e)) => return Err(e.into()),
+                    None => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, format!("unexpected EOF when waiting for {} event", E::NAME)).into()),
+                },
+                () = deadline => return Err(io::Error::new(io::ErrorKind::TimedOut, match response {
+                    Some(..) => format!("timed out waiting for {} event", E::NAME),
+                    None => format!("QAPI command {} timed out", C::NAME),
+                }).into()),
+            }
+
+            match (response.take(), matched.take()) {
+                (Some(response), Some(event)) => return Ok((response, event)),
+                (res, event) => {
+                    response = res;
+                    matched = event;
+                },
+            }
+        }
+    }
+
+    /// Executes the commands sent to the returned sink, see [`QapiService::command_stream`].
+    ///
+    /// Responses are processed as the results are polled.
+    pub fn command_stream<C: Command>(&mut self) -> CommandStream<'_, W, C, &mut QapiEvents<R>> where
+        QapiEvents<R>: Future<Output=io::Result<()>> + Unpin,
+    {
+        CommandStream::new(&self.service, &mut self.events)
+    }
+
+    /// Executes a command by name, see [`QapiService::execute_raw`].
+    #[cfg(any(feature = "qapi-qmp", feature = "qapi-qga"))]
+    pub fn execute_raw<'a>(&'a mut self, name: &str, arguments: Any) -> impl Future<Output=io::Result<Result<Any, crate::Error>>> + 'a where
+        QapiEvents<R>: Future<Output=io::Result<()>> + Unpin,
+        W: Sink<Serialized<crate::ExecuteRaw<u64>>, Error=io::Error> + Unpin
+    {
+        let execute = self.service.execute_raw(name, arguments).map(Ok);
+        self.drive(execute).map(|res| res.map_err(io::Error::from).and_then(|res| res))
+    }
+
+    /// Runs an HMP command, returning its text output, see [`crate::Qmp::hmp`].
+    #[cfg(feature = "qapi-qmp")]
+    pub fn hmp<'a>(&'a mut self, command_line: &str) -> impl Future<Output=io::Result<Result<String, crate::Error>>> + 'a where
+        QapiEvents<R>: Future<Output=io::Result<()>> + Unpin,
+        W: Sink<Serialized<Execute<qapi_qmp::human_monitor_command, u64>>, Error=io::Error> + Unpin
+    {
+        let execute = self.service.hmp(command_line).map(Ok);
+        self.drive(execute).map(|res| res.map_err(io::Error::from).and_then(|res| res))
+    }
+
+    /// Checks that QEMU is responsive, see [`QapiService::ping`].
+    #[cfg(all(feature = "qapi-qmp", feature = "async-tokio-time"))]
+    pub fn ping<'a>(&'a mut self) -> impl Future<Output=io::Result<()>> + 'a where
+        QapiEvents<R>: Future<Output=io::Result<()>> + Unpin,
+        W: Sink<Serialized<Execute<qapi_qmp::query_version, u64>>, Error=io::Error> + Unpin
+    {
+        let execute = self.service.ping().map(Ok);
+        self.drive(execute).map(|res| res.map_err(io::Error::from).and_then(|res| res))
+    }
+
+    /// Executes a command that was already serialized, see [`QapiService::execute_serialized`].
+    #[cfg(any(feature = "qapi-qmp", feature = "qapi-qga"))]
+    pub fn execute_serialized<'a>(&'a mut self, json: &[u8]) -> impl Future<Output=io::Result<Result<Any, crate::Error>>> + 'a where
+        QapiEvents<R>: Future<Output=io::Result<()>> + Unpin,
+        W: Sink<Serialized<crate::ExecuteRaw<u64>>, Error=io::Error> + Unpin
+    {
+        let execute = self.service.execute_serialized(json).map(Ok);
+        self.drive(execute).map(|res| res.map_err(io::Error::from).and_then(|res| res))
+    }
+
+    /// Executes a command along with a file descriptor, see [`QapiService::execute_with_fd`].
+    #[cfg(unix)]
+    pub fn execute_with_fd<'a, C: Command + 'a>(&'a mut self, command: C, fd: RawFd) -> impl Future<Output=ExecuteResult<C>> + 'a where
+        QapiEvents<R>: Future<Output=io::Result<()>> + Unpin,
+        W: Sink<Serialized<Execute<C, u64>>, Error=io::Error> + SendFd + Unpin
+    {
+        let execute = self.service.execute_with_fd(command, fd);
+        self.drive(execute)
+    }
+
+    /// Executes a command out-of-band, see [`QapiService::execute_oob`].
+    pub fn execute_oob<'a, C: OobCommand + 'a>(&'a mut self, command: C) -> impl Future<Output=ExecuteResult<C>> + 'a where
+        QapiEvents<R>: Future<Output=io::Result<()>> + Unpin,
+        W: Sink<Serialized<ExecuteOob<C, u64>>, Error=io::Error> + Unpin
+    {
+        let execute = self.service.execute_oob(command);
+        self.drive(execute)
+    }
+
+    /// Executes a command out-of-band if possible, see [`QapiService::execute_oob_fallback`].
+    pub fn execute_oob_fallback<'a, C: OobCommand + 'a>(&'a mut self, command: C, fallback: OobFallback) -> impl Future<Output=ExecuteResult<C>> + 'a where
+        QapiEvents<R>: Future<Output=io::Result<()>> + Unpin,
+        W: Sink<Serialized<Execute<C, u64>>, Error=io::Error> + Sink<Serialized<ExecuteOob<C, u64>>, Error=io::Error> + Unpin
+    {
+        let execute = self.service.execute_oob_fallback(command, fallback);
+        self.drive(execute)
+    }
+
+    /// Executes several commands out-of-band at once, see [`QapiService::execute_all`].
+    pub fn execute_all<'a, C: OobCommand + 'a>(&'a mut self, commands: Vec<C>) -> impl Future<Output=Vec<ExecuteResult<C>>> + 'a where
+        QapiEvents<R>: Future<Output=io::Result<()>> + Unpin,
+        W: Sink<Serialized<Execute<C, u64>>, Error=io::Error> + Sink<Serialized<ExecuteOob<C, u64>>, Error=io::Error> + Unpin
+    {
+        let count = commands.len();
+        let execute = self.service.execute_all(commands).map(Ok);
+        self.drive(execute).map(move |res| res.unwrap_or_else(|e| fail_each(count, e)))
+    }
+
+    fn drive<'a, T: 'a, F: Future<Output=Result<T, ExecuteError>> + 'a>(&'a mut self, future: F) -> impl Future<Output=Result<T, ExecuteError>> + 'a where
+        QapiEvents<R>: Future<Output=io::Result<()>> + Unpin,
+    {
+        let future = future.fuse();
 
         async move {
-            futures::pin_mut!(execute);
+            futures::pin_mut!(future);
 
             futures::select_biased! {
-                res = execute => res,
+                res = future => res,
                 res = (&mut self.events).fuse() => {
                     res?;
                     Err(io::Error::new(io::ErrorKind::UnexpectedEof, "unexpected EOF when executing command").into())
@@ -74,223 +571,2191 @@ impl<R, W> QapiStream<R, W> {
     }
 }
 
+/// Fails each of `count` commands with the error that stopped the stream.
+fn fail_each<T>(count: usize, e: ExecuteError) -> Vec<Result<T, ExecuteError>> {
+    let e = io::Error::from(e);
+    (0..count).map(|_| Err(io::Error::new(e.kind(), e.to_string()).into())).collect()
+}
+
+/// Cancels a command started by [`QapiService::execute_cancellable`].
+///
+/// Dropping the token without calling `cancel` leaves the command running.
+pub struct CancelToken {
+    sender: oneshot::Sender<()>,
+}
+
+impl CancelToken {
+    pub fn cancel(self) {
+        let _ = self.sender.send(());
+    }
+}
+
+/// A block node added by [`QapiService::blockdev_add`].
+///
+/// The node isn't deleted when this is dropped, only by an explicit [`Blockdev::remove`].
 #[cfg(feature = "qapi-qmp")]
-pub struct QmpStreamNegotiation<S, W> {
-    pub stream: QapiStream<S, W>,
-    pub capabilities: QapiCapabilities,
+#[must_use = "the block node remains until removed"]
+pub struct Blockdev<'a, W> {
+    service: &'a QapiService<W>,
+    node_name: String,
 }
 
 #[cfg(feature = "qapi-qmp")]
-impl<S, W> QmpStreamNegotiation<S, W> where
-    QapiEvents<S>: Future<Output=io::Result<()>> + Unpin,
-    W: Sink<Execute<qapi_qmp::qmp_capabilities, u32>, Error=io::Error> + Unpin,
-{
-    pub async fn negotiate_caps<C>(mut self, caps: C) -> io::Result<QapiStream<S, W>> where
-        C: IntoIterator<Item=QMPCapability>,
-    {
-        let _ = self.stream.execute(qapi_qmp::qmp_capabilities {
-            enable: Some(caps.into_iter().collect()),
-        }).await?;
+impl<'a, W> Blockdev<'a, W> {
+    pub fn node_name(&self) -> &str {
+        &self.node_name
+    }
 
-        Ok(self.stream)
+    /// Forgets the handle, leaving the node in place.
+    pub fn into_node_name(self) -> String {
+        self.node_name
     }
 
-    pub async fn negotiate(self) -> io::Result<QapiStream<S, W>> {
-        self.negotiate_caps(std::iter::empty()).await
+    /// Deletes the node with `blockdev-del`.
+    pub async fn remove(self) -> Result<(), ExecuteError> where
+        W: Sink<Serialized<Execute<qapi_qmp::blockdev_del, u64>>, Error=io::Error> + Unpin
+    {
+        self.service.execute(qapi_qmp::blockdev_del {
+            node_name: self.node_name,
+        }).await.map(drop)
     }
 }
 
-type QapiCommandMap = BTreeMap<u32, oneshot::Sender<Result<Any, qapi_spec::Error>>>;
+/// What [`QapiService::execute_oob_fallback`] does when out-of-band execution isn't available.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum OobFallback {
+    /// Executes the command in-band instead, after any commands already in progress.
+    Serial,
+    /// Fails with an `io::Error`.
+    Error,
+}
 
-pub struct QapiService<W> {
-    shared: Arc<QapiShared>,
-    write: Arc<Mutex<W>>,
-    id_counter: AtomicUsize,
+/// How responses are checked against the order their commands were sent in, see
+/// [`QapiService::set_response_order`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ResponseOrder {
+    /// Responses are matched to commands in the order they arrive.
+    Unchecked,
+    /// Logs a warning on responses that arrive out of order, and delivers them to the command they belong to.
+    Warn,
+    /// Fails the stream on responses that arrive out of order.
+    Error,
 }
 
-impl<W> QapiService<W> {
-    #[cfg(feature = "tokio")]
-    fn new(write: W, shared: Arc<QapiShared>) -> Self {
-        QapiService {
-            shared,
-            write: Mutex::new(write).into(),
-            id_counter: AtomicUsize::new(0),
+impl ResponseOrder {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            1 => ResponseOrder::Warn,
+            2 => ResponseOrder::Error,
+            _ => ResponseOrder::Unchecked,
         }
     }
+}
+
+/// Which way a message logged by a wire logger was travelling.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum WireDirection {
+    /// A command written to the peer
+    Send,
+    /// A line received from the peer, before it is parsed
+    Receive,
+}
+
+type WireLogger = Box<dyn Fn(WireDirection, &[u8]) + Send + Sync>;
 
-    fn next_oob_id(&self) -> u32 {
-        self.id_counter.fetch_add(1, Ordering::Relaxed) as _
+/// Where raw messages are reported, see [`QapiService::set_wire_logger`].
+pub(crate) struct WireLog {
+    enabled: AtomicBool,
+    logger: StdMutex<Option<WireLogger>>,
+    /// whether `received` is kept up to date, see [`QapiEvents::with_idle_timeout`]
+    #[cfg(feature = "async-tokio-time")]
+    tracking: AtomicBool,
+    /// when a line was last received, in nanoseconds since `created`
+    #[cfg(feature = "async-tokio-time")]
+    received: AtomicU64,
+    #[cfg(feature = "async-tokio-time")]
+    created: ::tokio::time::Instant,
+}
+
+impl Default for WireLog {
+    fn default() -> Self {
+        Self {
+            enabled: Default::default(),
+            logger: Default::default(),
+            #[cfg(feature = "async-tokio-time")]
+            tracking: Default::default(),
+            #[cfg(feature = "async-tokio-time")]
+            received: Default::default(),
+            #[cfg(feature = "async-tokio-time")]
+            created: ::tokio::time::Instant::now(),
+        }
     }
+}
 
-    fn command_id(&self) -> Option<u32> {
-        if self.shared.supports_oob {
-            Some(self.next_oob_id())
-        } else {
-            None
+impl WireLog {
+    pub(crate) fn log(&self, direction: WireDirection, line: &[u8]) {
+        #[cfg(feature = "async-tokio-time")]
+        if direction == WireDirection::Receive && self.tracking.load(Ordering::Relaxed) {
+            self.received.store(self.created.elapsed().as_nanos() as u64, Ordering::Relaxed);
+        }
+        if self.enabled.load(Ordering::Relaxed) {
+            if let Some(logger) = &*self.logger.lock().unwrap() {
+                logger(direction, line)
+            }
         }
     }
 
-    fn command_response<C: Command>(receiver: oneshot::Receiver<Result<Any, qapi_spec::Error>>) -> impl Future<Output=ExecuteResult<C>> {
-        receiver.map(|res| match res {
-            Ok(Ok(res)) => C::Ok::deserialize(&res)
-                .map_err(io::Error::from).map_err(From::from),
-            Ok(Err(e)) => Err(e.into()),
-            Err(_cancelled) => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "QAPI stream disconnected").into()),
-        })
+    fn set(&self, logger: Option<WireLogger>) {
+        let mut slot = self.logger.lock().unwrap();
+        self.enabled.store(logger.is_some(), Ordering::Relaxed);
+        *slot = logger;
     }
 
-    pub fn execute<C: Command>(&self, command: C) -> impl Future<Output=ExecuteResult<C>> where
-        W: Sink<Execute<C, u32>, Error=io::Error> + Unpin
-    {
-        let id = self.command_id();
-        let sink = self.write.clone();
-        let shared = self.shared.clone();
-        let command = Execute::new(command, id);
+    /// Starts keeping track of when lines are received, as of now.
+    #[cfg(feature = "async-tokio-time")]
+    fn track_received(&self) {
+        self.received.store(self.created.elapsed().as_nanos() as u64, Ordering::Relaxed);
+        self.tracking.store(true, Ordering::Relaxed);
+    }
 
-        async move {
-            let mut sink = sink.lock().await;
-            let receiver = shared.command_insert(id.unwrap_or_default());
+    #[cfg(feature = "async-tokio-time")]
+    fn last_received(&self) -> ::tokio::time::Instant {
+        self.created + Duration::from_nanos(self.received.load(Ordering::Relaxed))
+    }
+}
 
-            sink.send(command).await?;
-            if id.is_some() {
-                // retain write lock only if id/oob execution isn't supported
-                drop(sink)
-            }
+/// Observes the commands sent over a stream and how long they take, see
+/// [`QapiService::set_metrics_sink`].
+///
+/// Each method does nothing by default. They're called from whichever task executes the
+/// command, so should return quickly.
+pub trait MetricsSink: Send + Sync {
+    /// The command was written to the peer.
+    fn command_sent(&self, command: &CommandInfo) {
+        let _ = command;
+    }
+
+    /// A response arrived `elapsed` after the command was sent, with the `error` the peer
+    /// returned if the command failed.
+    fn response_received(&self, command: &CommandInfo, elapsed: Duration, error: Option<&crate::Error>) {
+        let _ = (command, elapsed, error);
+    }
+
+    /// The command was abandoned with `TimedOut`, `elapsed` after it was sent.
+    fn command_timed_out(&self, command: &CommandInfo, elapsed: Duration) {
+        let _ = (command, elapsed);
+    }
+}
+
+/// Identifies a command reported to a [`MetricsSink`].
+#[derive(Debug, Clone, Copy)]
+pub struct CommandInfo<'a> {
+    pub name: &'a str,
+    /// The id the command was sent with, or the key its response is matched by when sent
+    /// without one, as with [`QapiService::pending_ids`].
+    pub id: u64,
+    /// Whether the command was executed out-of-band.
+    pub oob: bool,
+}
+
+/// The name of a command message, for reporting to a [`MetricsSink`].
+trait CommandMessage {
+    fn command_name(&self) -> Cow<'static, str>;
+
+    fn oob(&self) -> bool {
+        false
+    }
+}
+
+impl<C: Command, I> CommandMessage for Execute<C, I> {
+    fn command_name(&self) -> Cow<'static, str> {
+        C::NAME.into()
+    }
+}
+
+impl<C: Command, I> CommandMessage for ExecuteOob<C, I> {
+    fn command_name(&self) -> Cow<'static, str> {
+        C::NAME.into()
+    }
+
+    fn oob(&self) -> bool {
+        true
+    }
+}
+
+impl<I> CommandMessage for crate::ExecuteRaw<I> {
+    fn command_name(&self) -> Cow<'static, str> {
+        self.execute.clone().into()
+    }
+}
+
+/// Covers a command from when it is sent until its response arrives.
+#[cfg(feature = "tracing")]
+fn command_span<M: CommandMessage>(key: u64, message: &M) -> tracing::Span {
+    tracing::debug_span!("qapi_execute",
+        command = %message.command_name(),
+        id = key,
+        oob = message.oob(),
+        error = tracing::field::Empty,
+    )
+}
+
+/// Records the error a command failed with on its span.
+#[cfg(feature = "tracing")]
+fn command_span_finish<T>(res: &Result<T, ExecuteError>) {
+    if let Err(e) = res {
+        tracing::Span::current().record("error", tracing::field::display(e));
+    }
+}
+
+/// A command sent by [`QapiService::execute_serialized`].
+#[cfg(any(feature = "qapi-qmp", feature = "qapi-qga"))]
+#[derive(serde::Deserialize)]
+struct SerializedCommand {
+    execute: Option<String>,
+    #[serde(rename = "exec-oob")]
+    exec_oob: Option<String>,
+    id: Option<Any>,
+}
+
+#[cfg(any(feature = "qapi-qmp", feature = "qapi-qga"))]
+impl CommandMessage for SerializedCommand {
+    fn command_name(&self) -> Cow<'static, str> {
+        self.execute.as_ref().or(self.exec_oob.as_ref())
+            .cloned().unwrap_or_default().into()
+    }
+
+    fn oob(&self) -> bool {
+        self.exec_oob.is_some()
+    }
+}
+
+#[cfg(any(feature = "qapi-qmp", feature = "qapi-qga"))]
+impl SerializedCommand {
+    fn parse(json: &[u8]) -> io::Result<Self> {
+        let invalid = |e| io::Error::new(io::ErrorKind::InvalidInput, e);
+        if json.iter().find(|b| !b.is_ascii_whitespace()) != Some(&b'{') {
+            return Err(invalid("QAPI command must be a JSON object".into()))
+        }
+        let command: Self = serde_json::from_slice(json)
+            .map_err(|e| invalid(format!("failed to parse QAPI command: {}", e)))?;
+        match (&command.execute, &command.exec_oob) {
+            (Some(..), None) | (None, Some(..)) => Ok(command),
+            _ => Err(invalid("QAPI command must have one of 'execute' or 'exec-oob'".into())),
+        }
+    }
+
+    /// The command with `id` added to it.
+    fn line(json: &[u8], id: Option<u64>) -> Vec<u8> {
+        let end = json.iter().rposition(|b| !b.is_ascii_whitespace()).unwrap_or(0);
+        let mut line = Vec::with_capacity(end + 24);
+        match id {
+            Some(id) => {
+                // replaces the closing brace of the object
+                line.extend_from_slice(&json[..end]);
+                line.extend_from_slice(format!(",\"id\":{}}}", id).as_bytes());
+            },
+            None => line.extend_from_slice(&json[..=end]),
+        }
+        line
+    }
+}
+
+/// Times a command for the installed [`MetricsSink`].
+struct CommandTiming {
+    sink: Arc<dyn MetricsSink>,
+    name: Cow<'static, str>,
+    id: u64,
+    oob: bool,
+    sent: Instant,
+}
+
+impl CommandTiming {
+    fn new<M: CommandMessage>(shared: &QapiShared, key: u64, message: &M) -> Option<Self> {
+        shared.metrics.lock().unwrap().clone().map(|sink| CommandTiming {
+            sink,
+            name: message.command_name(),
+            id: key,
+            oob: message.oob(),
+            sent: Instant::now(),
+        })
+    }
+
+    fn info(&self) -> CommandInfo<'_> {
+        CommandInfo {
+            name: &self.name,
+            id: self.id,
+            oob: self.oob,
+        }
+    }
+
+    fn sent(&mut self) {
+        self.sent = Instant::now();
+        self.sink.command_sent(&self.info());
+    }
+
+    fn finish<T>(&self, res: &Result<T, ExecuteError>) {
+        let elapsed = self.sent.elapsed();
+        match res {
+            Ok(..) => self.sink.response_received(&self.info(), elapsed, None),
+            Err(ExecuteError::Qapi(e)) => self.sink.response_received(&self.info(), elapsed, Some(e)),
+            Err(ExecuteError::Io(e)) if e.kind() == io::ErrorKind::TimedOut => self.sink.command_timed_out(&self.info(), elapsed),
+            Err(ExecuteError::Io(..)) => (),
+        }
+    }
+}
+
+/// A message that has already been serialized to JSON.
+///
+/// Commands are serialized before a [`QapiService`] takes its write lock, so that only the
+/// write itself is serialized between concurrent callers. The newline that ends the message
+/// isn't part of it, and is added by the sink as it writes the message, rather than growing
+/// the serialized message to append it.
+pub struct Serialized<M> {
+    json: Vec<u8>,
+    _message: PhantomData<fn() -> M>,
+}
+
+impl<M: Serialize> Serialized<M> {
+    pub fn new(message: &M) -> io::Result<Self> {
+        serde_json::to_vec(message).map(Self::from_json).map_err(From::from)
+    }
+}
+
+impl<M> Serialized<M> {
+    /// Wraps a message that was serialized elsewhere.
+    fn from_json(json: Vec<u8>) -> Self {
+        Self {
+            json,
+            _message: PhantomData,
+        }
+    }
+
+    /// The serialized message, without its trailing newline.
+    ///
+    /// A batch of commands holds several messages separated by newlines, see
+    /// [`QapiService::batch`].
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.json
+    }
+}
+
+/// A QMP stream whose greeting has been read, but which is still in capabilities negotiation mode.
+///
+/// `capabilities` holds the greeting, with the QEMU version and the capabilities it offers, so
+/// that the caller can decide which to enable before finishing with
+/// [`QmpStreamNegotiation::negotiate_caps`].
+#[cfg(feature = "qapi-qmp")]
+pub struct QmpStreamNegotiation<S, W> {
+    pub stream: QapiStream<S, W>,
+    pub capabilities: QapiCapabilities,
+}
+
+#[cfg(feature = "qapi-qmp")]
+impl<S, W> QmpStreamNegotiation<S, W> where
+    QapiEvents<S>: Stream<Item=io::Result<qapi_qmp::Event>> + Unpin,
+    W: Sink<Serialized<Execute<qapi_qmp::qmp_capabilities, u64>>, Error=io::Error> + Unpin,
+{
+    /// Enables the requested capabilities and leaves capabilities negotiation mode.
+    ///
+    /// Events that QEMU emits before responding are kept, and are the first to be yielded
+    /// by the resulting stream's events. Like any others, they're discarded if the events are
+    /// driven as a `Future` instead, such as by [`QapiStream::execute`].
+    /// Failures are reported as an `io::Error` wrapping a [`NegotiationError`].
+    pub async fn negotiate_caps<C>(mut self, caps: C) -> io::Result<QapiStream<S, W>> where
+        C: IntoIterator<Item=QMPCapability>,
+    {
+        use futures::StreamExt;
+
+        let caps: Vec<_> = caps.into_iter().collect();
+        let execute = self.stream.service.execute(qapi_qmp::qmp_capabilities {
+            enable: Some(caps.clone()),
+        }).fuse();
+        futures::pin_mut!(execute);
+        let mut deferred = Vec::new();
+        let res = loop {
+            futures::select_biased! {
+                res = execute => break res,
+                event = self.stream.events.next().fuse() => match event {
+                    Some(Ok(event)) => deferred.push(event),
+                    Some(Err(e)) => break Err(e.into()),
+                    None => break Err(io::Error::new(io::ErrorKind::UnexpectedEof, "unexpected EOF when executing command").into()),
+                },
+            }
+        };
+        self.stream.service.shared.history.lock().unwrap().deferred.extend(deferred);
+
+        match res {
+            Ok(..) => {
+                // QEMU rejects capabilities it didn't offer, but a peer that accepts them
+                // anyway doesn't enable them either
+                let mut enabled = Vec::with_capacity(caps.len());
+                for cap in caps {
+                    if !self.capabilities.supports(cap) {
+                        warn!("QMP capability {:?} was accepted without being advertised, assuming it is disabled", cap);
+                    } else if !enabled.contains(&cap) {
+                        enabled.push(cap);
+                    }
+                }
+                self.stream.service.shared.oob_enabled.store(enabled.contains(&QMPCapability::oob), Ordering::Relaxed);
+                self.stream.qmp.capabilities = enabled;
+                Ok(self.stream)
+            },
+            Err(e) => Err(NegotiationError::from(e).into()),
+        }
+    }
+
+    /// Enables those of the requested capabilities that QEMU advertised in its greeting.
+    ///
+    /// A warning is logged for each capability that isn't available, and
+    /// [`QapiStream::capabilities`] reports which ones were actually enabled.
+    pub async fn negotiate_supported<C>(self, caps: C) -> io::Result<QapiStream<S, W>> where
+        C: IntoIterator<Item=QMPCapability>,
+    {
+        let (supported, unsupported): (Vec<_>, Vec<_>) = caps.into_iter()
+            .partition(|&cap| self.capabilities.supports(cap));
+        for cap in unsupported {
+            warn!("QMP capability {:?} was not advertised by QEMU, leaving it disabled", cap);
+        }
+
+        self.negotiate_caps(supported).await
+    }
+
+    pub async fn negotiate(self) -> io::Result<QapiStream<S, W>> {
+        self.negotiate_caps(std::iter::empty()).await
+    }
+}
+
+/// Describes why QMP capabilities negotiation failed.
+#[cfg(feature = "qapi-qmp")]
+#[derive(Debug)]
+pub enum NegotiationError {
+    /// QEMU rejected the `qmp_capabilities` command
+    Command(qapi_spec::Error),
+    /// The stream was closed before negotiation completed
+    Eof,
+    /// QEMU sent a message other than the response to `qmp_capabilities`, such as one that
+    /// couldn't be parsed or a response to a command that was never sent
+    Unexpected(ProtocolError),
+    /// Reading from or writing to the stream failed
+    Io(io::Error),
+}
+
+#[cfg(feature = "qapi-qmp")]
+impl NegotiationError {
+    /// The class of the error returned by `qmp_capabilities`, if it was rejected.
+    pub fn class(&self) -> Option<qapi_spec::ErrorClass> {
+        match self {
+            NegotiationError::Command(e) => Some(e.class),
+            _ => None,
+        }
+    }
+
+    /// Retrieves the negotiation failure from an error returned by
+    /// [`QmpStreamNegotiation::negotiate_caps`].
+    pub fn from_io(e: &io::Error) -> Option<&Self> {
+        e.get_ref().and_then(|e| e.downcast_ref())
+    }
+}
+
+#[cfg(feature = "qapi-qmp")]
+impl fmt::Display for NegotiationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NegotiationError::Command(e) => write!(f, "QMP capabilities negotiation failed: {}", e),
+            NegotiationError::Eof => write!(f, "unexpected EOF during QMP capabilities negotiation"),
+            NegotiationError::Unexpected(e) => write!(f, "unexpected message during QMP capabilities negotiation: {}", e),
+            NegotiationError::Io(e) => write!(f, "QMP capabilities negotiation failed: {}", e),
+        }
+    }
+}
+
+#[cfg(feature = "qapi-qmp")]
+impl error::Error for NegotiationError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            NegotiationError::Command(e) => Some(e),
+            NegotiationError::Eof => None,
+            NegotiationError::Unexpected(e) => Some(e),
+            NegotiationError::Io(e) => Some(e),
+        }
+    }
+}
+
+#[cfg(feature = "qapi-qmp")]
+impl From<ExecuteError> for NegotiationError {
+    fn from(e: ExecuteError) -> Self {
+        match e {
+            ExecuteError::Qapi(e) => NegotiationError::Command(e),
+            ExecuteError::Io(e) if e.kind() == io::ErrorKind::UnexpectedEof => NegotiationError::Eof,
+            ExecuteError::Io(e) if ProtocolError::from_io(&e).is_some() => {
+                let e = e.into_inner().and_then(|e| e.downcast().ok())
+                    .expect("checked by ProtocolError::from_io");
+                NegotiationError::Unexpected(*e)
+            },
+            ExecuteError::Io(e) => NegotiationError::Io(e),
+        }
+    }
+}
+
+#[cfg(feature = "qapi-qmp")]
+impl From<NegotiationError> for io::Error {
+    fn from(e: NegotiationError) -> Self {
+        let kind = match &e {
+            NegotiationError::Command(e) => e.class.into(),
+            NegotiationError::Eof => io::ErrorKind::UnexpectedEof,
+            NegotiationError::Unexpected(..) => io::ErrorKind::InvalidData,
+            NegotiationError::Io(e) => e.kind(),
+        };
+        io::Error::new(kind, e)
+    }
+}
+
+/// Describes a message from the peer that couldn't be understood, as opposed to a failure of
+/// the connection itself.
+///
+/// [`QapiEvents`] and its event stream fail with an `io::Error` wrapping this when the peer sends
+/// something unexpected, while a peer that hangs up cleanly just ends them.
+#[derive(Debug)]
+pub enum ProtocolError {
+    /// A message that isn't valid JSON or isn't a QAPI message, including one cut short by EOF
+    Parse(serde_json::Error),
+    /// A message longer than the maximum length, in bytes
+    TooLong(usize),
+    /// A response that doesn't belong to any command that was sent
+    UnexpectedResponse(String),
+}
+
+impl ProtocolError {
+    /// Retrieves the protocol error from an error returned by a QAPI stream, or `None` if the
+    /// connection failed for some other reason.
+    pub fn from_io(e: &io::Error) -> Option<&Self> {
+        e.get_ref().and_then(|e| e.downcast_ref())
+    }
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProtocolError::Parse(e) => write!(f, "failed to parse QAPI message: {}", e),
+            ProtocolError::TooLong(max_length) => write!(f, "QAPI message exceeded maximum length of {} bytes", max_length),
+            ProtocolError::UnexpectedResponse(message) => f.write_str(message),
+        }
+    }
+}
+
+impl error::Error for ProtocolError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            ProtocolError::Parse(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<ProtocolError> for io::Error {
+    fn from(e: ProtocolError) -> Self {
+        let kind = match &e {
+            ProtocolError::Parse(e) if e.is_eof() => io::ErrorKind::UnexpectedEof,
+            _ => io::ErrorKind::InvalidData,
+        };
+        io::Error::new(kind, e)
+    }
+}
+
+/// The result of a command as it was received, only parsed once it reaches the command waiting
+/// for it, straight into that command's type.
+pub(crate) type RawReturn = Box<serde_json::value::RawValue>;
+
+type QapiCommandSender = oneshot::Sender<Result<RawReturn, ExecuteError>>;
+/// Commands awaiting a response, see [`PendingMap`].
+type QapiCommandMap = PendingSlots<QapiCommandSender>;
+
+/// A command sink that can be shut down regardless of which command types it accepts.
+pub trait CloseSink {
+    /// Flushes any buffered commands and closes the underlying writer.
+    fn poll_close_sink(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>>;
+}
+
+/// A transport that buffers incoming messages, see [`QapiEvents::with_capacity`].
+pub trait ReadBuffer {
+    /// Ensures the read buffer can hold at least `capacity` bytes without reallocating.
+    fn reserve_read(&mut self, capacity: usize);
+}
+
+/// A message read from a QAPI stream, which is either a command response or, for QMP, an event.
+pub trait QapiMessage: Sized {
+    /// The response carried by this message, or the message itself if it isn't one.
+    fn into_response(self) -> Result<Response<RawReturn>, Self>;
+
+    /// The event carried by this message, if any.
+    #[cfg(feature = "qapi-qmp")]
+    fn into_event(self) -> Option<qapi_qmp::Event>;
+}
+
+impl QapiMessage for Response<RawReturn> {
+    fn into_response(self) -> Result<Response<RawReturn>, Self> {
+        Ok(self)
+    }
+
+    #[cfg(feature = "qapi-qmp")]
+    fn into_event(self) -> Option<qapi_qmp::Event> {
+        None
+    }
+}
+
+#[cfg(feature = "qapi-qmp")]
+impl QapiMessage for QmpMessage<RawReturn> {
+    fn into_response(self) -> Result<Response<RawReturn>, Self> {
+        match self {
+            QmpMessage::Response(res) => Ok(res),
+            message => Err(message),
+        }
+    }
+
+    fn into_event(self) -> Option<qapi_qmp::Event> {
+        match self {
+            QmpMessage::Event(e) => Some(e),
+            QmpMessage::Response(..) => None,
+        }
+    }
+}
+
+/// A command sink that can pass a file descriptor along with a message, see [`QapiService::execute_with_fd`].
+#[cfg(unix)]
+pub trait SendFd {
+    /// Writes the start of `buf` directly to the underlying Unix socket, attaching `fd` as ancillary data.
+    fn poll_send_fd(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8], fd: Option<RawFd>) -> Poll<io::Result<usize>>;
+}
+
+pub struct QapiService<W> {
+    shared: Arc<QapiShared>,
+    write: Arc<Mutex<W>>,
+    closed: AtomicBool,
+}
+
+impl<W> QapiService<W> {
+    #[cfg(any(feature = "tokio", feature = "async-futures-io"))]
+    fn new(write: W, shared: Arc<QapiShared>) -> Self {
+        QapiService {
+            shared,
+            write: Mutex::new(write).into(),
+            closed: AtomicBool::new(false),
+        }
+    }
+
+    fn next_id(&self) -> u64 {
+        self.shared.next_id()
+    }
+
+    /// The key that tracks a command sent with `id`.
+    ///
+    /// Commands without an id are still tracked by a unique key, as their responses are matched in order.
+    fn command_key(&self, id: Option<u64>) -> u64 {
+        id.unwrap_or_else(|| self.next_id())
+    }
+
+    fn command_id(&self) -> Option<u64> {
+        if self.shared.oob_enabled() || self.shared.response_order() != ResponseOrder::Unchecked {
+            Some(self.next_id())
+        } else {
+            None
+        }
+    }
+
+    fn command_response<R: DeserializeOwned>(receiver: oneshot::Receiver<Result<RawReturn, ExecuteError>>) -> impl Future<Output=Result<R, ExecuteError>> {
+        receiver.map(|res| match res {
+            Ok(Ok(res)) => serde_json::from_str::<R>(res.get())
+                .map_err(io::Error::from).map_err(From::from),
+            Ok(Err(e)) => Err(e),
+            Err(_cancelled) => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "QAPI stream disconnected").into()),
+        })
+    }
+
+    /// Executes a command and waits for its response.
+    ///
+    /// Dropping the future before the response arrives abandons the command, and its response
+    /// is discarded if it eventually does arrive.
+    pub fn execute<C: Command>(&self, command: C) -> impl Future<Output=ExecuteResult<C>> where
+        W: Sink<Serialized<Execute<C, u64>>, Error=io::Error> + Unpin
+    {
+        let id = self.command_id();
+        self.execute_message::<C::Ok, _, _>(id, Execute::new(command, id), futures::future::pending(), None)
+    }
+
+    /// Executes a command with an `id` chosen by the caller, such as a string, which the peer
+    /// echoes back in its response.
+    ///
+    /// Responses are matched by comparing their id to `id`, so it must differ from the ids
+    /// of other pending commands, including the numeric ids assigned to them.
+    pub fn execute_with_id<C: Command>(&self, command: C, id: Any) -> impl Future<Output=ExecuteResult<C>> where
+        W: Sink<Serialized<Execute<C, Any>>, Error=io::Error> + Unpin
+    {
+        let shared = self.shared.clone();
+        let key = self.next_id();
+        let execute = self.execute_message::<C::Ok, _, _>(Some(key), Execute::new(command, Some(id.clone())), futures::future::pending(), None);
+
+        async move {
+            shared.command_alias(id, key);
+            let res = execute.await;
+            // the response may never have arrived to remove it
+            shared.command_unalias_key(key);
+            res
+        }
+    }
+
+    /// A `Sink` of commands that yields their results as a `Stream`, in the order they were sent.
+    ///
+    /// The events must be driven elsewhere, such as by [`QapiEvents::spawn_tokio`].
+    pub fn command_stream<C: Command>(&self) -> CommandStream<'_, W, C> {
+        CommandStream::new(self, futures::future::pending())
+    }
+
+    /// Collects commands to be written all at once, such as a burst of out-of-band commands.
+    ///
+    /// ```no_run
+    /// # #[cfg(feature = "qapi-qmp")]
+    /// # async fn batch<W: futures::Sink<qapi::futures::Serialized<qapi::ExecuteRaw<u64>>, Error=std::io::Error> + Unpin>(service: &qapi::futures::QapiService<W>) -> std::io::Result<()> {
+    /// let mut batch = service.batch();
+    /// let pause = batch.execute_oob(qapi::qmp::migrate_pause { });
+    /// let status = batch.execute(qapi::qmp::query_status { });
+    /// batch.send().await?;
+    /// let (pause, status) = futures::join!(pause, status);
+    /// # Ok(())
+    /// # }
+    /// ```
+    #[cfg(any(feature = "qapi-qmp", feature = "qapi-qga"))]
+    pub fn batch(&self) -> CommandBatch<'_, W> {
+        CommandBatch::new(self)
+    }
+
+    /// Executes a command by name, for commands that aren't part of the generated schema.
+    ///
+    /// Errors returned by QEMU are distinguished from I/O errors.
+    #[cfg(any(feature = "qapi-qmp", feature = "qapi-qga"))]
+    pub fn execute_raw(&self, name: &str, arguments: Any) -> impl Future<Output=io::Result<Result<Any, crate::Error>>> where
+        W: Sink<Serialized<crate::ExecuteRaw<u64>>, Error=io::Error> + Unpin
+    {
+        let id = self.command_id();
+        self.execute_message::<Any, _, _>(id, crate::ExecuteRaw::new(name, arguments, id), futures::future::pending(), None)
+            .map(crate::raw_result)
+    }
+
+    /// Executes a command that was already serialized, such as one being forwarded from
+    /// another client.
+    ///
+    /// `json` must be a QAPI command object. It is sent as-is, other than an id being added
+    /// to it when the peer needs one to match the response. A command that already has an
+    /// id keeps it, and is matched as with [`QapiService::execute_with_id`].
+    #[cfg(any(feature = "qapi-qmp", feature = "qapi-qga"))]
+    pub fn execute_serialized(&self, json: &[u8]) -> impl Future<Output=io::Result<Result<Any, crate::Error>>> where
+        W: Sink<Serialized<crate::ExecuteRaw<u64>>, Error=io::Error> + Unpin
+    {
+        let command = SerializedCommand::parse(json);
+        let sink = self.write.clone();
+        let shared = self.shared.clone();
+        let (key, line) = match &command {
+            Ok(SerializedCommand { id: Some(..), .. }) => (self.next_id(), SerializedCommand::line(json, None)),
+            Ok(command) => {
+                let id = if command.oob() { Some(self.next_id()) } else { self.command_id() };
+                (self.command_key(id), SerializedCommand::line(json, id))
+            },
+            Err(..) => (0, Vec::new()),
+        };
+        let oob_enabled = self.oob_enabled();
+
+        async move {
+            let command = command?;
+            if command.oob() && !oob_enabled {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("cannot execute {} out-of-band, QMP oob capability is not enabled", command.command_name())))
+            }
+
+            let message = Ok(Serialized::<crate::ExecuteRaw<u64>>::from_json(line));
+            let execute = Self::send_serialized::<Any, _, _, _>(sink, shared.clone(), key, &command, message, futures::future::pending(), None);
+            let res = match &command.id {
+                Some(id) => {
+                    shared.command_alias(id.clone(), key);
+                    let res = execute.await;
+                    shared.command_unalias_key(key);
+                    res
+                },
+                None => execute.await,
+            };
+            crate::raw_result(res)
+        }
+    }
+
+    /// Executes a command such as `getfd` or `add-fd` that expects a file descriptor to accompany it.
+    ///
+    /// `fd` is sent over the Unix socket as ancillary data along with the command, and remains
+    /// owned by the caller.
+    ///
+    /// The command is written directly to the socket, so dropping the future before it has
+    /// been written in full leaves the stream unusable, and fails it along with any pending commands.
+    #[cfg(unix)]
+    pub fn execute_with_fd<C: Command>(&self, command: C, fd: RawFd) -> impl Future<Output=ExecuteResult<C>> where
+        W: Sink<Serialized<Execute<C, u64>>, Error=io::Error> + SendFd + Unpin
+    {
+        let sink = self.write.clone();
+        let shared = self.shared.clone();
+        let id = self.command_id();
+        let key = self.command_key(id);
+        let message = Execute::new(command, id);
+        let mut timing = CommandTiming::new(&shared, key, &message);
+        #[cfg(feature = "tracing")]
+        let span = command_span(key, &message);
+        let message = Serialized::new(&message);
+
+        let execute = async move {
+            let message = message?;
+            shared.command_accepting()?;
+            let _permit = shared.command_permit().await;
+            let mut sink = sink.lock().await;
+            // commands buffered by the sink must be written ahead of this one
+            SinkExt::<Serialized<Execute<C, u64>>>::flush(&mut *sink).await?;
+            let (receiver, mut pending) = shared.command_insert_guarded(key)?;
+
+            shared.wire_log.log(WireDirection::Send, message.as_bytes());
+            let line = message.as_bytes();
+            let mut written = 0;
+            let mut partial = QapiWriteGuard {
+                shared: &shared,
+                partial: false,
+            };
+            // the newline is written after the message, rather than copying it to append one
+            while written <= line.len() {
+                let fd = if written == 0 { Some(fd) } else { None };
+                let buf = match line.get(written..) {
+                    Some(rest) if !rest.is_empty() => rest,
+                    _ => &b"\n"[..],
+                };
+                let res = futures::future::poll_fn(|cx| Pin::new(&mut *sink).poll_send_fd(cx, buf, fd)).await;
+                match res {
+                    Ok(len) if len > 0 => written += len,
+                    res => {
+                        let e = res.err().unwrap_or_else(|| io::Error::new(io::ErrorKind::WriteZero, "failed to write QAPI command"));
+                        return Err(e.into())
+                    },
+                }
+                partial.partial = written <= line.len();
+            }
+            drop(partial);
+            pending.sent = true;
+            if let Some(timing) = &mut timing {
+                timing.sent();
+            }
+            if shared.oob_enabled() {
+                // the whole command has been written, so others can be written while this
+                // one waits for its response, which is matched by id
+                drop(sink)
+            }
+
+            let res = Self::command_response::<C::Ok>(receiver).await;
+            if let Some(timing) = &timing {
+                timing.finish(&res);
+            }
+            #[cfg(feature = "tracing")]
+            command_span_finish(&res);
+            res
+        };
+        #[cfg(feature = "tracing")]
+        let execute = tracing::Instrument::instrument(execute, span);
+        execute
+    }
+
+    /// Executes a command, failing with `TimedOut` if no response arrives within `timeout`.
+    ///
+    /// The deadline starts when the returned future is first polled, and also covers waiting
+    /// for the command to be written. The pending command is forgotten on timeout, and its
+    /// response is discarded if it eventually does arrive.
+    #[cfg(feature = "async-tokio-time")]
+    pub fn execute_timeout<C: Command>(&self, command: C, timeout: Duration) -> impl Future<Output=ExecuteResult<C>> where
+        W: Sink<Serialized<Execute<C, u64>>, Error=io::Error> + Unpin
+    {
+        let execute = self.execute(command);
+        async move {
+            match ::tokio::time::timeout(timeout, execute).await {
+                Ok(res) => res,
+                Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, format!("QAPI command {} timed out", C::NAME)).into()),
+            }
+        }
+    }
+
+    /// Executes a command that can be abandoned with the returned [`CancelToken`].
+    ///
+    /// Cancelling stops waiting for the response, and the future fails with `Interrupted`.
+    /// The command itself may still run to completion.
+    pub fn execute_cancellable<C: Command>(&self, command: C) -> (impl Future<Output=ExecuteResult<C>>, CancelToken) where
+        W: Sink<Serialized<Execute<C, u64>>, Error=io::Error> + Unpin
+    {
+        let (sender, receiver) = oneshot::channel();
+        let cancel = receiver.then(|res| match res {
+            Ok(()) => Either::Left(futures::future::ready(
+                io::Error::new(io::ErrorKind::Interrupted, format!("QAPI command {} cancelled", C::NAME))
+            )),
+            // the token was dropped without cancelling
+            Err(_) => Either::Right(futures::future::pending()),
+        });
+        let id = self.command_id();
+        let execute = self.execute_message::<C::Ok, _, _>(id, Execute::new(command, id), cancel, None);
+        (execute, CancelToken { sender })
+    }
+
+    /// Executes a command out-of-band, so that it may overtake commands that are still in progress.
+    ///
+    /// Fails if the `oob` capability wasn't enabled during negotiation.
+    pub fn execute_oob<C: OobCommand>(&self, command: C) -> impl Future<Output=ExecuteResult<C>> where
+        W: Sink<Serialized<ExecuteOob<C, u64>>, Error=io::Error> + Unpin
+    {
+        let enabled = self.oob_enabled();
+        let id = self.next_id();
+        let execute = self.execute_message::<C::Ok, _, _>(Some(id), ExecuteOob::new(command, id), futures::future::pending(), None);
+
+        async move {
+            if !enabled {
+                return Err(Self::oob_unsupported::<C>().into())
+            }
+
+            execute.await
+        }
+    }
+
+    /// Executes a command out-of-band, or as decided by `fallback` when the `oob` capability
+    /// wasn't enabled during negotiation.
+    pub fn execute_oob_fallback<C: OobCommand>(&self, command: C, fallback: OobFallback) -> impl Future<Output=ExecuteResult<C>> where
+        W: Sink<Serialized<Execute<C, u64>>, Error=io::Error> + Sink<Serialized<ExecuteOob<C, u64>>, Error=io::Error> + Unpin
+    {
+        match (self.oob_enabled(), fallback) {
+            (true, _) => Either::Left(Either::Left(self.execute_oob(command))),
+            (false, OobFallback::Serial) => Either::Left(Either::Right(self.execute(command))),
+            (false, OobFallback::Error) => Either::Right(futures::future::err(Self::oob_unsupported::<C>().into())),
+        }
+    }
+
+    /// Executes several commands out-of-band at once, returning their results in the same order
+    /// as `commands`.
+    ///
+    /// Each command is sent with its own id, and responses are matched up as they arrive. When
+    /// the `oob` capability wasn't enabled during negotiation, the commands are instead executed
+    /// in-band one after another, each waiting for the previous response.
+    pub fn execute_all<C: OobCommand>(&self, commands: Vec<C>) -> impl Future<Output=Vec<ExecuteResult<C>>> where
+        W: Sink<Serialized<Execute<C, u64>>, Error=io::Error> + Sink<Serialized<ExecuteOob<C, u64>>, Error=io::Error> + Unpin
+    {
+        let enabled = self.oob_enabled();
+        let executes: Vec<_> = commands.into_iter()
+            .map(|command| self.execute_oob_fallback(command, OobFallback::Serial))
+            .collect();
+
+        async move {
+            if enabled {
+                return futures::future::join_all(executes).await
+            }
+
+            let mut res = Vec::with_capacity(executes.len());
+            for execute in executes {
+                res.push(execute.await);
+            }
+            res
+        }
+    }
+
+    /// Installs a hook that sees the exact bytes of every command sent and every line received.
+    ///
+    /// Received lines are only reported by the transports in this crate, such as [`QmpStreamTokio`].
+    pub fn set_wire_logger<F: Fn(WireDirection, &[u8]) + Send + Sync + 'static>(&self, logger: F) {
+        self.shared.wire_log.set(Some(Box::new(logger)))
+    }
+
+    /// Removes the hook installed by [`QapiService::set_wire_logger`].
+    pub fn clear_wire_logger(&self) {
+        self.shared.wire_log.set(None)
+    }
+
+    /// Reports when each command is sent, and how long it takes to respond or time out.
+    ///
+    /// Only commands sent afterwards are reported.
+    pub fn set_metrics_sink<M: MetricsSink + 'static>(&self, sink: M) {
+        *self.shared.metrics.lock().unwrap() = Some(Arc::new(sink))
+    }
+
+    /// Removes the sink installed by [`QapiService::set_metrics_sink`].
+    pub fn clear_metrics_sink(&self) {
+        *self.shared.metrics.lock().unwrap() = None
+    }
+
+    /// Checks that responses arrive in the order their commands were sent.
+    ///
+    /// Without out-of-band support, responses carry no id and are matched to commands in the
+    /// order they arrive. When checked, each command is instead sent with a local sequence
+    /// number as its id, which the peer echoes back. Commands are still executed one at a time.
+    /// This is meant for debugging proxies and the like, and only affects commands sent afterwards.
+    /// Responses to commands that were sent with a sequence number are still routed by it after
+    /// checking is turned off again.
+    pub fn set_response_order(&self, order: ResponseOrder) {
+        self.shared.response_order.store(order as u8, Ordering::Relaxed)
+    }
+
+    /// Fails the stream on responses that don't belong to any pending command.
+    ///
+    /// Such responses are otherwise logged and skipped, as they may be late responses to
+    /// commands that timed out or were otherwise abandoned.
+    pub fn set_strict_responses(&self, strict: bool) {
+        self.shared.strict_responses.store(strict, Ordering::Relaxed)
+    }
+
+    /// Tolerates malformed JSON from peers such as munging proxies, by retrying each message
+    /// that fails to parse after dropping trailing commas and replacing `NaN` and infinities
+    /// with `null`.
+    ///
+    /// Parsing is strict by default. Only applies to the transports in this crate, and not to
+    /// the QMP greeting.
+    pub fn set_lenient(&self, lenient: bool) {
+        self.shared.lenient.store(lenient, Ordering::Relaxed)
+    }
+
+    /// Limits how many commands may await a response at once, or lifts the limit with `None`.
+    ///
+    /// Further commands wait to be sent until earlier ones complete, fail, or are dropped, which
+    /// keeps a burst of out-of-band commands from piling up in QEMU's monitor queue. Commands
+    /// sent by [`QapiService::execute_pipelined`] or in a `CommandBatch` aren't limited.
+    pub fn set_max_inflight(&self, max: Option<usize>) {
+        let mut inflight = self.shared.inflight.lock().unwrap();
+        inflight.max = max;
+        inflight.grant();
+    }
+
+    /// The events retained by [`QapiEvents::with_history`], oldest first.
+    #[cfg(feature = "qapi-qmp")]
+    pub fn recent_events(&self) -> Vec<qapi_qmp::Event> {
+        self.shared.recent_events()
+    }
+
+    /// Yields the retained events followed by live ones, see [`QapiEvents::replay_events`].
+    #[cfg(feature = "qapi-qmp")]
+    pub fn replay_events(&self) -> impl Stream<Item=qapi_qmp::Event> {
+        self.shared.history.lock().unwrap().replay()
+    }
+
+    /// Whether the connection is still up.
+    ///
+    /// Becomes `false` once the peer disconnects, reading from it fails, or the stream is closed.
+    /// Only the events notice a disconnect, so this stays `true` while they aren't being driven.
+    pub fn is_connected(&self) -> bool {
+        self.shared.is_connected()
+    }
+
+    /// The number of commands still waiting for a response.
+    pub fn pending_count(&self) -> usize {
+        self.shared.commands.lock().unwrap().pending.len()
+    }
+
+    /// The ids of commands still waiting for a response.
+    ///
+    /// Commands are sent without an id when the peer doesn't support one, and are reported by
+    /// the key used to match their responses instead.
+    pub fn pending_ids(&self) -> Vec<u64> {
+        self.shared.commands.lock().unwrap().pending.keys()
+    }
+
+    /// Fails every command still waiting for a response with an error of the given `kind`,
+    /// returning how many were failed.
+    ///
+    /// Unlike dropping the stream, the connection remains usable. Responses that arrive later
+    /// for the failed commands are discarded.
+    pub fn fail_pending(&self, kind: io::ErrorKind) -> usize {
+        self.shared.command_fail_pending(|| io::Error::new(kind, "QAPI command failed while pending"))
+    }
+
+    /// Whether the `oob` capability was enabled during negotiation.
+    pub fn oob_enabled(&self) -> bool {
+        self.shared.oob_enabled()
+    }
+
+    fn oob_unsupported<C: Command>() -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidInput, format!("cannot execute {} out-of-band, QMP oob capability is not enabled", C::NAME))
+    }
+
+    /// Sends `message` with the given `id` and waits for its response.
+    ///
+    /// The command is abandoned with the error produced by `interrupt` if it completes first.
+    /// `sync` is the expected return value of a `guest-sync` command, see [`QapiService::guest_sync`]
+    fn execute_message<R: DeserializeOwned, M: Serialize + CommandMessage, T: Future<Output=io::Error>>(&self, id: Option<u64>, message: M, interrupt: T, sync: Option<Any>) -> impl Future<Output=Result<R, ExecuteError>> where
+        W: Sink<Serialized<M>, Error=io::Error> + Unpin
+    {
+        Self::send_message::<R, M, T>(self.write.clone(), self.shared.clone(), self.command_key(id), message, interrupt, sync)
+    }
+
+    /// Sends `message` tracked by `key`, see [`QapiService::execute_message`].
+    fn send_message<R: DeserializeOwned, M: Serialize + CommandMessage, T: Future<Output=io::Error>>(sink: Arc<Mutex<W>>, shared: Arc<QapiShared>, key: u64, message: M, interrupt: T, sync: Option<Any>) -> impl Future<Output=Result<R, ExecuteError>> where
+        W: Sink<Serialized<M>, Error=io::Error> + Unpin
+    {
+        let serialized = Serialized::new(&message);
+        Self::send_serialized::<R, M, _, T>(sink, shared, key, &message, serialized, interrupt, sync)
+    }
+
+    /// Sends an already serialized `message` described by `info`, see [`QapiService::send_message`].
+    fn send_serialized<R: DeserializeOwned, M, I: CommandMessage, T: Future<Output=io::Error>>(sink: Arc<Mutex<W>>, shared: Arc<QapiShared>, key: u64, info: &I, message: io::Result<Serialized<M>>, interrupt: T, sync: Option<Any>) -> impl Future<Output=Result<R, ExecuteError>> where
+        W: Sink<Serialized<M>, Error=io::Error> + Unpin
+    {
+        let mut timing = CommandTiming::new(&shared, key, info);
+        #[cfg(feature = "tracing")]
+        let span = command_span(key, info);
+
+        let execute = async move {
+            futures::pin_mut!(interrupt);
+
+            let message = message?;
+            shared.command_accepting()?;
+            let _permit = shared.command_permit().await;
+            let mut sink = sink.lock().await;
+            let (receiver, mut pending) = shared.command_insert_guarded(key)?;
+            let _sync = sync.map(|sync| shared.command_sync(sync));
+
+            shared.wire_log.log(WireDirection::Send, message.as_bytes());
+            sink.feed(message).await?;
+            // once buffered, the command is written by a later flush even if this one is abandoned
+            pending.sent = true;
+            if let Err(e) = sink.flush().await {
+                pending.sent = false;
+                return Err(e.into())
+            }
+            if let Some(timing) = &mut timing {
+                timing.sent();
+            }
+            if shared.oob_enabled() {
+                // the whole command has been flushed, so others can be written while this
+                // one waits for its response, which is matched by id
+                drop(sink)
+            }
+
+            let response = Self::command_response::<R>(receiver);
+            futures::pin_mut!(response);
+            let res = match futures::future::select(response, interrupt).await {
+                Either::Left((res, _)) => res,
+                Either::Right((e, _)) => Err(e.into()),
+            };
+            if let Some(timing) = &timing {
+                timing.finish(&res);
+            }
+            #[cfg(feature = "tracing")]
+            command_span_finish(&res);
+            res
+        };
+        #[cfg(feature = "tracing")]
+        let execute = tracing::Instrument::instrument(execute, span);
+        execute
+    }
+
+    /// Executes several commands, writing them back to back rather than waiting for each response in turn.
+    ///
+    /// The results are returned in the same order as `commands`. When the peer doesn't support
+    /// command ids, responses are matched in the order they arrive, and other commands wait for
+    /// the whole batch to complete.
+    pub fn execute_pipelined<C: Command, I: IntoIterator<Item=C>>(&self, commands: I) -> impl Future<Output=Vec<ExecuteResult<C>>> where
+        W: Sink<Serialized<Execute<C, u64>>, Error=io::Error> + Unpin
+    {
+        let sink = self.write.clone();
+        let shared = self.shared.clone();
+        let messages: Vec<_> = commands.into_iter().map(|command| {
+            let id = self.command_id();
+            let key = self.command_key(id);
+            let message = Execute::new(command, id);
+            (key, CommandTiming::new(&self.shared, key, &message), Serialized::new(&message))
+        }).collect();
+
+        async move {
+            let count = messages.len();
+            let messages = match shared.command_accepting().and_then(|()| messages.into_iter().map(|(key, timing, message)| message.map(|message| (key, timing, message))).collect::<io::Result<Vec<_>>>()) {
+                Ok(messages) => messages,
+                Err(e) => return (0..count).map(|_| Err(io::Error::new(e.kind(), e.to_string()).into())).collect(),
+            };
+            let mut sink = sink.lock().await;
+            let mut receivers = Vec::with_capacity(count);
+            let mut res = Ok(());
+            for (key, timing, message) in messages {
+                let (receiver, pending) = match shared.command_insert_guarded(key) {
+                    Ok(pending) => pending,
+                    Err(e) => {
+                        res = Err(e);
+                        break
+                    },
+                };
+                receivers.push((pending, timing, receiver));
+                shared.wire_log.log(WireDirection::Send, message.as_bytes());
+                res = sink.feed(message).await;
+                if res.is_err() {
+                    break
+                }
+            }
+            if res.is_ok() {
+                res = sink.flush().await;
+            }
+
+            if let Err(e) = res {
+                return (0..count).map(|_| Err(io::Error::new(e.kind(), e.to_string()).into())).collect()
+            }
+            for (pending, timing, _) in &mut receivers {
+                pending.sent = true;
+                if let Some(timing) = timing {
+                    timing.sent();
+                }
+            }
+            if shared.oob_enabled() {
+                // the whole command has been flushed, so others can be written while this
+                // one waits for its response, which is matched by id
+                drop(sink)
+            }
+
+            futures::future::join_all(receivers.into_iter()
+                .map(|(pending, timing, receiver)| Self::command_response::<C::Ok>(receiver).inspect(move |res| {
+                    drop(pending);
+                    if let Some(timing) = &timing {
+                        timing.finish(res);
+                    }
+                }))
+            ).await
+        }
+    }
+
+    /// Synchronizes with the guest agent.
+    ///
+    /// Anything other than a successful response echoing `sync_value` is assumed to be stale
+    /// output from a previous session, including errors and messages that aren't valid JSON,
+    /// and is discarded until the matching response arrives.
+    #[cfg(feature = "qapi-qga")]
+    pub fn guest_sync(&self, sync_value: i32) -> impl Future<Output=Result<(), crate::ExecuteError>> where
+        W: Sink<Serialized<Execute<qapi_qga::guest_sync, u64>>, Error=io::Error> + Unpin
+    {
+        let id = sync_value.into();
+        let command_id = self.command_id();
+        self.execute_message::<<qapi_qga::guest_sync as Command>::Ok, _, _>(command_id, Execute::new(qapi_qga::guest_sync {
+            id,
+        }, command_id), futures::future::pending(), Some(id.into())).map(move |res| res.and_then(|res| if res == id {
+            Ok(())
+        } else {
+            Err(io::Error::new(io::ErrorKind::InvalidData, "QGA sync failed").into())
+        }))
+    }
+
+    /// Runs an HMP command, returning its text output, see [`crate::Qmp::hmp`].
+    #[cfg(feature = "qapi-qmp")]
+    pub fn hmp(&self, command_line: &str) -> impl Future<Output=io::Result<Result<String, crate::Error>>> where
+        W: Sink<Serialized<Execute<qapi_qmp::human_monitor_command, u64>>, Error=io::Error> + Unpin
+    {
+        self.execute(crate::hmp_command(command_line))
+            .map(crate::raw_result)
+    }
+
+    /// Checks that QEMU is responsive by issuing `query-version`.
+    ///
+    /// The command is always available and its response is tiny, which makes it a cheap probe
+    /// for keep-alives and health checks. Fails with `TimedOut` if QEMU doesn't respond within
+    /// five seconds.
+    #[cfg(all(feature = "qapi-qmp", feature = "async-tokio-time"))]
+    pub fn ping(&self) -> impl Future<Output=io::Result<()>> where
+        W: Sink<Serialized<Execute<qapi_qmp::query_version, u64>>, Error=io::Error> + Unpin
+    {
+        self.execute_timeout(qapi_qmp::query_version { }, PING_TIMEOUT)
+            .map(|res| res.map(drop).map_err(io::Error::from))
+    }
+
+    /// Retrieves the schema supported by QEMU.
+    #[cfg(feature = "qapi-qmp")]
+    pub fn query_schema(&self) -> impl Future<Output=Result<qapi_qmp::Schema, ExecuteError>> where
+        W: Sink<Serialized<Execute<qapi_qmp::query_qmp_schema, u64>>, Error=io::Error> + Unpin
+    {
+        self.execute(qapi_qmp::query_qmp_schema { arguments: Default::default() })
+            .map(|res| res.map(qapi_qmp::Schema::new))
+    }
+
+    /// The names of the events that QEMU may emit, see [`crate::Qmp::query_events`].
+    #[cfg(feature = "qapi-qmp")]
+    #[allow(deprecated)]
+    pub fn query_events(&self) -> impl Future<Output=Result<Vec<String>, ExecuteError>> where
+        W: Sink<Serialized<Execute<qapi_qmp::query_events, u64>>, Error=io::Error> + Unpin
+    {
+        self.execute(qapi_qmp::query_events { })
+            .map(|res| res.map(crate::event_names))
+    }
+
+    /// Whether QEMU may emit the event called `name`, see [`QapiService::query_events`].
+    #[cfg(feature = "qapi-qmp")]
+    #[allow(deprecated)]
+    pub async fn supports_event(&self, name: &str) -> Result<bool, ExecuteError> where
+        W: Sink<Serialized<Execute<qapi_qmp::query_events, u64>>, Error=io::Error> + Unpin
+    {
+        let events = self.query_events().await?;
+        Ok(events.iter().any(|event| event == name))
+    }
+
+    /// Adds a block node, returning a handle that deletes it again with [`Blockdev::remove`].
+    ///
+    /// Fails with `InvalidInput` without sending anything if the options don't name the node,
+    /// as it couldn't be deleted later.
+    #[cfg(feature = "qapi-qmp")]
+    pub async fn blockdev_add(&self, options: qapi_qmp::BlockdevOptions) -> Result<Blockdev<'_, W>, ExecuteError> where
+        W: Sink<Serialized<Execute<qapi_qmp::blockdev_add, u64>>, Error=io::Error> + Unpin
+    {
+        let node_name = serde_json::to_value(&options).map_err(io::Error::from)?.get("node-name").and_then(Any::as_str).map(String::from)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "blockdev-add requires a node-name"))?;
+        self.execute(qapi_qmp::blockdev_add(options)).await?;
+
+        Ok(Blockdev {
+            service: self,
+            node_name,
+        })
+    }
+
+    /// Runs a program in the guest and waits for it to exit, see [`crate::Qga::guest_exec_wait`].
+    #[cfg(all(feature = "qapi-qga", feature = "async-tokio-time"))]
+    pub async fn guest_exec_wait<A: IntoIterator>(&self, path: &str, args: A, input: Option<Vec<u8>>, poll_interval: Duration) -> Result<qapi_qga::GuestExecOutput, ExecuteError> where
+        A::Item: Into<String>,
+        W: Sink<Serialized<Execute<qapi_qga::guest_exec, u64>>, Error=io::Error> + Sink<Serialized<Execute<qapi_qga::guest_exec_status, u64>>, Error=io::Error> + Unpin,
+    {
+        let pid = self.execute(crate::guest_exec_capture(path, args, input)).await?.pid;
+
+        loop {
+            if let Some(output) = self.execute(qapi_qga::guest_exec_status { pid }).await?.into_output() {
+                return Ok(output)
+            }
+            ::tokio::time::sleep(poll_interval).await;
+        }
+    }
+
+    /// Brings a guest CPU online or offline, see [`crate::Qga::set_vcpu_online`].
+    #[cfg(feature = "qapi-qga")]
+    pub async fn set_vcpu_online(&self, logical_id: i64, online: bool) -> Result<(), ExecuteError> where
+        W: Sink<Serialized<Execute<qapi_qga::guest_get_vcpus, u64>>, Error=io::Error> + Sink<Serialized<Execute<qapi_qga::guest_set_vcpus, u64>>, Error=io::Error> + Unpin,
+    {
+        let vcpus = self.execute(qapi_qga::guest_get_vcpus { }).await?;
+        match crate::guest_set_vcpu(vcpus, logical_id, online)? {
+            Some(command) => crate::guest_set_vcpu_result(logical_id, online, self.execute(command).await?),
+            None => Ok(()),
+        }
+    }
+
+    /// Reads the rest of the guest file `handle`, see [`crate::Qga::guest_read_to_end`].
+    ///
+    /// Without the `async-tokio-time` feature, there's no timer to back off with, so reads that
+    /// return no data are retried after yielding to the executor instead.
+    #[cfg(feature = "qapi-qga")]
+    pub async fn guest_read_to_end(&self, handle: i64) -> Result<Vec<u8>, ExecuteError> where
+        W: Sink<Serialized<Execute<qapi_qga::guest_file_read, u64>>, Error=io::Error> + Unpin
+    {
+        let mut buf = Vec::new();
+        let mut empty_reads = 0;
+        loop {
+            let res = self.execute(crate::guest_read_chunk(handle)).await?;
+            if crate::guest_read_chunk_result(&mut buf, &mut empty_reads, res)? {
+                break Ok(buf)
+            }
+            if let Some(_delay) = crate::guest_read_backoff(empty_reads) {
+                #[cfg(feature = "async-tokio-time")]
+                ::tokio::time::sleep(_delay).await;
+                #[cfg(not(feature = "async-tokio-time"))]
+                yield_now().await;
+            }
+        }
+    }
+
+    /// Shuts down the guest, see [`crate::Qga::guest_shutdown`].
+    ///
+    /// The connection only drops while the stream is being driven. Wrap this in a timeout when
+    /// the guest agent is reached over virtio-serial, whose host side may stay open.
+    #[cfg(feature = "qapi-qga")]
+    pub fn guest_shutdown(&self, mode: qapi_qga::GuestShutdownMode) -> impl Future<Output=Result<(), ExecuteError>> where
+        W: Sink<Serialized<Execute<qapi_qga::guest_shutdown, u64>>, Error=io::Error> + Unpin
+    {
+        self.execute(qapi_qga::guest_shutdown { mode: Some(mode) })
+            .map(crate::guest_shutdown_result)
+    }
+
+    /// Freezes the guest's filesystems while `f` runs, see [`crate::Qga::freeze_scope`].
+    ///
+    /// The filesystems are thawed even if the future returned by `f` panics, but not if it's
+    /// dropped before completing.
+    #[cfg(feature = "qapi-qga")]
+    pub async fn freeze_scope<T, F, Fut>(&self, f: F) -> Result<(T, Result<i64, ExecuteError>), ExecuteError> where
+        F: FnOnce(i64) -> Fut,
+        Fut: Future<Output=T>,
+        W: Sink<Serialized<Execute<qapi_qga::guest_fsfreeze_status, u64>>, Error=io::Error>
+            + Sink<Serialized<Execute<qapi_qga::guest_fsfreeze_freeze, u64>>, Error=io::Error>
+            + Sink<Serialized<Execute<qapi_qga::guest_fsfreeze_thaw, u64>>, Error=io::Error> + Unpin,
+    {
+        crate::guest_fsfreeze_check(self.execute(qapi_qga::guest_fsfreeze_status { }).await?)?;
+        let frozen = self.execute(qapi_qga::guest_fsfreeze_freeze { }).await?;
+        let res = std::panic::AssertUnwindSafe(f(frozen)).catch_unwind().await;
+        let thawed = self.execute(qapi_qga::guest_fsfreeze_thaw { }).await;
+        match res {
+            Ok(res) => Ok((res, thawed)),
+            Err(payload) => std::panic::resume_unwind(payload),
+        }
+    }
+
+    /// The commands the guest agent implements, see [`crate::Qga::guest_supported_commands`].
+    #[cfg(feature = "qapi-qga")]
+    pub fn guest_supported_commands(&self) -> impl Future<Output=Result<Vec<qapi_qga::GuestAgentCommandInfo>, ExecuteError>> where
+        W: Sink<Serialized<Execute<qapi_qga::guest_info, u64>>, Error=io::Error> + Unpin
+    {
+        self.execute(qapi_qga::guest_info { })
+            .map(|res| res.map(|info| info.supported_commands))
+    }
+
+    /// Whether the guest agent supports the command `name`, see [`crate::Qga::guest_supports`].
+    #[cfg(feature = "qapi-qga")]
+    pub fn guest_supports(&self, name: &str) -> impl Future<Output=Result<bool, ExecuteError>> where
+        W: Sink<Serialized<Execute<qapi_qga::guest_info, u64>>, Error=io::Error> + Unpin
+    {
+        let name = name.to_owned();
+        self.execute(qapi_qga::guest_info { })
+            .map(move |res| res.map(|info| info.supports(&name)))
+    }
+
+    /// Details of the guest operating system, see [`crate::Qga::guest_osinfo`].
+    #[cfg(feature = "qapi-qga")]
+    pub fn guest_osinfo(&self) -> impl Future<Output=Result<qapi_qga::GuestOSInfo, ExecuteError>> where
+        W: Sink<Serialized<Execute<qapi_qga::guest_get_osinfo, u64>>, Error=io::Error> + Unpin
+    {
+        self.execute(qapi_qga::guest_get_osinfo { })
+    }
+
+    /// The guest's network interfaces, see [`crate::Qga::guest_interfaces`].
+    #[cfg(feature = "qapi-qga")]
+    pub fn guest_interfaces(&self) -> impl Future<Output=Result<Vec<qapi_qga::GuestNetworkInterface>, ExecuteError>> where
+        W: Sink<Serialized<Execute<qapi_qga::guest_network_get_interfaces, u64>>, Error=io::Error> + Unpin
+    {
+        self.execute(qapi_qga::guest_network_get_interfaces { })
+    }
+
+    /// The number of the guest's logical CPUs that are online.
+    #[cfg(feature = "qapi-qga")]
+    pub fn online_vcpu_count(&self) -> impl Future<Output=Result<usize, ExecuteError>> where
+        W: Sink<Serialized<Execute<qapi_qga::guest_get_vcpus, u64>>, Error=io::Error> + Unpin
+    {
+        self.execute(qapi_qga::guest_get_vcpus { })
+            .map(|res| res.map(|vcpus| vcpus.iter().filter(|vcpu| vcpu.online).count()))
+    }
+
+    /// Synchronizes with the guest agent using a freshly generated `guest-sync` value.
+    #[cfg(feature = "qapi-qga")]
+    pub fn handshake(&self) -> impl Future<Output=Result<(), crate::ExecuteError>> where
+        W: Sink<Serialized<Execute<qapi_qga::guest_sync, u64>>, Error=io::Error> + Unpin
+    {
+        self.guest_sync(crate::guest_sync_value())
+    }
+
+    /// Shuts down the connection.
+    ///
+    /// Any pending commands fail with `UnexpectedEof`, the associated `QapiEvents` stops
+    /// processing and completes, and the write half is flushed and closed.
+    /// Closing an already closed service does nothing.
+    ///
+    /// This includes commands whose responses were received but not yet processed, unless
+    /// [`QapiEvents::drain_buffered`] is called beforehand, as [`QapiStream::close`] does.
+    pub async fn close(&self) -> io::Result<()> where
+        W: CloseSink + Unpin,
+    {
+        self.shared.stop();
+        self.shared.command_fail_all(|| io::Error::new(io::ErrorKind::UnexpectedEof, "QAPI stream closed"));
+
+        if self.closed.swap(true, Ordering::Relaxed) {
+            return Ok(())
+        }
+
+        let mut sink = self.write.lock().await;
+        futures::future::poll_fn(|cx| Pin::new(&mut *sink).poll_close_sink(cx)).await
+    }
+
+    /// Shuts down the connection after the commands already in progress complete.
+    ///
+    /// New commands fail with `NotConnected` in the meantime. Commands still pending after
+    /// `timeout` fail as with [`QapiService::close`]. Responses only arrive while the
+    /// associated `QapiEvents` is being driven, such as after [`QapiStream::spawn_tokio`].
+    #[cfg(feature = "async-tokio-time")]
+    pub async fn shutdown(&self, timeout: Duration) -> io::Result<()> where
+        W: CloseSink + Unpin,
+    {
+        self.shared.draining.store(true, Ordering::Relaxed);
+        if ::tokio::time::timeout(timeout, self.idle()).await.is_err() {
+            warn!("QAPI shutdown timed out with {} commands pending", self.pending_count());
+        }
+        self.close().await
+    }
+
+    /// Issues `guest-ping` every `interval` while no other commands are in progress.
+    ///
+    /// If the guest agent doesn't respond within `timeout`, the stream is considered dead:
+    /// pending commands fail with `TimedOut`, and the stream ends as if it was disconnected.
+    /// The task exits once that happens, or once the service is dropped.
+    #[cfg(all(feature = "qapi-qga", feature = "async-tokio-spawn", feature = "async-tokio-time"))]
+    pub fn spawn_keepalive(&self, interval: Duration, timeout: Duration) -> ::tokio::task::JoinHandle<()> where
+        W: Sink<Serialized<Execute<qapi_qga::guest_ping, u64>>, Error=io::Error> + Unpin + Send + 'static,
+    {
+        let write = Arc::downgrade(&self.write);
+        let shared = Arc::downgrade(&self.shared);
+        ::tokio::spawn(async move {
+            loop {
+                ::tokio::time::sleep(interval).await;
+                let (write, shared) = match (write.upgrade(), shared.upgrade()) {
+                    (Some(write), Some(shared)) if !shared.is_stopped() => (write, shared),
+                    _ => break,
+                };
+                if !shared.commands.lock().unwrap().pending.is_empty() {
+                    // QGA executes one command at a time, so a ping would only queue up behind it
+                    continue
+                }
+
+                let key = shared.next_id();
+                let timeout = ::tokio::time::sleep(timeout).map(|()|
+                    io::Error::new(io::ErrorKind::TimedOut, "QGA keep-alive ping timed out")
+                );
+                let ping = Execute::<_, u64>::new(qapi_qga::guest_ping { }, None);
+                match Self::send_message::<<qapi_qga::guest_ping as Command>::Ok, _, _>(write, shared.clone(), key, ping, timeout, None).await {
+                    Ok(..) => (),
+                    Err(ExecuteError::Io(e)) if e.kind() == io::ErrorKind::TimedOut => {
+                        warn!("QGA guest agent stopped responding, closing the stream");
+                        shared.command_fail_all(|| io::Error::new(io::ErrorKind::TimedOut, "QGA guest agent stopped responding to keep-alive pings"));
+                        shared.stop();
+                        break
+                    },
+                    Err(e) => {
+                        info!("QGA keep-alive stopped: {}", e);
+                        break
+                    },
+                }
+            }
+        })
+    }
+
+    #[cfg(feature = "async-tokio-time")]
+    fn idle(&self) -> impl Future<Output=()> + '_ {
+        futures::future::poll_fn(move |cx| self.shared.poll_idle(cx))
+    }
+
+    fn stop(&self) {
+        self.shared.commands.lock().unwrap().abandoned = true;
+        self.shared.stop();
+    }
+}
+
+impl<W> Drop for QapiService<W> {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+#[derive(Default)]
+struct QapiSharedCommands {
+    pending: QapiCommandMap,
+    /// responses that are still expected for commands that are no longer pending
+    stale: BTreeMap<u64, usize>,
+    /// successful responses are discarded until one returns this `guest-sync` value
+    sync: Option<Any>,
+    abandoned: bool,
+    /// the keys of commands sent with ids chosen by the caller, see [`QapiService::execute_with_id`]
+    aliases: Vec<(Any, u64)>,
+}
+
+/// How many live events a stream from [`QapiEvents::replay_events`] may fall behind by before it is dropped.
+#[cfg(feature = "qapi-qmp")]
+const REPLAY_BUFFER: usize = 256;
+
+/// Recently received events, see [`QapiEvents::with_history`].
+#[cfg(feature = "qapi-qmp")]
+#[derive(Default)]
+struct QapiEventHistory {
+    events: VecDeque<qapi_qmp::Event>,
+    capacity: usize,
+    /// streams from [`QapiEvents::replay_events`] that continue with live events
+    live: Vec<futures::channel::mpsc::Sender<qapi_qmp::Event>>,
+    /// events received during negotiation, which are yielded ahead of any others
+    deferred: VecDeque<qapi_qmp::Event>,
+}
+
+#[cfg(feature = "qapi-qmp")]
+impl QapiEventHistory {
+    fn set_capacity(&mut self, capacity: usize) {
+        if self.capacity == 0 {
+            // events received before recording started, but not yet yielded
+            self.events.extend(self.deferred.iter().cloned());
+        }
+        self.capacity = capacity;
+        while self.events.len() > capacity {
+            self.events.pop_front();
+        }
+    }
+
+    fn record(&mut self, event: &qapi_qmp::Event) {
+        if self.capacity > 0 {
+            if self.events.len() == self.capacity {
+                self.events.pop_front();
+            }
+            self.events.push_back(event.clone());
+        }
+        self.live.retain_mut(|live| match live.try_send(event.clone()) {
+            Ok(()) => true,
+            Err(e) if e.is_full() => {
+                warn!("Dropping QMP event replay stream that fell {} events behind", REPLAY_BUFFER);
+                false
+            },
+            Err(..) => false,
+        });
+    }
+
+    /// Whether received events need to be parsed to be recorded.
+    fn is_recording(&self) -> bool {
+        self.capacity > 0 || !self.live.is_empty()
+    }
+
+    fn replay(&mut self) -> impl Stream<Item=qapi_qmp::Event> {
+        use futures::StreamExt;
+
+        let (sender, receiver) = futures::channel::mpsc::channel(REPLAY_BUFFER);
+        self.live.push(sender);
+        futures::stream::iter(self.events.clone()).chain(receiver)
+    }
+}
+
+/// Commands awaiting a response, see [`QapiService::set_max_inflight`].
+#[derive(Default)]
+struct QapiInflight {
+    max: Option<usize>,
+    count: usize,
+    /// commands waiting for a permit, in the order they asked for one
+    waiters: VecDeque<(u64, Waker)>,
+    next_waiter: u64,
+}
+
+impl QapiInflight {
+    fn available(&self) -> bool {
+        self.max.map(|max| self.count < max).unwrap_or(true)
+    }
+
+    /// Hands out permits to waiters in order, waking only those that get one.
+    fn grant(&mut self) {
+        while self.available() {
+            match self.waiters.pop_front() {
+                Some((_, waker)) => {
+                    self.count += 1;
+                    waker.wake()
+                },
+                None => break,
+            }
+        }
+    }
+
+    fn release(&mut self) {
+        self.count -= 1;
+        self.grant()
+    }
+}
+
+struct QapiShared {
+    commands: StdMutex<QapiSharedCommands>,
+    stop_waker: AtomicWaker,
+    stop: AtomicBool,
+    /// cleared once reading from the peer fails
+    connected: AtomicBool,
+    /// set once the `oob` capability has been negotiated, after which responses are matched by id
+    oob_enabled: AtomicBool,
+    wire_log: Arc<WireLog>,
+    metrics: StdMutex<Option<Arc<dyn MetricsSink>>>,
+    id_counter: AtomicU64,
+    /// set once a graceful shutdown has started
+    draining: AtomicBool,
+    idle_waker: AtomicWaker,
+    response_order: AtomicU8,
+    strict_responses: AtomicBool,
+    /// shared with the codec, see [`QapiService::set_lenient`]
+    lenient: Arc<AtomicBool>,
+    /// set while waiting for a `guest-sync` response, shared with the codec
+    syncing: Arc<AtomicBool>,
+    inflight: StdMutex<QapiInflight>,
+    #[cfg(feature = "qapi-qmp")]
+    history: StdMutex<QapiEventHistory>,
+    /// set while the events are driven as a `Future`, which discards them
+    #[cfg(feature = "qapi-qmp")]
+    skip_events: Arc<AtomicBool>,
+}
+
+impl QapiShared {
+    #[cfg(any(feature = "tokio", feature = "async-futures-io"))]
+    fn new(wire_log: Arc<WireLog>) -> Self {
+        Self {
+            commands: Default::default(),
+            stop_waker: Default::default(),
+            stop: Default::default(),
+            connected: AtomicBool::new(true),
+            oob_enabled: Default::default(),
+            wire_log,
+            metrics: Default::default(),
+            id_counter: Default::default(),
+            draining: Default::default(),
+            idle_waker: Default::default(),
+            response_order: Default::default(),
+            strict_responses: Default::default(),
+            lenient: Default::default(),
+            syncing: Default::default(),
+            inflight: Default::default(),
+            #[cfg(feature = "qapi-qmp")]
+            history: Default::default(),
+            #[cfg(feature = "qapi-qmp")]
+            skip_events: Default::default(),
+        }
+    }
+
+    fn oob_enabled(&self) -> bool {
+        self.oob_enabled.load(Ordering::Relaxed)
+    }
+
+    /// Waits until fewer than the maximum number of commands are awaiting a response.
+    ///
+    /// Permits are handed out in the order they were asked for.
+    fn command_permit(&self) -> QapiInflightAcquire<'_> {
+        QapiInflightAcquire {
+            shared: self,
+            waiter: None,
+        }
+    }
+
+    /// Generates an id that no pending or abandoned command is using.
+    ///
+    /// `Relaxed` is enough, since `fetch_add` alone hands out each id once, and nothing else is
+    /// ordered by the counter. Callers choose an id once and use it both in the serialized
+    /// command and as its key in the pending map, where `command_insert` rejects any collision.
+    fn next_id(&self) -> u64 {
+        loop {
+            let id = self.id_counter.fetch_add(1, Ordering::Relaxed);
+            // ids are only reused once the counter wraps around
+            if !self.command_in_use(id) {
+                break id
+            }
+        }
+    }
+
+    fn command_in_use(&self, id: u64) -> bool {
+        let commands = self.commands.lock().unwrap();
+        commands.pending.contains(id) || commands.stale.contains_key(&id)
+    }
+
+    #[cfg(feature = "qapi-qmp")]
+    fn recent_events(&self) -> Vec<qapi_qmp::Event> {
+        self.history.lock().unwrap().events.iter().cloned().collect()
+    }
+
+    fn response_order(&self) -> ResponseOrder {
+        ResponseOrder::from_u8(self.response_order.load(Ordering::Relaxed))
+    }
+
+    fn stop(&self) {
+        self.stop.store(true, Ordering::Relaxed);
+        self.stop_waker.wake();
+    }
+
+    fn is_stopped(&self) -> bool {
+        self.stop.load(Ordering::Relaxed)
+    }
+
+    fn disconnect(&self) {
+        self.connected.store(false, Ordering::Relaxed);
+    }
+
+    fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::Relaxed) && !self.is_stopped()
+    }
+
+    fn poll_next<T, P: FnOnce(&mut Context) -> Poll<Option<T>>>(&self, cx: &mut Context, poll: P) -> Poll<Option<T>> {
+        if self.is_stopped() {
+            return Poll::Ready(None)
+        }
+
+        // attempt to complete the future
+        match poll(cx) {
+            Poll::Ready(res) => {
+                if res.is_none() {
+                    self.stop.store(true, Ordering::Relaxed);
+                }
+                Poll::Ready(res)
+            },
+            Poll::Pending => {
+                self.stop_waker.register(cx.waker());
+                if self.is_stopped() {
+                    Poll::Ready(None)
+                } else {
+                    Poll::Pending
+                }
+            },
+        }
+    }
+
+    fn command_remove(&self, id: u64) -> Option<QapiCommandSender> {
+        let mut commands = self.commands.lock().unwrap();
+        let sender = commands.pending.remove(id);
+        self.wake_idle(&commands);
+        sender
+    }
+
+    /// Forgets about a pending command whose response may still arrive later.
+    fn command_abandon(&self, id: u64) {
+        let mut commands = self.commands.lock().unwrap();
+        if commands.pending.remove(id).is_some() {
+            *commands.stale.entry(id).or_default() += 1;
+        }
+        self.wake_idle(&commands);
+    }
+
+    /// Fails the pending commands, expecting their responses to arrive later.
+    fn command_fail_pending<E: Fn() -> io::Error>(&self, err: E) -> usize {
+        let pending = {
+            let mut commands = self.commands.lock().unwrap();
+            let pending = commands.pending.take_all();
+            for &(id, _) in &pending {
+                *commands.stale.entry(id).or_default() += 1;
+            }
+            self.wake_idle(&commands);
+            pending
+        };
+        let count = pending.len();
+        for (_, sender) in pending {
+            let _ = sender.send(Err(err().into()));
+        }
+        count
+    }
+
+    fn wake_idle(&self, commands: &QapiSharedCommands) {
+        if commands.pending.is_empty() {
+            self.idle_waker.wake();
+        }
+    }
+
+    /// Completes once no commands are pending.
+    #[cfg(feature = "async-tokio-time")]
+    fn poll_idle(&self, cx: &mut Context) -> Poll<()> {
+        let commands = self.commands.lock().unwrap();
+        if commands.pending.is_empty() {
+            Poll::Ready(())
+        } else {
+            self.idle_waker.register(cx.waker());
+            Poll::Pending
+        }
+    }
+
+    /// Rejects new commands once a graceful shutdown has started.
+    fn command_accepting(&self) -> io::Result<()> {
+        if self.draining.load(Ordering::Relaxed) {
+            Err(io::Error::new(io::ErrorKind::NotConnected, "QAPI stream is shutting down"))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Consumes an expected response for an abandoned command.
+    fn command_stale(&self, id: u64) -> bool {
+        let mut commands = self.commands.lock().unwrap();
+        match commands.stale.get_mut(&id) {
+            Some(count) if *count > 1 => *count -= 1,
+            Some(..) => { commands.stale.remove(&id); },
+            None => return false,
+        }
+        true
+    }
+
+    /// The oldest command still expecting a response, which responses without an id belong to.
+    fn command_oldest(&self) -> Option<u64> {
+        let commands = self.commands.lock().unwrap();
+        let pending = commands.pending.oldest();
+        let stale = commands.stale.keys().next().copied();
+        match (pending, stale) {
+            (Some(pending), Some(stale)) => Some(pending.min(stale)),
+            (pending, stale) => pending.or(stale),
+        }
+    }
+
+    fn command_sync(&self, sync: Any) -> QapiSyncGuard<'_> {
+        let mut commands = self.commands.lock().unwrap();
+        commands.sync = Some(sync);
+        self.syncing.store(true, Ordering::Relaxed);
+        QapiSyncGuard {
+            shared: self,
+        }
+    }
+
+    /// Whether a response should be discarded while waiting for a `guest-sync` response.
+    fn command_sync_stale(&self, res: &Response<RawReturn>) -> bool {
+        let mut commands = self.commands.lock().unwrap();
+        match (&commands.sync, res.as_result()) {
+            (Some(sync), Ok(res)) if serde_json::from_str::<Any>(res.get()).map(|res| sync == &res).unwrap_or(false) => {
+                commands.sync = None;
+                self.syncing.store(false, Ordering::Relaxed);
+                false
+            },
+            (Some(..), _) => true,
+            (None, _) => false,
+        }
+    }
+
+    fn command_fail_all<E: Fn() -> io::Error>(&self, err: E) {
+        let pending = {
+            let mut commands = self.commands.lock().unwrap();
+            commands.abandoned = true;
+            let pending = commands.pending.take_all();
+            self.wake_idle(&commands);
+            pending
+        };
+        for (_, sender) in pending {
+            let _ = sender.send(Err(err().into()));
+        }
+    }
+
+    /// Matches responses carrying `id` to the command tracked by `key`.
+    fn command_alias(&self, id: Any, key: u64) {
+        self.commands.lock().unwrap().aliases.push((id, key))
+    }
+
+    /// Removes the alias for `id`, returning the key it was tracked by.
+    fn command_unalias(&self, id: &Any) -> Option<u64> {
+        let mut commands = self.commands.lock().unwrap();
+        let index = commands.aliases.iter().position(|(alias, _)| alias == id)?;
+        Some(commands.aliases.swap_remove(index).1)
+    }
 
-            Self::command_response::<C>(receiver).await
-        }
+    fn command_unalias_key(&self, key: u64) {
+        self.commands.lock().unwrap().aliases.retain(|&(_, k)| k != key)
     }
 
-    /*pub async fn execute_oob<C: Command>(&self, command: C) -> io::Result<ExecuteResult<C>> {
-        /* TODO: should we assert C::ALLOW_OOB here and/or at the type level?
-         * If oob isn't supported should we fall back to serial execution or error?
-         */
-        self.execute_(command, true).await
-    }*/
+    /// Fails with `AlreadyExists` if another command is still using `id`, as their responses
+    /// couldn't be told apart.
+    fn command_insert(&self, id: u64) -> io::Result<oneshot::Receiver<Result<RawReturn, ExecuteError>>> {
+        let (sender, receiver) = oneshot::channel();
+        let mut commands = self.commands.lock().unwrap();
+        if commands.pending.contains(id) || commands.stale.contains_key(&id) {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("QAPI command id {} is already in use", id)))
+        }
+        if !commands.abandoned {
+            // otherwise sender is dropped immediately
+            commands.pending.insert(id, sender);
+        }
+        Ok(receiver)
+    }
 
-    #[cfg(feature = "qapi-qga")]
-    pub fn guest_sync(&self, sync_value: i32) -> impl Future<Output=Result<(), crate::ExecuteError>> where
-        W: Sink<Execute<qapi_qga::guest_sync, u32>, Error=io::Error> + Unpin
-    {
-        let id = sync_value.into();
-        self.execute(qapi_qga::guest_sync {
-            id,
-        }).map(move |res| res.and_then(|res| if res == id {
-            Ok(())
-        } else {
-            Err(io::Error::new(io::ErrorKind::InvalidData, "QGA sync failed").into())
+    /// Tracks a command until its response arrives, see [`QapiPendingGuard`].
+    fn command_insert_guarded(&self, id: u64) -> io::Result<(oneshot::Receiver<Result<RawReturn, ExecuteError>>, QapiPendingGuard<'_>)> {
+        let receiver = self.command_insert(id)?;
+        Ok((receiver, QapiPendingGuard {
+            shared: self,
+            key: id,
+            sent: false,
         }))
     }
+}
 
-    fn stop(&self) {
-        let mut commands = self.shared.commands.lock().unwrap();
-        if self.shared.abandoned.load(Ordering::Relaxed) {
-            self.shared.stop();
+/// Forgets a pending command if its execute future is dropped before the response arrives.
+///
+/// A command that was never sent is simply removed, while one that was sent is abandoned so
+/// that its response is discarded when it arrives. Does nothing once the response has been
+/// delivered.
+struct QapiPendingGuard<'a> {
+    shared: &'a QapiShared,
+    key: u64,
+    sent: bool,
+}
+
+impl<'a> Drop for QapiPendingGuard<'a> {
+    fn drop(&mut self) {
+        if self.sent {
+            self.shared.command_abandon(self.key);
+        } else {
+            self.shared.command_remove(self.key);
         }
-        commands.abandoned = true;
     }
 }
 
-impl<W> Drop for QapiService<W> {
+/// Counts a command against [`QapiService::set_max_inflight`] until it completes or is dropped.
+struct QapiInflightPermit<'a> {
+    shared: &'a QapiShared,
+}
+
+impl<'a> Drop for QapiInflightPermit<'a> {
     fn drop(&mut self) {
-        self.stop();
+        self.shared.inflight.lock().unwrap().release();
     }
 }
 
-#[derive(Default)]
-struct QapiSharedCommands {
-    pending: QapiCommandMap,
-    abandoned: bool,
+/// Waits for a [`QapiInflightPermit`], see [`QapiShared::command_permit`].
+struct QapiInflightAcquire<'a> {
+    shared: &'a QapiShared,
+    /// the key it's queued under once it has had to wait
+    waiter: Option<u64>,
 }
 
-struct QapiShared {
-    commands: StdMutex<QapiSharedCommands>,
-    stop_waker: AtomicWaker,
-    stop: AtomicBool,
-    abandoned: AtomicBool,
-    supports_oob: bool,
-}
+impl<'a> Future for QapiInflightAcquire<'a> {
+    type Output = QapiInflightPermit<'a>;
 
-impl QapiShared {
-    #[cfg(feature = "tokio")]
-    fn new(supports_oob: bool) -> Self {
-        Self {
-            commands: Default::default(),
-            stop_waker: Default::default(),
-            stop: Default::default(),
-            abandoned: Default::default(),
-            supports_oob,
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let shared = self.shared;
+        let mut inflight = shared.inflight.lock().unwrap();
+        match self.waiter {
+            Some(key) => match inflight.waiters.iter_mut().find(|(waiter, _)| *waiter == key) {
+                Some((_, waker)) => {
+                    if !waker.will_wake(cx.waker()) {
+                        *waker = cx.waker().clone();
+                    }
+                    return Poll::Pending
+                },
+                // dequeued by `QapiInflight::grant`, which counted the permit
+                None => self.waiter = None,
+            },
+            None if inflight.waiters.is_empty() && inflight.available() => inflight.count += 1,
+            None => {
+                let key = inflight.next_waiter;
+                inflight.next_waiter += 1;
+                inflight.waiters.push_back((key, cx.waker().clone()));
+                self.waiter = Some(key);
+                return Poll::Pending
+            },
         }
-    }
 
-    fn stop(&self) {
-        self.stop.store(true, Ordering::Relaxed);
-        self.stop_waker.wake();
+        Poll::Ready(QapiInflightPermit {
+            shared,
+        })
     }
+}
 
-    fn is_stopped(&self) -> bool {
-        self.stop.load(Ordering::Relaxed)
+impl<'a> Drop for QapiInflightAcquire<'a> {
+    fn drop(&mut self) {
+        let key = match self.waiter {
+            Some(key) => key,
+            None => return,
+        };
+        let mut inflight = self.shared.inflight.lock().unwrap();
+        match inflight.waiters.iter().position(|&(waiter, _)| waiter == key) {
+            Some(index) => drop(inflight.waiters.remove(index)),
+            // a permit was granted, but nobody will use it
+            None => inflight.release(),
+        }
     }
+}
 
-    fn poll_next<T, P: FnOnce(&mut Context) -> Poll<Option<T>>>(&self, cx: &mut Context, poll: P) -> Poll<Option<T>> {
-        if self.is_stopped() {
-            return Poll::Ready(None)
-        }
+struct QapiSyncGuard<'a> {
+    shared: &'a QapiShared,
+}
 
-        // attempt to complete the future
-        match poll(cx) {
-            Poll::Ready(res) => {
-                if res.is_none() {
-                    self.stop.store(true, Ordering::Relaxed);
-                }
-                Poll::Ready(res)
-            },
-            Poll::Pending => {
-                self.stop_waker.register(cx.waker());
-                if self.is_stopped() {
-                    Poll::Ready(None)
-                } else {
-                    Poll::Pending
-                }
-            },
-        }
+impl<'a> Drop for QapiSyncGuard<'a> {
+    fn drop(&mut self) {
+        let mut commands = self.shared.commands.lock().unwrap();
+        commands.sync = None;
+        self.shared.syncing.store(false, Ordering::Relaxed);
     }
+}
 
-    fn command_remove(&self, id: u32) -> Option<oneshot::Sender<Result<Any, qapi_spec::Error>>> {
-        let mut commands = self.commands.lock().unwrap();
-        commands.pending.remove(&id)
-    }
+/// Poisons the stream if a command is abandoned partway through being written, since the
+/// peer would otherwise see the remains of it merged with the next command.
+struct QapiWriteGuard<'a> {
+    shared: &'a QapiShared,
+    partial: bool,
+}
 
-    fn command_insert(&self, id: u32) -> oneshot::Receiver<Result<Any, qapi_spec::Error>> {
-        let (sender, receiver) = oneshot::channel();
-        let mut commands = self.commands.lock().unwrap();
-        if !commands.abandoned {
-            // otherwise sender is dropped immediately
-            if let Some(_prev) = commands.pending.insert(id, sender) {
-                panic!("QAPI duplicate command id {:?}, this should not happen", id);
-            }
+impl<'a> Drop for QapiWriteGuard<'a> {
+    fn drop(&mut self) {
+        if self.partial {
+            warn!("QAPI command abandoned while partially written, closing the stream");
+            self.shared.command_fail_all(|| io::Error::new(io::ErrorKind::BrokenPipe, "QAPI command abandoned while partially written"));
+            self.shared.stop();
         }
-        receiver
     }
 }
 
+/// The read half of a QAPI stream, which routes responses to the commands of its [`QapiService`].
+///
+/// With QMP this is a `Stream` of events in its own right, so it can be stored, adapted with
+/// `StreamExt`, or polled alongside other streams by `futures::stream::select_all`, and is
+/// `Unpin` whenever the underlying stream is. Awaiting it as a `Future` instead discards
+/// events until the stream ends.
 #[must_use]
 pub struct QapiEvents<S> {
     stream: S,
+    /// Dropping or closing the [`QapiService`] stops the stream however it is being driven,
+    /// after which it ends, and dropping it releases this reference.
     shared: Arc<QapiShared>,
+    idle: IdleTimeout,
+}
+
+/// Fails a stream whose peer has gone quiet, see [`QapiEvents::with_idle_timeout`].
+#[derive(Default)]
+struct IdleTimeout {
+    #[cfg(feature = "async-tokio-time")]
+    timeout: Option<Duration>,
+    /// created when first polled, as it needs to be within a tokio runtime
+    #[cfg(feature = "async-tokio-time")]
+    sleep: Option<Pin<Box<::tokio::time::Sleep>>>,
+}
+
+impl IdleTimeout {
+    /// Polls `stream`, failing with `TimedOut` if nothing was received for too long.
+    fn poll_next<T, S: Stream<Item=io::Result<T>>>(&mut self, stream: Pin<&mut S>, shared: &QapiShared, cx: &mut Context) -> Poll<Option<io::Result<T>>> {
+        let res = stream.poll_next(cx);
+        #[cfg(feature = "async-tokio-time")]
+        if res.is_pending() {
+            if let Poll::Ready(e) = self.poll_expired(shared, cx) {
+                shared.command_fail_all(|| io::Error::new(e.kind(), e.to_string()));
+                return Poll::Ready(Some(Err(e)))
+            }
+        }
+        #[cfg(not(feature = "async-tokio-time"))]
+        let _ = shared;
+        res
+    }
+
+    #[cfg(feature = "async-tokio-time")]
+    fn poll_expired(&mut self, shared: &QapiShared, cx: &mut Context) -> Poll<io::Error> {
+        use ::tokio::time::Instant;
+
+        let timeout = match self.timeout {
+            Some(timeout) => timeout,
+            None => return Poll::Pending,
+        };
+        let sleep = self.sleep.get_or_insert_with(|| Box::pin(::tokio::time::sleep(timeout)));
+        loop {
+            futures::ready!(sleep.as_mut().poll(cx));
+
+            // lines may have been received without being seen here, such as skipped events
+            let deadline = shared.wire_log.last_received() + timeout;
+            if deadline > Instant::now() {
+                sleep.as_mut().reset(deadline);
+            } else {
+                sleep.as_mut().reset(Instant::now() + timeout);
+                return Poll::Ready(io::Error::new(io::ErrorKind::TimedOut, format!("nothing received from QAPI peer for {:?}", timeout)))
+            }
+        }
+    }
 }
 
 impl<S> QapiEvents<S> {
+    /// Fails if the [`QapiService`] is already gone, in which case the events have ended.
     pub fn release(&self) -> Result<(), ()> {
-        let commands = self.shared.commands.lock().unwrap();
-        if commands.abandoned {
-            Err(())
-        } else {
-            self.shared.abandoned.store(true, Ordering::Relaxed);
-            Ok(())
+        match self.shared.commands.lock().unwrap().abandoned {
+            true => Err(()),
+            false => Ok(()),
         }
     }
 
+    /// Fails with `TimedOut` if nothing at all is received for `timeout`, along with any
+    /// pending commands.
+    ///
+    /// This detects a peer that is wedged while its socket stays open, even when no command is
+    /// outstanding. QEMU sends nothing while idle, so this should be paired with commands that
+    /// are issued periodically, such as [`QapiService::spawn_keepalive`] for the guest agent.
+    /// The timeout only runs while this stream is being polled.
+    #[cfg(feature = "async-tokio-time")]
+    pub fn with_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.shared.wire_log.track_received();
+        self.idle = IdleTimeout {
+            timeout: Some(timeout),
+            sleep: None,
+        };
+        self
+    }
+
     pub async fn into_future(self) -> () where
         Self: Future<Output=io::Result<()>>,
     {
@@ -306,6 +2771,46 @@ impl<S> QapiEvents<S> {
         }
     }
 
+    /// Processes responses until the stream reaches EOF or is closed, returning any error
+    /// instead of logging it like [`QapiEvents::into_future`].
+    ///
+    /// The stream is closed by [`QapiService::close`], or by dropping the service. Dropping
+    /// the future instead fails any pending commands, so it's safe to cancel.
+    pub async fn spin(self) -> io::Result<()> where
+        Self: Future<Output=io::Result<()>>,
+    {
+        if self.release().is_err() {
+            info!("QAPI service abandoned before spinning");
+            return Ok(())
+        }
+
+        self.await
+    }
+
+    /// Like [`QapiEvents::into_future`], but also finishes as soon as `stop` resolves.
+    ///
+    /// The events are dropped when this returns, failing any commands still pending and
+    /// releasing their reference to the shared state. This makes it suitable for running the
+    /// event loop in a background task that's cancelled on shutdown.
+    pub async fn spin_until<F: Future>(self, stop: F) -> () where
+        Self: Future<Output=io::Result<()>>,
+    {
+        if self.release().is_err() {
+            info!("QAPI service abandoned before spawning");
+            return
+        }
+
+        let events = self;
+        futures::pin_mut!(events, stop);
+        match futures::future::select(events, stop).await {
+            Either::Left((Ok(()), _)) => (),
+            Either::Left((Err(e), _)) =>
+                warn!("QAPI stream closed with error {:?}", e),
+            Either::Right(..) =>
+                info!("QAPI stream stopped"),
+        }
+    }
+
     pub fn spawn<SP: futures::task::Spawn>(self, spawn: SP) -> Result<(), futures::task::SpawnError> where
         Self: Future<Output=io::Result<()>> + Send + 'static,
         S: 'static
@@ -324,56 +2829,411 @@ impl<S> QapiEvents<S> {
     }
 }
 
+#[cfg(feature = "qapi-qmp")]
+impl<S> QapiEvents<S> where
+    Self: Stream<Item=io::Result<qapi_qmp::Event>>,
+{
+    /// Yields only the events that satisfy `pred`.
+    ///
+    /// Other events are discarded, while command responses continue to be processed.
+    pub fn into_stream_filtered<F>(self, mut pred: F) -> impl Stream<Item=io::Result<qapi_qmp::Event>> where
+        F: FnMut(&qapi_qmp::Event) -> bool,
+    {
+        self.try_filter(move |e| futures::future::ready(pred(e)))
+    }
+
+    /// Yields only events of type `E`, such as `qmp::BLOCK_JOB_COMPLETED`.
+    pub fn events_of_kind<E: qapi_spec::Event>(self) -> impl Stream<Item=io::Result<qapi_qmp::Event>> {
+        self.into_stream_filtered(|e| e.name() == E::NAME)
+    }
+
+    /// Suppresses events identical to one of the last `window` events, see [`DedupEvents`].
+    pub fn dedup_events(self, window: usize) -> DedupEvents<Self> {
+        DedupEvents::new(self, window)
+    }
+
+    /// Drives the stream until it ends, passing each event to `on_event`.
+    ///
+    /// Command responses are routed to the [`QapiService`] meanwhile, so this is the usual way to
+    /// handle events while commands are executed elsewhere. Unlike [`QapiEvents::into_future`],
+    /// events aren't discarded, and the error that ended the stream is returned.
+    pub async fn run<F: FnMut(qapi_qmp::Event)>(self, mut on_event: F) -> io::Result<()> {
+        use futures::StreamExt;
+
+        if self.release().is_err() {
+            info!("QAPI service abandoned before spawning");
+            return Ok(())
+        }
+
+        let events = self;
+        futures::pin_mut!(events);
+        while let Some(event) = events.next().await {
+            on_event(event?);
+        }
+
+        Ok(())
+    }
+
+    /// Drives the stream for `duration`, returning the events received meanwhile.
+    ///
+    /// Command responses continue to be routed to the [`QapiService`], so commands executed
+    /// concurrently aren't held up. Returns early if the stream ends, or fails: the events
+    /// collected until then are still returned, and the error is only logged, as the stream is
+    /// disconnected and pending commands fail with it.
+    #[cfg(feature = "async-tokio-time")]
+    pub async fn collect_for(&mut self, duration: Duration) -> io::Result<Vec<qapi_qmp::Event>> where
+        Self: Unpin,
+    {
+        use futures::StreamExt;
+
+        let deadline = ::tokio::time::Instant::now() + duration;
+        let mut events = Vec::new();
+        while let Ok(event) = ::tokio::time::timeout_at(deadline, self.next()).await {
+            match event {
+                Some(Ok(event)) => events.push(event),
+                Some(Err(e)) => {
+                    warn!("QMP stream failed while collecting events: {}", e);
+                    break
+                },
+                None => break,
+            }
+        }
+
+        Ok(events)
+    }
+
+    /// Drives the stream until an event satisfies `pred`, and returns it.
+    ///
+    /// Command responses continue to be routed meanwhile. Other events aren't lost, and are
+    /// yielded by this stream again afterwards, even if waiting fails or is cancelled. Fails
+    /// with `TimedOut` if no event matches within `timeout`.
+    #[cfg(feature = "async-tokio-time")]
+    pub async fn wait_for<F: FnMut(&qapi_qmp::Event) -> bool>(&mut self, mut pred: F, timeout: Duration) -> io::Result<qapi_qmp::Event> where
+        Self: Unpin,
+    {
+        use futures::StreamExt;
+
+        let shared = self.shared.clone();
+        let mut skipped = QapiDeferGuard {
+            shared: &shared,
+            events: Vec::new(),
+        };
+        let wait = async {
+            while let Some(event) = self.next().await {
+                let event = event?;
+                if pred(&event) {
+                    return Ok(event)
+                }
+                skipped.events.push(event);
+            }
+            Err(io::Error::new(io::ErrorKind::UnexpectedEof, "QAPI stream ended while waiting for an event"))
+        };
+        match ::tokio::time::timeout(timeout, wait).await {
+            Ok(res) => res,
+            Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "timed out waiting for QAPI event")),
+        }
+    }
+}
+
+/// Returns events to the front of the stream, so that they are yielded again.
+#[cfg(all(feature = "qapi-qmp", feature = "async-tokio-time"))]
+struct QapiDeferGuard<'a> {
+    shared: &'a QapiShared,
+    events: Vec<qapi_qmp::Event>,
+}
+
+#[cfg(all(feature = "qapi-qmp", feature = "async-tokio-time"))]
+impl<'a> Drop for QapiDeferGuard<'a> {
+    fn drop(&mut self) {
+        let mut history = self.shared.history.lock().unwrap();
+        for event in self.events.drain(..).rev() {
+            history.deferred.push_front(event);
+        }
+    }
+}
+
+impl<S: ReadBuffer> QapiEvents<S> {
+    /// Sizes the buffer that messages are read into, which is reused across messages.
+    ///
+    /// A buffer large enough for bursts of events avoids growing it repeatedly while they're read.
+    pub fn with_capacity(mut self, capacity: usize) -> Self {
+        self.stream.reserve_read(capacity);
+        self
+    }
+}
+
+#[cfg(feature = "qapi-qmp")]
+impl<S> QapiEvents<S> {
+    /// Retains the last `capacity` events, for consumers that weren't around to see them.
+    ///
+    /// Events are recorded as they're received, whether this stream is polled for them or only
+    /// driven as a `Future`, as [`QapiStream::execute`] does. Events received during
+    /// negotiation that haven't been yielded yet are retained too.
+    pub fn with_history(self, capacity: usize) -> Self {
+        self.shared.history.lock().unwrap().set_capacity(capacity);
+        self
+    }
+
+    /// The events retained by [`QapiEvents::with_history`], oldest first.
+    pub fn recent_events(&self) -> Vec<qapi_qmp::Event> {
+        self.shared.recent_events()
+    }
+
+    /// Yields the retained events followed by those received from now on.
+    ///
+    /// Ends when this stream does, or once it falls too far behind the live events, rather
+    /// than buffering them without bound.
+    pub fn replay_events(&self) -> impl Stream<Item=qapi_qmp::Event> {
+        self.shared.history.lock().unwrap().replay()
+    }
+}
+
+#[cfg(all(feature = "qapi-qmp", feature = "async-tokio-spawn"))]
+impl<S> QapiEvents<S> where
+    Self: Stream<Item=io::Result<qapi_qmp::Event>> + Send + 'static,
+{
+    /// Processes the stream in a spawned task, buffering up to `capacity` events for the returned receiver.
+    ///
+    /// Command responses are routed even when the receiver isn't being polled. Events that
+    /// arrive while the buffer is full are dropped and counted by [`QapiEventReceiver::lagged`].
+    pub fn spawn_events_tokio(self, capacity: usize) -> (QapiEventReceiver, ::tokio::task::JoinHandle<()>) {
+        use futures::StreamExt;
+
+        let (mut sender, receiver) = futures::channel::mpsc::channel(capacity);
+        let lagged = Arc::new(AtomicU64::new(0));
+        let receiver = QapiEventReceiver {
+            receiver,
+            lagged: lagged.clone(),
+        };
+
+        let handle = ::tokio::spawn(async move {
+            if self.release().is_err() {
+                info!("QAPI service abandoned before spawning");
+                return
+            }
+
+            let mut events = Box::pin(self);
+            while let Some(event) = events.next().await {
+                match event {
+                    Ok(event) => match sender.try_send(event) {
+                        Err(e) if e.is_full() => {
+                            lagged.fetch_add(1, Ordering::Relaxed);
+                            warn!("QAPI event buffer full, dropping {:?}", e.into_inner().name());
+                        },
+                        // keep routing responses even if nobody is listening for events
+                        _ => (),
+                    },
+                    Err(e) => {
+                        warn!("QAPI stream closed with error {:?}", e);
+                        break
+                    },
+                }
+            }
+        });
+
+        (receiver, handle)
+    }
+
+    /// Runs [`QapiEvents::run`] in a spawned task.
+    pub fn spawn_run_tokio<F: FnMut(qapi_qmp::Event) + Send + 'static>(self, on_event: F) -> ::tokio::task::JoinHandle<io::Result<()>> {
+        ::tokio::spawn(self.run(on_event))
+    }
+}
+
+/// Events received by [`QapiEvents::spawn_events_tokio`].
+#[cfg(all(feature = "qapi-qmp", feature = "async-tokio-spawn"))]
+pub struct QapiEventReceiver {
+    receiver: futures::channel::mpsc::Receiver<qapi_qmp::Event>,
+    lagged: Arc<AtomicU64>,
+}
+
+#[cfg(all(feature = "qapi-qmp", feature = "async-tokio-spawn"))]
+impl QapiEventReceiver {
+    /// The number of events dropped so far because the buffer was full.
+    pub fn lagged(&self) -> u64 {
+        self.lagged.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(all(feature = "qapi-qmp", feature = "async-tokio-spawn"))]
+impl Stream for QapiEventReceiver {
+    type Item = qapi_qmp::Event;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.receiver).poll_next(cx)
+    }
+}
+
 impl<S> Drop for QapiEvents<S> {
+    /// Fails any pending commands, as their responses can no longer be received.
     fn drop(&mut self) {
-        let mut commands = self.shared.commands.lock().unwrap();
-        commands.pending.clear();
-        commands.abandoned = true;
+        self.shared.command_fail_all(|| io::Error::new(io::ErrorKind::UnexpectedEof, "QAPI stream dropped"));
+        self.shared.stop();
+        #[cfg(feature = "qapi-qmp")]
+        self.shared.history.lock().unwrap().live.clear();
     }
 }
 
-fn response_id<T>(res: &Response<T>, supports_oob: bool) -> io::Result<u32> {
-    match (res.id().and_then(|id| id.as_u64()), supports_oob) {
-        (Some(id), true) =>
-            id.try_into().map_err(|e|
-                io::Error::new(io::ErrorKind::InvalidData, e)
-            ),
-        (None, false) =>
-            Ok(Default::default()),
-        (None, true) =>
-            Err(io::Error::new(io::ErrorKind::InvalidData, format!("QAPI expected response with numeric ID, got {:?}", res.id()))),
-        (Some(..), false) =>
-            Err(io::Error::new(io::ErrorKind::InvalidData, format!("QAPI expected response without ID, got {:?}", res.id()))),
+/// Lets other tasks run before continuing.
+#[cfg(all(feature = "qapi-qga", not(feature = "async-tokio-time")))]
+async fn yield_now() {
+    let mut yielded = false;
+    futures::future::poll_fn(|cx| {
+        if yielded {
+            return Poll::Ready(())
+        }
+        yielded = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }).await
+}
+
+/// The key of the command that `res` responds to, or `None` if it doesn't belong to any.
+fn response_id<T>(res: &Response<T>, shared: &QapiShared) -> io::Result<Option<u64>> {
+    let id = match res.id() {
+        Some(id) => id,
+        None if shared.oob_enabled() =>
+            return Err(ProtocolError::UnexpectedResponse("QAPI expected response with an ID".into()).into()),
+        None => return Ok(shared.command_oldest()),
+    };
+
+    if let Some(key) = shared.command_unalias(id) {
+        return Ok(Some(key))
+    }
+
+    match id.as_u64() {
+        Some(id) if shared.oob_enabled() =>
+            Ok(Some(id)),
+        Some(id) if shared.response_order() != ResponseOrder::Unchecked => {
+            let expected = shared.command_oldest();
+            if expected != Some(id) {
+                response_out_of_order(shared, format!("QAPI response arrived out of order, expected ID {:?}, got {}", expected, id))?;
+            }
+            Ok(Some(id))
+        },
+        // sent while responses were still checked
+        Some(id) if shared.command_in_use(id) =>
+            Ok(Some(id)),
+        Some(..) =>
+            Err(ProtocolError::UnexpectedResponse(format!("QAPI expected response without ID, got {:?}", id)).into()),
+        None => Ok(None),
+    }
+}
+
+/// Reports a response ordering violation as configured by [`QapiService::set_response_order`].
+fn response_out_of_order(shared: &QapiShared, message: String) -> io::Result<()> {
+    match shared.response_order() {
+        ResponseOrder::Error => Err(ProtocolError::UnexpectedResponse(message).into()),
+        _ => {
+            warn!("{}", message);
+            Ok(())
+        },
+    }
+}
+
+/// Reports a response that doesn't belong to any pending command, see [`QapiService::set_strict_responses`].
+fn response_unmatched(shared: &QapiShared, message: String) -> io::Result<()> {
+    if shared.strict_responses.load(Ordering::Relaxed) {
+        Err(ProtocolError::UnexpectedResponse(message).into())
+    } else {
+        warn!("Ignoring {}", message);
+        Ok(())
     }
 }
 
-fn handle_response(shared: &QapiShared, res: Response<Any>) -> io::Result<()> {
-    let id = response_id(&res, shared.supports_oob)?;
+fn handle_response(shared: &QapiShared, res: Response<RawReturn>) -> io::Result<()> {
+    let id = match response_id(&res, shared)? {
+        Some(id) => id,
+        None if res.id().is_none() => return response_unmatched(shared, "unexpected QAPI response with no commands pending".into()),
+        None => return response_unmatched(shared, format!("unknown QAPI response with ID {:?}", res.id())),
+    };
+
+    if shared.command_stale(id) {
+        trace!("Discarding late QAPI response with ID {:?}", id);
+        return Ok(())
+    }
+
+    if shared.command_sync_stale(&res) {
+        trace!("Discarding stale QAPI response {:?} while synchronizing", res);
+        return Ok(())
+    }
 
     if let Some(sender) = shared.command_remove(id) {
-        sender.send(res.result()).map_err(|_e|
-            io::Error::new(io::ErrorKind::InvalidData, format!("failed to send response for ID {:?}", id))
-        )
+        if sender.send(res.result().map_err(From::from)).is_err() {
+            // the caller dropped its execute future before the response arrived
+            trace!("Discarding QAPI response with ID {:?}, the command was abandoned", id);
+        }
+        Ok(())
     } else {
-        Err(io::Error::new(io::ErrorKind::InvalidData, format!("unknown QAPI response with ID {:?}", res.id())))
+        response_unmatched(shared, format!("unknown QAPI response with ID {:?}", res.id()))
+    }
+}
+
+/// Records whether a poll asked to be polled again, see [`QapiEvents::drain_buffered`].
+struct WakeFlag(AtomicBool);
+
+impl futures::task::ArcWake for WakeFlag {
+    fn wake_by_ref(arc_self: &Arc<Self>) {
+        arc_self.0.store(true, Ordering::Relaxed);
+    }
+}
+
+impl<S> QapiEvents<S> where
+    Self: Future<Output=io::Result<()>> + Unpin,
+{
+    /// Routes the responses that have already been received to their commands, without
+    /// waiting for more to arrive.
+    ///
+    /// Responses are only processed while the stream is driven, so some may still be buffered
+    /// when deciding to close the connection. Draining them first lets those commands complete
+    /// rather than failing with `UnexpectedEof`. Events received meanwhile are discarded, as
+    /// when driving the stream as a `Future`.
+    pub fn drain_buffered(&mut self) -> io::Result<()> {
+        let flag = Arc::new(WakeFlag(AtomicBool::new(false)));
+        let waker = futures::task::waker(flag.clone());
+        let mut cx = Context::from_waker(&waker);
+        loop {
+            flag.0.store(false, Ordering::Relaxed);
+            match Pin::new(&mut *self).poll(&mut cx) {
+                Poll::Ready(res) => return res,
+                // a message was processed, and there may be more
+                Poll::Pending if flag.0.load(Ordering::Relaxed) => (),
+                Poll::Pending => return Ok(()),
+            }
+        }
     }
 }
 
 impl<M, S> Future for QapiEvents<S> where
     S: Stream<Item=io::Result<M>>,
-    M: TryInto<Response<Any>>,
+    M: QapiMessage,
 {
     type Output = io::Result<()>;
 
     fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
         let this = unsafe { self.get_unchecked_mut() };
         let stream = unsafe { Pin::new_unchecked(&mut this.stream) };
-        let shared = &this.shared;
+        let (shared, idle) = (&this.shared, &mut this.idle);
+        // events are only parsed if there's a history to record them in, and events that were
+        // deferred to be yielded by the stream are discarded along with any others
+        #[cfg(feature = "qapi-qmp")]
+        {
+            let mut history = shared.history.lock().unwrap();
+            history.deferred.clear();
+            shared.skip_events.store(!history.is_recording(), Ordering::Relaxed);
+        }
 
-        shared.poll_next(cx, |cx| Poll::Ready(Some(match futures::ready!(stream.poll_next(cx)) {
-            None => return Poll::Ready(None),
-            Some(Err(e)) => Err(e),
-            Some(Ok(res)) => match res.try_into() {
+        shared.poll_next(cx, |cx| Poll::Ready(Some(match futures::ready!(idle.poll_next(stream, shared, cx)) {
+            None => {
+                #[cfg(feature = "qapi-qmp")]
+                shared.history.lock().unwrap().live.clear();
+                return Poll::Ready(None)
+            },
+            Some(Err(e)) => {
+                shared.disconnect();
+                Err(e)
+            },
+            Some(Ok(res)) => match res.into_response() {
                 Ok(res) => match handle_response(shared, res) {
                     Err(e) => Err(e),
                     Ok(()) => {
@@ -381,7 +3241,13 @@ impl<M, S> Future for QapiEvents<S> where
                         return Poll::Pending
                     },
                 },
-                Err(..) => {
+                Err(message) => {
+                    #[cfg(feature = "qapi-qmp")]
+                    if let Some(e) = message.into_event() {
+                        shared.history.lock().unwrap().record(&e);
+                    }
+                    #[cfg(not(feature = "qapi-qmp"))]
+                    drop(message);
                     trace!("Ignoring QAPI event");
                     cx.waker().wake_by_ref(); // TODO: I've seen this not work with tokio?
                     return Poll::Pending
@@ -392,25 +3258,43 @@ impl<M, S> Future for QapiEvents<S> where
 }
 
 #[cfg(feature = "qapi-qmp")]
-impl<S: Stream<Item=io::Result<QmpMessageAny>>> Stream for QapiEvents<S> {
+impl<S: Stream<Item=io::Result<QmpMessage<RawReturn>>>> Stream for QapiEvents<S> {
     type Item = io::Result<qapi_qmp::Event>;
 
     fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
         let this = unsafe { self.get_unchecked_mut() };
         let stream = unsafe { Pin::new_unchecked(&mut this.stream) };
-        let shared = &this.shared;
-
-        shared.poll_next(cx, |cx| Poll::Ready(match futures::ready!(stream.poll_next(cx)) {
-            None => None, // eof
-            Some(Err(e)) => Some(Err(e)),
-            Some(Ok(QmpMessage::Event(e))) => Some(Ok(e)),
-            Some(Ok(QmpMessage::Response(res))) => match handle_response(shared, res) {
-                Err(e) => Some(Err(e)),
-                Ok(()) => {
-                    cx.waker().wake_by_ref(); // TODO: I've seen this not work with tokio?
-                    return Poll::Pending
+        let (shared, idle) = (&this.shared, &mut this.idle);
+        shared.skip_events.store(false, Ordering::Relaxed);
+
+        shared.poll_next(cx, |cx| {
+            // deferred events are only yielded until the stream stops
+            if let Some(e) = shared.history.lock().unwrap().deferred.pop_front() {
+                return Poll::Ready(Some(Ok(e)))
+            }
+
+            Poll::Ready(match futures::ready!(idle.poll_next(stream, shared, cx)) {
+                None => {
+                    // eof
+                    shared.history.lock().unwrap().live.clear();
+                    None
                 },
-            },
-        }))
+                Some(Err(e)) => {
+                    shared.disconnect();
+                    Some(Err(e))
+                },
+                Some(Ok(QmpMessage::Event(e))) => {
+                    shared.history.lock().unwrap().record(&e);
+                    Some(Ok(e))
+                },
+                Some(Ok(QmpMessage::Response(res))) => match handle_response(shared, res) {
+                    Err(e) => Some(Err(e)),
+                    Ok(()) => {
+                        cx.waker().wake_by_ref(); // TODO: I've seen this not work with tokio?
+                        return Poll::Pending
+                    },
+                },
+            })
+        })
     }
 }
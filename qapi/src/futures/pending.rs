@@ -0,0 +1,213 @@
+//! The commands that are waiting for a response, by the key their response is matched with.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::mem;
+
+/// A map from command keys to the commands waiting on them.
+///
+/// Keys are handed out in increasing order and aren't recycled, since a response may still
+/// arrive for a command that was abandoned. Responses without an id belong to the oldest
+/// command, so implementations must be able to find the smallest key.
+pub(crate) trait PendingMap<V>: Default {
+    /// Tracks `value` by `key`, returning the value it replaces.
+    fn insert(&mut self, key: u64, value: V) -> Option<V>;
+
+    fn remove(&mut self, key: u64) -> Option<V>;
+
+    fn contains(&self, key: u64) -> bool;
+
+    /// The smallest key.
+    fn oldest(&self) -> Option<u64>;
+
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The keys, in increasing order.
+    fn keys(&self) -> Vec<u64>;
+
+    /// Removes everything, in increasing order of key.
+    fn take_all(&mut self) -> Vec<(u64, V)>;
+}
+
+impl<V> PendingMap<V> for BTreeMap<u64, V> {
+    fn insert(&mut self, key: u64, value: V) -> Option<V> {
+        BTreeMap::insert(self, key, value)
+    }
+
+    fn remove(&mut self, key: u64) -> Option<V> {
+        BTreeMap::remove(self, &key)
+    }
+
+    fn contains(&self, key: u64) -> bool {
+        self.contains_key(&key)
+    }
+
+    fn oldest(&self) -> Option<u64> {
+        BTreeMap::keys(self).next().copied()
+    }
+
+    fn len(&self) -> usize {
+        BTreeMap::len(self)
+    }
+
+    fn keys(&self) -> Vec<u64> {
+        BTreeMap::keys(self).copied().collect()
+    }
+
+    fn take_all(&mut self) -> Vec<(u64, V)> {
+        mem::take(self).into_iter().collect()
+    }
+}
+
+/// Keeps entries sorted in a ring buffer, which is reused rather than allocating per command.
+///
+/// As new keys are the largest, inserting one only appends it. Removed entries leave an empty
+/// slot behind, which is dropped once it reaches either end, or once empty slots outnumber
+/// the entries, so a command that never completes doesn't hold on to those sent after it.
+pub(crate) struct PendingSlots<V> {
+    /// never starts or ends with an empty slot
+    slots: VecDeque<(u64, Option<V>)>,
+    len: usize,
+}
+
+impl<V> Default for PendingSlots<V> {
+    fn default() -> Self {
+        Self {
+            slots: VecDeque::new(),
+            len: 0,
+        }
+    }
+}
+
+impl<V> PendingSlots<V> {
+    fn find(&self, key: u64) -> Result<usize, usize> {
+        self.slots.binary_search_by_key(&key, |&(key, _)| key)
+    }
+
+    fn trim(&mut self) {
+        while let Some((_, None)) = self.slots.front() {
+            self.slots.pop_front();
+        }
+        while let Some((_, None)) = self.slots.back() {
+            self.slots.pop_back();
+        }
+        if self.slots.len() > self.len * 2 + 16 {
+            self.slots.retain(|(_, value)| value.is_some());
+        }
+    }
+}
+
+impl<V> PendingMap<V> for PendingSlots<V> {
+    fn insert(&mut self, key: u64, value: V) -> Option<V> {
+        let index = match self.slots.back() {
+            Some(&(last, _)) if last >= key => self.find(key),
+            _ => Err(self.slots.len()),
+        };
+        match index {
+            Ok(index) => {
+                let old = self.slots[index].1.replace(value);
+                if old.is_none() {
+                    self.len += 1;
+                }
+                old
+            },
+            Err(index) => {
+                self.slots.insert(index, (key, Some(value)));
+                self.len += 1;
+                None
+            },
+        }
+    }
+
+    fn remove(&mut self, key: u64) -> Option<V> {
+        let index = self.find(key).ok()?;
+        let value = self.slots[index].1.take()?;
+        self.len -= 1;
+        self.trim();
+        Some(value)
+    }
+
+    fn contains(&self, key: u64) -> bool {
+        match self.find(key) {
+            Ok(index) => self.slots[index].1.is_some(),
+            Err(..) => false,
+        }
+    }
+
+    fn oldest(&self) -> Option<u64> {
+        self.slots.front().map(|&(key, _)| key)
+    }
+
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn keys(&self) -> Vec<u64> {
+        self.slots.iter()
+            .filter(|(_, value)| value.is_some())
+            .map(|&(key, _)| key)
+            .collect()
+    }
+
+    fn take_all(&mut self) -> Vec<(u64, V)> {
+        self.len = 0;
+        mem::take(&mut self.slots).into_iter()
+            .filter_map(|(key, value)| value.map(|value| (key, value)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn check<M: PendingMap<u64>>() {
+        let mut map = M::default();
+        for key in (0..8).chain(10..20).chain(8..10) {
+            assert_eq!(map.insert(key, key), None);
+        }
+        assert_eq!(map.insert(3, 33), Some(3));
+        assert_eq!((map.len(), map.oldest()), (20, Some(0)));
+
+        for key in (0..20).filter(|key| key % 3 != 1) {
+            assert_eq!(map.remove(key), Some(if key == 3 { 33 } else { key }));
+            assert_eq!(map.remove(key), None);
+            assert!(!map.contains(key));
+        }
+        assert_eq!(map.keys(), vec![1, 4, 7, 10, 13, 16, 19]);
+        assert_eq!(map.oldest(), Some(1));
+        assert!(map.contains(19));
+
+        assert_eq!(map.take_all(), vec![(1, 1), (4, 4), (7, 7), (10, 10), (13, 13), (16, 16), (19, 19)]);
+        assert!(map.is_empty());
+        assert_eq!(map.oldest(), None);
+    }
+
+    #[test]
+    fn btree_map() {
+        check::<BTreeMap<u64, u64>>()
+    }
+
+    #[test]
+    fn pending_slots() {
+        check::<PendingSlots<u64>>()
+    }
+
+    /// Completed commands don't pile up behind one that never does.
+    #[test]
+    fn pending_slots_stuck() {
+        let mut map = PendingSlots::default();
+        map.insert(0, ());
+        for key in 1..10000 {
+            map.insert(key, ());
+            if key > 1 {
+                map.remove(key - 1);
+            }
+        }
+        assert_eq!((map.len(), map.oldest()), (2, Some(0)));
+        assert!(map.slots.len() <= 32);
+    }
+}
@@ -0,0 +1,471 @@
+use std::io;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use futures::Future;
+#[cfg(feature = "qapi-qmp")]
+use futures::{StreamExt, channel::mpsc};
+use tokio::io::{AsyncRead, AsyncWrite, WriteHalf};
+#[cfg(feature = "qapi-qmp")]
+use qapi_qmp::{QmpCommand, QMPCapability, Event};
+#[cfg(feature = "qapi-qga")]
+use qapi_qga::QgaCommand;
+use log::{info, warn};
+use crate::{ExecuteResult, ExecuteError, is_disconnect};
+use super::QapiService;
+#[cfg(feature = "qapi-qmp")]
+use super::QmpStreamTokio;
+#[cfg(feature = "qapi-qga")]
+use super::QgaStreamTokio;
+
+/// Controls how [`ReconnectingStream`] and [`ReconnectingQgaStream`] retry a lost connection.
+#[derive(Debug, Clone)]
+pub struct ReconnectPolicy {
+    /// Gives up after this many consecutive failed connection attempts, or never if `None`.
+    pub max_attempts: Option<usize>,
+    /// The delay before retrying a failed attempt, doubled after each failure.
+    pub backoff: Duration,
+    /// The upper bound for `backoff`.
+    pub max_backoff: Duration,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: Some(5),
+            backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(5),
+        }
+    }
+}
+
+#[cfg(feature = "qapi-qmp")]
+/// Items yielded by the events stream of a [`ReconnectingStream`].
+#[derive(Debug, Clone)]
+pub enum ReconnectEvent {
+    Event(Event),
+    /// The connection was re-established and capabilities negotiated again.
+    ///
+    /// Events may have been missed while disconnected.
+    Reconnected,
+}
+
+#[cfg(feature = "qapi-qmp")]
+impl super::EventItem for ReconnectEvent {
+    fn event(&self) -> Option<&Event> {
+        match self {
+            ReconnectEvent::Event(event) => Some(event),
+            ReconnectEvent::Reconnected => None,
+        }
+    }
+}
+
+#[cfg(feature = "qapi-qmp")]
+/// A QMP connection that re-opens its socket whenever it is lost.
+///
+/// Every connection is negotiated with the same capabilities, and its events are forwarded
+/// from a spawned task to the stream returned by [`ReconnectingStream::take_events`].
+pub struct ReconnectingStream<S, F> {
+    connect: F,
+    capabilities: Vec<QMPCapability>,
+    policy: ReconnectPolicy,
+    service: QapiService<QmpStreamTokio<WriteHalf<S>>>,
+    connected: Arc<AtomicBool>,
+    events: mpsc::UnboundedSender<ReconnectEvent>,
+    receiver: Option<mpsc::UnboundedReceiver<ReconnectEvent>>,
+}
+
+#[cfg(feature = "qapi-qmp")]
+impl<S, F, R> ReconnectingStream<S, F> where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    F: FnMut() -> R,
+    R: Future<Output=io::Result<S>>,
+{
+    /// Opens the initial connection using `connect`, retrying according to `policy`.
+    pub async fn connect<C: IntoIterator<Item=QMPCapability>>(mut connect: F, capabilities: C, policy: ReconnectPolicy) -> io::Result<Self> {
+        let capabilities: Vec<_> = capabilities.into_iter().collect();
+        let (events, receiver) = mpsc::unbounded();
+        let (service, connected) = Self::open(&mut connect, &capabilities, &policy, &events).await?;
+
+        Ok(Self {
+            connect,
+            capabilities,
+            policy,
+            service,
+            connected,
+            events,
+            receiver: Some(receiver),
+        })
+    }
+
+    /// Drops the current connection and opens a new one.
+    pub async fn reconnect(&mut self) -> io::Result<()> {
+        let (service, connected) = Self::open(&mut self.connect, &self.capabilities, &self.policy, &self.events).await?;
+        self.service = service;
+        self.connected = connected;
+        let _ = self.events.unbounded_send(ReconnectEvent::Reconnected);
+
+        Ok(())
+    }
+
+    /// Executes a command, reconnecting first if the connection was lost.
+    ///
+    /// The command isn't retried if the connection drops while it is in progress.
+    pub async fn execute<C: QmpCommand>(&mut self, command: C) -> ExecuteResult<C> {
+        if !self.is_connected() {
+            self.reconnect().await?;
+        }
+
+        self.service.execute(command).await
+    }
+
+    /// Executes a command that is safe to repeat, retrying it on a new connection if the
+    /// connection drops before its response arrives.
+    pub async fn execute_idempotent<C: QmpCommand + Clone>(&mut self, command: C) -> ExecuteResult<C> {
+        let mut retries = 0;
+        loop {
+            if !self.is_connected() {
+                self.reconnect().await?;
+            }
+
+            match self.service.execute(command.clone()).await {
+                Err(ExecuteError::Io(e)) if is_disconnect(&e) && self.policy.max_attempts.map(|max| retries < max).unwrap_or(true) => {
+                    info!("QMP connection lost during {}, retrying: {:?}", C::NAME, e);
+                    self.connected.store(false, Ordering::Relaxed);
+                    retries += 1;
+                },
+                res => break res,
+            }
+        }
+    }
+
+    async fn open(connect: &mut F, capabilities: &[QMPCapability], policy: &ReconnectPolicy, events: &mpsc::UnboundedSender<ReconnectEvent>) -> io::Result<(QapiService<QmpStreamTokio<WriteHalf<S>>>, Arc<AtomicBool>)> {
+        let mut backoff = policy.backoff;
+        let mut attempts = 0;
+        let stream = loop {
+            let res = match connect().await {
+                Ok(stream) => match QmpStreamTokio::open(stream).await {
+                    Ok(stream) => stream.negotiate_caps(capabilities.iter().copied()).await,
+                    Err(e) => Err(e),
+                },
+                Err(e) => Err(e),
+            };
+
+            attempts += 1;
+            match res {
+                Ok(stream) => break stream,
+                Err(e) if policy.max_attempts.map(|max| attempts >= max).unwrap_or(false) => return Err(e),
+                Err(e) => {
+                    warn!("QMP connection attempt {} failed: {:?}", attempts, e);
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(policy.max_backoff);
+                },
+            }
+        };
+
+        let (service, mut stream) = stream.into_parts();
+        let connected = Arc::new(AtomicBool::new(true));
+        let _ = stream.release();
+        tokio::spawn({
+            let connected = connected.clone();
+            let events = events.clone();
+            async move {
+                while let Some(event) = stream.next().await {
+                    match event {
+                        Ok(event) => {
+                            let _ = events.unbounded_send(ReconnectEvent::Event(event));
+                        },
+                        Err(e) => {
+                            warn!("QMP stream closed with error {:?}", e);
+                            break
+                        },
+                    }
+                }
+                connected.store(false, Ordering::Relaxed);
+            }
+        });
+
+        Ok((service, connected))
+    }
+}
+
+#[cfg(feature = "qapi-qmp")]
+impl<S, F> ReconnectingStream<S, F> {
+    /// Takes the stream of events received across all connections.
+    ///
+    /// Wrap it in a [`DedupEvents`](super::DedupEvents) to suppress events seen on both sides of
+    /// a reconnection.
+    ///
+    /// Returns `None` if the events were already taken.
+    pub fn take_events(&mut self) -> Option<mpsc::UnboundedReceiver<ReconnectEvent>> {
+        self.receiver.take()
+    }
+
+    /// Whether the current connection is still open, as far as is known.
+    pub fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::Relaxed)
+    }
+
+    /// The service for the current connection.
+    pub fn service(&self) -> &QapiService<QmpStreamTokio<WriteHalf<S>>> {
+        &self.service
+    }
+}
+
+/// A guest agent connection that re-opens its socket whenever it is lost, such as when the
+/// guest reboots.
+///
+/// Each new connection is synchronized with `guest-sync` before use, which discards any stale
+/// output the guest agent left behind from the previous connection.
+#[cfg(feature = "qapi-qga")]
+pub struct ReconnectingQgaStream<S, F> {
+    connect: F,
+    sync_timeout: Duration,
+    policy: ReconnectPolicy,
+    service: QapiService<QgaStreamTokio<WriteHalf<S>>>,
+    connected: Arc<AtomicBool>,
+    task: tokio::task::JoinHandle<()>,
+}
+
+#[cfg(feature = "qapi-qga")]
+impl<S, F, R> ReconnectingQgaStream<S, F> where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    F: FnMut() -> R,
+    R: Future<Output=io::Result<S>>,
+{
+    /// Opens the initial connection using `connect`, retrying according to `policy`.
+    ///
+    /// An attempt fails if the guest agent doesn't respond to `guest-sync` within `sync_timeout`.
+    pub async fn connect(mut connect: F, sync_timeout: Duration, policy: ReconnectPolicy) -> io::Result<Self> {
+        let (service, connected, task) = Self::open(&mut connect, sync_timeout, &policy).await?;
+
+        Ok(Self {
+            connect,
+            sync_timeout,
+            policy,
+            service,
+            connected,
+            task,
+        })
+    }
+
+    /// Drops the current connection and opens a new one.
+    pub async fn reconnect(&mut self) -> io::Result<()> {
+        self.task.abort();
+        let (service, connected, task) = Self::open(&mut self.connect, self.sync_timeout, &self.policy).await?;
+        self.service = service;
+        self.connected = connected;
+        self.task = task;
+
+        Ok(())
+    }
+
+    /// Executes a command, reconnecting first if the connection was lost.
+    ///
+    /// The command isn't retried if the connection drops while it is in progress.
+    pub async fn execute<C: QgaCommand>(&mut self, command: C) -> ExecuteResult<C> {
+        if !self.is_connected() {
+            self.reconnect().await?;
+        }
+
+        self.service.execute(command).await
+    }
+
+    /// Executes a command that is safe to repeat, retrying it on a new connection if the
+    /// connection drops before its response arrives.
+    pub async fn execute_idempotent<C: QgaCommand + Clone>(&mut self, command: C) -> ExecuteResult<C> {
+        let mut retries = 0;
+        loop {
+            if !self.is_connected() {
+                self.reconnect().await?;
+            }
+
+            match self.service.execute(command.clone()).await {
+                Err(ExecuteError::Io(e)) if is_disconnect(&e) && self.policy.max_attempts.map(|max| retries < max).unwrap_or(true) => {
+                    info!("QGA connection lost during {}, retrying: {:?}", C::NAME, e);
+                    self.connected.store(false, Ordering::Relaxed);
+                    retries += 1;
+                },
+                res => break res,
+            }
+        }
+    }
+
+    async fn open(connect: &mut F, sync_timeout: Duration, policy: &ReconnectPolicy) -> io::Result<(QapiService<QgaStreamTokio<WriteHalf<S>>>, Arc<AtomicBool>, tokio::task::JoinHandle<()>)> {
+        let mut backoff = policy.backoff;
+        let mut attempts = 0;
+        let stream = loop {
+            let res = match connect().await {
+                Ok(stream) => QgaStreamTokio::open_timeout(stream, sync_timeout).await,
+                Err(e) => Err(e),
+            };
+
+            attempts += 1;
+            match res {
+                Ok(stream) => break stream,
+                Err(e) if policy.max_attempts.map(|max| attempts >= max).unwrap_or(false) => return Err(e),
+                Err(e) => {
+                    warn!("QGA connection attempt {} failed: {:?}", attempts, e);
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(policy.max_backoff);
+                },
+            }
+        };
+
+        let (service, events) = stream.into_parts();
+        let connected = Arc::new(AtomicBool::new(true));
+        let task = tokio::spawn({
+            let connected = connected.clone();
+            async move {
+                if let Err(e) = events.await {
+                    warn!("QGA stream closed with error {:?}", e);
+                }
+                connected.store(false, Ordering::Relaxed);
+            }
+        });
+
+        Ok((service, connected, task))
+    }
+}
+
+#[cfg(feature = "qapi-qga")]
+impl<S, F> ReconnectingQgaStream<S, F> {
+    /// Whether the current connection is still open, as far as is known.
+    pub fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::Relaxed)
+    }
+
+    /// The service for the current connection.
+    pub fn service(&self) -> &QapiService<QgaStreamTokio<WriteHalf<S>>> {
+        &self.service
+    }
+}
+
+#[cfg(feature = "qapi-qga")]
+impl<S, F> Drop for ReconnectingQgaStream<S, F> {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+#[cfg(all(test, feature = "testing"))]
+mod test {
+    use std::sync::Mutex;
+    use std::time::Duration;
+    use serde_json::json;
+    use qapi_spec::Command;
+    use super::*;
+    use crate::futures::testing::MockQmpServer;
+
+    /// Answers `guest-sync` with its id, and every other command with an empty object.
+    #[cfg(feature = "qapi-qga")]
+    async fn qga_server(socket: tokio::io::DuplexStream) -> io::Result<()> {
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+        let (read, mut write) = tokio::io::split(socket);
+        let mut lines = BufReader::new(read).lines();
+        while let Some(line) = lines.next_line().await? {
+            let command: qapi_spec::Any = serde_json::from_str(&line)?;
+            let reply = match command["execute"].as_str() {
+                Some("guest-sync") => json!({ "return": command["arguments"]["id"] }),
+                _ => json!({ "return": {} }),
+            };
+            write.write_all(format!("{}\n", reply).as_bytes()).await?;
+        }
+        Ok(())
+    }
+
+    /// A lost connection is re-opened and negotiated again before the next command.
+    #[tokio::test(start_paused = true)]
+    async fn reconnect_on_execute() {
+        let servers = Arc::new(Mutex::new(Vec::new()));
+        let connect = {
+            let servers = servers.clone();
+            move || {
+                let (socket, server) = MockQmpServer::new()
+                    .reply_raw(qapi_qmp::stop::NAME, json!({}))
+                    .pair();
+                servers.lock().unwrap().push(tokio::spawn(server));
+                async { Ok(socket) }
+            }
+        };
+        let mut stream = ReconnectingStream::connect(connect, None, Default::default()).await.unwrap();
+        let mut events = stream.take_events().unwrap();
+        stream.execute(qapi_qmp::stop { }).await.unwrap();
+
+        // QEMU goes away
+        servers.lock().unwrap()[0].abort();
+        while stream.is_connected() {
+            tokio::task::yield_now().await;
+        }
+        stream.execute(qapi_qmp::stop { }).await.unwrap();
+
+        assert!(stream.is_connected());
+        assert_eq!(servers.lock().unwrap().len(), 2);
+        assert!(matches!(events.next().await, Some(ReconnectEvent::Reconnected)));
+    }
+
+    /// Failed attempts are retried after a growing delay, until the limit is reached.
+    #[tokio::test(start_paused = true)]
+    async fn connect_backoff() {
+        let policy = ReconnectPolicy {
+            max_attempts: Some(3),
+            backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(10),
+        };
+        let start = tokio::time::Instant::now();
+        let mut attempts = 0;
+        let connect = || {
+            attempts += 1;
+            async { Err::<tokio::io::DuplexStream, _>(io::Error::from(io::ErrorKind::ConnectionRefused)) }
+        };
+        let res = ReconnectingStream::connect(connect, None, policy.clone()).await;
+        assert_eq!(res.err().unwrap().kind(), io::ErrorKind::ConnectionRefused);
+        assert_eq!(attempts, 3);
+        assert_eq!(start.elapsed(), Duration::from_secs(3));
+
+        let mut attempts = 0;
+        let connect = || {
+            attempts += 1;
+            let socket = match attempts {
+                1 => Err(io::Error::from(io::ErrorKind::ConnectionRefused)),
+                _ => {
+                    let (socket, server) = MockQmpServer::new().pair();
+                    tokio::spawn(server);
+                    Ok(socket)
+                },
+            };
+            async { socket }
+        };
+        let stream = ReconnectingStream::connect(connect, None, policy).await.unwrap();
+        assert!(stream.is_connected());
+    }
+
+    /// A guest agent that went away, such as during a reboot, is synced with again before the
+    /// next command.
+    #[cfg(feature = "qapi-qga")]
+    #[tokio::test(start_paused = true)]
+    async fn reconnect_qga() {
+        let servers = Arc::new(Mutex::new(Vec::new()));
+        let connect = {
+            let servers = servers.clone();
+            move || {
+                let (socket, server) = tokio::io::duplex(0x1000);
+                servers.lock().unwrap().push(tokio::spawn(qga_server(server)));
+                async { Ok(socket) }
+            }
+        };
+        let mut stream = ReconnectingQgaStream::connect(connect, Duration::from_secs(1), Default::default()).await.unwrap();
+        stream.execute(qapi_qga::guest_ping { }).await.unwrap();
+
+        // the guest reboots
+        servers.lock().unwrap()[0].abort();
+        while stream.is_connected() {
+            tokio::task::yield_now().await;
+        }
+        stream.execute(qapi_qga::guest_ping { }).await.unwrap();
+
+        assert!(stream.is_connected());
+        assert_eq!(servers.lock().unwrap().len(), 2);
+    }
+}
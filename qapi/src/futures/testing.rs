@@ -0,0 +1,864 @@
+//! An in-memory stand-in for QEMU, for testing code that uses QMP without running a VM.
+
+use std::io;
+use std::collections::{HashMap, VecDeque};
+use futures::Future;
+use serde::Serialize;
+use serde_json::json;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, DuplexStream, split};
+use qapi_qmp::Event;
+use qapi_spec::{Any, Command, ErrorClass};
+
+/// The size of the in-memory buffer used by [`MockQmpServer::pair`].
+const PAIR_CAPACITY: usize = 0x10000;
+
+#[derive(Debug, Clone)]
+enum MockReply {
+    Return(Any),
+    Error(ErrorClass, String),
+    Silent,
+}
+
+/// A command received by a [`MockQmpServer`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct MockCommand {
+    pub name: String,
+    pub arguments: Any,
+    /// Whether the command was sent with `exec-oob`.
+    pub oob: bool,
+}
+
+/// Speaks QMP to a single client, replying to commands from a script.
+///
+/// Capabilities negotiation is handled automatically. Other commands get the replies scripted
+/// for their name, in order, with the last one repeated once the rest are used up. Commands
+/// without a reply fail with `CommandNotFound`, as QEMU does for unknown commands.
+///
+/// ```
+/// # use qapi::{qmp, futures::{QmpStreamTokio, testing::MockQmpServer}};
+/// # futures::executor::block_on(async {
+/// let (socket, server) = MockQmpServer::new()
+///     .reply_raw("human-monitor-command", "QEMU 7.2.0".into())
+///     .pair();
+/// let client = async {
+///     let mut stream = QmpStreamTokio::open(socket).await?.negotiate().await?;
+///     stream.execute(qmp::human_monitor_command {
+///         command_line: "info version".into(),
+///         cpu_index: None,
+///     }).await
+/// };
+/// let (version, commands) = futures::join!(client, server);
+/// assert_eq!(version.unwrap(), "QEMU 7.2.0");
+/// assert_eq!(commands.unwrap()[1].name, "human-monitor-command");
+/// # });
+/// ```
+#[derive(Debug, Clone)]
+pub struct MockQmpServer {
+    version: (u64, u64, u64),
+    oob: bool,
+    replies: HashMap<String, VecDeque<MockReply>>,
+    events: HashMap<String, Vec<Event>>,
+    initial_events: Vec<Event>,
+}
+
+impl Default for MockQmpServer {
+    fn default() -> Self {
+        Self {
+            version: (7, 2, 0),
+            oob: false,
+            replies: Default::default(),
+            events: Default::default(),
+            initial_events: Default::default(),
+        }
+    }
+}
+
+impl MockQmpServer {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// The QEMU version reported in the greeting, 7.2.0 by default.
+    pub fn version(mut self, major: u64, minor: u64, micro: u64) -> Self {
+        self.version = (major, minor, micro);
+        self
+    }
+
+    /// Offers the `oob` capability in the greeting.
+    pub fn oob(mut self, oob: bool) -> Self {
+        self.oob = oob;
+        self
+    }
+
+    /// Replies to the command `C` with `value`.
+    pub fn reply<C: Command>(self, value: C::Ok) -> Self where
+        C::Ok: Serialize,
+    {
+        let value = serde_json::to_value(value).expect("QMP return value must serialize");
+        self.reply_raw(C::NAME, value)
+    }
+
+    /// Replies to the command called `name` with `value`.
+    pub fn reply_raw<N: Into<String>>(mut self, name: N, value: Any) -> Self {
+        self.replies.entry(name.into()).or_default().push_back(MockReply::Return(value));
+        self
+    }
+
+    /// Fails the command called `name` with an error.
+    pub fn reply_error<N: Into<String>, D: Into<String>>(mut self, name: N, class: ErrorClass, desc: D) -> Self {
+        self.replies.entry(name.into()).or_default().push_back(MockReply::Error(class, desc.into()));
+        self
+    }
+
+    /// Never replies to the command called `name`, as if QEMU had stopped responding.
+    pub fn no_reply<N: Into<String>>(mut self, name: N) -> Self {
+        self.replies.entry(name.into()).or_default().push_back(MockReply::Silent);
+        self
+    }
+
+    /// Emits `event` after each reply to the command called `name`.
+    pub fn event_after<N: Into<String>>(mut self, name: N, event: Event) -> Self {
+        self.events.entry(name.into()).or_default().push(event);
+        self
+    }
+
+    /// Emits `event` as soon as capabilities have been negotiated.
+    pub fn event(mut self, event: Event) -> Self {
+        self.initial_events.push(event);
+        self
+    }
+
+    /// Creates an in-memory socket for the client, along with the server that must be driven
+    /// alongside it.
+    ///
+    /// The socket can be passed to [`QmpStreamTokio::open`](super::QmpStreamTokio::open).
+    pub fn pair(self) -> (DuplexStream, impl Future<Output=io::Result<Vec<MockCommand>>>) {
+        let (client, server) = tokio::io::duplex(PAIR_CAPACITY);
+        (client, self.serve(server))
+    }
+
+    /// Serves a client over `socket` until it disconnects, returning every command it sent.
+    pub async fn serve<S: AsyncRead + AsyncWrite>(mut self, socket: S) -> io::Result<Vec<MockCommand>> {
+        let (read, mut write) = split(socket);
+        let mut lines = BufReader::new(read).lines();
+        let mut commands = Vec::new();
+
+        let (major, minor, micro) = self.version;
+        let capabilities: &[&str] = if self.oob { &["oob"] } else { &[] };
+        send(&mut write, json!({
+            "QMP": {
+                "version": {
+                    "qemu": { "major": major, "minor": minor, "micro": micro },
+                    "package": "",
+                },
+                "capabilities": capabilities,
+            },
+        })).await?;
+
+        while let Some(line) = lines.next_line().await? {
+            if line.trim().is_empty() {
+                continue
+            }
+
+            let message: Any = match serde_json::from_str(&line) {
+                Ok(message) => message,
+                Err(e) => {
+                    send(&mut write, error_message(ErrorClass::GenericError, format!("JSON parse error, {}", e), None)).await?;
+                    continue
+                },
+            };
+            let id = message.get("id").cloned();
+            let (name, oob) = match (message.get("execute"), message.get("exec-oob")) {
+                (Some(Any::String(name)), None) => (name.clone(), false),
+                (None, Some(Any::String(name))) => (name.clone(), true),
+                _ => {
+                    send(&mut write, error_message(ErrorClass::GenericError, "QMP input lacks member 'execute'".into(), id)).await?;
+                    continue
+                },
+            };
+            let arguments = message.get("arguments").cloned().unwrap_or_else(|| json!({}));
+
+            let events = if name == qapi_qmp::qmp_capabilities::NAME {
+                let oob_requested = arguments.get("enable").and_then(Any::as_array)
+                    .map(|caps| caps.iter().any(|cap| cap == "oob")).unwrap_or(false);
+                let reply = if oob_requested && !self.oob {
+                    error_message(ErrorClass::GenericError, "Capability 'oob' not available".into(), id)
+                } else {
+                    return_message(json!({}), id)
+                };
+                send(&mut write, reply).await?;
+                self.initial_events.drain(..).collect()
+            } else {
+                let reply = match self.next_reply(&name) {
+                    Some(MockReply::Return(value)) => Some(return_message(value, id)),
+                    Some(MockReply::Error(class, desc)) => Some(error_message(class, desc, id)),
+                    Some(MockReply::Silent) => None,
+                    None => Some(error_message(ErrorClass::CommandNotFound, format!("The command {} has not been found", name), id)),
+                };
+                match reply {
+                    Some(reply) => {
+                        send(&mut write, reply).await?;
+                        self.events.get(&name).cloned().unwrap_or_default()
+                    },
+                    None => Vec::new(),
+                }
+            };
+
+            for event in events {
+                send(&mut write, serde_json::to_value(event)?).await?;
+            }
+
+            commands.push(MockCommand {
+                name,
+                arguments,
+                oob,
+            });
+        }
+
+        Ok(commands)
+    }
+
+    fn next_reply(&mut self, name: &str) -> Option<MockReply> {
+        let replies = self.replies.get_mut(name)?;
+        match replies.len() {
+            1 => replies.front().cloned(),
+            _ => replies.pop_front(),
+        }
+    }
+}
+
+fn return_message(value: Any, id: Option<Any>) -> Any {
+    with_id(json!({ "return": value }), id)
+}
+
+fn error_message(class: ErrorClass, desc: String, id: Option<Any>) -> Any {
+    with_id(json!({ "error": { "class": class, "desc": desc } }), id)
+}
+
+fn with_id(mut message: Any, id: Option<Any>) -> Any {
+    if let (Some(id), Some(message)) = (id, message.as_object_mut()) {
+        message.insert("id".into(), id);
+    }
+    message
+}
+
+async fn send<W: AsyncWrite + Unpin>(write: &mut W, message: Any) -> io::Result<()> {
+    let mut line = serde_json::to_vec(&message)?;
+    line.push(b'\n');
+    write.write_all(&line).await?;
+    write.flush().await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::futures::QmpStreamTokio;
+
+    /// Commands that QEMU never answers fail once their deadline passes.
+    #[cfg(feature = "async-tokio-time")]
+    #[tokio::test(start_paused = true)]
+    async fn execute_timeout() {
+        use std::time::Duration;
+
+        let (socket, server) = MockQmpServer::new()
+            .no_reply(qapi_qmp::stop::NAME)
+            .reply_raw(qapi_qmp::cont::NAME, json!({}))
+            .pair();
+        let server = tokio::spawn(server);
+        let stream = QmpStreamTokio::open(socket).await.unwrap().negotiate().await.unwrap();
+        let (service, spin) = stream.into_spin();
+        let client = async move {
+            // the deadline only starts once the command is polled
+            let cont = service.execute_timeout(qapi_qmp::cont { }, Duration::from_secs(1));
+            tokio::time::sleep(Duration::from_secs(2)).await;
+            let cont = cont.await;
+
+            let stop = service.execute_timeout(qapi_qmp::stop { }, Duration::from_secs(1)).await;
+            (cont, stop)
+        };
+        let ((cont, stop), spin) = futures::join!(client, spin);
+        cont.unwrap();
+        assert_eq!(stop.unwrap_err().to_string(), "QAPI command stop timed out");
+        spin.unwrap();
+        assert_eq!(server.await.unwrap().unwrap().len(), 3);
+    }
+
+    /// Any event prompts another look at the run state, not just `STOP` and `RESUME`.
+    #[cfg(feature = "async-tokio-time")]
+    #[tokio::test(start_paused = true)]
+    async fn wait_for_state() {
+        use std::time::Duration;
+        use qapi_qmp::RunState;
+
+        let suspend: Event = serde_json::from_value(json!({
+            "event": "SUSPEND",
+            "timestamp": { "seconds": 0, "microseconds": 0 },
+        })).unwrap();
+        let (socket, server) = MockQmpServer::new()
+            .reply_raw(qapi_qmp::query_status::NAME, json!({ "running": false, "singlestep": false, "status": "running" }))
+            .reply_raw(qapi_qmp::query_status::NAME, json!({ "running": false, "singlestep": false, "status": "suspended" }))
+            .event_after(qapi_qmp::query_status::NAME, suspend)
+            .pair();
+        let server = tokio::spawn(server);
+        let mut stream = QmpStreamTokio::open(socket).await.unwrap().negotiate().await.unwrap();
+
+        let status = stream.wait_for_state(RunState::suspended, Duration::from_secs(1)).await.unwrap();
+        assert_eq!(status.status, RunState::suspended);
+        // the event isn't lost
+        let (service, mut events) = stream.into_parts();
+        let event = futures::StreamExt::next(&mut events).await.unwrap().unwrap();
+        assert!(matches!(event, Event::SUSPEND { .. }));
+        drop((service, events));
+        assert_eq!(server.await.unwrap().unwrap().len(), 3);
+    }
+
+    /// Events are recorded while the stream is only driven to execute commands.
+    #[tokio::test]
+    async fn history_while_executing() {
+        let stop: Event = serde_json::from_value(json!({
+            "event": "STOP",
+            "timestamp": { "seconds": 0, "microseconds": 0 },
+        })).unwrap();
+        let (socket, server) = MockQmpServer::new()
+            .reply_raw(qapi_qmp::stop::NAME, json!({}))
+            .reply_raw(qapi_qmp::cont::NAME, json!({}))
+            .event_after(qapi_qmp::stop::NAME, stop)
+            .pair();
+        let server = tokio::spawn(server);
+        let mut stream = QmpStreamTokio::open(socket).await.unwrap().negotiate().await.unwrap()
+            .with_history(4);
+
+        stream.execute(qapi_qmp::stop { }).await.unwrap();
+        stream.execute(qapi_qmp::cont { }).await.unwrap();
+        let recent = stream.recent_events();
+        assert_eq!(recent.len(), 1);
+        assert!(matches!(recent[0], Event::STOP { .. }));
+        drop(stream);
+        assert_eq!(server.await.unwrap().unwrap().len(), 3);
+    }
+
+    /// The builder's wire logger sees everything, starting with the greeting.
+    #[cfg(feature = "async-tokio-net")]
+    #[tokio::test]
+    async fn builder_wire_logger() {
+        use std::sync::{Arc, Mutex};
+        use futures::future::{BoxFuture, FutureExt};
+        use crate::futures::{QapiStreamBuilder, Transport, WireDirection};
+
+        struct MockTransport(Mutex<Option<DuplexStream>>);
+
+        impl Transport for MockTransport {
+            type Socket = DuplexStream;
+
+            fn connect(&self) -> BoxFuture<'_, io::Result<Self::Socket>> {
+                let socket = self.0.lock().unwrap().take()
+                    .ok_or_else(|| io::Error::new(io::ErrorKind::ConnectionRefused, "already connected"));
+                futures::future::ready(socket).boxed()
+            }
+        }
+
+        let (socket, server) = MockQmpServer::new().pair();
+        let server = tokio::spawn(server);
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let stream = QapiStreamBuilder::new(MockTransport(Mutex::new(Some(socket))))
+            .wire_logger({
+                let log = log.clone();
+                move |direction, line| log.lock().unwrap().push((direction, String::from_utf8_lossy(line).into_owned()))
+            })
+            .connect().await.unwrap();
+        drop(stream);
+        server.await.unwrap().unwrap();
+
+        let log = log.lock().unwrap();
+        let directions: Vec<_> = log.iter().map(|&(direction, _)| direction).collect();
+        assert_eq!(directions, [WireDirection::Receive, WireDirection::Send, WireDirection::Receive]);
+        assert!(log[0].1.contains("\"QMP\""), "{:?}", log[0]);
+    }
+
+    /// Commands sent while responses were checked still get their responses once checking is turned off.
+    #[tokio::test]
+    async fn response_order_unchecked_while_pending() {
+        use crate::futures::ResponseOrder;
+
+        let (socket, server) = MockQmpServer::new()
+            .reply_raw(qapi_qmp::stop::NAME, json!({}))
+            .reply_raw(qapi_qmp::cont::NAME, json!({}))
+            .pair();
+        let server = tokio::spawn(server);
+        let stream = QmpStreamTokio::open(socket).await.unwrap().negotiate().await.unwrap();
+        let (service, spin) = stream.into_spin();
+        service.set_response_order(ResponseOrder::Error);
+        let client = async move {
+            let stop = service.execute(qapi_qmp::stop { });
+            futures::pin_mut!(stop);
+            assert!(futures::poll!(stop.as_mut()).is_pending());
+            service.set_response_order(ResponseOrder::Unchecked);
+            let stop = stop.await;
+            let cont = service.execute(qapi_qmp::cont { }).await;
+            (stop, cont)
+        };
+        let ((stop, cont), spin) = futures::join!(client, spin);
+        stop.unwrap();
+        cont.unwrap();
+        spin.unwrap();
+        assert_eq!(server.await.unwrap().unwrap().len(), 3);
+    }
+
+    /// Commands executed by name are reported under that name.
+    #[tokio::test]
+    async fn execute_raw_metrics() {
+        use std::sync::{Arc, Mutex};
+        use std::time::Duration;
+        use crate::futures::{CommandInfo, MetricsSink};
+
+        #[derive(Default)]
+        struct Names(Mutex<Vec<(String, Option<ErrorClass>)>>);
+
+        impl MetricsSink for Arc<Names> {
+            fn response_received(&self, command: &CommandInfo, _elapsed: Duration, error: Option<&crate::Error>) {
+                self.0.lock().unwrap().push((command.name.into(), error.map(|e| e.class.clone())));
+            }
+        }
+
+        let (socket, server) = MockQmpServer::new()
+            .reply_raw("x-custom", json!({ "a": 1 }))
+            .pair();
+        let server = tokio::spawn(server);
+        let stream = QmpStreamTokio::open(socket).await.unwrap().negotiate().await.unwrap();
+        let names = Arc::new(Names::default());
+        stream.set_metrics_sink(names.clone());
+        let (service, spin) = stream.into_spin();
+        let client = async move {
+            let custom = service.execute_raw("x-custom", Any::Null).await;
+            let missing = service.execute_serialized(br#"{"execute": "x-missing"}"#).await;
+            (custom, missing)
+        };
+        let ((custom, missing), spin) = futures::join!(client, spin);
+        assert_eq!(custom.unwrap().unwrap(), json!({ "a": 1 }));
+        assert_eq!(missing.unwrap().unwrap_err().class, ErrorClass::CommandNotFound);
+        spin.unwrap();
+        server.await.unwrap().unwrap();
+
+        let names = names.0.lock().unwrap();
+        assert_eq!(*names, [("x-custom".into(), None), ("x-missing".into(), Some(ErrorClass::CommandNotFound))]);
+    }
+
+    /// Batched commands are written in order and each gets its own result, while a dropped
+    /// batch fails its commands without writing them.
+    #[tokio::test]
+    async fn batch() {
+        let (socket, server) = MockQmpServer::new()
+            .reply_raw(qapi_qmp::stop::NAME, json!({}))
+            .reply_error(qapi_qmp::cont::NAME, ErrorClass::GenericError, "not stopped")
+            .pair();
+        let server = tokio::spawn(server);
+        let stream = QmpStreamTokio::open(socket).await.unwrap().negotiate().await.unwrap();
+        let (service, spin) = stream.into_spin();
+        let client = async move {
+            let mut dropped = service.batch();
+            let dropped_stop = dropped.execute(qapi_qmp::stop { });
+            drop(dropped);
+
+            let mut batch = service.batch();
+            let stop = batch.execute(qapi_qmp::stop { });
+            let cont = batch.execute(qapi_qmp::cont { });
+            assert_eq!(batch.len(), 2);
+            batch.send().await.unwrap();
+            (dropped_stop.await, stop.await, cont.await)
+        };
+        let ((dropped_stop, stop, cont), spin) = futures::join!(client, spin);
+        match dropped_stop {
+            Err(crate::ExecuteError::Io(e)) => assert_eq!(e.kind(), io::ErrorKind::Interrupted),
+            res => panic!("unexpected result {:?}", res),
+        }
+        stop.unwrap();
+        match cont {
+            Err(crate::ExecuteError::Qapi(e)) => assert_eq!(e.desc, "not stopped"),
+            res => panic!("unexpected result {:?}", res),
+        }
+        spin.unwrap();
+
+        let names: Vec<_> = server.await.unwrap().unwrap().into_iter().map(|command| command.name).collect();
+        assert_eq!(names, ["qmp_capabilities", "stop", "cont"]);
+    }
+
+    /// Without the `oob` capability, commands run in-band or fail as the fallback chooses.
+    #[tokio::test]
+    async fn execute_oob_fallback() {
+        use qapi_qmp::QMPCapability;
+        use crate::futures::OobFallback;
+
+        for &oob in &[false, true] {
+            let (socket, server) = MockQmpServer::new()
+                .oob(oob)
+                .reply_raw(qapi_qmp::migrate_pause::NAME, json!({}))
+                .pair();
+            let server = tokio::spawn(server);
+            let stream = QmpStreamTokio::open(socket).await.unwrap()
+                .negotiate_supported(Some(QMPCapability::oob)).await.unwrap();
+            let (service, spin) = stream.into_spin();
+            let client = async move {
+                let serial = service.execute_oob_fallback(qapi_qmp::migrate_pause { }, OobFallback::Serial).await;
+                let error = service.execute_oob_fallback(qapi_qmp::migrate_pause { }, OobFallback::Error).await;
+                (serial, error)
+            };
+            let ((serial, error), spin) = futures::join!(client, spin);
+            serial.unwrap();
+            assert_eq!(error.is_ok(), oob);
+            spin.unwrap();
+
+            let commands = server.await.unwrap().unwrap();
+            let sent: Vec<_> = commands[1..].iter().map(|command| command.oob).collect();
+            assert_eq!(sent, if oob { vec![true, true] } else { vec![false] });
+        }
+    }
+
+    /// Results come back in the order of the commands, whether they ran out-of-band or not.
+    #[tokio::test]
+    async fn execute_all() {
+        use qapi_qmp::QMPCapability;
+
+        for &oob in &[false, true] {
+            let (socket, server) = MockQmpServer::new()
+                .oob(oob)
+                .reply_raw(qapi_qmp::migrate_pause::NAME, json!({}))
+                .reply_error(qapi_qmp::migrate_pause::NAME, ErrorClass::GenericError, "not migrating")
+                .reply_raw(qapi_qmp::migrate_pause::NAME, json!({}))
+                .pair();
+            let server = tokio::spawn(server);
+            let stream = QmpStreamTokio::open(socket).await.unwrap()
+                .negotiate_supported(Some(QMPCapability::oob)).await.unwrap();
+            let (service, spin) = stream.into_spin();
+            let client = async move {
+                service.execute_all(vec![qapi_qmp::migrate_pause { }; 3]).await
+            };
+            let (res, spin) = futures::join!(client, spin);
+            let res: Vec<_> = res.iter().map(|res| res.is_ok()).collect();
+            assert_eq!(res, [true, false, true]);
+            spin.unwrap();
+
+            let commands = server.await.unwrap().unwrap();
+            assert_eq!(commands.len(), 4);
+            assert!(commands[1..].iter().all(|command| command.oob == oob));
+        }
+    }
+
+    /// A cancelled command fails without waiting for its response, which is discarded once it
+    /// arrives.
+    #[tokio::test]
+    async fn execute_cancellable() {
+        let (socket, server) = MockQmpServer::new()
+            .reply_raw(qapi_qmp::stop::NAME, json!({}))
+            .reply_raw(qapi_qmp::cont::NAME, json!({}))
+            .pair();
+        let server = tokio::spawn(server);
+        let stream = QmpStreamTokio::open(socket).await.unwrap().negotiate().await.unwrap();
+        let (service, spin) = stream.into_spin();
+        let client = async move {
+            let (stop, cancel) = service.execute_cancellable(qapi_qmp::stop { });
+            futures::pin_mut!(stop);
+            // the server can't reply before this task yields
+            assert!(futures::poll!(stop.as_mut()).is_pending());
+            assert_eq!(service.pending_count(), 1);
+            cancel.cancel();
+            let stop = stop.await;
+            let pending = service.pending_count();
+            let (cont, _cancel) = service.execute_cancellable(qapi_qmp::cont { });
+            (stop, pending, cont.await)
+        };
+        let ((stop, pending, cont), spin) = futures::join!(client, spin);
+        match stop {
+            Err(crate::ExecuteError::Io(e)) => assert_eq!(e.kind(), io::ErrorKind::Interrupted),
+            res => panic!("unexpected result {:?}", res),
+        }
+        assert_eq!(pending, 0);
+        cont.unwrap();
+        spin.unwrap();
+        assert_eq!(server.await.unwrap().unwrap().len(), 3);
+    }
+
+    /// Failed commands report the given error, and the stream carries on past their responses.
+    #[tokio::test]
+    async fn fail_pending() {
+        let (socket, server) = MockQmpServer::new()
+            .reply_raw(qapi_qmp::stop::NAME, json!({}))
+            .reply_raw(qapi_qmp::cont::NAME, json!({}))
+            .pair();
+        let server = tokio::spawn(server);
+        let stream = QmpStreamTokio::open(socket).await.unwrap().negotiate().await.unwrap();
+        let (service, spin) = stream.into_spin();
+        let client = async move {
+            let stop = service.execute(qapi_qmp::stop { });
+            futures::pin_mut!(stop);
+            // the server can't reply before this task yields
+            assert!(futures::poll!(stop.as_mut()).is_pending());
+            assert_eq!(service.pending_ids().len(), 1);
+            let failed = service.fail_pending(io::ErrorKind::ConnectionAborted);
+            let stop = stop.await;
+            (failed, stop, service.execute(qapi_qmp::cont { }).await)
+        };
+        let ((failed, stop, cont), spin) = futures::join!(client, spin);
+        assert_eq!(failed, 1);
+        match stop {
+            Err(crate::ExecuteError::Io(e)) => assert_eq!(e.kind(), io::ErrorKind::ConnectionAborted),
+            res => panic!("unexpected result {:?}", res),
+        }
+        cont.unwrap();
+        spin.unwrap();
+        assert_eq!(server.await.unwrap().unwrap().len(), 3);
+    }
+
+    /// The connection is reported as lost once the events notice the peer went away.
+    #[tokio::test]
+    async fn is_connected() {
+        let (socket, server) = MockQmpServer::new().pair();
+        let server = tokio::spawn(server);
+        let stream = QmpStreamTokio::open(socket).await.unwrap().negotiate().await.unwrap();
+        assert!(stream.is_connected());
+        let (service, spin) = stream.into_spin();
+
+        server.abort();
+        spin.await.unwrap();
+        assert!(!service.is_connected());
+    }
+
+    /// Responses are matched to commands sent with ids that aren't numbers.
+    #[tokio::test]
+    async fn execute_with_id() {
+        let (socket, server) = MockQmpServer::new()
+            .reply_raw(qapi_qmp::stop::NAME, json!({}))
+            .reply_error(qapi_qmp::cont::NAME, ErrorClass::GenericError, "not stopped")
+            .pair();
+        let server = tokio::spawn(server);
+        let stream = QmpStreamTokio::open(socket).await.unwrap().negotiate().await.unwrap();
+        let (service, spin) = stream.into_spin();
+        let client = async move {
+            let stop = service.execute_with_id(qapi_qmp::stop { }, json!("stop-1"));
+            let cont = service.execute_with_id(qapi_qmp::cont { }, json!({ "n": 2 }));
+            let res = futures::join!(stop, cont);
+            (res, service.pending_count())
+        };
+        let (((stop, cont), pending), spin) = futures::join!(client, spin);
+        stop.unwrap();
+        match cont {
+            Err(crate::ExecuteError::Qapi(e)) => assert_eq!(e.desc, "not stopped"),
+            res => panic!("unexpected result {:?}", res),
+        }
+        assert_eq!(pending, 0);
+        spin.unwrap();
+        assert_eq!(server.await.unwrap().unwrap().len(), 3);
+    }
+
+    /// Stopping the event loop fails the commands still waiting on it.
+    #[tokio::test]
+    async fn spin_until() {
+        use futures::channel::oneshot;
+
+        let (socket, server) = MockQmpServer::new()
+            .no_reply(qapi_qmp::stop::NAME)
+            .pair();
+        let server = tokio::spawn(server);
+        let stream = QmpStreamTokio::open(socket).await.unwrap().negotiate().await.unwrap();
+        let (service, events) = stream.into_parts();
+        let (stop_events, stopped) = oneshot::channel::<()>();
+        let client = async move {
+            let stop = service.execute(qapi_qmp::stop { });
+            futures::pin_mut!(stop);
+            assert!(futures::poll!(stop.as_mut()).is_pending());
+            stop_events.send(()).unwrap();
+            stop.await
+        };
+        let (stop, ()) = futures::join!(client, events.spin_until(stopped));
+        assert!(stop.is_err());
+        assert_eq!(server.await.unwrap().unwrap().len(), 2);
+    }
+
+    /// Events skipped while waiting for a match are yielded afterwards, even after a timeout.
+    #[cfg(feature = "async-tokio-time")]
+    #[tokio::test(start_paused = true)]
+    async fn wait_for() {
+        use std::time::Duration;
+        use futures::StreamExt;
+
+        let event = |name: &str| -> Event {
+            serde_json::from_value(json!({
+                "event": name,
+                "timestamp": { "seconds": 0, "microseconds": 0 },
+            })).unwrap()
+        };
+        let (socket, server) = MockQmpServer::new()
+            .reply_raw(qapi_qmp::stop::NAME, json!({}))
+            .event_after(qapi_qmp::stop::NAME, event("STOP"))
+            .event_after(qapi_qmp::stop::NAME, event("RESUME"))
+            .pair();
+        let server = tokio::spawn(server);
+        let stream = QmpStreamTokio::open(socket).await.unwrap().negotiate().await.unwrap();
+        let (service, mut events) = stream.into_parts();
+
+        let (stop, resume) = futures::join!(
+            service.execute(qapi_qmp::stop { }),
+            events.wait_for(|event| matches!(event, Event::RESUME { .. }), Duration::from_secs(1)),
+        );
+        stop.unwrap();
+        assert!(matches!(resume.unwrap(), Event::RESUME { .. }));
+
+        let shutdown = events.wait_for(|event| matches!(event, Event::SHUTDOWN { .. }), Duration::from_secs(1)).await;
+        assert_eq!(shutdown.unwrap_err().kind(), io::ErrorKind::TimedOut);
+        assert!(matches!(events.next().await.unwrap().unwrap(), Event::STOP { .. }));
+
+        drop((service, events));
+        assert_eq!(server.await.unwrap().unwrap().len(), 2);
+    }
+
+    /// Events missing from the schema reach the client intact.
+    #[tokio::test]
+    async fn unknown_event() {
+        use futures::StreamExt;
+
+        let vendor: Event = serde_json::from_value(json!({
+            "event": "X_VENDOR_EVENT",
+            "data": { "level": 3 },
+            "timestamp": { "seconds": 1, "microseconds": 0 },
+        })).unwrap();
+        let (socket, server) = MockQmpServer::new()
+            .event(vendor)
+            .pair();
+        let server = tokio::spawn(server);
+        let stream = QmpStreamTokio::open(socket).await.unwrap().negotiate().await.unwrap();
+        let (service, mut events) = stream.into_parts();
+        let event = events.next().await.unwrap().unwrap();
+        match &event {
+            Event::Unknown { name, data, .. } => {
+                assert_eq!(name, "X_VENDOR_EVENT");
+                assert_eq!(*data, json!({ "level": 3 }));
+            },
+            event => panic!("unexpected event {:?}", event),
+        }
+        assert_eq!(event.name(), "X_VENDOR_EVENT");
+
+        drop((service, events));
+        server.await.unwrap().unwrap();
+    }
+
+    /// Progress is queried right away, again whenever a `MIGRATION` event arrives or the
+    /// interval passes, and no longer once the migration has finished.
+    #[cfg(feature = "async-tokio-time")]
+    #[tokio::test(start_paused = true)]
+    async fn watch_migration() {
+        use std::time::Duration;
+        use futures::StreamExt;
+        use qapi_qmp::MigrationStatus;
+
+        let migration: Event = serde_json::from_value(json!({
+            "event": "MIGRATION",
+            "data": { "status": "active" },
+            "timestamp": { "seconds": 0, "microseconds": 0 },
+        })).unwrap();
+        let stop: Event = serde_json::from_value(json!({
+            "event": "STOP",
+            "timestamp": { "seconds": 0, "microseconds": 0 },
+        })).unwrap();
+        let (socket, server) = MockQmpServer::new()
+            .reply_raw(qapi_qmp::query_migrate::NAME, json!({}))
+            .reply_raw(qapi_qmp::query_migrate::NAME, json!({ "status": "active" }))
+            .reply_raw(qapi_qmp::query_migrate::NAME, json!({ "status": "active" }))
+            .reply_raw(qapi_qmp::query_migrate::NAME, json!({ "status": "completed" }))
+            .event(migration)
+            .event_after(qapi_qmp::query_migrate::NAME, stop)
+            .pair();
+        let server = tokio::spawn(server);
+        let mut stream = QmpStreamTokio::open(socket).await.unwrap().negotiate().await.unwrap();
+
+        let start = tokio::time::Instant::now();
+        let progress: Vec<_> = stream.watch_migration(Duration::from_secs(10)).collect().await;
+        let statuses: Vec<_> = progress.into_iter().map(|progress| progress.unwrap().status).collect();
+        assert_eq!(statuses, [MigrationStatus::none, MigrationStatus::active, MigrationStatus::active, MigrationStatus::completed]);
+        // only the event cut an interval short
+        assert_eq!(start.elapsed(), Duration::from_secs(20));
+        assert!(matches!(stream.events.next().await.unwrap().unwrap(), Event::STOP { .. }));
+
+        drop(stream);
+        assert_eq!(server.await.unwrap().unwrap().len(), 5);
+    }
+
+    /// Nodes without a name are refused before anything is sent, and named nodes are deleted
+    /// by name.
+    #[tokio::test]
+    async fn blockdev_add() {
+        let options = |options| -> qapi_qmp::BlockdevOptions { serde_json::from_value(options).unwrap() };
+        let (socket, server) = MockQmpServer::new()
+            .reply_raw(qapi_qmp::blockdev_add::NAME, json!({}))
+            .reply_raw(qapi_qmp::blockdev_del::NAME, json!({}))
+            .pair();
+        let server = tokio::spawn(server);
+        let stream = QmpStreamTokio::open(socket).await.unwrap().negotiate().await.unwrap();
+        let (service, spin) = stream.into_spin();
+        let client = async move {
+            let unnamed = service.blockdev_add(options(json!({ "driver": "null-co" }))).await.map(drop);
+            let node = service.blockdev_add(options(json!({ "driver": "null-co", "node-name": "null0" }))).await.unwrap();
+            assert_eq!(node.node_name(), "null0");
+            (unnamed, node.remove().await)
+        };
+        let ((unnamed, removed), spin) = futures::join!(client, spin);
+        match unnamed {
+            Err(crate::ExecuteError::Io(e)) => assert_eq!(e.kind(), io::ErrorKind::InvalidInput),
+            res => panic!("unexpected result {:?}", res),
+        }
+        removed.unwrap();
+        spin.unwrap();
+
+        let commands = server.await.unwrap().unwrap();
+        let names: Vec<_> = commands.iter().map(|command| &command.name[..]).collect();
+        assert_eq!(names, ["qmp_capabilities", "blockdev-add", "blockdev-del"]);
+        assert_eq!(commands[2].arguments, json!({ "node-name": "null0" }));
+    }
+
+    /// Pings succeed while QEMU answers, and time out once it stops.
+    #[cfg(feature = "async-tokio-time")]
+    #[tokio::test(start_paused = true)]
+    async fn ping() {
+        let version = json!({ "qemu": { "major": 7, "minor": 2, "micro": 0 }, "package": "" });
+        let (socket, server) = MockQmpServer::new()
+            .reply_raw(qapi_qmp::query_version::NAME, version)
+            .no_reply(qapi_qmp::query_version::NAME)
+            .pair();
+        let server = tokio::spawn(server);
+        let stream = QmpStreamTokio::open(socket).await.unwrap().negotiate().await.unwrap();
+        let (service, spin) = stream.into_spin();
+        let client = async move {
+            (service.ping().await, service.ping().await)
+        };
+        let ((alive, stuck), spin) = futures::join!(client, spin);
+        alive.unwrap();
+        assert_eq!(stuck.unwrap_err().kind(), io::ErrorKind::TimedOut);
+        spin.unwrap();
+        assert_eq!(server.await.unwrap().unwrap().len(), 3);
+    }
+
+    /// Dropping a command before its response arrives forgets it, and the late response is
+    /// discarded rather than given to the next command.
+    #[tokio::test]
+    async fn drop_pending_command() {
+        let (socket, server) = MockQmpServer::new()
+            .reply_raw(qapi_qmp::query_status::NAME, json!({ "running": true, "singlestep": false, "status": "running" }))
+            .reply_raw(qapi_qmp::query_version::NAME, json!({ "qemu": { "major": 7, "minor": 2, "micro": 0 }, "package": "" }))
+            .pair();
+        let server = tokio::spawn(server);
+        let stream = QmpStreamTokio::open(socket).await.unwrap().negotiate().await.unwrap();
+        let (service, spin) = stream.into_spin();
+        let client = async move {
+            let mut status = Box::pin(service.execute(qapi_qmp::query_status { }));
+            // the server can't reply before this task yields
+            assert!(futures::poll!(status.as_mut()).is_pending());
+            assert_eq!(service.pending_count(), 1);
+            drop(status);
+            let pending = service.pending_count();
+            (pending, service.execute(qapi_qmp::query_version { }).await)
+        };
+        let ((pending, version), spin) = futures::join!(client, spin);
+        assert_eq!(pending, 0);
+        assert_eq!(version.unwrap().qemu.major, 7);
+        spin.unwrap();
+        assert_eq!(server.await.unwrap().unwrap().len(), 3);
+    }
+}
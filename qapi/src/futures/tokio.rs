@@ -2,22 +2,34 @@ use std::io;
 use std::pin::Pin;
 use std::task::{Context, Poll};
 use std::sync::Arc;
-use futures::Stream;
-#[cfg(any(feature = "qapi-qmp", feature = "qapi-qga"))]
-use futures::Sink;
+use futures::{Stream, Sink};
 use tokio::io::{AsyncRead, AsyncWrite, ReadHalf, WriteHalf, split};
 use tokio_util::codec::{Framed, FramedParts};
-use qapi_spec::{Response, Any};
+use qapi_spec::Response;
 #[cfg(any(feature = "qapi-qmp", feature = "qapi-qga"))]
 use qapi_spec::Execute;
 #[cfg(feature = "qapi-qmp")]
-use qapi_qmp::{QmpMessageAny, QmpCommand, QapiCapabilities, QMPCapability};
+use qapi_spec::ExecuteOob;
+#[cfg(any(feature = "qapi-qmp", feature = "qapi-qga"))]
+use qapi_spec::ExecuteRaw;
+#[cfg(feature = "qapi-qmp")]
+use qapi_qmp::{QmpMessage, QmpCommand, QapiCapabilities, QMPCapability};
 #[cfg(feature = "qapi-qmp")]
 use super::QmpStreamNegotiation;
-use super::{codec::JsonLinesCodec, QapiEvents, QapiService, QapiStream, QapiShared};
+use super::{codec::{JsonLinesCodec, Framing, DEFAULT_MAX_LINE_LENGTH}, CloseSink, ReadBuffer, QapiEvents, QapiService, QapiStream, QapiShared, RawReturn, WireLog};
+#[cfg(all(unix, feature = "qapi-qmp", feature = "async-tokio-net"))]
+use std::os::unix::io::{AsRawFd, RawFd};
+#[cfg(all(unix, feature = "qapi-qmp", feature = "async-tokio-net"))]
+use super::SendFd;
+#[cfg(any(feature = "qapi-qmp", feature = "qapi-qga"))]
+use super::Serialized;
+#[cfg(all(windows, feature = "async-tokio-net", feature = "async-tokio-time"))]
+use tokio::net::windows::named_pipe::{ClientOptions, NamedPipeClient};
+#[cfg(all(windows, feature = "async-tokio-net", feature = "async-tokio-time"))]
+use std::ffi::OsStr;
 
 pub struct QgaStreamTokio<S> {
-    stream: Framed<S, JsonLinesCodec<Response<Any>>>
+    stream: Framed<S, JsonLinesCodec<Response<RawReturn>>>
 }
 
 impl<S> QgaStreamTokio<S> {
@@ -27,22 +39,50 @@ impl<S> QgaStreamTokio<S> {
         }
     }
 
-    fn pair<W>(self, write: W) -> QapiStream<Self, W> {
-        let shared = Arc::new(QapiShared::new(false));
+    fn with_max_length(stream: S, max_length: usize) -> Self {
+        Self {
+            stream: Framed::from_parts(FramedParts::new::<()>(stream, JsonLinesCodec::new_with_max_length(max_length))),
+        }
+    }
+
+    fn pair<W>(mut self, write: W) -> QapiStream<Self, W> {
+        let wire_log = Arc::new(WireLog::default());
+        self.stream.codec_mut().set_wire_log(wire_log.clone());
+        let shared = Arc::new(QapiShared::new(wire_log));
+        self.stream.codec_mut().set_lenient(shared.lenient.clone());
+        self.stream.codec_mut().set_syncing(shared.syncing.clone());
         let events = QapiEvents {
             stream: self,
             shared: shared.clone(),
+            idle: Default::default(),
         };
         let service = QapiService::new(write, shared);
-        QapiStream {
-            service,
-            events,
-        }
+        QapiStream::with_parts(service, events)
     }
 
     pub fn open_split<W>(read: S, write: W) -> QapiStream<Self, QgaStreamTokio<W>> {
-        let r = Self::new(read);
-        let w = QgaStreamTokio::new(write);
+        Self::open_split_with_max_length(read, write, DEFAULT_MAX_LINE_LENGTH)
+    }
+
+    /// Like `open_split`, but fails the stream when an incoming message exceeds `max_length` bytes.
+    pub fn open_split_with_max_length<W>(read: S, write: W, max_length: usize) -> QapiStream<Self, QgaStreamTokio<W>> {
+        Self::open_split_with(read, write, max_length, None)
+    }
+
+    /// Like `open_split`, but messages are framed by `framing` rather than separated by
+    /// newlines, see [`Framing`].
+    pub fn open_split_framed<W, F: Framing + Clone + 'static>(read: S, write: W, framing: F) -> QapiStream<Self, QgaStreamTokio<W>> {
+        Self::open_split_with(read, write, DEFAULT_MAX_LINE_LENGTH, Some((Box::new(framing.clone()), Box::new(framing))))
+    }
+
+    /// Opens the stream with a framing for each half, if not separated by newlines.
+    pub(super) fn open_split_with<W>(read: S, write: W, max_length: usize, framing: Option<(Box<dyn Framing>, Box<dyn Framing>)>) -> QapiStream<Self, QgaStreamTokio<W>> {
+        let mut r = Self::with_max_length(read, max_length);
+        let mut w = QgaStreamTokio::new(write);
+        if let Some((read_framing, write_framing)) = framing {
+            r.stream.codec_mut().set_framing(read_framing);
+            w.stream.codec_mut().set_framing(write_framing);
+        }
 
         r.pair(w)
     }
@@ -58,28 +98,125 @@ impl<R> QgaStreamTokio<ReadHalf<R>> {
 
         r.pair(w)
     }
+
+    /// Like `open`, but messages are framed by `framing`, see [`Framing`].
+    pub fn open_framed<F: Framing + Clone + 'static>(stream: R, framing: F) -> QapiStream<Self, QgaStreamTokio<WriteHalf<R>>> where
+        R: AsyncRead + AsyncWrite,
+    {
+        let (r, w) = split(stream);
+        Self::open_split_framed(r, w, framing)
+    }
+
+    /// Like `open`, but also synchronizes with the guest agent, failing with `TimedOut` if
+    /// it doesn't respond within `timeout`.
+    ///
+    /// Unlike QEMU, the guest agent doesn't greet new connections, so a `guest-sync` handshake
+    /// is the only way to tell that it's listening.
+    #[cfg(all(feature = "qapi-qga", feature = "async-tokio-time"))]
+    pub async fn open_timeout(stream: R, timeout: std::time::Duration) -> io::Result<QapiStream<Self, QgaStreamTokio<WriteHalf<R>>>> where
+        R: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut stream = Self::open(stream);
+        let handshake = stream.service.handshake();
+        match tokio::time::timeout(timeout, stream.drive(handshake)).await {
+            Ok(res) => res.map_err(io::Error::from)?,
+            Err(_) => return Err(io::Error::new(io::ErrorKind::TimedOut, "timed out waiting for the QGA handshake")),
+        }
+
+        Ok(stream)
+    }
+}
+
+#[cfg(all(unix, feature = "async-tokio-net"))]
+pub(super) async fn connect_uds(socket_addr: &std::path::Path) -> io::Result<tokio::net::UnixStream> {
+    tokio::net::UnixStream::connect(socket_addr).await.map_err(|e| match e.kind() {
+        io::ErrorKind::ConnectionRefused | io::ErrorKind::NotFound =>
+            io::Error::new(e.kind(), format!("QAPI socket {} is not listening: {}", socket_addr.display(), e)),
+        _ => e,
+    })
+}
+
+#[cfg(all(unix, feature = "async-tokio-net", feature = "async-tokio-time"))]
+async fn connect_uds_timeout(socket_addr: &std::path::Path, timeout: std::time::Duration) -> io::Result<tokio::net::UnixStream> {
+    match tokio::time::timeout(timeout, connect_uds(socket_addr)).await {
+        Ok(res) => res,
+        Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, format!("timed out connecting to QAPI socket {}", socket_addr.display()))),
+    }
 }
 
 #[cfg(all(unix, feature = "async-tokio-net"))]
 impl QgaStreamTokio<ReadHalf<tokio::net::UnixStream>> {
     pub async fn open_uds<P: AsRef<std::path::Path>>(socket_addr: P) -> io::Result<QapiStream<Self, QgaStreamTokio<WriteHalf<tokio::net::UnixStream>>>> {
-        let socket = tokio::net::UnixStream::connect(socket_addr).await?;
+        let socket = connect_uds(socket_addr.as_ref()).await?;
+        let (r, w) = split(socket);
+        Ok(Self::open_split(r, w))
+    }
+
+    /// Like `open_uds`, but fails with `TimedOut` if the socket can't be connected within `timeout`.
+    #[cfg(feature = "async-tokio-time")]
+    pub async fn open_uds_timeout<P: AsRef<std::path::Path>>(socket_addr: P, timeout: std::time::Duration) -> io::Result<QapiStream<Self, QgaStreamTokio<WriteHalf<tokio::net::UnixStream>>>> {
+        let socket = connect_uds_timeout(socket_addr.as_ref(), timeout).await?;
         let (r, w) = split(socket);
         Ok(Self::open_split(r, w))
     }
 }
 
+/// Returned when every instance of a named pipe is busy with another client.
+#[cfg(all(windows, feature = "async-tokio-net", feature = "async-tokio-time"))]
+const ERROR_PIPE_BUSY: i32 = 231;
+
+/// How long to wait before retrying a busy named pipe.
+#[cfg(all(windows, feature = "async-tokio-net", feature = "async-tokio-time"))]
+const PIPE_BUSY_RETRY: std::time::Duration = std::time::Duration::from_millis(50);
+
+/// Connects to a Windows named pipe, waiting for as long as it is busy.
+#[cfg(all(windows, feature = "async-tokio-net", feature = "async-tokio-time"))]
+pub(super) async fn connect_named_pipe(name: &OsStr) -> io::Result<NamedPipeClient> {
+    loop {
+        match ClientOptions::new().open(name) {
+            Err(e) if e.raw_os_error() == Some(ERROR_PIPE_BUSY) => (),
+            Err(e) if e.kind() == io::ErrorKind::NotFound =>
+                return Err(io::Error::new(e.kind(), format!("QAPI named pipe {} is not listening: {}", name.to_string_lossy(), e))),
+            res => return res,
+        }
+        tokio::time::sleep(PIPE_BUSY_RETRY).await;
+    }
+}
+
+#[cfg(all(windows, feature = "async-tokio-net", feature = "async-tokio-time"))]
+impl QgaStreamTokio<ReadHalf<NamedPipeClient>> {
+    /// Connects to a named pipe such as `\\.\pipe\qga`, waiting while it is busy with another client.
+    pub async fn open_named_pipe<P: AsRef<OsStr>>(name: P) -> io::Result<QapiStream<Self, QgaStreamTokio<WriteHalf<NamedPipeClient>>>> {
+        let pipe = connect_named_pipe(name.as_ref()).await?;
+        let (r, w) = split(pipe);
+        Ok(Self::open_split(r, w))
+    }
+}
+
+#[cfg(feature = "async-tokio-net")]
+pub(super) async fn connect_tcp<A: tokio::net::ToSocketAddrs>(socket_addr: A, nodelay: bool) -> io::Result<tokio::net::TcpStream> {
+    let socket = tokio::net::TcpStream::connect(socket_addr).await?;
+    socket.set_nodelay(nodelay)?;
+    Ok(socket)
+}
+
 #[cfg(feature = "async-tokio-net")]
 impl QgaStreamTokio<ReadHalf<tokio::net::TcpStream>> {
+    /// Connects over TCP with `TCP_NODELAY` enabled.
     pub async fn open_tcp<A: tokio::net::ToSocketAddrs>(socket_addr: A) -> io::Result<QapiStream<Self, QgaStreamTokio<WriteHalf<tokio::net::TcpStream>>>> {
-        let socket = tokio::net::TcpStream::connect(socket_addr).await?;
+        Self::open_tcp_nodelay(socket_addr, true).await
+    }
+
+    /// Like `open_tcp`, but chooses whether `TCP_NODELAY` is enabled.
+    pub async fn open_tcp_nodelay<A: tokio::net::ToSocketAddrs>(socket_addr: A, nodelay: bool) -> io::Result<QapiStream<Self, QgaStreamTokio<WriteHalf<tokio::net::TcpStream>>>> {
+        let socket = connect_tcp(socket_addr, nodelay).await?;
         let (r, w) = split(socket);
         Ok(Self::open_split(r, w))
     }
 }
 
 impl<S> QgaStreamTokio<S> {
-    fn stream(self: Pin<&mut Self>) -> Pin<&mut Framed<S, JsonLinesCodec<Response<Any>>>> {
+    fn stream(self: Pin<&mut Self>) -> Pin<&mut Framed<S, JsonLinesCodec<Response<RawReturn>>>> {
         unsafe {
             self.map_unchecked_mut(|this| &mut this.stream)
         }
@@ -87,42 +224,75 @@ impl<S> QgaStreamTokio<S> {
 }
 
 impl<S: AsyncRead> Stream for QgaStreamTokio<S> {
-    type Item = io::Result<Response<Any>>;
+    type Item = io::Result<Response<RawReturn>>;
 
     fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
         self.stream().poll_next(cx)
     }
 }
 
+impl<S> ReadBuffer for QgaStreamTokio<S> {
+    fn reserve_read(&mut self, capacity: usize) {
+        self.stream.read_buffer_mut().reserve(capacity)
+    }
+}
+
+impl<S: AsyncWrite> CloseSink for QgaStreamTokio<S> {
+    fn poll_close_sink(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        Sink::<()>::poll_close(self.stream(), cx)
+    }
+}
+
 #[cfg(feature = "qapi-qga")]
-impl<S: AsyncWrite, C: qapi_qga::QgaCommand, I: serde::Serialize> Sink<Execute<C, I>> for QgaStreamTokio<S> {
+impl<S: AsyncWrite, C: qapi_qga::QgaCommand, I: serde::Serialize> Sink<Serialized<Execute<C, I>>> for QgaStreamTokio<S> {
     type Error = io::Error;
 
-    fn start_send(self: Pin<&mut Self>, item: Execute<C, I>) -> Result<(), Self::Error> {
+    fn start_send(self: Pin<&mut Self>, item: Serialized<Execute<C, I>>) -> Result<(), Self::Error> {
         self.stream().start_send(item)
     }
 
     fn poll_ready(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
-        Sink::<Execute<C, I>>::poll_ready(self.stream(), cx)
+        Sink::<Serialized<Execute<C, I>>>::poll_ready(self.stream(), cx)
     }
 
     fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
-        Sink::<Execute<C, I>>::poll_flush(self.stream(), cx)
+        Sink::<Serialized<Execute<C, I>>>::poll_flush(self.stream(), cx)
     }
 
     fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
-        Sink::<Execute<C, I>>::poll_close(self.stream(), cx)
+        Sink::<Serialized<Execute<C, I>>>::poll_close(self.stream(), cx)
+    }
+}
+
+#[cfg(feature = "qapi-qga")]
+impl<S: AsyncWrite, I: serde::Serialize> Sink<Serialized<ExecuteRaw<I>>> for QgaStreamTokio<S> {
+    type Error = io::Error;
+
+    fn start_send(self: Pin<&mut Self>, item: Serialized<ExecuteRaw<I>>) -> Result<(), Self::Error> {
+        self.stream().start_send(item)
+    }
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        Sink::<Serialized<ExecuteRaw<I>>>::poll_ready(self.stream(), cx)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        Sink::<Serialized<ExecuteRaw<I>>>::poll_flush(self.stream(), cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        Sink::<Serialized<ExecuteRaw<I>>>::poll_close(self.stream(), cx)
     }
 }
 
 #[cfg(feature = "qapi-qmp")]
 pub struct QmpStreamTokio<S> {
-    stream: Framed<S, JsonLinesCodec<QmpMessageAny>>,
+    stream: Framed<S, JsonLinesCodec<QmpMessage<RawReturn>>>,
 }
 
 #[cfg(feature = "qapi-qmp")]
 impl<S> QmpStreamTokio<S> {
-    fn stream(self: Pin<&mut Self>) -> Pin<&mut Framed<S, JsonLinesCodec<QmpMessageAny>>> {
+    fn stream(self: Pin<&mut Self>) -> Pin<&mut Framed<S, JsonLinesCodec<QmpMessage<RawReturn>>>> {
         unsafe {
             self.map_unchecked_mut(|this| &mut this.stream)
         }
@@ -131,7 +301,7 @@ impl<S> QmpStreamTokio<S> {
 
 #[cfg(feature = "qapi-qmp")]
 impl<S: AsyncRead> Stream for QmpStreamTokio<S> {
-    type Item = io::Result<QmpMessageAny>;
+    type Item = io::Result<QmpMessage<RawReturn>>;
 
     fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
         self.stream().poll_next(cx)
@@ -139,23 +309,79 @@ impl<S: AsyncRead> Stream for QmpStreamTokio<S> {
 }
 
 #[cfg(feature = "qapi-qmp")]
-impl<S: AsyncWrite, C: QmpCommand, I: serde::Serialize> Sink<Execute<C, I>> for QmpStreamTokio<S> {
+impl<S> ReadBuffer for QmpStreamTokio<S> {
+    fn reserve_read(&mut self, capacity: usize) {
+        self.stream.read_buffer_mut().reserve(capacity)
+    }
+}
+
+#[cfg(feature = "qapi-qmp")]
+impl<S: AsyncWrite> CloseSink for QmpStreamTokio<S> {
+    fn poll_close_sink(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        Sink::<()>::poll_close(self.stream(), cx)
+    }
+}
+
+#[cfg(feature = "qapi-qmp")]
+impl<S: AsyncWrite, C: QmpCommand, I: serde::Serialize> Sink<Serialized<Execute<C, I>>> for QmpStreamTokio<S> {
+    type Error = io::Error;
+
+    fn start_send(self: Pin<&mut Self>, item: Serialized<Execute<C, I>>) -> Result<(), Self::Error> {
+        self.stream().start_send(item)
+    }
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        Sink::<Serialized<Execute<C, I>>>::poll_ready(self.stream(), cx)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        Sink::<Serialized<Execute<C, I>>>::poll_flush(self.stream(), cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        Sink::<Serialized<Execute<C, I>>>::poll_close(self.stream(), cx)
+    }
+}
+
+#[cfg(feature = "qapi-qmp")]
+impl<S: AsyncWrite, C: QmpCommand, I: serde::Serialize> Sink<Serialized<ExecuteOob<C, I>>> for QmpStreamTokio<S> {
+    type Error = io::Error;
+
+    fn start_send(self: Pin<&mut Self>, item: Serialized<ExecuteOob<C, I>>) -> Result<(), Self::Error> {
+        self.stream().start_send(item)
+    }
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        Sink::<Serialized<ExecuteOob<C, I>>>::poll_ready(self.stream(), cx)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        Sink::<Serialized<ExecuteOob<C, I>>>::poll_flush(self.stream(), cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        Sink::<Serialized<ExecuteOob<C, I>>>::poll_close(self.stream(), cx)
+    }
+}
+
+#[cfg(feature = "qapi-qmp")]
+impl<S: AsyncWrite, I: serde::Serialize> Sink<Serialized<ExecuteRaw<I>>> for QmpStreamTokio<S> {
     type Error = io::Error;
 
-    fn start_send(self: Pin<&mut Self>, item: Execute<C, I>) -> Result<(), Self::Error> {
+    fn start_send(self: Pin<&mut Self>, item: Serialized<ExecuteRaw<I>>) -> Result<(), Self::Error> {
         self.stream().start_send(item)
     }
 
     fn poll_ready(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
-        Sink::<Execute<C, I>>::poll_ready(self.stream(), cx)
+        Sink::<Serialized<ExecuteRaw<I>>>::poll_ready(self.stream(), cx)
     }
 
     fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
-        Sink::<Execute<C, I>>::poll_flush(self.stream(), cx)
+        Sink::<Serialized<ExecuteRaw<I>>>::poll_flush(self.stream(), cx)
     }
 
     fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
-        Sink::<Execute<C, I>>::poll_close(self.stream(), cx)
+        Sink::<Serialized<ExecuteRaw<I>>>::poll_close(self.stream(), cx)
     }
 }
 
@@ -163,41 +389,120 @@ impl<S: AsyncWrite, C: QmpCommand, I: serde::Serialize> Sink<Execute<C, I>> for
 impl<S> QmpStreamTokio<S> {
     pub fn new(stream: S) -> Self {
         Self {
-            stream: Framed::from_parts(FramedParts::new::<()>(stream, JsonLinesCodec::<QmpMessageAny>::new())),
+            stream: Framed::from_parts(FramedParts::new::<()>(stream, JsonLinesCodec::<QmpMessage<RawReturn>>::new())),
         }
     }
 
     pub async fn open_split<W>(read: S, write: W) -> io::Result<QmpStreamNegotiation<Self, QmpStreamTokio<W>>> where
         S: AsyncRead + Unpin,
+    {
+        Self::open_split_with_max_length(read, write, DEFAULT_MAX_LINE_LENGTH).await
+    }
+
+    /// Like `open_split`, but fails with `TimedOut` if the QMP greeting doesn't arrive within `timeout`.
+    ///
+    /// A successful connection doesn't guarantee that QEMU is responsive, or even that the
+    /// socket belongs to QEMU at all.
+    #[cfg(feature = "async-tokio-time")]
+    pub async fn open_split_timeout<W>(read: S, write: W, timeout: std::time::Duration) -> io::Result<QmpStreamNegotiation<Self, QmpStreamTokio<W>>> where
+        S: AsyncRead + Unpin,
+    {
+        match tokio::time::timeout(timeout, Self::open_split(read, write)).await {
+            Ok(res) => res,
+            Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "timed out waiting for the QMP greeting")),
+        }
+    }
+
+    /// Like `open_split`, but fails the stream when an incoming message exceeds `max_length` bytes.
+    pub async fn open_split_with_max_length<W>(read: S, write: W, max_length: usize) -> io::Result<QmpStreamNegotiation<Self, QmpStreamTokio<W>>> where
+        S: AsyncRead + Unpin,
+    {
+        Self::open_split_with(read, write, max_length, None, Default::default()).await
+    }
+
+    /// Like `open_split`, but messages are framed by `framing` rather than separated by newlines.
+    ///
+    /// This adapts the stream to relays that frame QMP some other way, such as with a length
+    /// prefix. `framing` is cloned for each half of the stream, and only replaces the framing:
+    /// the greeting, negotiation and everything after are unchanged.
+    pub async fn open_split_framed<W, F: Framing + Clone + 'static>(read: S, write: W, framing: F) -> io::Result<QmpStreamNegotiation<Self, QmpStreamTokio<W>>> where
+        S: AsyncRead + Unpin,
+    {
+        Self::open_split_with(read, write, DEFAULT_MAX_LINE_LENGTH, Some((Box::new(framing.clone()), Box::new(framing))), Default::default()).await
+    }
+
+    /// Opens the stream with a framing for each half, if not separated by newlines.
+    ///
+    /// The greeting is reported to `wire_log` too, which is kept for the rest of the stream.
+    pub(super) async fn open_split_with<W>(read: S, write: W, max_length: usize, framing: Option<(Box<dyn Framing>, Box<dyn Framing>)>, wire_log: Arc<WireLog>) -> io::Result<QmpStreamNegotiation<Self, QmpStreamTokio<W>>> where
+        S: AsyncRead + Unpin,
+    {
+        let mut codec = JsonLinesCodec::new_with_max_length(max_length);
+        codec.set_wire_log(wire_log.clone());
+        let mut write = QmpStreamTokio::new(write);
+        if let Some((read_framing, write_framing)) = framing {
+            codec.set_framing(read_framing);
+            write.stream.codec_mut().set_framing(write_framing);
+        }
+        let (stream, capabilities) = Self::read_greeting(read, codec).await?;
+        Ok(Self::with_greeting(stream, write, capabilities, wire_log))
+    }
+
+    async fn read_greeting(read: S, codec: JsonLinesCodec<QapiCapabilities>) -> io::Result<(Framed<S, JsonLinesCodec<QmpMessage<RawReturn>>>, QapiCapabilities)> where
+        S: AsyncRead + Unpin,
     {
         use futures::StreamExt;
 
-        let mut lines = Framed::from_parts(FramedParts::new::<()>(read, JsonLinesCodec::<QapiCapabilities>::new()));
+        let mut lines = Framed::from_parts(FramedParts::new::<()>(read, codec.expecting("QMP greeting")));
 
         let capabilities = lines.next().await.ok_or_else(||
-            io::Error::new(io::ErrorKind::UnexpectedEof, "QMP greeting expected")
+            io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed before the QMP greeting")
         )??;
 
         let lines = lines.into_parts();
-        let mut read = FramedParts::new::<()>(lines.io, JsonLinesCodec::new());
+        let mut read = FramedParts::new::<()>(lines.io, lines.codec.decoding());
         read.read_buf = lines.read_buf;
-        let stream = Framed::from_parts(read);
+        Ok((Framed::from_parts(read), capabilities))
+    }
+
+    /// Builds the stream from halves whose QMP greeting has already been read by the caller,
+    /// such as after handling a preamble that a proxy prefixes the stream with.
+    ///
+    /// Pass `None` for `greeting` to read it from `read` as [`QmpStreamTokio::open_split`] does.
+    /// The stream fails when an incoming message exceeds `max_length` bytes, which is usually
+    /// [`DEFAULT_MAX_LINE_LENGTH`].
+    pub async fn from_parts<W>(read: S, write: W, greeting: Option<QapiCapabilities>, max_length: usize) -> io::Result<QmpStreamNegotiation<Self, QmpStreamTokio<W>>> where
+        S: AsyncRead + Unpin,
+    {
+        match greeting {
+            Some(capabilities) => {
+                let read = FramedParts::new::<()>(read, JsonLinesCodec::new_with_max_length(max_length));
+                Ok(Self::with_greeting(Framed::from_parts(read), QmpStreamTokio::new(write), capabilities, Default::default()))
+            },
+            None => Self::open_split_with(read, write, max_length, None, Default::default()).await,
+        }
+    }
+
+    fn with_greeting<W>(mut stream: Framed<S, JsonLinesCodec<QmpMessage<RawReturn>>>, write: QmpStreamTokio<W>, capabilities: QapiCapabilities, wire_log: Arc<WireLog>) -> QmpStreamNegotiation<Self, QmpStreamTokio<W>> {
+        stream.codec_mut().set_wire_log(wire_log.clone());
 
-        let supports_oob = capabilities.capabilities().any(|c| c == QMPCapability::oob);
-        let shared = Arc::new(QapiShared::new(supports_oob));
+        let shared = Arc::new(QapiShared::new(wire_log));
+        stream.codec_mut().set_skip_events(shared.skip_events.clone());
+        stream.codec_mut().set_lenient(shared.lenient.clone());
         let events = QapiEvents {
             stream: Self { stream },
             shared: shared.clone(),
+            idle: Default::default(),
         };
-        let service = QapiService::new(QmpStreamTokio::new(write), shared);
+        let service = QapiService::new(write, shared);
 
-        Ok(QmpStreamNegotiation {
-            stream: QapiStream {
-                service,
-                events,
-            },
+        let mut stream = QapiStream::with_parts(service, events);
+        stream.qmp.greeting = Some(capabilities.QMP.clone());
+
+        QmpStreamNegotiation {
+            stream,
             capabilities,
-        })
+        }
     }
 }
 
@@ -207,22 +512,1138 @@ impl<RW: AsyncRead + AsyncWrite> QmpStreamTokio<ReadHalf<RW>> {
         let (r, w) = split(stream);
         Self::open_split(r, w).await
     }
+
+    /// Like `open`, but fails with `TimedOut` if the QMP greeting doesn't arrive within `timeout`.
+    #[cfg(feature = "async-tokio-time")]
+    pub async fn open_timeout(stream: RW, timeout: std::time::Duration) -> io::Result<QmpStreamNegotiation<Self, QmpStreamTokio<WriteHalf<RW>>>> where RW: Unpin {
+        let (r, w) = split(stream);
+        Self::open_split_timeout(r, w, timeout).await
+    }
+
+    /// Like `open`, but messages are framed by `framing`, see [`QmpStreamTokio::open_split_framed`].
+    pub async fn open_framed<F: Framing + Clone + 'static>(stream: RW, framing: F) -> io::Result<QmpStreamNegotiation<Self, QmpStreamTokio<WriteHalf<RW>>>> where RW: Unpin {
+        let (r, w) = split(stream);
+        Self::open_split_framed(r, w, framing).await
+    }
+
+    /// Opens the stream and enables those of `caps` that QEMU advertises, see
+    /// [`QmpStreamNegotiation::negotiate_supported`].
+    pub async fn open_with_caps<C>(stream: RW, caps: C) -> io::Result<QapiStream<Self, QmpStreamTokio<WriteHalf<RW>>>> where
+        RW: Unpin,
+        C: IntoIterator<Item=QMPCapability>,
+    {
+        Self::open(stream).await?.negotiate_supported(caps).await
+    }
 }
 
 #[cfg(all(unix, feature = "qapi-qmp", feature = "async-tokio-net"))]
 impl QmpStreamTokio<ReadHalf<tokio::net::UnixStream>> {
     pub async fn open_uds<P: AsRef<std::path::Path>>(socket_addr: P) -> io::Result<QmpStreamNegotiation<Self, QmpStreamTokio<WriteHalf<tokio::net::UnixStream>>>> {
-        let socket = tokio::net::UnixStream::connect(socket_addr).await?;
+        let socket = connect_uds(socket_addr.as_ref()).await?;
         let (r, w) = split(socket);
         Self::open_split(r, w).await
     }
+
+    /// Like `open_uds`, but fails with `TimedOut` if the socket can't be connected within `timeout`.
+    ///
+    /// The timeout doesn't cover waiting for the QMP greeting, see [`QmpStreamTokio::open_split_timeout`].
+    #[cfg(feature = "async-tokio-time")]
+    pub async fn open_uds_timeout<P: AsRef<std::path::Path>>(socket_addr: P, timeout: std::time::Duration) -> io::Result<QmpStreamNegotiation<Self, QmpStreamTokio<WriteHalf<tokio::net::UnixStream>>>> {
+        let socket = connect_uds_timeout(socket_addr.as_ref(), timeout).await?;
+        let (r, w) = split(socket);
+        Self::open_split(r, w).await
+    }
+}
+
+#[cfg(all(unix, feature = "qapi-qmp", feature = "async-tokio-net"))]
+impl QmpStreamTokio<tokio::net::unix::OwnedReadHalf> {
+    /// Connects over a Unix socket that file descriptors can be passed over, see [`QapiService::execute_with_fd`].
+    pub async fn open_uds_fd<P: AsRef<std::path::Path>>(socket_addr: P) -> io::Result<QmpStreamNegotiation<Self, QmpStreamTokio<tokio::net::unix::OwnedWriteHalf>>> {
+        let socket = connect_uds(socket_addr.as_ref()).await?;
+        let (r, w) = socket.into_split();
+        Self::open_split(r, w).await
+    }
+}
+
+#[cfg(all(unix, feature = "qapi-qmp", feature = "async-tokio-net"))]
+impl SendFd for QmpStreamTokio<tokio::net::unix::OwnedWriteHalf> {
+    fn poll_send_fd(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8], fd: Option<RawFd>) -> Poll<io::Result<usize>> {
+        let socket: &tokio::net::UnixStream = self.stream.get_ref().as_ref();
+        loop {
+            futures::ready!(socket.poll_write_ready(cx))?;
+            match socket.try_io(tokio::io::Interest::WRITABLE, || crate::fd::send_with_fd(socket.as_raw_fd(), buf, fd)) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => (),
+                res => return Poll::Ready(res),
+            }
+        }
+    }
+}
+
+#[cfg(all(windows, feature = "qapi-qmp", feature = "async-tokio-net", feature = "async-tokio-time"))]
+impl QmpStreamTokio<ReadHalf<NamedPipeClient>> {
+    /// Connects to a named pipe such as `\\.\pipe\qmp`, which QEMU on Windows creates for `-qmp pipe:qmp`.
+    ///
+    /// A pipe that is busy with another client is retried until it becomes available, which
+    /// may be never, so callers may want to impose a timeout of their own.
+    pub async fn open_named_pipe<P: AsRef<OsStr>>(name: P) -> io::Result<QmpStreamNegotiation<Self, QmpStreamTokio<WriteHalf<NamedPipeClient>>>> {
+        let pipe = connect_named_pipe(name.as_ref()).await?;
+        let (r, w) = split(pipe);
+        Self::open_split(r, w).await
+    }
 }
 
 #[cfg(all(feature = "qapi-qmp", feature = "async-tokio-net"))]
 impl QmpStreamTokio<ReadHalf<tokio::net::TcpStream>> {
+    /// Connects over TCP with `TCP_NODELAY` enabled, since QMP round trips suffer from Nagle's algorithm.
     pub async fn open_tcp<A: tokio::net::ToSocketAddrs>(socket_addr: A) -> io::Result<QmpStreamNegotiation<Self, QmpStreamTokio<WriteHalf<tokio::net::TcpStream>>>> {
-        let socket = tokio::net::TcpStream::connect(socket_addr).await?;
+        Self::open_tcp_nodelay(socket_addr, true).await
+    }
+
+    /// Like `open_tcp`, but chooses whether `TCP_NODELAY` is enabled.
+    pub async fn open_tcp_nodelay<A: tokio::net::ToSocketAddrs>(socket_addr: A, nodelay: bool) -> io::Result<QmpStreamNegotiation<Self, QmpStreamTokio<WriteHalf<tokio::net::TcpStream>>>> {
+        let socket = connect_tcp(socket_addr, nodelay).await?;
         let (r, w) = split(socket);
         Self::open_split(r, w).await
     }
 }
+
+#[cfg(all(test, feature = "qapi-qmp"))]
+mod test {
+    use super::*;
+    use qapi_spec::Any;
+    use tokio::io::DuplexStream;
+    #[cfg(feature = "testing")]
+    use crate::futures::testing::MockQmpServer;
+
+    type Events = QapiEvents<QmpStreamTokio<ReadHalf<DuplexStream>>>;
+    type Service = QapiService<QmpStreamTokio<WriteHalf<DuplexStream>>>;
+
+    fn assert_spawnable<T: Send + 'static>(_: &T) { }
+
+    /// Streams can be driven by `tokio::spawn` without a `LocalSet`.
+    #[test]
+    fn futures_are_send() {
+        let _ = |events: Events| assert_spawnable(&events.into_future());
+        let _ = |events: Events| assert_spawnable(&events.run(|_| ()));
+        let _ = |events: Events| assert_spawnable(&events.spin_until(futures::future::pending::<()>()));
+        let _ = |service: &Service| assert_spawnable(&service.execute(qapi_qmp::stop { }));
+        #[cfg(feature = "async-tokio-time")]
+        let _ = |stream: QapiStream<QmpStreamTokio<ReadHalf<DuplexStream>>, QmpStreamTokio<WriteHalf<DuplexStream>>>|
+            assert_spawnable(&stream.shutdown(std::time::Duration::from_secs(1), |_| ()));
+    }
+
+    /// Events can be polled in place alongside other streams.
+    #[test]
+    fn events_are_streams() {
+        let _ = |a: Events, b: Events| futures::stream::select_all(vec![a, b]);
+        let _ = |events: &mut Events| drop(futures::StreamExt::next(events));
+    }
+
+    /// Only capabilities that were both advertised and accepted are reported as enabled, once each.
+    #[test]
+    fn negotiated_capabilities() {
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+        fn negotiate(advertised: &'static str) -> (Vec<QMPCapability>, bool, String) {
+            let (client, server) = tokio::io::duplex(0x1000);
+            let server = async move {
+                let (read, mut write) = split(server);
+                let mut lines = BufReader::new(read).lines();
+                write.write_all(format!("{{\"QMP\": {{\"version\": {{\"qemu\": {{\"major\": 7, \"minor\": 2, \"micro\": 0}}, \"package\": \"\"}}, \"capabilities\": [{}]}}}}\n", advertised).as_bytes()).await.unwrap();
+                let command = lines.next_line().await.unwrap().unwrap();
+                // accepts capabilities it didn't offer, unlike QEMU
+                write.write_all(b"{\"return\": {}}\n").await.unwrap();
+                (command, lines, write)
+            };
+            let client = async {
+                let stream = QmpStreamTokio::open(client).await.unwrap()
+                    .negotiate_caps(vec![QMPCapability::oob, QMPCapability::oob]).await.unwrap();
+                (stream.capabilities().to_vec(), stream.supports_oob())
+            };
+
+            let ((caps, oob), (command, ..)) = futures::executor::block_on(futures::future::join(client, server));
+            (caps, oob, command)
+        }
+
+        let (caps, oob, command) = negotiate("");
+        assert!(command.contains("\"enable\":[\"oob\",\"oob\"]"), "{}", command);
+        assert_eq!(caps, []);
+        assert!(!oob);
+
+        let (caps, oob, _) = negotiate("\"oob\"");
+        assert_eq!(caps, [QMPCapability::oob]);
+        assert!(oob);
+    }
+
+    /// Dropping the service ends the events while the peer is still connected, which then
+    /// release the state they share, whether they're polled directly or spun.
+    #[tokio::test]
+    async fn drop_service_stops_events() {
+        use futures::StreamExt;
+        use tokio::io::AsyncWriteExt;
+
+        async fn open() -> (QapiStream<QmpStreamTokio<ReadHalf<DuplexStream>>, QmpStreamTokio<WriteHalf<DuplexStream>>>, DuplexStream) {
+            let (client, mut server) = tokio::io::duplex(0x1000);
+            server.write_all(b"{\"QMP\": {\"version\": {\"qemu\": {\"major\": 7, \"minor\": 2, \"micro\": 0}, \"package\": \"\"}, \"capabilities\": []}}\n").await.unwrap();
+            (QmpStreamTokio::open(client).await.unwrap().stream, server)
+        }
+
+        let (stream, _server) = open().await;
+        let (service, mut events) = stream.into_parts();
+        let shared = Arc::downgrade(&events.shared);
+        assert!(futures::poll!(events.next()).is_pending());
+        drop(service);
+        assert!(events.next().await.is_none());
+        drop(events);
+        assert!(shared.upgrade().is_none());
+
+        let (stream, _server) = open().await;
+        let (service, spin) = stream.into_spin();
+        let mut spin = Box::pin(spin);
+        assert!(futures::poll!(spin.as_mut()).is_pending());
+        drop(service);
+        spin.await.unwrap();
+    }
+
+    /// Out-of-band responses reach the command with the matching id, in whatever order they arrive.
+    #[test]
+    fn concurrent_oob() {
+        use std::collections::HashSet;
+        use futures::future::{join_all, select, Either};
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+        const COUNT: usize = 2048;
+        const GROUP: usize = 8;
+
+        let (client, server) = tokio::io::duplex(0x10000);
+        let server = async move {
+            let (read, mut write) = split(server);
+            let mut lines = BufReader::new(read).lines();
+            let mut ids = HashSet::new();
+            let mut group = Vec::new();
+            write.write_all(b"{\"QMP\": {\"version\": {\"qemu\": {\"major\": 7, \"minor\": 2, \"micro\": 0}, \"package\": \"\"}, \"capabilities\": [\"oob\"]}}\n").await.unwrap();
+            while let Some(line) = lines.next_line().await.unwrap() {
+                let command: Any = serde_json::from_str(&line).unwrap();
+                assert!(ids.insert(command["id"].to_string()), "duplicate id in {}", line);
+                group.push(command);
+                // answers each group in reverse, after qmp_capabilities
+                if group.len() == GROUP || ids.len() == 1 {
+                    for command in group.drain(..).rev() {
+                        let mut reply = serde_json::to_vec(&serde_json::json!({ "return": command["arguments"], "id": command["id"] })).unwrap();
+                        reply.push(b'\n');
+                        write.write_all(&reply).await.unwrap();
+                    }
+                }
+            }
+            assert!(group.is_empty());
+            ids.len()
+        };
+        let client = async {
+            let stream = QmpStreamTokio::open(client).await.unwrap()
+                .negotiate_caps(Some(QMPCapability::oob)).await.unwrap();
+            let (service, events) = stream.into_parts();
+            let commands = join_all((0..COUNT).map(|n| {
+                let json = serde_json::to_vec(&serde_json::json!({ "exec-oob": "x-echo", "arguments": { "n": n } })).unwrap();
+                service.execute_serialized(&json)
+            }));
+            match select(Box::pin(commands), events).await {
+                Either::Left((res, _)) => res,
+                Either::Right((res, _)) => panic!("QMP stream ended early with {:?}", res),
+            }
+        };
+
+        let (results, ids) = futures::executor::block_on(futures::future::join(client, server));
+        assert_eq!(ids, COUNT + 1);
+        for (n, res) in results.into_iter().enumerate() {
+            assert_eq!(res.unwrap().unwrap(), serde_json::json!({ "n": n }));
+        }
+    }
+
+    /// Out-of-band commands too large to write at once are still written one after another,
+    /// rather than interleaved.
+    #[test]
+    fn oob_partial_writes() {
+        use futures::future::{join_all, select, Either};
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+        const COUNT: usize = 16;
+
+        // far smaller than each command, so that every one takes many writes
+        let (client, server) = tokio::io::duplex(64);
+        let server = async move {
+            let (read, mut write) = split(server);
+            let mut lines = BufReader::new(read).lines();
+            let mut count = 0;
+            write.write_all(b"{\"QMP\": {\"version\": {\"qemu\": {\"major\": 7, \"minor\": 2, \"micro\": 0}, \"package\": \"\"}, \"capabilities\": [\"oob\"]}}\n").await.unwrap();
+            while let Some(line) = lines.next_line().await.unwrap() {
+                let command: Any = serde_json::from_str(&line).unwrap_or_else(|e| panic!("{} in {:?}", e, line));
+                let mut reply = serde_json::to_vec(&serde_json::json!({ "return": command["arguments"], "id": command["id"] })).unwrap();
+                reply.push(b'\n');
+                write.write_all(&reply).await.unwrap();
+                count += 1;
+            }
+            count
+        };
+        let client = async {
+            let stream = QmpStreamTokio::open(client).await.unwrap()
+                .negotiate_caps(Some(QMPCapability::oob)).await.unwrap();
+            let (service, events) = stream.into_parts();
+            let commands = join_all((0..COUNT).map(|n| {
+                let fill = std::iter::repeat(char::from(b'a' + n as u8)).take(0x1000).collect::<String>();
+                let json = serde_json::to_vec(&serde_json::json!({ "exec-oob": "x-echo", "arguments": { "n": n, "fill": fill } })).unwrap();
+                service.execute_serialized(&json)
+            }));
+            match select(Box::pin(commands), events).await {
+                Either::Left((res, _)) => res,
+                Either::Right((res, _)) => panic!("QMP stream ended early with {:?}", res),
+            }
+        };
+
+        let (results, count) = futures::executor::block_on(futures::future::join(client, server));
+        assert_eq!(count, COUNT + 1);
+        for (n, res) in results.into_iter().enumerate() {
+            assert_eq!(res.unwrap().unwrap()["n"], n);
+        }
+    }
+
+    /// A client waits while the pipe is busy with another, and connects once the server
+    /// creates another instance.
+    #[cfg(all(windows, feature = "async-tokio-net", feature = "async-tokio-time"))]
+    #[tokio::test]
+    async fn open_named_pipe() {
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+        use tokio::net::windows::named_pipe::{NamedPipeServer, ServerOptions};
+
+        async fn serve(pipe: NamedPipeServer) -> Vec<String> {
+            pipe.connect().await.unwrap();
+            let (read, mut write) = split(pipe);
+            let mut lines = BufReader::new(read).lines();
+            let mut commands = Vec::new();
+            write.write_all(b"{\"QMP\": {\"version\": {\"qemu\": {\"major\": 7, \"minor\": 2, \"micro\": 0}, \"package\": \"\"}, \"capabilities\": []}}\n").await.unwrap();
+            while let Some(line) = lines.next_line().await.unwrap() {
+                let command: Any = serde_json::from_str(&line).unwrap();
+                commands.push(command["execute"].as_str().unwrap().to_owned());
+                write.write_all(b"{\"return\": {}}\n").await.unwrap();
+            }
+            commands
+        }
+
+        let name = format!(r"\\.\pipe\qapi-rs-test-{}", std::process::id());
+        assert_eq!(QmpStreamTokio::open_named_pipe(&name).await.err().map(|e| e.kind()), Some(io::ErrorKind::NotFound));
+
+        let first = ServerOptions::new().first_pipe_instance(true).create(&name).unwrap();
+        let first = tokio::spawn(serve(first));
+        let mut busy = QmpStreamTokio::open_named_pipe(&name).await.unwrap().negotiate().await.unwrap();
+
+        let second = {
+            let name = name.clone();
+            tokio::spawn(async move {
+                let mut stream = QmpStreamTokio::open_named_pipe(&name).await.unwrap().negotiate().await.unwrap();
+                stream.execute(qapi_qmp::cont { }).await.unwrap();
+            })
+        };
+        tokio::time::sleep(PIPE_BUSY_RETRY * 4).await;
+        assert!(!second.is_finished());
+        let server = tokio::spawn(serve(ServerOptions::new().create(&name).unwrap()));
+        second.await.unwrap();
+        assert_eq!(server.await.unwrap(), ["qmp_capabilities", "cont"]);
+
+        busy.execute(qapi_qmp::stop { }).await.unwrap();
+        drop(busy);
+        assert_eq!(first.await.unwrap(), ["qmp_capabilities", "stop"]);
+    }
+
+    /// Responses that arrived while the stream wasn't being driven can still be routed before closing.
+    #[cfg(feature = "testing")]
+    #[tokio::test(start_paused = true)]
+    async fn drain_buffered_responses() {
+        let (socket, server) = MockQmpServer::new()
+            .reply_raw(<qapi_qmp::stop as qapi_spec::Command>::NAME, serde_json::json!({}))
+            .pair();
+        let server = tokio::spawn(server);
+        let mut stream = QmpStreamTokio::open(socket).await.unwrap().negotiate().await.unwrap();
+        let stop = stream.service.execute(qapi_qmp::stop { });
+        futures::pin_mut!(stop);
+        assert!(futures::poll!(stop.as_mut()).is_pending());
+        // paused time only advances once the server is idle, having replied
+        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+        stream.events.drain_buffered().unwrap();
+        let res = futures::poll!(stop);
+        stream.close().await.unwrap();
+
+        assert!(matches!(res, std::task::Poll::Ready(Ok(_))));
+        assert_eq!(server.await.unwrap().unwrap().len(), 2);
+    }
+
+    /// Ids still in use are skipped when generating new ones, and can't be used twice.
+    #[test]
+    fn command_id_in_use() {
+        use std::sync::atomic::Ordering;
+
+        let shared = QapiShared::new(Default::default());
+        let _pending = shared.command_insert(1).unwrap();
+        assert_eq!(shared.command_insert(1).unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+
+        shared.id_counter.store(1, Ordering::Relaxed);
+        assert_eq!(shared.next_id(), 2);
+    }
+
+    /// Commands beyond the limit wait their turn, and only the next in line is woken.
+    #[test]
+    fn max_inflight() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use futures::Future;
+        use futures::task::{waker, ArcWake};
+
+        struct CountWakes(AtomicUsize);
+
+        impl ArcWake for CountWakes {
+            fn wake_by_ref(arc_self: &Arc<Self>) {
+                arc_self.0.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        let shared = QapiShared::new(Default::default());
+        shared.inflight.lock().unwrap().max = Some(1);
+        let wakes: Vec<_> = (0..3).map(|_| Arc::new(CountWakes(AtomicUsize::new(0)))).collect();
+        let wakers: Vec<_> = wakes.iter().map(|wakes| waker(wakes.clone())).collect();
+        let mut acquire: Vec<_> = (0..3).map(|_| shared.command_permit()).collect();
+        let mut poll = |n: usize| Pin::new(&mut acquire[n]).poll(&mut Context::from_waker(&wakers[n]));
+
+        let first = match poll(0) {
+            Poll::Ready(permit) => permit,
+            Poll::Pending => panic!("the first command should not wait"),
+        };
+        assert!(poll(1).is_pending());
+        assert!(poll(2).is_pending());
+        assert!(poll(1).is_pending());
+        assert_eq!(shared.inflight.lock().unwrap().waiters.len(), 2);
+
+        drop(first);
+        assert_eq!((wakes[1].0.load(Ordering::Relaxed), wakes[2].0.load(Ordering::Relaxed)), (1, 0));
+        assert!(poll(2).is_pending());
+        // a command dropped after being granted a permit passes it on
+        drop(acquire.remove(1));
+        assert_eq!(wakes[2].0.load(Ordering::Relaxed), 1);
+        let third = Pin::new(&mut acquire[1]).poll(&mut Context::from_waker(&wakers[2]));
+        assert!(third.is_ready());
+        assert_eq!(shared.inflight.lock().unwrap().count, 1);
+        drop(third);
+        assert_eq!(shared.inflight.lock().unwrap().count, 0);
+    }
+
+    /// A replay stream that stops being polled is dropped, rather than buffering events forever.
+    #[test]
+    fn replay_drops_lagging_consumer() {
+        use futures::StreamExt;
+        use crate::futures::{QapiEventHistory, REPLAY_BUFFER};
+
+        let event: qapi_qmp::Event = serde_json::from_value(serde_json::json!({
+            "event": "STOP",
+            "timestamp": { "seconds": 0, "microseconds": 0 },
+        })).unwrap();
+        let mut history = QapiEventHistory::default();
+        let replay = history.replay();
+        for _ in 0..REPLAY_BUFFER * 2 {
+            history.record(&event);
+        }
+        assert!(!history.is_recording());
+
+        let replayed = futures::executor::block_on(replay.count());
+        assert!(replayed > 0 && replayed < REPLAY_BUFFER * 2, "replayed {} events", replayed);
+    }
+
+    /// Messages framed by something other than newlines can span several lines.
+    #[test]
+    fn open_split_framed() {
+        use bytes::BytesMut;
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+        #[derive(Clone)]
+        struct NulFraming;
+
+        impl Framing for NulFraming {
+            fn decode(&mut self, buf: &mut BytesMut) -> io::Result<Option<String>> {
+                Ok(buf.iter().position(|&b| b == 0).map(|index| {
+                    let frame = buf.split_to(index + 1);
+                    String::from_utf8_lossy(&frame[..index]).into_owned()
+                }))
+            }
+
+            fn decode_eof(&mut self, buf: &mut BytesMut) -> io::Result<Option<String>> {
+                self.decode(buf)
+            }
+
+            fn encode(&mut self, message: String, buf: &mut BytesMut) -> io::Result<()> {
+                buf.extend_from_slice(message.as_bytes());
+                buf.extend_from_slice(b"\0");
+                Ok(())
+            }
+        }
+
+        let (client, server) = tokio::io::duplex(0x1000);
+        let server = async move {
+            let (read, mut write) = split(server);
+            let mut read = BufReader::new(read);
+            let mut commands = Vec::new();
+            write.write_all(b"{\"QMP\": {\"version\": {\"qemu\": {\"major\": 7, \"minor\": 2, \"micro\": 0}, \"package\": \"\"}, \"capabilities\": []}}\0").await.unwrap();
+            loop {
+                let mut command = Vec::new();
+                if read.read_until(0, &mut command).await.unwrap() == 0 {
+                    break commands
+                }
+                command.pop();
+                commands.push(serde_json::from_slice::<Any>(&command).unwrap()["execute"].clone());
+                write.write_all(b"{\n  \"event\": \"STOP\",\n  \"timestamp\": {\"seconds\": 0, \"microseconds\": 0}\n}\0").await.unwrap();
+                write.write_all(b"{\n  \"return\": {}\n}\0").await.unwrap();
+            }
+        };
+        let client = async {
+            let mut stream = QmpStreamTokio::open_framed(client, NulFraming).await.unwrap().negotiate().await.unwrap()
+                .with_history(4);
+            stream.execute(qapi_qmp::stop { }).await.unwrap();
+            assert!(matches!(stream.recent_events()[..], [qapi_qmp::Event::STOP { .. }, ..]));
+        };
+
+        let ((), commands) = futures::executor::block_on(futures::future::join(client, server));
+        assert_eq!(commands, vec!["qmp_capabilities", "stop"]);
+    }
+
+    /// A peer that answers negotiation with nonsense fails it, rather than panicking.
+    #[test]
+    fn negotiate_unexpected_message() {
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+        use crate::futures::{NegotiationError, ProtocolError};
+
+        let (client, server) = tokio::io::duplex(0x1000);
+        let server = async move {
+            let (read, mut write) = split(server);
+            let mut lines = BufReader::new(read).lines();
+            write.write_all(b"{\"QMP\": {\"version\": {\"qemu\": {\"major\": 7, \"minor\": 2, \"micro\": 0}, \"package\": \"\"}, \"capabilities\": []}}\n").await.unwrap();
+            lines.next_line().await.unwrap().unwrap();
+            write.write_all(b"{\"greeting\": true}\n").await.unwrap();
+            lines
+        };
+        let client = async {
+            QmpStreamTokio::open(client).await.unwrap().negotiate().await.err().unwrap()
+        };
+
+        let (e, _lines) = futures::executor::block_on(futures::future::join(client, server));
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        assert!(matches!(NegotiationError::from_io(&e), Some(NegotiationError::Unexpected(ProtocolError::Parse(..)))));
+    }
+
+    /// Events deferred during negotiation are yielded first, but not once the stream has stopped,
+    /// and are discarded along with any others when the events are driven as a `Future`.
+    #[test]
+    fn negotiate_deferred_events() {
+        use futures::StreamExt;
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+        let (client, server) = tokio::io::duplex(0x1000);
+        let server = async move {
+            let (read, mut write) = split(server);
+            let mut lines = BufReader::new(read).lines();
+            write.write_all(b"{\"QMP\": {\"version\": {\"qemu\": {\"major\": 7, \"minor\": 2, \"micro\": 0}, \"package\": \"\"}, \"capabilities\": []}}\n").await.unwrap();
+            lines.next_line().await.unwrap().unwrap();
+            for _ in 0..3 {
+                write.write_all(b"{\"event\": \"STOP\", \"timestamp\": {\"seconds\": 0, \"microseconds\": 0}}\n").await.unwrap();
+            }
+            write.write_all(b"{\"return\": {}}\n").await.unwrap();
+            (lines, write)
+        };
+        let client = async {
+            let stream = QmpStreamTokio::open(client).await.unwrap().negotiate().await.unwrap();
+            let (service, mut events) = stream.into_parts();
+            assert!(matches!(events.next().await, Some(Ok(qapi_qmp::Event::STOP { .. }))));
+            events.drain_buffered().unwrap();
+            assert!(service.shared.history.lock().unwrap().deferred.is_empty());
+
+            service.shared.history.lock().unwrap().deferred.push_back(serde_json::from_value(serde_json::json!({
+                "event": "STOP",
+                "timestamp": { "seconds": 0, "microseconds": 0 },
+            })).unwrap());
+            service.shared.stop();
+            assert!(events.next().await.is_none());
+        };
+
+        let ((), _server) = futures::executor::block_on(futures::future::join(client, server));
+    }
+
+    /// Only a successful response echoing the sync value ends the handshake.
+    #[cfg(feature = "qapi-qga")]
+    #[test]
+    fn guest_sync_discards_stale_output() {
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+        let (client, server) = tokio::io::duplex(0x1000);
+        let server = async move {
+            let (read, mut write) = split(server);
+            let mut lines = BufReader::new(read).lines();
+            let command: Any = serde_json::from_str(&lines.next_line().await.unwrap().unwrap()).unwrap();
+            let sync = &command["arguments"]["id"];
+            write.write_all(b"{\"return\": 1}\n{\"error\": {\"class\": \"GenericError\", \"desc\": \"stale\"}}\n\xffgarbage\n").await.unwrap();
+            write.write_all(format!("{{\"return\": {}}}\n", sync).as_bytes()).await.unwrap();
+            lines.next_line().await.unwrap()
+        };
+        let client = async {
+            let (service, spin) = QgaStreamTokio::open(client).into_spin();
+            let sync = service.guest_sync(2);
+            futures::pin_mut!(spin);
+            let res = match futures::future::select(Box::pin(sync), spin.as_mut()).await {
+                futures::future::Either::Left((res, _)) => res,
+                futures::future::Either::Right((res, _)) => panic!("QGA stream ended early with {:?}", res),
+            };
+            drop(service);
+            spin.await.unwrap();
+            res
+        };
+
+        let (res, rest) = futures::executor::block_on(futures::future::join(client, server));
+        res.unwrap();
+        assert_eq!(rest, None);
+    }
+
+    /// Peers that never greet or sync time out, while those that do are opened as usual.
+    #[cfg(feature = "async-tokio-time")]
+    #[tokio::test(start_paused = true)]
+    async fn open_timeout() {
+        use std::time::Duration;
+        use tokio::io::AsyncWriteExt;
+
+        let timeout = Duration::from_secs(5);
+        let start = tokio::time::Instant::now();
+        let (client, _silent) = tokio::io::duplex(0x1000);
+        let res = QmpStreamTokio::open_timeout(client, timeout).await;
+        assert_eq!(res.err().unwrap().kind(), io::ErrorKind::TimedOut);
+        assert_eq!(start.elapsed(), timeout);
+
+        let (client, server) = tokio::io::duplex(0x1000);
+        let server = async move {
+            let (read, mut write) = split(server);
+            write.write_all(b"{\"QMP\": {\"version\": {\"qemu\": {\"major\": 7, \"minor\": 2, \"micro\": 0}, \"package\": \"\"}, \"capabilities\": []}}\n").await.unwrap();
+            (read, write)
+        };
+        let (stream, _server) = futures::future::join(QmpStreamTokio::open_timeout(client, timeout), server).await;
+        assert_eq!(stream.unwrap().capabilities.QMP.version.qemu.major, 7);
+
+        #[cfg(feature = "qapi-qga")]
+        {
+            use tokio::io::{AsyncBufReadExt, BufReader};
+
+            let start = tokio::time::Instant::now();
+            let (client, _silent) = tokio::io::duplex(0x1000);
+            let res = QgaStreamTokio::open_timeout(client, timeout).await;
+            assert_eq!(res.err().unwrap().kind(), io::ErrorKind::TimedOut);
+            assert_eq!(start.elapsed(), timeout);
+
+            let (client, server) = tokio::io::duplex(0x1000);
+            let server = async move {
+                let (read, mut write) = split(server);
+                let mut lines = BufReader::new(read).lines();
+                let command: Any = serde_json::from_str(&lines.next_line().await.unwrap().unwrap()).unwrap();
+                write.write_all(format!("{{\"return\": {}}}\n", command["arguments"]["id"]).as_bytes()).await.unwrap();
+                (lines, write)
+            };
+            let (stream, _server) = futures::future::join(QgaStreamTokio::open_timeout(client, timeout), server).await;
+            stream.unwrap();
+        }
+    }
+
+    /// Events collected before the stream fails are kept, and responses are routed meanwhile.
+    #[cfg(feature = "async-tokio-time")]
+    #[tokio::test(start_paused = true)]
+    async fn collect_for_until_failure() {
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+        let (client, server) = tokio::io::duplex(0x1000);
+        let server = async move {
+            let (read, mut write) = split(server);
+            let mut lines = BufReader::new(read).lines();
+            write.write_all(b"{\"QMP\": {\"version\": {\"qemu\": {\"major\": 7, \"minor\": 2, \"micro\": 0}, \"package\": \"\"}, \"capabilities\": []}}\n").await.unwrap();
+            for _ in 0..2 {
+                lines.next_line().await.unwrap().unwrap();
+                write.write_all(b"{\"return\": {}}\n").await.unwrap();
+            }
+            write.write_all(b"{\"timestamp\": {\"seconds\": 1, \"microseconds\": 0}, \"event\": \"STOP\"}\nnonsense\n").await.unwrap();
+            (lines, write)
+        };
+        let client = async {
+            let stream = QmpStreamTokio::open(client).await.unwrap().negotiate().await.unwrap();
+            let (service, mut events) = stream.into_parts();
+            let start = tokio::time::Instant::now();
+            let (stop, collected) = futures::future::join(service.execute(qapi_qmp::stop { }), events.collect_for(std::time::Duration::from_secs(10))).await;
+            (stop, collected, start.elapsed())
+        };
+
+        let ((stop, collected, elapsed), _server) = futures::future::join(client, server).await;
+        stop.unwrap();
+        let collected = collected.unwrap();
+        assert_eq!(collected.len(), 1);
+        assert!(matches!(collected[0], qapi_qmp::Event::STOP { .. }));
+        assert!(elapsed < std::time::Duration::from_secs(10));
+    }
+
+    /// Pipelined commands fail with the error that stopped the stream.
+    #[test]
+    fn execute_pipelined_error_kind() {
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+        let (client, server) = tokio::io::duplex(0x1000);
+        let server = async move {
+            let (read, mut write) = split(server);
+            let mut lines = BufReader::new(read).lines();
+            write.write_all(b"{\"QMP\": {\"version\": {\"qemu\": {\"major\": 7, \"minor\": 2, \"micro\": 0}, \"package\": \"\"}, \"capabilities\": []}}\n").await.unwrap();
+            lines.next_line().await.unwrap().unwrap();
+            write.write_all(b"{\"return\": {}}\n").await.unwrap();
+            for _ in 0..2 {
+                lines.next_line().await.unwrap().unwrap();
+            }
+            write.write_all(b"nonsense\n").await.unwrap();
+            (lines, write)
+        };
+        let client = async {
+            let mut stream = QmpStreamTokio::open(client).await.unwrap().negotiate().await.unwrap();
+            stream.execute_pipelined(vec![qapi_qmp::stop { }, qapi_qmp::stop { }]).await
+        };
+
+        let (res, _server) = futures::executor::block_on(futures::future::join(client, server));
+        assert_eq!(res.len(), 2);
+        for res in res {
+            match res {
+                Err(crate::ExecuteError::Io(e)) => assert_eq!(e.kind(), io::ErrorKind::InvalidData),
+                res => panic!("unexpected result {:?}", res),
+            }
+        }
+    }
+
+    /// Responses with an id that no command was sent with are skipped, unless they're set to
+    /// fail the stream.
+    #[test]
+    fn unmatched_responses() {
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+        for &strict in &[false, true] {
+            let (client, server) = tokio::io::duplex(0x1000);
+            let server = async move {
+                let (read, mut write) = split(server);
+                let mut lines = BufReader::new(read).lines();
+                write.write_all(b"{\"QMP\": {\"version\": {\"qemu\": {\"major\": 7, \"minor\": 2, \"micro\": 0}, \"package\": \"\"}, \"capabilities\": [\"oob\"]}}\n").await.unwrap();
+                lines.next_line().await.unwrap().unwrap();
+                write.write_all(b"{\"return\": {}}\n").await.unwrap();
+                let command: Any = serde_json::from_str(&lines.next_line().await.unwrap().unwrap()).unwrap();
+                write.write_all(b"{\"return\": {}, \"id\": 999}\n").await.unwrap();
+                write.write_all(format!("{{\"return\": {{}}, \"id\": {}}}\n", command["id"]).as_bytes()).await.unwrap();
+                (lines, write)
+            };
+            let client = async {
+                let stream = QmpStreamTokio::open(client).await.unwrap()
+                    .negotiate_caps(Some(QMPCapability::oob)).await.unwrap();
+                stream.set_strict_responses(strict);
+                let (service, events) = stream.into_parts();
+                let stop = service.execute(qapi_qmp::stop { });
+                futures::pin_mut!(stop);
+                match futures::future::select(stop, events).await {
+                    futures::future::Either::Left((res, _)) => res.map(drop),
+                    futures::future::Either::Right((res, _)) => Err(res.unwrap_err().into()),
+                }
+            };
+
+            let (res, _server) = futures::executor::block_on(futures::future::join(client, server));
+            match res {
+                Ok(()) => assert!(!strict),
+                Err(crate::ExecuteError::Io(e)) => {
+                    assert!(strict);
+                    assert!(matches!(crate::futures::ProtocolError::from_io(&e), Some(crate::futures::ProtocolError::UnexpectedResponse(..))), "{:?}", e);
+                },
+                res => panic!("unexpected result {:?}", res),
+            }
+        }
+    }
+
+    /// Halves whose greeting was already read keep the caller's limit on message length.
+    #[test]
+    fn from_parts_max_length() {
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+        let (client, server) = tokio::io::duplex(0x1000);
+        let server = async move {
+            let (read, mut write) = split(server);
+            let mut lines = BufReader::new(read).lines();
+            lines.next_line().await.unwrap().unwrap();
+            write.write_all(b"{\"return\": {}}\n").await.unwrap();
+            lines.next_line().await.unwrap().unwrap();
+            write.write_all(format!("{{\"return\": \"{}\"}}\n", "x".repeat(64)).as_bytes()).await.unwrap();
+            (lines, write)
+        };
+        let client = async {
+            let greeting = serde_json::from_str("{\"QMP\": {\"version\": {\"qemu\": {\"major\": 7, \"minor\": 2, \"micro\": 0}, \"package\": \"\"}, \"capabilities\": []}}").unwrap();
+            let (read, write) = split(client);
+            let mut stream = QmpStreamTokio::from_parts(read, write, Some(greeting), 64).await.unwrap()
+                .negotiate().await.unwrap();
+            stream.execute_raw("x-long", Any::Null).await
+        };
+
+        let (res, _server) = futures::executor::block_on(futures::future::join(client, server));
+        let e = res.unwrap_err();
+        assert!(matches!(e.get_ref().and_then(|e| e.downcast_ref()), Some(crate::futures::ProtocolError::TooLong(64))), "{:?}", e);
+    }
+
+    /// A peer that goes quiet fails the stream and any pending commands, once nothing at all,
+    /// not even an event, has been received for the timeout.
+    #[cfg(feature = "async-tokio-time")]
+    #[tokio::test(start_paused = true)]
+    async fn idle_timeout() {
+        use std::time::Duration;
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+        let (client, server) = tokio::io::duplex(0x1000);
+        let server = async move {
+            let (read, mut write) = split(server);
+            let mut lines = BufReader::new(read).lines();
+            write.write_all(b"{\"QMP\": {\"version\": {\"qemu\": {\"major\": 7, \"minor\": 2, \"micro\": 0}, \"package\": \"\"}, \"capabilities\": []}}\n").await.unwrap();
+            lines.next_line().await.unwrap().unwrap();
+            write.write_all(b"{\"return\": {}}\n").await.unwrap();
+            // stop is never answered
+            lines.next_line().await.unwrap().unwrap();
+            tokio::time::sleep(Duration::from_secs(3)).await;
+            write.write_all(b"{\"timestamp\": {\"seconds\": 1, \"microseconds\": 0}, \"event\": \"STOP\"}\n").await.unwrap();
+            (lines, write)
+        };
+        let client = async {
+            let stream = QmpStreamTokio::open(client).await.unwrap().negotiate().await.unwrap()
+                .with_idle_timeout(Duration::from_secs(5));
+            let (service, events) = stream.into_parts();
+            let start = tokio::time::Instant::now();
+            let (stop, res) = futures::future::join(service.execute(qapi_qmp::stop { }), events).await;
+            (stop, res, start.elapsed())
+        };
+
+        let ((stop, res, elapsed), _server) = futures::future::join(client, server).await;
+        assert_eq!(res.unwrap_err().kind(), io::ErrorKind::TimedOut);
+        match stop {
+            Err(crate::ExecuteError::Io(e)) => assert_eq!(e.kind(), io::ErrorKind::TimedOut),
+            res => panic!("unexpected result {:?}", res),
+        }
+        assert!(elapsed >= Duration::from_secs(8), "timed out after {:?}", elapsed);
+    }
+
+    /// The guest's OS details are read from `guest-get-osinfo`, with missing fields left out.
+    #[cfg(feature = "qapi-qga")]
+    #[test]
+    fn guest_osinfo() {
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+        let (client, server) = tokio::io::duplex(0x1000);
+        let server = async move {
+            let (read, mut write) = split(server);
+            let mut lines = BufReader::new(read).lines();
+            let command = lines.next_line().await.unwrap().unwrap();
+            write.write_all(b"{\"return\": {\"id\": \"debian\", \"kernel-release\": \"6.1.0-13-amd64\", \"machine\": \"x86_64\"}}\n").await.unwrap();
+            command
+        };
+        let client = async {
+            let (service, spin) = QgaStreamTokio::open(client).into_spin();
+            let osinfo = service.guest_osinfo();
+            futures::pin_mut!(spin);
+            match futures::future::select(Box::pin(osinfo), spin.as_mut()).await {
+                futures::future::Either::Left((res, _)) => res,
+                futures::future::Either::Right((res, _)) => panic!("QGA stream ended early with {:?}", res),
+            }
+        };
+
+        let (osinfo, command) = futures::executor::block_on(futures::future::join(client, server));
+        let osinfo = osinfo.unwrap();
+        assert!(command.contains("\"guest-get-osinfo\""), "{}", command);
+        assert_eq!(osinfo.id.as_deref(), Some("debian"));
+        assert_eq!(osinfo.kernel_release.as_deref(), Some("6.1.0-13-amd64"));
+        assert_eq!(osinfo.pretty_name, None);
+    }
+
+    /// The future driving a guest agent connection ends when the agent hangs up, or when the
+    /// service is closed while the agent is still there.
+    #[cfg(feature = "qapi-qga")]
+    #[test]
+    fn spin_ends() {
+        futures::executor::block_on(async {
+            let (client, server) = tokio::io::duplex(0x1000);
+            let (_service, spin) = QgaStreamTokio::open(client).into_spin();
+            drop(server);
+            spin.await.unwrap();
+
+            let (client, _server) = tokio::io::duplex(0x1000);
+            let (service, spin) = QgaStreamTokio::open(client).into_spin();
+            let close = async {
+                service.close().await.unwrap();
+                service
+            };
+            let (service, res) = futures::future::join(close, spin).await;
+            res.unwrap();
+            assert!(!service.is_connected());
+        });
+    }
+
+    /// Filesystems are frozen around the scope and thawed after it, once the guest agent
+    /// confirms they aren't frozen already.
+    #[cfg(feature = "qapi-qga")]
+    #[test]
+    fn freeze_scope() {
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+        let (client, server) = tokio::io::duplex(0x1000);
+        let server = async move {
+            let (read, mut write) = split(server);
+            let mut lines = BufReader::new(read).lines();
+            let mut commands = Vec::new();
+            for reply in &["\"thawed\"", "2", "2", "\"frozen\""] {
+                let command: Any = serde_json::from_str(&lines.next_line().await.unwrap().unwrap()).unwrap();
+                commands.push(command["execute"].as_str().unwrap().to_owned());
+                write.write_all(format!("{{\"return\": {}}}\n", reply).as_bytes()).await.unwrap();
+            }
+            commands
+        };
+        let client = async {
+            let (service, spin) = QgaStreamTokio::open(client).into_spin();
+            let scopes = async move {
+                let scope = service.freeze_scope(|frozen| async move { frozen * 10 }).await;
+                let nested = service.freeze_scope(|_| async { unreachable!() }).await;
+                (scope, nested)
+            };
+            futures::pin_mut!(spin);
+            match futures::future::select(Box::pin(scopes), spin.as_mut()).await {
+                futures::future::Either::Left((res, _)) => res,
+                futures::future::Either::Right((res, _)) => panic!("QGA stream ended early with {:?}", res),
+            }
+        };
+
+        let ((scope, nested), commands) = futures::executor::block_on(futures::future::join(client, server));
+        let (res, thawed) = scope.unwrap();
+        assert_eq!((res, thawed.unwrap()), (20, 2));
+        assert!(nested.is_err());
+        assert_eq!(commands, ["guest-fsfreeze-status", "guest-fsfreeze-freeze", "guest-fsfreeze-thaw", "guest-fsfreeze-status"]);
+    }
+
+    /// Only vCPUs that need to change are set, and those the guest can't take offline aren't.
+    #[cfg(feature = "qapi-qga")]
+    #[test]
+    fn set_vcpu_online() {
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+        let (client, server) = tokio::io::duplex(0x1000);
+        let server = async move {
+            let (read, mut write) = split(server);
+            let mut lines = BufReader::new(read).lines();
+            let vcpus = r#"[{"logical-id": 0, "online": true, "can-offline": false}, {"logical-id": 1, "online": true, "can-offline": true}, {"logical-id": 2, "online": false}]"#;
+            let mut commands = Vec::new();
+            for reply in &[vcpus, vcpus, "1", vcpus] {
+                let command: Any = serde_json::from_str(&lines.next_line().await.unwrap().unwrap()).unwrap();
+                commands.push(command);
+                write.write_all(format!("{{\"return\": {}}}\n", reply).as_bytes()).await.unwrap();
+            }
+            commands
+        };
+        let client = async {
+            let (service, spin) = QgaStreamTokio::open(client).into_spin();
+            let vcpus = async move {
+                let fixed = service.set_vcpu_online(0, false).await;
+                let offline = service.set_vcpu_online(1, false).await;
+                let unchanged = service.set_vcpu_online(2, false).await;
+                (fixed, offline, unchanged)
+            };
+            futures::pin_mut!(spin);
+            match futures::future::select(Box::pin(vcpus), spin.as_mut()).await {
+                futures::future::Either::Left((res, _)) => res,
+                futures::future::Either::Right((res, _)) => panic!("QGA stream ended early with {:?}", res),
+            }
+        };
+
+        let ((fixed, offline, unchanged), commands) = futures::executor::block_on(futures::future::join(client, server));
+        match fixed {
+            Err(crate::ExecuteError::Io(e)) => assert_eq!(e.kind(), io::ErrorKind::InvalidInput),
+            res => panic!("unexpected result {:?}", res),
+        }
+        offline.unwrap();
+        unchanged.unwrap();
+        let names: Vec<_> = commands.iter().map(|command| command["execute"].as_str().unwrap()).collect();
+        assert_eq!(names, ["guest-get-vcpus", "guest-get-vcpus", "guest-set-vcpus", "guest-get-vcpus"]);
+        assert_eq!(commands[2]["arguments"], serde_json::json!({ "vcpus": [{ "logical-id": 1, "online": false }] }));
+    }
+
+    /// The guest agent going away counts as a successful shutdown, unlike an error response.
+    #[cfg(feature = "qapi-qga")]
+    #[test]
+    fn guest_shutdown() {
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+        for &fails in &[false, true] {
+            let (client, server) = tokio::io::duplex(0x1000);
+            let server = async move {
+                let (read, mut write) = split(server);
+                let mut lines = BufReader::new(read).lines();
+                let command = lines.next_line().await.unwrap().unwrap();
+                if fails {
+                    write.write_all(b"{\"error\": {\"class\": \"GenericError\", \"desc\": \"shutdown failed\"}}\n").await.unwrap();
+                }
+                command
+            };
+            let client = async {
+                let (service, spin) = QgaStreamTokio::open(client).into_spin();
+                futures::future::join(service.guest_shutdown(qapi_qga::GuestShutdownMode::Powerdown), spin).await.0
+            };
+
+            let (res, command) = futures::executor::block_on(futures::future::join(client, server));
+            assert!(command.contains("\"mode\":\"powerdown\""), "{}", command);
+            match res {
+                Ok(()) => assert!(!fails),
+                Err(crate::ExecuteError::Qapi(e)) => assert!(fails, "{:?}", e),
+                res => panic!("unexpected result {:?}", res),
+            }
+        }
+    }
+
+    /// A guest agent that stops answering pings ends the stream after the ping timeout.
+    #[cfg(all(feature = "qapi-qga", feature = "async-tokio-spawn", feature = "async-tokio-time"))]
+    #[tokio::test(start_paused = true)]
+    async fn keepalive() {
+        use std::time::Duration;
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+        let (client, server) = tokio::io::duplex(0x1000);
+        let server = tokio::spawn(async move {
+            let (read, mut write) = split(server);
+            let mut lines = BufReader::new(read).lines();
+            let mut pings = 0;
+            while let Some(line) = lines.next_line().await.unwrap() {
+                assert!(line.contains("\"guest-ping\""), "{}", line);
+                pings += 1;
+                // the guest hangs after the second ping
+                if pings <= 2 {
+                    write.write_all(b"{\"return\": {}}\n").await.unwrap();
+                }
+            }
+            pings
+        });
+        let start = tokio::time::Instant::now();
+        let stream = QgaStreamTokio::open(client).with_keepalive(Duration::from_secs(10), Duration::from_secs(1));
+        let (service, spin) = stream.into_spin();
+        // as if the guest agent had disconnected
+        spin.await.unwrap();
+
+        assert_eq!(start.elapsed(), Duration::from_secs(31));
+        assert!(!service.is_connected());
+        drop(service);
+        assert_eq!(server.await.unwrap(), 3);
+    }
+
+    /// Reads that return no data before EOF are retried after a growing delay.
+    #[cfg(all(feature = "qapi-qga", feature = "async-tokio-time"))]
+    #[tokio::test(start_paused = true)]
+    async fn guest_read_to_end_backs_off() {
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+        let (client, server) = tokio::io::duplex(0x1000);
+        let server = async move {
+            let (read, mut write) = split(server);
+            let mut lines = BufReader::new(read).lines();
+            let mut reads = Vec::new();
+            for reply in &["{\"count\": 0, \"buf-b64\": \"\", \"eof\": false}"; 3] {
+                reads.push(lines.next_line().await.unwrap().unwrap());
+                write.write_all(format!("{{\"return\": {}}}\n", reply).as_bytes()).await.unwrap();
+            }
+            reads.push(lines.next_line().await.unwrap().unwrap());
+            write.write_all(b"{\"return\": {\"count\": 2, \"buf-b64\": \"aGk=\", \"eof\": true}}\n").await.unwrap();
+            reads
+        };
+        let client = async {
+            let (service, spin) = QgaStreamTokio::open(client).into_spin();
+            let start = tokio::time::Instant::now();
+            let read = service.guest_read_to_end(7);
+            futures::pin_mut!(spin);
+            let res = match futures::future::select(Box::pin(read), spin.as_mut()).await {
+                futures::future::Either::Left((res, _)) => res,
+                futures::future::Either::Right((res, _)) => panic!("QGA stream ended early with {:?}", res),
+            };
+            (res, start.elapsed())
+        };
+
+        let ((res, elapsed), reads) = futures::future::join(client, server).await;
+        assert_eq!(res.unwrap(), b"hi");
+        assert_eq!(reads.len(), 4);
+        assert!(reads.iter().all(|read| read.contains("guest-file-read") && read.contains("\"handle\":7")));
+        // 1, 2 and 4ms between the four reads
+        assert!(elapsed >= std::time::Duration::from_millis(7), "retried after {:?}", elapsed);
+    }
+
+    /// The file descriptor arrives as `SCM_RIGHTS` alongside the command it belongs to.
+    #[cfg(all(unix, feature = "async-tokio-net"))]
+    #[tokio::test]
+    async fn execute_with_fd() {
+        use std::io::{Read, Write};
+        use std::os::unix::io::FromRawFd;
+
+        /// Reads a line from `socket`, along with any file descriptors that accompany it.
+        fn recv_line(socket: &std::os::unix::net::UnixStream) -> (String, Vec<RawFd>) {
+            let (mut line, mut fds) = (Vec::new(), Vec::new());
+            while !line.ends_with(b"\n") {
+                let mut byte = 0u8;
+                let mut iov = libc::iovec {
+                    iov_base: &mut byte as *mut u8 as *mut _,
+                    iov_len: 1,
+                };
+                let mut control = [0u64; 8];
+                let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+                msg.msg_iov = &mut iov;
+                msg.msg_iovlen = 1;
+                msg.msg_control = control.as_mut_ptr() as *mut _;
+                msg.msg_controllen = std::mem::size_of_val(&control) as _;
+                assert_eq!(unsafe { libc::recvmsg(socket.as_raw_fd(), &mut msg, 0) }, 1);
+                unsafe {
+                    let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+                    while !cmsg.is_null() {
+                        assert_eq!(((*cmsg).cmsg_level, (*cmsg).cmsg_type), (libc::SOL_SOCKET, libc::SCM_RIGHTS));
+                        fds.push(std::ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const RawFd));
+                        cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+                    }
+                }
+                line.push(byte);
+            }
+            (String::from_utf8(line).unwrap(), fds)
+        }
+
+        let (client, server) = std::os::unix::net::UnixStream::pair().unwrap();
+        let server = std::thread::spawn(move || {
+            let mut server = server;
+            server.write_all(b"{\"QMP\": {\"version\": {\"qemu\": {\"major\": 7, \"minor\": 2, \"micro\": 0}, \"package\": \"\"}, \"capabilities\": []}}\n").unwrap();
+            let mut commands = Vec::new();
+            for _ in 0..2 {
+                let (line, fds) = recv_line(&server);
+                for &fd in &fds {
+                    let mut file = unsafe { std::fs::File::from_raw_fd(fd) };
+                    file.write_all(b"passed").unwrap();
+                }
+                commands.push((serde_json::from_str::<Any>(&line).unwrap(), fds.len()));
+                server.write_all(b"{\"return\": {}}\n").unwrap();
+            }
+            commands
+        });
+
+        let mut pipe = [0; 2];
+        assert_eq!(unsafe { libc::pipe(pipe.as_mut_ptr()) }, 0);
+        let (mut read, write) = unsafe { (std::fs::File::from_raw_fd(pipe[0]), std::fs::File::from_raw_fd(pipe[1])) };
+
+        client.set_nonblocking(true).unwrap();
+        let (r, w) = tokio::net::UnixStream::from_std(client).unwrap().into_split();
+        let mut stream = QmpStreamTokio::open_split(r, w).await.unwrap().negotiate().await.unwrap();
+        stream.execute_with_fd(qapi_qmp::getfd { fdname: "fd0".into() }, write.as_raw_fd()).await.unwrap();
+        // the caller still owns its copy
+        drop(write);
+
+        let mut passed = String::new();
+        read.read_to_string(&mut passed).unwrap();
+        assert_eq!(passed, "passed");
+
+        let commands = server.join().unwrap();
+        assert_eq!(commands.iter().map(|(command, fds)| (command["execute"].as_str().unwrap(), *fds)).collect::<Vec<_>>(), [("qmp_capabilities", 0), ("getfd", 1)]);
+        assert_eq!(commands[1].0["arguments"]["fdname"], "fd0");
+    }
+}
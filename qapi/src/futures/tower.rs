@@ -4,12 +4,12 @@ use std::task::{Context, Poll};
 use futures::{Sink, Future, FutureExt};
 use tower_service::Service;
 use crate::{Command, Execute, ExecuteError};
-use super::QapiService;
+use super::{QapiService, Serialized};
 
 // this really doesn't work well for lifetime reasons?
 
 impl<W: 'static, C: Command + 'static> Service<C> for QapiService<W> where
-    W: Sink<Execute<C, u32>, Error=io::Error> + Unpin + Send,
+    W: Sink<Serialized<Execute<C, u64>>, Error=io::Error> + Unpin + Send,
 {
     type Response = C::Ok;
     type Error = ExecuteError;
@@ -6,7 +6,7 @@ pub use qapi_qmp as qmp;
 #[cfg(feature = "qapi-qga")]
 pub use qapi_qga as qga;
 
-pub use qapi_spec::{Any, Dictionary, Empty, Never, Execute, ExecuteOob, Command, CommandResult, Event, Enum, Error, ErrorClass, Timestamp};
+pub use qapi_spec::{Any, Dictionary, Empty, Never, Execute, ExecuteOob, ExecuteRaw, Command, OobCommand, CommandResult, Event, Enum, Error, ErrorClass, Timestamp};
 
 pub use self::stream::Stream;
 
@@ -21,6 +21,9 @@ use std::{error, fmt, io};
 #[cfg(feature = "async")]
 pub mod futures;
 
+#[cfg(all(unix, feature = "qapi-qmp"))]
+mod fd;
+
 #[derive(Debug)]
 pub enum ExecuteError {
     Qapi(Error),
@@ -29,6 +32,24 @@ pub enum ExecuteError {
 
 pub type ExecuteResult<C> = Result<<C as Command>::Ok, ExecuteError>;
 
+impl ExecuteError {
+    /// The class of the error returned by the command, if it failed with one.
+    pub fn class(&self) -> Option<ErrorClass> {
+        match self {
+            ExecuteError::Qapi(e) => Some(e.class),
+            ExecuteError::Io(..) => None,
+        }
+    }
+
+    /// The error returned by the command, for inspecting with helpers such as [`Error::is_device_not_found`].
+    pub fn qapi_error(&self) -> Option<&Error> {
+        match self {
+            ExecuteError::Qapi(e) => Some(e),
+            ExecuteError::Io(..) => None,
+        }
+    }
+}
+
 impl fmt::Display for ExecuteError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
@@ -47,6 +68,31 @@ impl error::Error for ExecuteError {
     }
 }
 
+/// Separates the error returned by a command from a failure to communicate.
+#[cfg(any(feature = "qapi-qmp", feature = "qapi-qga"))]
+pub(crate) fn raw_result<T>(res: Result<T, ExecuteError>) -> io::Result<Result<T, Error>> {
+    match res {
+        Ok(res) => Ok(Ok(res)),
+        Err(ExecuteError::Qapi(e)) => Ok(Err(e)),
+        Err(ExecuteError::Io(e)) => Err(e),
+    }
+}
+
+/// The error for a message that isn't what was `expected`, quoting the start of it.
+#[cfg(any(feature = "qapi-qmp", feature = "tokio-util", feature = "async-futures-io"))]
+pub(crate) fn unexpected_message(expected: &str, message: &[u8]) -> io::Error {
+    let message = String::from_utf8_lossy(&message[..message.len().min(80)]);
+    io::Error::new(io::ErrorKind::InvalidData, format!("expected {}, got: {}", expected, message.trim_end()))
+}
+
+/// Whether `e` means that the peer went away.
+#[cfg(any(feature = "qapi-qga", all(feature = "qapi-qmp", feature = "async-tokio-spawn", feature = "async-tokio-time")))]
+pub(crate) fn is_disconnect(e: &io::Error) -> bool {
+    matches!(e.kind(),
+        io::ErrorKind::UnexpectedEof | io::ErrorKind::BrokenPipe | io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionAborted | io::ErrorKind::NotConnected
+    )
+}
+
 impl From<io::Error> for ExecuteError {
     fn from(e: io::Error) -> Self {
         ExecuteError::Io(e)
@@ -72,9 +118,9 @@ impl From<ExecuteError> for io::Error {
 mod qapi {
     use serde_json;
     use serde::{Serialize, Deserialize};
-    use std::io::{self, BufRead, Write};
-    use crate::{Command, Execute};
-    use log::trace;
+    use std::io::{self, BufRead, IoSlice, Write};
+    use crate::{Any, Command, Execute, ExecuteRaw, Never};
+    use log::{trace, warn};
 
     pub struct Qapi<S> {
         pub stream: S,
@@ -91,28 +137,38 @@ mod qapi {
     }
 
     impl<S: BufRead> Qapi<S> {
-        pub fn decode_line<'de, D: Deserialize<'de>>(&'de mut self) -> io::Result<Option<D>> {
+        /// Reads the next line, which is empty at EOF.
+        pub fn read_line(&mut self) -> io::Result<&[u8]> {
             self.buffer.clear();
             let line = self.stream.read_until(b'\n', &mut self.buffer)?;
             let line = &self.buffer[..line];
             trace!("<- {}", String::from_utf8_lossy(line));
 
+            Ok(line)
+        }
+
+        pub fn decode_line<'de, D: Deserialize<'de>>(&'de mut self) -> io::Result<Option<D>> {
+            let line = self.read_line()?;
+
             if line.is_empty() {
-                Ok(None)
-            } else {
-                serde_json::from_slice(line).map(Some).map_err(From::from)
+                return Ok(None)
+            }
+
+            match serde_json::from_slice(line) {
+                // a line without its newline was cut short at EOF
+                Err(e) if e.is_eof() && !line.ends_with(b"\n") => {
+                    warn!("QAPI stream closed after a truncated line of {} bytes", line.len());
+                    Ok(None)
+                },
+                res => res.map(Some).map_err(From::from),
             }
         }
     }
 
     impl<S: Write> Qapi<S> {
         pub fn encode_line<C: Serialize>(&mut self, command: &C) -> io::Result<()> {
-            {
-                let mut ser = serde_json::Serializer::new(&mut self.stream);
-                command.serialize(&mut ser)?;
-            }
-
-            self.stream.write(&[b'\n'])?;
+            let line = serde_json::to_vec(command)?;
+            write_line(&mut self.stream, &line)?;
 
             self.stream.flush()
         }
@@ -124,6 +180,59 @@ mod qapi {
 
             Ok(())
         }
+
+        /// Writes a command by name, see [`crate::Qmp::execute_raw`].
+        pub fn write_raw(&mut self, name: &str, arguments: Any) -> io::Result<()> {
+            let command = ExecuteRaw::<Never>::new(name, arguments, None);
+            self.encode_line(&command)?;
+
+            trace!("-> execute {}: {}", name, serde_json::to_string_pretty(&command.arguments).unwrap());
+
+            Ok(())
+        }
+    }
+
+    #[cfg(all(unix, feature = "qapi-qmp"))]
+    impl<S: Write + std::os::unix::io::AsRawFd> Qapi<S> {
+        /// Writes a command with `fd` attached to it as `SCM_RIGHTS` ancillary data.
+        pub fn write_command_with_fd<C: Command>(&mut self, command: &C, fd: std::os::unix::io::RawFd) -> io::Result<()> {
+            let mut line = serde_json::to_vec(&Execute::<&C>::from(command))?;
+            line.push(b'\n');
+
+            // anything buffered must be sent before the message carrying the fd
+            self.stream.flush()?;
+            let written = loop {
+                match crate::fd::send_with_fd(self.stream.as_raw_fd(), &line, Some(fd)) {
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                    res => break res?,
+                }
+            };
+            self.stream.write_all(&line[written..])?;
+            self.stream.flush()?;
+
+            trace!("-> execute {} with fd {}: {}", C::NAME, fd, serde_json::to_string_pretty(command).unwrap());
+
+            Ok(())
+        }
+    }
+
+    /// Writes `line` followed by a newline, without copying it to append the newline.
+    fn write_line<W: Write>(stream: &mut W, line: &[u8]) -> io::Result<()> {
+        let mut written = 0;
+        while written <= line.len() {
+            let res = match line.get(written..) {
+                Some(rest) if !rest.is_empty() => stream.write_vectored(&[IoSlice::new(rest), IoSlice::new(b"\n")]),
+                _ => stream.write(b"\n"),
+            };
+            match res {
+                Ok(0) => return Err(io::Error::new(io::ErrorKind::WriteZero, "failed to write QAPI command")),
+                Ok(len) => written += len,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(())
     }
 }
 
@@ -178,14 +287,23 @@ mod stream {
             self.w.flush()
         }
     }
+
+    /// The write half, which is what file descriptors are passed over.
+    #[cfg(unix)]
+    impl<R, W: std::os::unix::io::AsRawFd> std::os::unix::io::AsRawFd for Stream<R, W> {
+        fn as_raw_fd(&self) -> std::os::unix::io::RawFd {
+            self.w.as_raw_fd()
+        }
+    }
 }
 
 #[cfg(feature = "qapi-qmp")]
 mod qmp_impl {
     use std::io::{self, BufRead, Read, Write, BufReader};
     use std::vec::Drain;
-    use qapi_qmp::{QMP, QapiCapabilities, QmpMessage, Event, qmp_capabilities, query_version};
-    use crate::{qapi::Qapi, Stream, ExecuteResult, ExecuteError, Command};
+    use qapi_qmp::{QMP, QapiCapabilities, QmpMessage, Event, EventInfo, human_monitor_command, Schema, qmp_capabilities, query_version, query_qmp_schema};
+    use serde::de::DeserializeOwned;
+    use crate::{qapi::Qapi, Stream, Any, Error, ExecuteResult, ExecuteError, Command};
 
     pub struct Qmp<S> {
         inner: Qapi<S>,
@@ -198,6 +316,18 @@ mod qmp_impl {
         }
     }
 
+    pub(crate) fn event_names(events: Vec<EventInfo>) -> Vec<String> {
+        events.into_iter().map(|event| event.name).collect()
+    }
+
+    /// Builds the `human-monitor-command` for [`Qmp::hmp`], run on the default CPU.
+    pub(crate) fn hmp_command(command_line: &str) -> human_monitor_command {
+        human_monitor_command {
+            command_line: command_line.into(),
+            cpu_index: None,
+        }
+    }
+
     impl<S> Qmp<S> {
         pub fn new(stream: S) -> Self {
             Qmp {
@@ -225,17 +355,37 @@ mod qmp_impl {
 
     impl<S: BufRead> Qmp<S> {
         pub fn read_capabilities(&mut self) -> io::Result<QMP> {
-            self.inner.decode_line().map(|v: Option<QapiCapabilities>|
-                v.expect("unexpected eof").QMP
-            )
+            let line = self.inner.read_line()?;
+            if line.is_empty() {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed before the QMP greeting"))
+            }
+
+            serde_json::from_slice::<QapiCapabilities>(line)
+                .map(|caps| caps.QMP)
+                .map_err(|_| crate::unexpected_message("QMP greeting", line))
         }
 
         pub fn read_response<C: Command>(&mut self) -> ExecuteResult<C> {
+            self.read_result()
+        }
+
+        /// Reads a command response, passing any events received beforehand to `on_event`
+        /// instead of queueing them.
+        pub fn read_response_with<C: Command, F: FnMut(Event)>(&mut self, on_event: F) -> ExecuteResult<C> {
+            Self::read_response_inner(&mut self.inner, on_event)
+        }
+
+        fn read_result<R: DeserializeOwned>(&mut self) -> Result<R, ExecuteError> {
+            let event_queue = &mut self.event_queue;
+            Self::read_response_inner(&mut self.inner, |e| event_queue.push(e))
+        }
+
+        fn read_response_inner<R: DeserializeOwned, F: FnMut(Event)>(inner: &mut Qapi<S>, mut on_event: F) -> Result<R, ExecuteError> {
             loop {
-                match self.inner.decode_line()? {
+                match inner.decode_line()? {
                     None => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "expected command response").into()),
                     Some(QmpMessage::Response(res)) => return res.result().map_err(From::from),
-                    Some(QmpMessage::Event(e)) => self.event_queue.push(e),
+                    Some(QmpMessage::Event(e)) => on_event(e),
                 }
             }
         }
@@ -251,12 +401,65 @@ mod qmp_impl {
             self.read_response::<C>()
         }
 
+        /// Executes a command by name, for commands that aren't part of the generated schema.
+        ///
+        /// Errors returned by QEMU are distinguished from I/O errors.
+        pub fn execute_raw(&mut self, name: &str, arguments: Any) -> io::Result<Result<Any, Error>> {
+            self.inner.write_raw(name, arguments)?;
+            crate::raw_result(self.read_result())
+        }
+
+        /// Executes a command such as `getfd` or `add-fd` that expects a file descriptor to accompany it.
+        ///
+        /// `fd` is sent over the Unix socket that `S` writes to, and remains owned by the caller.
+        #[cfg(unix)]
+        pub fn execute_with_fd<C: Command>(&mut self, command: &C, fd: std::os::unix::io::RawFd) -> ExecuteResult<C> where
+            S: std::os::unix::io::AsRawFd,
+        {
+            self.inner.write_command_with_fd(command, fd)?;
+            self.read_response::<C>()
+        }
+
+        /// Executes a command, passing any events received before its response to `on_event`.
+        pub fn execute_with<C: Command, F: FnMut(Event)>(&mut self, command: &C, on_event: F) -> ExecuteResult<C> {
+            self.write_command(command)?;
+            self.read_response_with::<C, F>(on_event)
+        }
+
         pub fn handshake(&mut self) -> Result<QMP, ExecuteError> {
             let caps = self.read_capabilities()?;
             self.execute(&qmp_capabilities { enable: None })
                 .map(|_| caps)
         }
 
+        /// Runs an HMP command through `human-monitor-command`, returning its text output.
+        ///
+        /// Some functionality such as `info registers` is only available through HMP.
+        pub fn hmp(&mut self, command_line: &str) -> io::Result<Result<String, Error>> {
+            crate::raw_result(self.execute(&hmp_command(command_line)))
+        }
+
+        /// Retrieves the schema supported by QEMU.
+        pub fn query_schema(&mut self) -> Result<Schema, ExecuteError> {
+            self.execute(&query_qmp_schema { arguments: Default::default() })
+                .map(Schema::new)
+        }
+
+        /// The names of the events that QEMU may emit.
+        ///
+        /// `query-events` is deprecated in favour of [`Qmp::query_schema`], which also lists events.
+        #[allow(deprecated)]
+        pub fn query_events(&mut self) -> Result<Vec<String>, ExecuteError> {
+            self.execute(&qapi_qmp::query_events { })
+                .map(event_names)
+        }
+
+        /// Whether QEMU may emit the event called `name`, see [`Qmp::query_events`].
+        pub fn supports_event(&mut self, name: &str) -> Result<bool, ExecuteError> {
+            self.query_events()
+                .map(|events| events.iter().any(|event| event == name))
+        }
+
         /// Can be used to poll the socket for pending events
         pub fn nop(&mut self) -> io::Result<()> {
             self.execute(&query_version { })
@@ -268,10 +471,135 @@ mod qmp_impl {
 
 #[cfg(feature = "qapi-qga")]
 mod qga_impl {
-    use std::io::{self, BufRead, Read, Write, BufReader};
-    use qapi_qga::guest_sync;
+    use std::io::{self, BufRead, Read, Write, BufReader, Seek, SeekFrom};
+    use std::collections::hash_map::RandomState;
+    use std::hash::{BuildHasher, Hasher};
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+    use std::{panic, thread};
+    use qapi_qga::{guest_sync, guest_info, GuestAgentCommandInfo, guest_get_osinfo, GuestOSInfo, guest_network_get_interfaces, GuestNetworkInterface, guest_fsfreeze_status, guest_fsfreeze_freeze, guest_fsfreeze_thaw, GuestFsfreezeStatus, guest_shutdown, GuestShutdownMode, guest_exec, guest_exec_status, GuestExecOutput, guest_file_open, guest_file_close, guest_file_read, guest_file_write, guest_file_seek, guest_file_flush, GuestFileWhence, QGASeek, guest_get_vcpus, guest_set_vcpus, GuestLogicalProcessor, GuestFileRead};
     use qapi_spec::Response;
-    use crate::{qapi::Qapi, Stream, Command, ExecuteResult, ExecuteError};
+    use log::trace;
+    use serde::de::DeserializeOwned;
+    use crate::{qapi::Qapi, Stream, Any, Error, Command, ExecuteResult, ExecuteError};
+
+    /// Generates an unpredictable `guest-sync` value.
+    ///
+    /// Values are unlikely to repeat across calls or processes, so stale responses left over
+    /// from an earlier session won't be mistaken for the handshake.
+    pub fn guest_sync_value() -> i32 {
+        static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+        let time = SystemTime::now().duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos()).unwrap_or_default();
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u64(COUNTER.fetch_add(1, Ordering::Relaxed));
+        hasher.write_u128(time);
+        hasher.finish() as i32
+    }
+
+    /// Builds a `guest-exec` command that captures the program's output.
+    pub fn guest_exec_capture<A: IntoIterator>(path: &str, args: A, input: Option<Vec<u8>>) -> guest_exec where
+        A::Item: Into<String>,
+    {
+        guest_exec {
+            path: path.into(),
+            arg: Some(args.into_iter().map(Into::into).collect()),
+            env: None,
+            input_data: input,
+            capture_output: Some(true),
+        }
+    }
+
+    /// Builds the `guest-set-vcpus` command that brings vCPU `logical_id` online or offline,
+    /// or `None` if it already is.
+    pub(crate) fn guest_set_vcpu(vcpus: Vec<GuestLogicalProcessor>, logical_id: i64, online: bool) -> Result<Option<guest_set_vcpus>, ExecuteError> {
+        let vcpu = vcpus.into_iter().find(|vcpu| vcpu.logical_id == logical_id)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("guest has no vCPU {}", logical_id)))?;
+        if vcpu.online == online {
+            return Ok(None)
+        }
+        if !online && vcpu.can_offline == Some(false) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("guest vCPU {} can't be taken offline", logical_id)).into())
+        }
+
+        Ok(Some(guest_set_vcpus {
+            vcpus: vec![GuestLogicalProcessor {
+                logical_id,
+                online,
+                can_offline: None,
+            }],
+        }))
+    }
+
+    /// Checks the number of vCPUs that `guest-set-vcpus` reports having changed.
+    pub(crate) fn guest_set_vcpu_result(logical_id: i64, online: bool, processed: i64) -> Result<(), ExecuteError> {
+        match processed {
+            1 => Ok(()),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData, format!("guest agent processed {} vCPUs when bringing vCPU {} {}", processed, logical_id, if online { "online" } else { "offline" })).into()),
+        }
+    }
+
+    /// Interprets the outcome of `guest-shutdown`.
+    ///
+    /// The guest agent only responds if it fails to shut down, so losing the connection is
+    /// taken as success.
+    pub(crate) fn guest_shutdown_result<T>(res: Result<T, ExecuteError>) -> Result<(), ExecuteError> {
+        match res {
+            Ok(..) => Ok(()),
+            Err(ExecuteError::Io(e)) if crate::is_disconnect(&e) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Refuses to freeze guest filesystems that are already frozen, see [`Qga::freeze_scope`].
+    pub(crate) fn guest_fsfreeze_check(status: GuestFsfreezeStatus) -> Result<(), ExecuteError> {
+        match status {
+            GuestFsfreezeStatus::thawed => Ok(()),
+            GuestFsfreezeStatus::frozen => Err(io::Error::new(io::ErrorKind::AlreadyExists, "guest filesystems are already frozen").into()),
+        }
+    }
+
+    /// The largest amount of data requested by a single `guest-file-read` or `guest-file-write`.
+    pub(crate) const GUEST_FILE_CHUNK_SIZE: usize = 0x10000;
+
+    /// How many consecutive reads may return no data before EOF, before giving up.
+    const GUEST_FILE_EMPTY_READS: usize = 16;
+
+    /// The longest wait between reads that return no data, see [`guest_read_backoff`].
+    const GUEST_FILE_EMPTY_READ_DELAY: Duration = Duration::from_millis(100);
+
+    /// Builds the `guest-file-read` command for the next chunk of [`Qga::guest_read_to_end`].
+    pub(crate) fn guest_read_chunk(handle: i64) -> guest_file_read {
+        guest_file_read {
+            handle,
+            count: Some(GUEST_FILE_CHUNK_SIZE as i64),
+        }
+    }
+
+    /// Appends a chunk read by [`guest_read_chunk`] to `buf`, returning whether EOF was reached.
+    pub(crate) fn guest_read_chunk_result(buf: &mut Vec<u8>, empty_reads: &mut usize, res: GuestFileRead) -> Result<bool, ExecuteError> {
+        if res.buf_b64.is_empty() && !res.eof {
+            *empty_reads += 1;
+            if *empty_reads >= GUEST_FILE_EMPTY_READS {
+                return Err(io::Error::new(io::ErrorKind::WouldBlock, format!("guest-file-read returned no data {} times before EOF", empty_reads)).into())
+            }
+        } else {
+            *empty_reads = 0;
+        }
+
+        buf.extend_from_slice(&res.buf_b64);
+        Ok(res.eof)
+    }
+
+    /// How long to wait before retrying after `empty_reads` consecutive reads returned no data,
+    /// doubling from a millisecond up to [`GUEST_FILE_EMPTY_READ_DELAY`].
+    pub(crate) fn guest_read_backoff(empty_reads: usize) -> Option<Duration> {
+        match empty_reads {
+            0 => None,
+            n => Some(Duration::from_millis(1 << (n - 1).min(16)).min(GUEST_FILE_EMPTY_READ_DELAY)),
+        }
+    }
 
     pub struct Qga<S> {
         inner: Qapi<S>,
@@ -305,6 +633,10 @@ mod qga_impl {
 
     impl<S: BufRead> Qga<S> {
         pub fn read_response<C: Command>(&mut self) -> ExecuteResult<C> {
+            self.read_result()
+        }
+
+        fn read_result<R: DeserializeOwned>(&mut self) -> Result<R, ExecuteError> {
             loop {
                 match self.inner.decode_line()?.map(|r: Response<_>| r.result()) {
                     None => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "expected command response").into()),
@@ -325,16 +657,251 @@ mod qga_impl {
             self.read_response::<C>()
         }
 
+        /// Executes a command by name, see [`crate::Qmp::execute_raw`].
+        pub fn execute_raw(&mut self, name: &str, arguments: Any) -> io::Result<Result<Any, Error>> {
+            self.inner.write_raw(name, arguments)?;
+            crate::raw_result(self.read_result())
+        }
+
+        /// Synchronizes with the guest agent.
+        ///
+        /// Anything other than a successful response echoing `sync_value` is assumed to be
+        /// stale output from a previous session, including errors and lines that aren't valid
+        /// JSON, and is discarded until the matching response arrives.
         pub fn guest_sync(&mut self, sync_value: i32) -> Result<(), ExecuteError> {
             let id = sync_value.into();
-            let sync = guest_sync {
+            self.write_command(&guest_sync {
                 id,
+            })?;
+
+            let sync = Any::from(id);
+            loop {
+                let line = self.inner.read_line()?;
+                if line.is_empty() {
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "guest-sync handshake failed").into())
+                }
+                match serde_json::from_slice::<Response<Any>>(line).map(Response::result) {
+                    Ok(Ok(res)) if res == sync => return Ok(()),
+                    _ => trace!("discarding stale QGA output {:?}", String::from_utf8_lossy(line)),
+                }
+            }
+        }
+
+        /// Synchronizes with the guest agent using a freshly generated `guest-sync` value.
+        pub fn handshake(&mut self) -> Result<(), ExecuteError> {
+            self.guest_sync(guest_sync_value())
+        }
+
+        /// Runs a program in the guest and waits for it to exit, checking every `poll_interval`.
+        ///
+        /// `input` is passed to the program's stdin, and its output is captured.
+        pub fn guest_exec_wait<A: IntoIterator>(&mut self, path: &str, args: A, input: Option<Vec<u8>>, poll_interval: Duration) -> Result<GuestExecOutput, ExecuteError> where
+            A::Item: Into<String>,
+        {
+            let pid = self.execute(&guest_exec_capture(path, args, input))?.pid;
+
+            loop {
+                if let Some(output) = self.execute(&guest_exec_status { pid })?.into_output() {
+                    return Ok(output)
+                }
+                thread::sleep(poll_interval);
+            }
+        }
+
+        /// Brings the guest's logical CPU `logical_id` online or offline.
+        ///
+        /// Fails without changing anything if the guest reports that the CPU can't be taken offline.
+        pub fn set_vcpu_online(&mut self, logical_id: i64, online: bool) -> Result<(), ExecuteError> {
+            let vcpus = self.execute(&guest_get_vcpus { })?;
+            match guest_set_vcpu(vcpus, logical_id, online)? {
+                Some(command) => guest_set_vcpu_result(logical_id, online, self.execute(&command)?),
+                None => Ok(()),
+            }
+        }
+
+        /// Shuts down the guest, waiting until the guest agent goes away.
+        ///
+        /// `Halt` stops the guest without powering it off, `Powerdown` powers it off, and
+        /// `Reboot` restarts it. The guest agent doesn't respond unless the shutdown fails, so
+        /// this succeeds once the connection drops. That is inherently racy: an error may be lost
+        /// if the guest goes down anyway, and with virtio-serial the host side of the channel may
+        /// never close, so set a read timeout on the stream to avoid waiting forever.
+        pub fn guest_shutdown(&mut self, mode: GuestShutdownMode) -> Result<(), ExecuteError> {
+            guest_shutdown_result(self.execute(&guest_shutdown { mode: Some(mode) }))
+        }
+
+        /// The commands the guest agent implements, including those that have been disabled.
+        pub fn guest_supported_commands(&mut self) -> Result<Vec<GuestAgentCommandInfo>, ExecuteError> {
+            self.execute(&guest_info { })
+                .map(|info| info.supported_commands)
+        }
+
+        /// Whether the guest agent implements the command `name` and hasn't disabled it.
+        ///
+        /// See [`GuestAgentInfo::supports`](qapi_qga::GuestAgentInfo::supports) to check several
+        /// commands with a single `guest-info`.
+        pub fn guest_supports(&mut self, name: &str) -> Result<bool, ExecuteError> {
+            self.execute(&guest_info { })
+                .map(|info| info.supports(name))
+        }
+
+        /// Details of the guest operating system, such as its name and kernel version.
+        ///
+        /// Fields that the guest agent can't determine are left out.
+        pub fn guest_osinfo(&mut self) -> Result<GuestOSInfo, ExecuteError> {
+            self.execute(&guest_get_osinfo { })
+        }
+
+        /// The guest's network interfaces and their addresses.
+        ///
+        /// Useful for discovering the addresses a guest has acquired after booting, see
+        /// [`GuestNetworkInterface::ips`](qapi_qga::GuestNetworkInterface::ips).
+        pub fn guest_interfaces(&mut self) -> Result<Vec<GuestNetworkInterface>, ExecuteError> {
+            self.execute(&guest_network_get_interfaces { })
+        }
+
+        /// Freezes the guest's filesystems, calls `f` with the number frozen, then thaws them again.
+        ///
+        /// This is the sequence for taking a consistent snapshot of the guest's disks from the
+        /// host. Fails without calling `f` if the filesystems are already frozen, since thawing
+        /// them afterwards would cut short whoever froze them. They are thawed even if `f`
+        /// panics, and the result of `f` is returned along with the number of filesystems thawed.
+        pub fn freeze_scope<T, F: FnOnce(i64) -> T>(&mut self, f: F) -> Result<(T, Result<i64, ExecuteError>), ExecuteError> {
+            guest_fsfreeze_check(self.execute(&guest_fsfreeze_status { })?)?;
+            let frozen = self.execute(&guest_fsfreeze_freeze { })?;
+            let res = panic::catch_unwind(panic::AssertUnwindSafe(|| f(frozen)));
+            let thawed = self.execute(&guest_fsfreeze_thaw { });
+            match res {
+                Ok(res) => Ok((res, thawed)),
+                Err(payload) => panic::resume_unwind(payload),
+            }
+        }
+
+        /// The number of the guest's logical CPUs that are online.
+        pub fn online_vcpu_count(&mut self) -> Result<usize, ExecuteError> {
+            self.execute(&guest_get_vcpus { })
+                .map(|vcpus| vcpus.iter().filter(|vcpu| vcpu.online).count())
+        }
+
+        /// Reads the rest of the guest file `handle`, in as many `guest-file-read` calls as it takes.
+        ///
+        /// Reads that return no data without reaching EOF are retried a few times, backing off
+        /// for a little longer each time, before failing with `WouldBlock`, as happens with pipes
+        /// that have nothing to read yet.
+        pub fn guest_read_to_end(&mut self, handle: i64) -> Result<Vec<u8>, ExecuteError> {
+            let mut buf = Vec::new();
+            let mut empty_reads = 0;
+            loop {
+                let res = self.execute(&guest_read_chunk(handle))?;
+                if guest_read_chunk_result(&mut buf, &mut empty_reads, res)? {
+                    break Ok(buf)
+                }
+                if let Some(delay) = guest_read_backoff(empty_reads) {
+                    std::thread::sleep(delay);
+                }
+            }
+        }
+
+        /// Opens a file in the guest, with a `mode` as accepted by `fopen()` (defaults to `"r"`).
+        pub fn guest_open(&mut self, path: &str, mode: Option<&str>) -> Result<QgaFile<'_, S>, ExecuteError> {
+            let handle = self.execute(&guest_file_open {
+                path: path.into(),
+                mode: mode.map(Into::into),
+            })?;
+
+            Ok(QgaFile {
+                qga: self,
+                handle,
+                closed: false,
+            })
+        }
+    }
+
+    /// An open file in the guest, which is closed when dropped.
+    pub struct QgaFile<'a, S: BufRead + Write> {
+        qga: &'a mut Qga<S>,
+        handle: i64,
+        closed: bool,
+    }
+
+    impl<'a, S: BufRead + Write> QgaFile<'a, S> {
+        /// The largest amount of data transferred by a single read or write.
+        pub const CHUNK_SIZE: usize = GUEST_FILE_CHUNK_SIZE;
+
+        pub fn handle(&self) -> i64 {
+            self.handle
+        }
+
+        /// Reads the rest of the file, see [`Qga::guest_read_to_end`].
+        pub fn read_all(&mut self) -> Result<Vec<u8>, ExecuteError> {
+            self.qga.guest_read_to_end(self.handle)
+        }
+
+        /// Closes the file, reporting any error that dropping it would ignore.
+        pub fn close(mut self) -> Result<(), ExecuteError> {
+            self.closed = true;
+            self.qga.execute(&guest_file_close {
+                handle: self.handle,
+            }).map(drop)
+        }
+    }
+
+    impl<'a, S: BufRead + Write> Read for QgaFile<'a, S> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if buf.is_empty() {
+                return Ok(0)
+            }
+
+            let res = self.qga.execute(&guest_file_read {
+                handle: self.handle,
+                count: Some(buf.len().min(Self::CHUNK_SIZE) as i64),
+            })?;
+            let len = res.buf_b64.len().min(buf.len());
+            buf[..len].copy_from_slice(&res.buf_b64[..len]);
+            Ok(len)
+        }
+    }
+
+    impl<'a, S: BufRead + Write> Write for QgaFile<'a, S> {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let buf = &buf[..buf.len().min(Self::CHUNK_SIZE)];
+            let res = self.qga.execute(&guest_file_write {
+                handle: self.handle,
+                buf_b64: buf.into(),
+                count: None,
+            })?;
+            Ok(res.count as usize)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.qga.execute(&guest_file_flush {
+                handle: self.handle,
+            }).map(drop).map_err(From::from)
+        }
+    }
+
+    impl<'a, S: BufRead + Write> Seek for QgaFile<'a, S> {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            let (offset, whence) = match pos {
+                SeekFrom::Start(offset) => (offset as i64, QGASeek::set),
+                SeekFrom::Current(offset) => (offset, QGASeek::cur),
+                SeekFrom::End(offset) => (offset, QGASeek::end),
             };
+            let res = self.qga.execute(&guest_file_seek {
+                handle: self.handle,
+                offset,
+                whence: GuestFileWhence::name(whence),
+            })?;
+            Ok(res.position as u64)
+        }
+    }
 
-            match self.execute(&sync) {
-                Ok(r) if r == sync.id => Ok(()),
-                Ok(..) => Err(io::Error::new(io::ErrorKind::InvalidData, "guest-sync handshake failed").into()),
-                Err(e) => Err(e.into()),
+    impl<'a, S: BufRead + Write> Drop for QgaFile<'a, S> {
+        fn drop(&mut self) {
+            if !self.closed {
+                if let Err(e) = self.qga.execute(&guest_file_close { handle: self.handle }) {
+                    trace!("failed to close QGA file handle {}: {:?}", self.handle, e);
+                }
             }
         }
     }
@@ -4,6 +4,7 @@
 include!(concat!(env!("OUT_DIR"), "/qga.rs"));
 
 use std::{io, str, fmt, error};
+use std::net::IpAddr;
 use serde::{Deserialize, Serialize};
 
 pub trait QgaCommand: qapi_spec::Command { }
@@ -18,6 +19,38 @@ pub enum GuestShutdownMode {
     Reboot,
 }
 
+impl GuestAgentInfo {
+    /// Looks up a command by name, whether or not it's enabled.
+    pub fn command(&self, name: &str) -> Option<&GuestAgentCommandInfo> {
+        self.supported_commands.iter().find(|command| command.name == name)
+    }
+
+    /// Whether the guest agent implements the command `name` and hasn't disabled it.
+    pub fn supports(&self, name: &str) -> bool {
+        self.command(name).map(|command| command.enabled).unwrap_or(false)
+    }
+}
+
+impl GuestNetworkInterface {
+    /// The interface's addresses, empty if the guest agent didn't report any.
+    pub fn addresses(&self) -> &[GuestIpAddress] {
+        self.ip_addresses.as_deref().unwrap_or(&[])
+    }
+
+    /// The interface's addresses that could be parsed.
+    pub fn ips(&self) -> impl Iterator<Item=IpAddr> + '_ {
+        self.addresses().iter().filter_map(|address| address.ip())
+    }
+}
+
+impl GuestIpAddress {
+    /// Parses the address, ignoring any IPv6 zone suffix such as `%eth0`.
+    pub fn ip(&self) -> Option<IpAddr> {
+        let address = self.ip_address.split('%').next().unwrap_or_default();
+        address.parse().ok()
+    }
+}
+
 impl GuestExecStatus {
     pub fn result(self) -> Result<Self, Self> {
         if self.exited {
@@ -54,6 +87,61 @@ impl GuestExecStatus {
     }
 }
 
+/// How a guest process terminated.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum GuestExit {
+    /// The process exited with this code
+    Code(i64),
+    /// The process was terminated by this signal
+    Signal(i64),
+    /// The guest agent didn't report how the process exited
+    Unknown,
+}
+
+/// The output of a guest process that has exited.
+#[derive(Clone, Debug)]
+pub struct GuestExecOutput {
+    pub exit: GuestExit,
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+    /// `stdout` was cut short because it exceeded the guest agent's buffer
+    pub stdout_truncated: bool,
+    /// `stderr` was cut short because it exceeded the guest agent's buffer
+    pub stderr_truncated: bool,
+}
+
+impl GuestExecStatus {
+    /// Converts the status of an exited process into its output.
+    ///
+    /// Returns `None` if the process is still running.
+    pub fn into_output(self) -> Option<GuestExecOutput> {
+        if !self.exited {
+            return None
+        }
+
+        let exit = match (self.signal, self.exitcode) {
+            (Some(signal), _) => GuestExit::Signal(signal),
+            (None, Some(code)) => GuestExit::Code(code),
+            (None, None) => GuestExit::Unknown,
+        };
+
+        Some(GuestExecOutput {
+            exit,
+            stdout: self.out_data.unwrap_or_default(),
+            stderr: self.err_data.unwrap_or_default(),
+            stdout_truncated: self.out_truncated.unwrap_or(false),
+            stderr_truncated: self.err_truncated.unwrap_or(false),
+        })
+    }
+}
+
+impl GuestExecOutput {
+    /// Whether the process exited successfully.
+    pub fn success(&self) -> bool {
+        self.exit == GuestExit::Code(0)
+    }
+}
+
 impl fmt::Display for GuestExecStatus {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{}", self.message())
@@ -3,9 +3,13 @@
 #![allow(deprecated)]
 
 use std::io;
+use std::collections::HashMap;
 use std::string::String as StdString;
 use std::convert::TryFrom;
-use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::marker::PhantomData;
+use serde::{Deserialize, Deserializer, Serialize};
+use serde::de::{Error as _, MapAccess, Visitor};
 
 include!(concat!(env!("OUT_DIR"), "/qmp.rs"));
 
@@ -15,13 +19,53 @@ pub trait QmpCommand: qapi_spec::Command { }
 impl<'a, T: QmpCommand> QmpCommand for &'a T { }
 impl<'a, T: QmpCommand> QmpCommand for &'a mut T { }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize)]
 #[serde(untagged)]
 pub enum QmpMessage<C> {
     Event(Event),
     Response(qapi_spec::Response<C>),
 }
 
+/// Parses the message only once, deserializing a response's `return` value straight into `C`.
+///
+/// An event's fields are buffered, since its name may come after its data.
+impl<'de, C: Deserialize<'de>> Deserialize<'de> for QmpMessage<C> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_map(QmpMessageVisitor(PhantomData))
+    }
+}
+
+struct QmpMessageVisitor<C>(PhantomData<fn() -> C>);
+
+impl<'de, C: Deserialize<'de>> Visitor<'de> for QmpMessageVisitor<C> {
+    type Value = QmpMessage<C>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a QMP event or response")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+        let mut response = qapi_spec::ResponseFields::default();
+        let mut event = qapi_spec::Dictionary::new();
+        while let Some(key) = map.next_key::<StdString>()? {
+            match &key[..] {
+                "event" | "data" | "timestamp" => {
+                    let value = map.next_value()?;
+                    event.insert(key, value);
+                },
+                _ => response.next_value(key, &mut map)?,
+            }
+        }
+
+        if event.contains_key("event") {
+            Event::deserialize(qapi_spec::Any::Object(event))
+                .map(QmpMessage::Event).map_err(A::Error::custom)
+        } else {
+            response.finish().map(QmpMessage::Response)
+        }
+    }
+}
+
 impl<C> TryFrom<QmpMessage<C>> for qapi_spec::Response<C> {
     type Error = io::Error;
 
@@ -41,13 +85,66 @@ pub struct QMP {
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(untagged)]
+#[serde(from = "qapi_spec::Any", into = "qapi_spec::Any")]
 pub enum QmpCapability {
-    #[serde(rename = "oob")]
     OutOfBand,
     Unknown(qapi_spec::Any),
 }
 
+impl From<qapi_spec::Any> for QmpCapability {
+    fn from(v: qapi_spec::Any) -> Self {
+        match v.as_str() {
+            Some("oob") => QmpCapability::OutOfBand,
+            _ => QmpCapability::Unknown(v),
+        }
+    }
+}
+
+impl From<QmpCapability> for qapi_spec::Any {
+    fn from(c: QmpCapability) -> Self {
+        match c {
+            QmpCapability::OutOfBand => "oob".into(),
+            QmpCapability::Unknown(v) => v,
+        }
+    }
+}
+
+impl QMP {
+    /// The QEMU `(major, minor, micro)` version.
+    pub fn qemu_version(&self) -> (u64, u64, u64) {
+        self.version.qemu_version()
+    }
+
+    /// The distribution-specific package version, which is often empty.
+    pub fn package(&self) -> &str {
+        self.version.package()
+    }
+
+    /// Whether QEMU is version `major.minor` or newer.
+    pub fn at_least(&self, major: u64, minor: u64) -> bool {
+        self.version.at_least(major, minor)
+    }
+}
+
+impl VersionInfo {
+    /// The QEMU `(major, minor, micro)` version.
+    pub fn qemu_version(&self) -> (u64, u64, u64) {
+        let VersionTriple { major, minor, micro } = self.qemu;
+        (major as u64, minor as u64, micro as u64)
+    }
+
+    /// The distribution-specific package version, which is often empty.
+    pub fn package(&self) -> &str {
+        &self.package
+    }
+
+    /// Whether QEMU is version `major.minor` or newer.
+    pub fn at_least(&self, major: u64, minor: u64) -> bool {
+        let (qemu_major, qemu_minor, _) = self.qemu_version();
+        (qemu_major, qemu_minor) >= (major, minor)
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct QapiCapabilities {
     pub QMP: QMP,
@@ -61,12 +158,53 @@ impl QapiCapabilities {
         })
     }
 
+    /// The advertised capabilities, excluding any that this version of the schema doesn't know.
     pub fn capabilities<'a>(&'a self) -> impl Iterator<Item=QMPCapability> + 'a {
         self.QMP.capabilities.iter().filter_map(|c| match c {
             QmpCapability::OutOfBand => Some(QMPCapability::oob),
-            QmpCapability::Unknown(..) => None,
+            QmpCapability::Unknown(v) => QMPCapability::deserialize(v).ok(),
         })
     }
+
+    /// Whether QEMU offered to enable `capability`.
+    pub fn supports(&self, capability: QMPCapability) -> bool {
+        self.capabilities().any(|c| c == capability)
+    }
+}
+
+impl BlockInfo {
+    /// Whether the drive has a medium inserted.
+    pub fn is_inserted(&self) -> bool {
+        self.inserted.is_some()
+    }
+
+    /// The filename of the inserted medium.
+    pub fn inserted_file(&self) -> Option<&str> {
+        self.inserted.as_ref().map(|inserted| &inserted.file[..])
+    }
+
+    /// Whether a medium is inserted and opened read-only.
+    pub fn is_read_only(&self) -> bool {
+        self.inserted.as_ref().map(|inserted| inserted.ro).unwrap_or(false)
+    }
+
+    /// The backing file of the inserted medium, as recorded in its image.
+    pub fn backing_file(&self) -> Option<&str> {
+        self.inserted.as_ref().and_then(|inserted| inserted.backing_file.as_deref())
+    }
+
+    /// The images of the inserted medium, from the active layer down to the base image.
+    pub fn backing_chain(&self) -> impl Iterator<Item=&ImageInfo> {
+        self.inserted.as_ref().map(|inserted| &inserted.image)
+            .into_iter().flat_map(ImageInfo::backing_chain)
+    }
+}
+
+impl ImageInfo {
+    /// This image followed by each of its backing images in turn.
+    pub fn backing_chain(&self) -> impl Iterator<Item=&ImageInfo> {
+        std::iter::successors(Some(self), |image| image.backing_image.as_deref())
+    }
 }
 
 impl device_add {
@@ -79,3 +217,154 @@ impl device_add {
         }
     }
 }
+
+/// Accumulates the actions of a `transaction`, which QEMU performs atomically.
+///
+/// ```no_run
+/// # use qapi_qmp::{TransactionBuilder, BlockdevSnapshotSync};
+/// let transaction = TransactionBuilder::new()
+///     .action(BlockdevSnapshotSync {
+///         device: Some("disk0".into()),
+///         node_name: None,
+///         snapshot_file: "/var/lib/snap0.qcow2".into(),
+///         snapshot_node_name: None,
+///         format: None,
+///         mode: None,
+///     })
+///     .build()?;
+/// # Ok::<(), std::io::Error>(())
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct TransactionBuilder {
+    actions: Vec<TransactionAction>,
+    properties: Option<TransactionProperties>,
+}
+
+impl TransactionBuilder {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Appends an action, such as a `BlockdevSnapshotSync` or `DriveBackup`.
+    pub fn action<A: Into<TransactionAction>>(mut self, action: A) -> Self {
+        self.actions.push(action.into());
+        self
+    }
+
+    pub fn actions<I: IntoIterator>(mut self, actions: I) -> Self where
+        I::Item: Into<TransactionAction>,
+    {
+        self.actions.extend(actions.into_iter().map(Into::into));
+        self
+    }
+
+    /// Sets properties that apply to every action in the transaction.
+    pub fn properties<P: Into<TransactionProperties>>(mut self, properties: P) -> Self {
+        self.properties = Some(properties.into());
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.actions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.actions.is_empty()
+    }
+
+    /// Produces the `transaction` command, failing with `InvalidInput` if there are no actions.
+    pub fn build(self) -> io::Result<transaction> {
+        if self.actions.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "QMP transaction requires at least one action"))
+        }
+
+        Ok(transaction {
+            actions: self.actions,
+            properties: self.properties,
+        })
+    }
+}
+
+impl SchemaInfo {
+    pub fn base(&self) -> &SchemaInfoBase {
+        match self {
+            SchemaInfo::alternate { base, .. } => base,
+            SchemaInfo::array { base, .. } => base,
+            SchemaInfo::builtin { base, .. } => base,
+            SchemaInfo::command { base, .. } => base,
+            SchemaInfo::enum_ { base, .. } => base,
+            SchemaInfo::event { base, .. } => base,
+            SchemaInfo::object { base, .. } => base,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.base().name
+    }
+}
+
+/// The schema returned by `query-qmp-schema`, for detecting which commands and events QEMU supports.
+///
+/// Only command and event names are meaningful, the names of types are masked by QEMU.
+#[derive(Debug, Clone)]
+pub struct Schema {
+    infos: Vec<SchemaInfo>,
+    index: HashMap<StdString, usize>,
+}
+
+impl Schema {
+    pub fn new(infos: Vec<SchemaInfo>) -> Self {
+        let index = infos.iter().enumerate()
+            .map(|(i, info)| (info.name().into(), i))
+            .collect();
+
+        Schema {
+            infos,
+            index,
+        }
+    }
+
+    pub fn infos(&self) -> &[SchemaInfo] {
+        &self.infos
+    }
+
+    pub fn get(&self, name: &str) -> Option<&SchemaInfo> {
+        self.index.get(name).map(|&i| &self.infos[i])
+    }
+
+    pub fn command(&self, name: &str) -> Option<&SchemaInfoCommand> {
+        match self.get(name) {
+            Some(SchemaInfo::command { command, .. }) => Some(command),
+            _ => None,
+        }
+    }
+
+    pub fn event(&self, name: &str) -> Option<&SchemaInfoEvent> {
+        match self.get(name) {
+            Some(SchemaInfo::event { event, .. }) => Some(event),
+            _ => None,
+        }
+    }
+
+    pub fn has_command(&self, name: &str) -> bool {
+        self.command(name).is_some()
+    }
+
+    pub fn has_event(&self, name: &str) -> bool {
+        self.event(name).is_some()
+    }
+
+    /// The names of all supported commands.
+    pub fn commands(&self) -> impl Iterator<Item=&str> {
+        self.infos.iter().filter_map(|info| match info {
+            SchemaInfo::command { base, .. } => Some(&base.name[..]),
+            _ => None,
+        })
+    }
+}
+
+impl From<Vec<SchemaInfo>> for Schema {
+    fn from(infos: Vec<SchemaInfo>) -> Self {
+        Self::new(infos)
+    }
+}
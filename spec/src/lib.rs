@@ -141,7 +141,7 @@ pub struct ResponseValue<C> {
     id: Option<Any>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize)]
 #[serde(untagged)]
 pub enum Response<C> {
     Err(Error),
@@ -156,6 +156,13 @@ impl<C> Response<C> {
         }
     }
 
+    pub fn as_result(&self) -> Result<&C, &Error> {
+        match self {
+            Response::Ok(ResponseValue { return_, .. }) => Ok(return_),
+            Response::Err(e) => Err(e),
+        }
+    }
+
     pub fn id(&self) -> Option<&Any> {
         match self {
             Response::Err(err) => err.id.as_ref(),
@@ -164,6 +171,105 @@ impl<C> Response<C> {
     }
 }
 
+mod response_serde {
+    use std::fmt;
+    use std::marker::PhantomData;
+    use serde::{Deserialize, Deserializer};
+    use serde::de::{Error as _, IgnoredAny, MapAccess, Visitor};
+    use crate::{Response, ResponseValue, Error, Any};
+    use crate::error_serde::ErrorValue;
+
+    /// Collects the fields of a response while its map is being visited, so that `return` is
+    /// deserialized straight into `C`.
+    ///
+    /// Also used to parse messages that may turn out to be something else, such as QMP events.
+    #[doc(hidden)]
+    pub struct ResponseFields<C> {
+        return_: Option<C>,
+        error: Option<ErrorValue>,
+        id: Option<Any>,
+        other: Vec<String>,
+    }
+
+    impl<C> Default for ResponseFields<C> {
+        fn default() -> Self {
+            Self {
+                return_: None,
+                error: None,
+                id: None,
+                other: Vec::new(),
+            }
+        }
+    }
+
+    impl<C> ResponseFields<C> {
+        /// Deserializes the value of the field `key` from `map`.
+        pub fn next_value<'de, A: MapAccess<'de>>(&mut self, key: String, map: &mut A) -> Result<(), A::Error> where
+            C: Deserialize<'de>,
+        {
+            match &key[..] {
+                "return" if self.return_.is_some() => return Err(A::Error::duplicate_field("return")),
+                "return" => self.return_ = Some(map.next_value::<C>()?),
+                "error" if self.error.is_some() => return Err(A::Error::duplicate_field("error")),
+                "error" => self.error = Some(map.next_value::<ErrorValue>()?),
+                "id" => self.id = map.next_value::<Option<Any>>()?,
+                _ => {
+                    map.next_value::<IgnoredAny>()?;
+                    self.other.push(key);
+                },
+            }
+            Ok(())
+        }
+
+        pub fn finish<E: serde::de::Error>(self) -> Result<Response<C>, E> {
+            let Self { return_, error, id, other } = self;
+            match (error, return_) {
+                (Some(..), Some(..)) => Err(E::custom("QAPI response had both 'return' and 'error'")),
+                (Some(error), None) => Ok(Response::Err(Error {
+                    class: error.class,
+                    desc: error.desc,
+                    id,
+                })),
+                (None, Some(return_)) => Ok(Response::Ok(ResponseValue {
+                    return_,
+                    id,
+                })),
+                (None, None) if other.is_empty() => Err(E::custom("QAPI response had neither 'return' nor 'error'")),
+                (None, None) => Err(E::custom(format_args!("QAPI response had neither 'return' nor 'error', only {:?}", other))),
+            }
+        }
+    }
+
+    struct ResponseVisitor<C>(PhantomData<fn() -> C>);
+
+    impl<'de, C: Deserialize<'de>> Visitor<'de> for ResponseVisitor<C> {
+        type Value = Response<C>;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("a QAPI response")
+        }
+
+        fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+            let mut fields = ResponseFields::default();
+            while let Some(key) = map.next_key()? {
+                fields.next_value(key, &mut map)?;
+            }
+            fields.finish()
+        }
+    }
+
+    /// Deserializes the `return` value straight into `C`, rather than buffering the whole
+    /// response as `#[serde(untagged)]` would.
+    impl<'de, C: Deserialize<'de>> Deserialize<'de> for Response<C> {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            deserializer.deserialize_map(ResponseVisitor(PhantomData))
+        }
+    }
+}
+
+#[doc(hidden)]
+pub use self::response_serde::ResponseFields;
+
 pub trait Command: Serialize + Sync + Send {
     type Ok: DeserializeOwned;
 
@@ -185,6 +291,12 @@ impl<'a, C: Command> Command for &'a mut C {
     const ALLOW_OOB: bool = C::ALLOW_OOB;
 }
 
+/// A command that may be executed out-of-band with `exec-oob`.
+pub trait OobCommand: Command { }
+
+impl<C: OobCommand> OobCommand for &C { }
+impl<C: OobCommand> OobCommand for &mut C { }
+
 pub trait Event: DeserializeOwned {
     const NAME: &'static str;
 }
@@ -209,7 +321,7 @@ pub unsafe trait Enum: DeserializeOwned + str::FromStr + Copy + 'static {
     const NAMES: &'static [&'static str];
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
 pub enum ErrorClass {
     /// this is used for errors that don’t require a specific error class. This should be the default case for most errors
     GenericError,
@@ -265,6 +377,26 @@ pub struct ExecuteOob<C, I = Any> {
     pub id: I,
 }
 
+/// A command executed by name, for commands that aren't part of the generated schema.
+#[derive(Serialize)]
+pub struct ExecuteRaw<I = Never> {
+    pub execute: String,
+    #[serde(skip_serializing_if = "Any::is_null")]
+    pub arguments: Any,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<I>,
+}
+
+impl<I> ExecuteRaw<I> {
+    pub fn new<N: Into<String>>(name: N, arguments: Any, id: Option<I>) -> Self {
+        Self {
+            execute: name.into(),
+            arguments,
+            id,
+        }
+    }
+}
+
 impl<C: Command, I> Execute<C, I> {
     pub fn new(arguments: C, id: Option<I>) -> Self {
         Self {
@@ -313,6 +445,68 @@ impl error::Error for Error {
     }
 }
 
+impl Error {
+    pub fn class(&self) -> ErrorClass {
+        self.class
+    }
+
+    /// Whether the error reports a missing device or block node.
+    ///
+    /// QEMU often reports these as a `GenericError`, so this falls back to recognizing the
+    /// wording of `desc`, which is a heuristic that may miss messages from other QEMU versions.
+    pub fn is_device_not_found(&self) -> bool {
+        self.class == ErrorClass::DeviceNotFound || self.device_not_found().is_some()
+    }
+
+    /// The name of the missing device or block node, guessed from `desc`.
+    ///
+    /// Recognizes messages like `Device 'x' not found` and `Cannot find device='x' nor node-name='y'`.
+    pub fn device_not_found(&self) -> Option<&str> {
+        desc_name(&self.desc, "Device ", " not found")
+            .or_else(|| desc_name(&self.desc, "Device ", " has not been found"))
+            .or_else(|| desc_name(&self.desc, "Cannot find device=", " nor node"))
+            .or_else(|| desc_name(&self.desc, "Node ", " not found"))
+            .or_else(|| desc_name(&self.desc, "Block node ", " not found"))
+    }
+
+    /// The ID that is already in use, guessed from `desc` messages like `Duplicate ID 'x' for device`.
+    pub fn duplicate_id(&self) -> Option<&str> {
+        desc_name(&self.desc, "Duplicate ID ", " for ")
+            .or_else(|| desc_name(&self.desc, "Duplicate device ID ", ""))
+    }
+
+    /// The name of a required argument that wasn't given, guessed from `desc` messages like
+    /// `Parameter 'x' is missing`.
+    pub fn missing_parameter(&self) -> Option<&str> {
+        desc_name(&self.desc, "Parameter ", " is missing")
+    }
+
+    /// The name of an argument with an unacceptable value, guessed from `desc` messages like
+    /// `Invalid parameter type for 'x', expected: y` or `Parameter 'x' expects y`.
+    pub fn invalid_parameter(&self) -> Option<&str> {
+        desc_name(&self.desc, "Invalid parameter type for ", ", expected")
+            .or_else(|| desc_name(&self.desc, "Parameter ", " expects "))
+    }
+}
+
+/// Extracts the name between `prefix` and `suffix` in `desc`, stripping the quotes QEMU usually adds.
+///
+/// An empty `suffix` matches the end of `desc`.
+fn desc_name<'a>(desc: &'a str, prefix: &str, suffix: &str) -> Option<&'a str> {
+    let rest = desc.strip_prefix(prefix)?;
+    let ends_name = |tail: &str| if suffix.is_empty() { tail.is_empty() } else { tail.starts_with(suffix) };
+    match rest.strip_prefix('\'') {
+        Some(quoted) => quoted.match_indices('\'')
+            .map(|(i, _)| i)
+            .find(|&i| ends_name(&quoted[i + 1..]))
+            .map(|i| &quoted[..i]),
+        None => match suffix {
+            "" => Some(rest),
+            suffix => rest.find(suffix).map(|i| &rest[..i]),
+        },
+    }.filter(|name| !name.is_empty())
+}
+
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         fmt::Display::fmt(&self.desc, f)
@@ -325,8 +519,47 @@ impl From<Error> for io::Error {
     }
 }
 
-#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct Timestamp {
     seconds: u64,
     microseconds: u64,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{Any, Error, ErrorClass, Response};
+
+    fn generic(desc: &str) -> Error {
+        Error {
+            class: ErrorClass::GenericError,
+            desc: desc.into(),
+            id: None,
+        }
+    }
+
+    #[test]
+    fn desc_patterns() {
+        assert_eq!(generic("Device 'virtio-disk0' not found").device_not_found(), Some("virtio-disk0"));
+        assert_eq!(generic("Cannot find device='drive0' nor node-name='node0'").device_not_found(), Some("drive0"));
+        assert_eq!(generic("Cannot find device=drive0 nor node_name=node0").device_not_found(), Some("drive0"));
+        assert!(!generic("Device 'x' is in use").is_device_not_found());
+        assert_eq!(generic("Duplicate ID 'net0' for device").duplicate_id(), Some("net0"));
+        assert_eq!(generic("Parameter 'driver' is missing").missing_parameter(), Some("driver"));
+        assert_eq!(generic("Invalid parameter type for 'size', expected: integer").invalid_parameter(), Some("size"));
+        assert_eq!(generic("Parameter 'it's' expects a number").invalid_parameter(), Some("it's"));
+    }
+
+    #[test]
+    fn response_missing_result() {
+        let e = serde_json::from_str::<Response<Any>>(r#"{"id": 1, "retrun": {}}"#).unwrap_err();
+        assert!(e.to_string().contains(r#"neither 'return' nor 'error', only ["retrun"]"#), "{}", e);
+        let e = serde_json::from_str::<Response<Any>>(r#"{}"#).unwrap_err();
+        assert!(e.to_string().contains("neither 'return' nor 'error'"), "{}", e);
+    }
+
+    #[test]
+    fn response_ambiguous_result() {
+        let e = serde_json::from_str::<Response<Any>>(r#"{"return": {}, "error": {"class": "GenericError", "desc": "x"}}"#).unwrap_err();
+        assert!(e.to_string().contains("both 'return' and 'error'"), "{}", e);
+    }
+}